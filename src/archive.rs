@@ -0,0 +1,251 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zip::ZipArchive;
+
+use crate::path_case::PathCase;
+
+// Failed bundles whose delivered entries are remembered at most
+const MAX_TRACKED_BUNDLES: usize = 1000;
+
+/// Limits applied while extracting an archive, to protect against zip bombs.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractionLimits {
+    pub max_entries: usize,
+    pub max_total_bytes: u64,
+}
+
+/// An XML document extracted from an archive.
+pub struct ArchiveEntry {
+    // Path of the entry inside the archive
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// The XML documents of a bundle, and a digest of the bundle file that tells
+/// whether a retry is for the same bundle.
+pub struct Bundle {
+    pub digest: [u8; 32],
+    pub entries: Vec<ArchiveEntry>,
+}
+
+/// Whether `path` has one of the BUNDLE_EXTENSIONS, given in lower case.
+pub fn is_bundle(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|bundle| bundle.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// Read every `.xml` entry of a zip archive into memory.
+///
+/// Fails without returning any entry when the archive has more entries than
+/// allowed or when the decompressed XML exceeds the size budget. The budget is
+/// enforced on the bytes actually inflated, not on the sizes the archive claims.
+pub fn extract_xml_entries(path: &Path, limits: ExtractionLimits) -> Result<Bundle, String> {
+    let mut file = File::open(path).map_err(|e| format!("failed to open archive: {}", e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| format!("failed to read archive: {}", e))?;
+    file.rewind().map_err(|e| format!("failed to read archive: {}", e))?;
+    let digest = hasher.finalize().into();
+    let mut archive = ZipArchive::new(file).map_err(|e| format!("failed to read archive: {}", e))?;
+
+    if archive.len() > limits.max_entries {
+        return Err(format!(
+            "archive has {} entries, more than the allowed {}",
+            archive.len(),
+            limits.max_entries
+        ));
+    }
+
+    let mut entries = Vec::new();
+    let mut total_bytes: u64 = 0;
+    for index in 0..archive.len() {
+        let entry = archive
+            .by_index(index)
+            .map_err(|e| format!("failed to read archive entry {}: {}", index, e))?;
+        let name = entry
+            .name()
+            .map_err(|e| format!("failed to read name of archive entry {}: {}", index, e))?
+            .into_owned();
+        if entry.is_dir() || !crate::is_xml_file(Path::new(&name)) {
+            continue;
+        }
+
+        let remaining = limits.max_total_bytes - total_bytes;
+        let mut data = Vec::new();
+        // Read one byte past the budget so that overruns are detected
+        entry
+            .take(remaining + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| format!("failed to extract '{}': {}", name, e))?;
+        total_bytes += data.len() as u64;
+        if total_bytes > limits.max_total_bytes {
+            return Err(format!(
+                "decompressed XML exceeds the allowed {} bytes",
+                limits.max_total_bytes
+            ));
+        }
+
+        entries.push(ArchiveEntry { name, data });
+    }
+    Ok(Bundle { digest, entries })
+}
+
+/// The entries already delivered from bundles that didn't get through whole,
+/// so that retrying one of them sends only the entries that failed.
+///
+/// A bundle is known by its path and digest: a different file at the same
+/// path is delivered in full. Kept in memory only, so the first retry after
+/// a restart sends every entry again.
+pub struct BundleProgress {
+    bundles: Mutex<HashMap<PathBuf, Progress>>,
+    case: PathCase,
+}
+
+struct Progress {
+    digest: [u8; 32],
+    delivered: HashSet<String>,
+}
+
+impl BundleProgress {
+    pub fn new(case: PathCase) -> Self {
+        BundleProgress {
+            bundles: Mutex::default(),
+            case,
+        }
+    }
+    
+    /// Names of the entries of the bundle at `path` with `digest` that were
+    /// delivered before.
+    pub fn delivered(&self, path: &Path, digest: [u8; 32]) -> HashSet<String> {
+        match self.bundles.lock().unwrap().get(&self.case.key(path)) {
+            Some(progress) if progress.digest == digest => progress.delivered.clone(),
+            _ => HashSet::new(),
+        }
+    }
+
+    /// Remember the entries delivered from a bundle that had failures.
+    pub fn record(&self, path: &Path, digest: [u8; 32], delivered: HashSet<String>) {
+        let key = self.case.key(path);
+        let mut bundles = self.bundles.lock().unwrap();
+        if bundles.len() >= MAX_TRACKED_BUNDLES && !bundles.contains_key(&key) {
+            // Any entry will do: the cost of losing one is a repeated delivery
+            if let Some(evicted) = bundles.keys().next().cloned() {
+                bundles.remove(&evicted);
+            }
+        }
+        bundles.insert(key, Progress { digest, delivered });
+    }
+
+    /// Forget a bundle once every entry has been delivered.
+    pub fn forget(&self, path: &Path) {
+        self.bundles.lock().unwrap().remove(&self.case.key(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const LIMITS: ExtractionLimits = ExtractionLimits { max_entries: 10, max_total_bytes: 1024 };
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, data) in entries {
+            if name.ends_with('/') {
+                zip.add_directory(*name, options).unwrap();
+            } else {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(data).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn only_xml_entries_are_extracted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.zip");
+        write_zip(&path, &[
+            ("orders/", b""),
+            ("orders/a.xml", b"<a/>"),
+            ("orders/B.XML", b"<b/>"),
+            ("readme.txt", b"not xml"),
+            ("c.xml.bak", b"<c/>"),
+        ]);
+        let bundle = extract_xml_entries(&path, LIMITS).unwrap();
+        let names: Vec<&str> = bundle.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["orders/a.xml", "orders/B.XML"]);
+        assert_eq!(bundle.entries[1].data, b"<b/>");
+        assert_eq!(bundle.digest, <[u8; 32]>::from(Sha256::digest(std::fs::read(&path).unwrap())));
+    }
+
+    #[test]
+    fn archives_with_too_many_entries_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.zip");
+        let names: Vec<String> = (0..11).map(|i| format!("{}.txt", i)).collect();
+        let entries: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), b"".as_slice())).collect();
+        write_zip(&path, &entries);
+        // Entries that aren't XML count too
+        let error = extract_xml_entries(&path, LIMITS).err().unwrap();
+        assert_eq!(error, "archive has 11 entries, more than the allowed 10");
+    }
+
+    #[test]
+    fn decompressed_bytes_are_held_to_the_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bomb.zip");
+        let zeros = vec![0u8; 1024 * 1024];
+        write_zip(&path, &[("bomb.xml", &zeros)]);
+        assert!(std::fs::metadata(&path).unwrap().len() < 10 * 1024);
+        let error = extract_xml_entries(&path, LIMITS).err().unwrap();
+        assert_eq!(error, "decompressed XML exceeds the allowed 1024 bytes");
+
+        // The budget covers all entries together, and exactly reaching it is fine
+        let half = vec![b'x'; 512];
+        write_zip(&path, &[("a.xml", &half), ("b.xml", &half)]);
+        assert_eq!(extract_xml_entries(&path, LIMITS).unwrap().entries.len(), 2);
+        write_zip(&path, &[("a.xml", &half), ("b.xml", &half), ("c.xml", b"<c/>")]);
+        assert!(extract_xml_entries(&path, LIMITS).is_err());
+    }
+
+    #[test]
+    fn files_that_arent_archives_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fake.zip");
+        std::fs::write(&path, "<a/>").unwrap();
+        assert!(extract_xml_entries(&path, LIMITS).err().unwrap().starts_with("failed to read archive"));
+        assert!(extract_xml_entries(&dir.path().join("missing.zip"), LIMITS).is_err());
+    }
+
+    #[test]
+    fn bundle_extensions_match_case_insensitively() {
+        let extensions = vec!["zip".to_string(), "xmlz".to_string()];
+        assert!(is_bundle(Path::new("/watch/a.ZIP"), &extensions));
+        assert!(is_bundle(Path::new("/watch/a.xmlz"), &extensions));
+        assert!(!is_bundle(Path::new("/watch/a.zip.tmp"), &extensions));
+        assert!(!is_bundle(Path::new("/watch/zip"), &extensions));
+    }
+
+    #[test]
+    fn retries_of_the_same_bundle_skip_delivered_entries() {
+        let progress = BundleProgress::new(PathCase::Sensitive);
+        let path = Path::new("/watch/bundle.zip");
+        let delivered: HashSet<String> = ["a.xml".to_string()].into();
+        progress.record(path, [1; 32], delivered.clone());
+        assert_eq!(progress.delivered(path, [1; 32]), delivered);
+        // A different file at the same path starts over
+        assert!(progress.delivered(path, [2; 32]).is_empty());
+        progress.forget(path);
+        assert!(progress.delivered(path, [1; 32]).is_empty());
+    }
+}
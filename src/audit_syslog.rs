@@ -0,0 +1,171 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use syslog::{Facility, Formatter5424, Logger, LoggerBackend};
+
+use crate::outcomes::OutcomeRecord;
+
+// Records waiting to be written; further ones are dropped
+const MAX_PENDING_RECORDS: usize = 10_000;
+
+// RFC 5424 SD-ID of the delivery fields. 32473 is the enterprise number
+// reserved for documentation (RFC 5612), as no number is registered for us.
+const SD_ID: &str = "delivery@32473";
+
+// SD-ID to parameters, as the RFC 5424 formatter takes them
+type StructuredData = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Where SYSLOG_ADDR sends records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTarget {
+    // The local daemon's socket (/dev/log or /var/run/syslog)
+    Local,
+    Udp(String),
+    Tcp(String),
+}
+
+impl SyslogTarget {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value.eq_ignore_ascii_case("local") {
+            return Ok(SyslogTarget::Local);
+        }
+        let (scheme, address) = value.split_once("://").unwrap_or(("udp", value));
+        if !address.contains(':') {
+            return Err(format!("Invalid SYSLOG_ADDR '{}': expected 'local' or host:port", value));
+        }
+        match scheme.to_lowercase().as_str() {
+            "udp" => Ok(SyslogTarget::Udp(address.to_string())),
+            "tcp" => Ok(SyslogTarget::Tcp(address.to_string())),
+            other => Err(format!("Invalid SYSLOG_ADDR '{}': unknown scheme '{}', expected udp or tcp", value, other)),
+        }
+    }
+
+    fn connect(&self, formatter: Formatter5424) -> Result<Logger<LoggerBackend, Formatter5424>, String> {
+        let logger = match self {
+            SyslogTarget::Local => syslog::unix(formatter),
+            SyslogTarget::Udp(address) => {
+                let local = if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+                syslog::udp(formatter, local, address.as_str())
+            }
+            SyslogTarget::Tcp(address) => syslog::tcp(formatter, address.as_str()),
+        };
+        logger.map_err(|e| format!("failed to connect to syslog at {}: {}", self, e))
+    }
+}
+
+impl fmt::Display for SyslogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyslogTarget::Local => write!(f, "local"),
+            SyslogTarget::Udp(address) => write!(f, "udp://{}", address),
+            SyslogTarget::Tcp(address) => write!(f, "tcp://{}", address),
+        }
+    }
+}
+
+/// Writes one RFC 5424 record per delivery outcome to syslog, on top of the
+/// normal log.
+///
+/// Records are written from a thread of their own, so a slow syslog server
+/// never holds up deliveries. A failed write is logged and the connection
+/// is re-established for the next record.
+pub struct SyslogAudit {
+    sender: SyncSender<OutcomeRecord>,
+    prefix: String,
+}
+
+impl SyslogAudit {
+    /// Connect to `target`. Fails when the first connection can't be made.
+    pub fn start(target: SyslogTarget, facility: Facility, prefix: String) -> Result<Self, String> {
+        let formatter = Formatter5424 {
+            facility,
+            hostname: hostname(),
+            process: env!("CARGO_PKG_NAME").to_string(),
+            pid: std::process::id(),
+        };
+        let logger = target.connect(formatter.clone())?;
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_RECORDS);
+        let thread_prefix = prefix.clone();
+        std::thread::spawn(move || write_records(logger, target, formatter, receiver, thread_prefix));
+        Ok(SyslogAudit { sender, prefix })
+    }
+
+    pub fn record(&self, record: OutcomeRecord) {
+        if self.sender.try_send(record).is_err() {
+            warn!("{}Too many syslog records pending, dropping one", self.prefix);
+        }
+    }
+}
+
+fn write_records(
+    logger: Logger<LoggerBackend, Formatter5424>,
+    target: SyslogTarget,
+    formatter: Formatter5424,
+    records: Receiver<OutcomeRecord>,
+    prefix: String,
+) {
+    let mut logger = Some(logger);
+    for record in records {
+        let message = || (0, structured_data(&record), format!("{} {}", record.outcome, record.path));
+        let result = match &mut logger {
+            Some(logger) => write(logger, record.outcome, message()),
+            None => target.connect(formatter.clone()).and_then(|mut connected| {
+                let result = write(&mut connected, record.outcome, message());
+                logger = Some(connected);
+                result
+            }),
+        };
+        if let Err(e) = result {
+            warn!("{}Failed to write syslog record for {}: {}", prefix, record.path, e);
+            logger = None;
+        }
+    }
+}
+
+fn write(
+    logger: &mut Logger<LoggerBackend, Formatter5424>,
+    outcome: &str,
+    message: (u32, StructuredData, String),
+) -> Result<(), String> {
+    let result = match outcome {
+        "delivered" | "skipped" | "suppressed" | "cached" => logger.info(message),
+        _ => logger.warning(message),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn structured_data(record: &OutcomeRecord) -> StructuredData {
+    let mut fields = BTreeMap::new();
+    fields.insert("event_id".to_string(), record.event_id.clone());
+    if let Some(profile) = &record.profile {
+        fields.insert("profile".to_string(), profile.clone());
+    }
+    fields.insert("path".to_string(), record.path.clone());
+    fields.insert("outcome".to_string(), record.outcome.to_string());
+    if let Some(reason) = record.reason {
+        fields.insert("reason".to_string(), reason.to_string());
+    }
+    if let Some(kind) = record.failure_kind {
+        fields.insert("failure_kind".to_string(), kind.to_string());
+    }
+    if let Some(policy) = record.content_policy {
+        fields.insert("content_policy".to_string(), policy.to_string());
+    }
+    if let Some(status) = record.status {
+        fields.insert("status".to_string(), status.to_string());
+    }
+    fields.insert("attempts".to_string(), record.attempts.to_string());
+    fields.insert("duration_ms".to_string(), record.duration_ms.to_string());
+    fields.insert("detected_at".to_string(), record.detected_at.clone());
+    fields.insert("completed_at".to_string(), record.completed_at.clone());
+    BTreeMap::from([(SD_ID.to_string(), fields)])
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
@@ -0,0 +1,112 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// When a batch is sent, BATCH_SIZE, BATCH_IDLE_MS and BATCH_MAX_WAIT_MS.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchPolicy {
+    pub size: usize,
+    // Without new files for this long, what is waiting is sent
+    pub idle: Duration,
+    // No file waits longer than this, however busy the directory is
+    pub max_wait: Duration,
+}
+
+/// Which trigger sent a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushTrigger {
+    Size,
+    Idle,
+    MaxWait,
+}
+
+impl FlushTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlushTrigger::Size => "size",
+            FlushTrigger::Idle => "idle",
+            FlushTrigger::MaxWait => "max_wait",
+        }
+    }
+}
+
+struct Pending<T> {
+    // With the time each was added
+    items: Vec<(Instant, T)>,
+    last_activity: Instant,
+}
+
+/// Collects items and hands them out in batches: as soon as `size` are
+/// waiting, once nothing has happened for `idle`, or once the oldest has
+/// waited `max_wait`, whichever comes first.
+pub struct Batcher<T> {
+    policy: BatchPolicy,
+    pending: Mutex<Pending<T>>,
+    changed: Notify,
+}
+
+impl<T> Batcher<T> {
+    pub fn new(policy: BatchPolicy) -> Self {
+        Batcher {
+            policy,
+            pending: Mutex::new(Pending { items: Vec::new(), last_activity: Instant::now() }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Note activity that may bring more items soon, which holds off the idle
+    /// trigger.
+    pub fn touch(&self) {
+        self.pending.lock().unwrap().last_activity = Instant::now();
+        self.changed.notify_one();
+    }
+
+    pub fn add(&self, item: T) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.items.push((now, item));
+        pending.last_activity = now;
+        drop(pending);
+        self.changed.notify_one();
+    }
+
+    /// Wait for the next batch, in the order the items were added.
+    pub async fn next(&self) -> (Vec<T>, FlushTrigger) {
+        loop {
+            let deadline = {
+                let mut pending = self.pending.lock().unwrap();
+                let now = Instant::now();
+                match pending.items.first() {
+                    None => None,
+                    Some(_) if pending.items.len() >= self.policy.size => {
+                        let batch = pending.items.drain(..self.policy.size).map(|(_, item)| item).collect();
+                        return (batch, FlushTrigger::Size);
+                    }
+                    Some((oldest, _)) => {
+                        let idle_at = pending.last_activity + self.policy.idle;
+                        let max_wait_at = *oldest + self.policy.max_wait;
+                        let trigger = match (max_wait_at <= now, idle_at <= now) {
+                            (true, _) => Some(FlushTrigger::MaxWait),
+                            (false, true) => Some(FlushTrigger::Idle),
+                            (false, false) => None,
+                        };
+                        if let Some(trigger) = trigger {
+                            let batch = pending.items.drain(..).map(|(_, item)| item).collect();
+                            return (batch, trigger);
+                        }
+                        Some(idle_at.min(max_wait_at))
+                    }
+                }
+            };
+            match deadline {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline.into()) => {}
+                        _ = self.changed.notified() => {}
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use regex::Regex;
+use std::fmt;
+
+use crate::xml_path::XmlPath;
+
+/// A check a webhook response body must pass, on top of a 2xx status, for the
+/// delivery to count as a success.
+///
+/// Written as `json:<pointer>=<value>`, `xpath:<path>=<value>` or `regex:<pattern>`.
+/// The pointer of a `json:` check can also be a simple JSONPath, `$.result.status`.
+#[derive(Debug, Clone)]
+pub enum BodyMatch {
+    // RFC 6901 pointer into a JSON body and the value expected there, with
+    // the pointer or JSONPath as written
+    Json { path: String, pointer: String, expected: serde_json::Value },
+    // Path into an XML body and the text expected there
+    XPath { path: XmlPath, expected: String },
+    // Pattern that must match somewhere in the raw body
+    Regex(Regex),
+}
+
+impl BodyMatch {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = |reason: String| format!("Invalid SUCCESS_BODY_MATCH '{}': {}", value, reason);
+        let (kind, rule) = value
+            .split_once(':')
+            .ok_or_else(|| invalid("expected 'json:', 'xpath:' or 'regex:' followed by the rule".to_string()))?;
+
+        match kind.trim().to_lowercase().as_str() {
+            "json" => {
+                let (pointer, expected) = rule
+                    .split_once('=')
+                    .ok_or_else(|| invalid("expected <pointer>=<value>".to_string()))?;
+                let path = pointer.to_string();
+                let pointer = if path.starts_with('$') {
+                    pointer_of_json_path(&path).map_err(invalid)?
+                } else if path.is_empty() || path.starts_with('/') {
+                    path.clone()
+                } else {
+                    return Err(invalid(format!("JSON pointer '{}' must start with '/', or '$' for a JSONPath", path)));
+                };
+                // `true`, `1` or `"x"` are JSON; anything else is taken as a plain string
+                let expected = serde_json::from_str(expected)
+                    .unwrap_or_else(|_| serde_json::Value::String(expected.to_string()));
+                Ok(BodyMatch::Json { path, pointer, expected })
+            }
+            "xpath" => {
+                let (path, expected) = rule
+                    .split_once('=')
+                    .ok_or_else(|| invalid("expected <path>=<value>".to_string()))?;
+                let path = XmlPath::parse(path).map_err(invalid)?;
+                Ok(BodyMatch::XPath { path, expected: expected.to_string() })
+            }
+            "regex" => Regex::new(rule)
+                .map(BodyMatch::Regex)
+                .map_err(|e| invalid(e.to_string())),
+            other => Err(invalid(format!("unknown kind '{}'", other))),
+        }
+    }
+
+    /// Check a response body. Both results describe what was found, for the log.
+    pub fn check(&self, content_type: &str, body: &[u8]) -> Result<String, String> {
+        match self {
+            BodyMatch::Json { path, pointer, expected } => {
+                if !content_type.contains("json") {
+                    return Err(format!("expected a JSON response, got '{}'", content_type));
+                }
+                let document: serde_json::Value =
+                    serde_json::from_slice(body).map_err(|e| format!("response is not valid JSON: {}", e))?;
+                match document.pointer(pointer) {
+                    Some(found) if found == expected => Ok(format!("{} is {}", path, found)),
+                    Some(found) => Err(format!("{} is {}, expected {}", path, found, expected)),
+                    None => Err(format!("{} not found in response", path)),
+                }
+            }
+            BodyMatch::XPath { path, expected } => {
+                if !content_type.contains("xml") {
+                    return Err(format!("expected an XML response, got '{}'", content_type));
+                }
+                let found = path
+                    .find_in(body)
+                    .map_err(|e| format!("response is {}", e))?
+                    .ok_or_else(|| format!("{} not found in response", path))?;
+                if found.trim() == expected {
+                    Ok(format!("{} is '{}'", path, expected))
+                } else {
+                    Err(format!("{} is '{}', expected '{}'", path, found.trim(), expected))
+                }
+            }
+            BodyMatch::Regex(pattern) => {
+                let text = String::from_utf8_lossy(body);
+                match pattern.find(&text) {
+                    Some(found) => Ok(format!("/{}/ matched '{}'", pattern, found.as_str())),
+                    None => Err(format!("/{}/ did not match", pattern)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for BodyMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyMatch::Json { path, expected, .. } => write!(f, "json:{}={}", path, expected),
+            BodyMatch::XPath { path, expected } => write!(f, "xpath:{}={}", path, expected),
+            BodyMatch::Regex(pattern) => write!(f, "regex:{}", pattern),
+        }
+    }
+}
+
+// The JSON pointer of a JSONPath made of member names and array indices:
+// `$`, `.name`, `['name']` and `[0]`. Filters, wildcards and recursive descent
+// can select more than one value, so they aren't accepted.
+fn pointer_of_json_path(path: &str) -> Result<String, String> {
+    let unsupported = |reason: &str| format!("JSONPath '{}' {}", path, reason);
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut pointer = String::new();
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            return Err(unsupported("uses recursive descent, which isn't supported"));
+        }
+        let segment;
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            segment = &after[..end];
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(|| unsupported("has an unclosed '['"))?;
+            let inner = after[..end].trim();
+            segment = match inner.strip_prefix('\'').or_else(|| inner.strip_prefix('"')) {
+                Some(quoted) => &quoted[..quoted.len().saturating_sub(1)],
+                None if !inner.is_empty() && inner.bytes().all(|b| b.is_ascii_digit()) => inner,
+                None => return Err(unsupported("may only have names and indices in brackets")),
+            };
+            rest = &after[end + 1..];
+        } else {
+            return Err(unsupported("must continue with '.' or '[' after each step"));
+        }
+        if segment.is_empty() || segment == "*" {
+            return Err(unsupported("has an empty step or a wildcard"));
+        }
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+    Ok(pointer)
+}
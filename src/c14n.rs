@@ -0,0 +1,173 @@
+use roxmltree::{Document, Node, NodeType};
+
+use crate::xml_path;
+
+/// Canonicalize an XML document with Canonical XML 1.0, omitting comments
+/// (`http://www.w3.org/TR/2001/REC-xml-c14n-20010315`).
+///
+/// The XML declaration and DTD are dropped, empty elements become start/end
+/// pairs, attributes and namespace declarations are sorted and double-quoted,
+/// superfluous namespace declarations are removed and character references
+/// are replaced by the characters (and back, where c14n requires it).
+/// Documents whose DTD declares attributes are refused, since the defaults
+/// it may supply aren't added.
+pub fn canonicalize(text: &str) -> Result<String, String> {
+    let document = xml_path::parse_document(text)?;
+    let root = document.root_element();
+    if text[..root.range().start].contains("<!ATTLIST") {
+        return Err("attribute declarations in a DTD are not supported".to_string());
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut before_root = true;
+    for node in document.root().children() {
+        match node.node_type() {
+            NodeType::Element => {
+                write_element(&document, node, &mut out);
+                before_root = false;
+            }
+            NodeType::PI if before_root => {
+                write_pi(node, &mut out);
+                out.push('\n');
+            }
+            NodeType::PI => {
+                out.push('\n');
+                write_pi(node, &mut out);
+            }
+            // Comments and whitespace outside the document element are dropped
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+fn write_element(document: &Document, node: Node, out: &mut String) {
+    let name = qualified_name(document, node.range().start + 1, node.tag_name().name(), || {
+        node.tag_name().namespace().and_then(|uri| node.lookup_prefix(uri))
+    });
+    out.push('<');
+    out.push_str(&name);
+
+    for (prefix, uri) in namespace_declarations(node) {
+        match prefix {
+            Some(prefix) => out.push_str(&format!(" xmlns:{}=\"", prefix)),
+            None => out.push_str(" xmlns=\""),
+        }
+        escape_attribute(uri, out);
+        out.push('"');
+    }
+
+    let mut attributes: Vec<_> = node.attributes().collect();
+    attributes.sort_by_key(|attribute| (attribute.namespace().unwrap_or(""), attribute.name()));
+    for attribute in attributes {
+        let name = qualified_name(document, attribute.range_qname().start, attribute.name(), || {
+            attribute.namespace().and_then(|uri| node.lookup_prefix(uri))
+        });
+        out.push(' ');
+        out.push_str(&name);
+        out.push_str("=\"");
+        escape_attribute(attribute.value(), out);
+        out.push('"');
+    }
+    out.push('>');
+
+    for child in node.children() {
+        match child.node_type() {
+            NodeType::Element => write_element(document, child, out),
+            NodeType::Text => escape_text(child.text().unwrap_or(""), out),
+            NodeType::PI => write_pi(child, out),
+            _ => {}
+        }
+    }
+
+    out.push_str("</");
+    out.push_str(&name);
+    out.push('>');
+}
+
+// The name as written in the document, prefix included. Falls back to the
+// prefix found by `lookup` when the source text isn't the name itself, as for
+// nodes from entity expansion.
+fn qualified_name<'a>(
+    document: &'a Document,
+    start: usize,
+    local: &'a str,
+    lookup: impl FnOnce() -> Option<&'a str>,
+) -> String {
+    let written: String = document.input_text()[start..]
+        .chars()
+        .take_while(|c| !c.is_whitespace() && !matches!(c, '/' | '>' | '='))
+        .collect();
+    if written == local || written.ends_with(&format!(":{}", local)) {
+        return written;
+    }
+    match lookup() {
+        Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, local),
+        _ => local.to_string(),
+    }
+}
+
+// Namespace declarations of `node` that differ from its parent element's,
+// sorted with the default namespace first, then by prefix
+fn namespace_declarations<'a>(node: Node<'a, '_>) -> Vec<(Option<&'a str>, &'a str)> {
+    let parent = node.parent_element();
+    let in_parent = |prefix: Option<&str>| -> &str {
+        parent
+            .and_then(|parent| parent.namespaces().find(|ns| ns.name() == prefix))
+            .map(|ns| ns.uri())
+            .unwrap_or("")
+    };
+
+    let mut declarations = Vec::new();
+    let default = node.namespaces().find(|ns| ns.name().is_none()).map(|ns| ns.uri()).unwrap_or("");
+    if default != in_parent(None) {
+        declarations.push((None, default));
+    }
+    let mut prefixed: Vec<_> = node
+        .namespaces()
+        .filter_map(|ns| Some((ns.name()?, ns.uri())))
+        .filter(|(prefix, uri)| *prefix != "xml" && in_parent(Some(prefix)) != *uri)
+        .collect();
+    prefixed.sort();
+    declarations.extend(prefixed.into_iter().map(|(prefix, uri)| (Some(prefix), uri)));
+    declarations
+}
+
+fn write_pi(node: Node, out: &mut String) {
+    let Some(pi) = node.pi() else {
+        return;
+    };
+    out.push_str("<?");
+    out.push_str(pi.target);
+    if let Some(value) = pi.value.filter(|value| !value.is_empty()) {
+        out.push(' ');
+        out.push_str(value);
+    }
+    out.push_str("?>");
+}
+
+fn escape_text(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn escape_attribute(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::write_guard::write_atomically;
+
+/// Bounds on what the startup scan delivers after downtime, CATCHUP_MAX_FILES
+/// and CATCHUP_MAX_AGE_SECS. What they leave out is listed in the report, and
+/// moved to CATCHUP_DEFER_DIR when that is set.
+#[derive(Debug, Clone)]
+pub struct CatchupPolicy {
+    // The newest this many files are delivered
+    pub max_files: Option<usize>,
+    // Files modified longer than this before startup are left out
+    pub max_age: Option<Duration>,
+    pub report: PathBuf,
+    pub defer_dir: Option<PathBuf>,
+}
+
+/// Why the startup scan didn't deliver a file it found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeftOutReason {
+    TooOld,
+    OverMaxFiles,
+}
+
+/// What the startup scan delivers of the files it found, and what it leaves
+/// out, both oldest first.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CatchupPlan {
+    pub deliver: Vec<(PathBuf, SystemTime)>,
+    pub left_out: Vec<(PathBuf, SystemTime, LeftOutReason)>,
+}
+
+/// Split the scanned `files`, with their modification times, by `policy`, for
+/// a scan at `now`. Of the files young enough, the newest are delivered.
+pub fn plan(policy: &CatchupPolicy, mut files: Vec<(PathBuf, SystemTime)>, now: SystemTime) -> CatchupPlan {
+    files.sort_by(|(a, a_modified), (b, b_modified)| a_modified.cmp(b_modified).then_with(|| a.cmp(b)));
+    let oldest = policy.max_age.and_then(|age| now.checked_sub(age));
+    let mut plan = CatchupPlan::default();
+    for (path, modified) in files {
+        match oldest {
+            Some(oldest) if modified < oldest => plan.left_out.push((path, modified, LeftOutReason::TooOld)),
+            _ => plan.deliver.push((path, modified)),
+        }
+    }
+    if let Some(max_files) = policy.max_files {
+        let excess = plan.deliver.len().saturating_sub(max_files);
+        plan.left_out
+            .extend(plan.deliver.drain(..excess).map(|(path, modified)| (path, modified, LeftOutReason::OverMaxFiles)));
+        plan.left_out.sort_by_key(|(_, modified, _)| *modified);
+    }
+    plan
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    scanned_at: DateTime<Utc>,
+    max_files: Option<usize>,
+    max_age_secs: Option<u64>,
+    delivered: usize,
+    left_out: Vec<ReportEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct ReportEntry<'a> {
+    path: &'a Path,
+    modified: DateTime<Utc>,
+    reason: LeftOutReason,
+    // Where the file was moved, with CATCHUP_DEFER_DIR
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moved_to: Option<&'a Path>,
+}
+
+/// Write the report of `plan` to the policy's report file, whether or not
+/// anything was left out. `moved` has the CATCHUP_DEFER_DIR location of each
+/// file left out that was moved there, in the order of `plan.left_out`.
+pub fn write_report(policy: &CatchupPolicy, plan: &CatchupPlan, moved: &[Option<PathBuf>]) -> Result<(), String> {
+    let report = Report {
+        scanned_at: Utc::now(),
+        max_files: policy.max_files,
+        max_age_secs: policy.max_age.map(|age| age.as_secs()),
+        delivered: plan.deliver.len(),
+        left_out: plan
+            .left_out
+            .iter()
+            .enumerate()
+            .map(|(index, (path, modified, reason))| ReportEntry {
+                path,
+                modified: DateTime::<Utc>::from(*modified),
+                reason: *reason,
+                moved_to: moved.get(index).and_then(Option::as_deref),
+            })
+            .collect(),
+    };
+    if let Some(parent) = policy.report.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+    }
+    let json = serde_json::to_vec_pretty(&report).map_err(|e| e.to_string())?;
+    write_atomically(&policy.report, &json)
+}
+
+/// How far the delivery of the startup scan has got, for `GET /catchup`.
+#[derive(Debug, Default)]
+pub struct CatchupProgress {
+    total: AtomicUsize,
+    done: AtomicUsize,
+    left_out: AtomicUsize,
+}
+
+impl CatchupProgress {
+    pub fn planned(&self, plan: &CatchupPlan) {
+        self.total.store(plan.deliver.len(), Ordering::Relaxed);
+        self.left_out.store(plan.left_out.len(), Ordering::Relaxed);
+    }
+
+    /// One more file of the scan was handed to delivery.
+    pub fn advance(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        let total = self.total.load(Ordering::Relaxed);
+        let done = self.done.load(Ordering::Relaxed);
+        serde_json::json!({
+            "total": total,
+            "done": done,
+            "remaining": total.saturating_sub(done),
+            "left_out": self.left_out.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_files: Option<usize>, max_age_secs: Option<u64>, report: PathBuf) -> CatchupPolicy {
+        CatchupPolicy { max_files, max_age: max_age_secs.map(Duration::from_secs), report, defer_dir: None }
+    }
+
+    fn files(ages: &[(&str, u64)], now: SystemTime) -> Vec<(PathBuf, SystemTime)> {
+        ages.iter().map(|(name, age)| (PathBuf::from(name), now - Duration::from_secs(*age))).collect()
+    }
+
+    fn names(files: impl IntoIterator<Item = PathBuf>) -> Vec<String> {
+        files.into_iter().map(|path| path.display().to_string()).collect()
+    }
+
+    #[test]
+    fn caps_keep_the_newest_young_enough_files() {
+        let now = SystemTime::now();
+        let found = files(&[("c.xml", 30), ("old.xml", 500), ("a.xml", 50), ("b.xml", 40), ("d.xml", 10)], now);
+        let plan = plan(&policy(Some(2), Some(100), PathBuf::new()), found, now);
+
+        assert_eq!(names(plan.deliver.iter().map(|(path, _)| path.clone())), ["c.xml", "d.xml"]);
+        let left_out: Vec<(String, LeftOutReason)> =
+            plan.left_out.iter().map(|(path, _, reason)| (path.display().to_string(), *reason)).collect();
+        assert_eq!(
+            left_out,
+            [
+                ("old.xml".to_string(), LeftOutReason::TooOld),
+                ("a.xml".to_string(), LeftOutReason::OverMaxFiles),
+                ("b.xml".to_string(), LeftOutReason::OverMaxFiles),
+            ]
+        );
+    }
+
+    #[test]
+    fn without_caps_being_hit_everything_is_delivered_oldest_first() {
+        let now = SystemTime::now();
+        let plan = plan(&policy(Some(10), None, PathBuf::new()), files(&[("b.xml", 5), ("a.xml", 50)], now), now);
+        assert_eq!(names(plan.deliver.into_iter().map(|(path, _)| path)), ["a.xml", "b.xml"]);
+        assert!(plan.left_out.is_empty());
+    }
+
+    #[test]
+    fn the_report_is_written_even_when_nothing_is_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = policy(Some(10), None, dir.path().join("reports/catchup.json"));
+        let now = SystemTime::now();
+        let plan = plan(&policy, files(&[("a.xml", 5)], now), now);
+        write_report(&policy, &plan, &[]).unwrap();
+
+        let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&policy.report).unwrap()).unwrap();
+        assert_eq!(report["delivered"], 1);
+        assert_eq!(report["max_files"], 10);
+        assert_eq!(report["left_out"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn progress_counts_down_what_remains() {
+        let now = SystemTime::now();
+        let plan = plan(&policy(Some(2), None, PathBuf::new()), files(&[("a.xml", 3), ("b.xml", 2), ("c.xml", 1)], now), now);
+        let progress = CatchupProgress::default();
+        progress.planned(&plan);
+        progress.advance();
+        assert_eq!(progress.summary(), serde_json::json!({"total": 2, "done": 1, "remaining": 1, "left_out": 1}));
+    }
+}
@@ -0,0 +1,286 @@
+use log::{info, Level};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::history::{notable, Kind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyMode {
+    Fixed,
+    Adaptive,
+}
+
+impl ConcurrencyMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "fixed" => Ok(ConcurrencyMode::Fixed),
+            "adaptive" => Ok(ConcurrencyMode::Adaptive),
+            other => Err(format!(
+                "Invalid CONCURRENCY_MODE '{}': expected 'fixed' or 'adaptive'",
+                other
+            )),
+        }
+    }
+}
+
+/// What a finished delivery tells the adaptive controller about the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    // Any response that doesn't indicate the receiver is struggling
+    Healthy,
+    // 429, 5xx or a failed request
+    Overloaded,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdaptiveSettings {
+    pub floor: usize,
+    pub ceiling: usize,
+    pub target_latency: Duration,
+}
+
+struct AdaptiveState {
+    settings: AdaptiveSettings,
+    limit: usize,
+    // Permits to swallow as they are released, for decreases that couldn't
+    // be applied immediately because the permits were in use
+    debt: usize,
+    latencies: Vec<Duration>,
+    overloaded: usize,
+}
+
+/// Bounds the number of webhooks in flight.
+///
+/// In fixed mode this is a plain semaphore. In adaptive mode the number of
+/// permits grows by one after every healthy observation window and halves when
+/// the p95 latency exceeds the target or the receiver signals overload.
+pub struct ConcurrencyLimiter {
+    semaphore: Arc<Semaphore>,
+    adaptive: Option<Mutex<AdaptiveState>>,
+}
+
+pub struct ConcurrencyPermit<'a> {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: &'a ConcurrencyLimiter,
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        if let (Some(permit), Some(adaptive)) = (self.permit.take(), &self.limiter.adaptive) {
+            let mut state = adaptive.lock().unwrap();
+            if state.debt > 0 {
+                state.debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn fixed(limit: usize) -> Self {
+        ConcurrencyLimiter {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            adaptive: None,
+        }
+    }
+
+    /// As many webhooks in flight as there are files ready to be sent.
+    pub fn unbounded() -> Self {
+        Self::fixed(Semaphore::MAX_PERMITS)
+    }
+
+    pub fn adaptive(settings: AdaptiveSettings) -> Self {
+        let limit = settings.floor;
+        ConcurrencyLimiter {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            adaptive: Some(Mutex::new(AdaptiveState {
+                settings,
+                limit,
+                debt: 0,
+                latencies: Vec::new(),
+                overloaded: 0,
+            })),
+        }
+    }
+
+    pub async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("concurrency semaphore is never closed");
+        ConcurrencyPermit {
+            permit: Some(permit),
+            limiter: self,
+        }
+    }
+
+    /// Current effective concurrency limit, or `None` in fixed mode.
+    pub fn current_limit(&self) -> Option<usize> {
+        self.adaptive
+            .as_ref()
+            .map(|adaptive| adaptive.lock().unwrap().limit)
+    }
+
+    /// Feed the outcome of one delivery into the adaptive controller.
+    pub fn record(&self, latency: Duration, signal: Signal) {
+        let Some(adaptive) = &self.adaptive else {
+            return;
+        };
+        let mut state = adaptive.lock().unwrap();
+        state.latencies.push(latency);
+        if signal == Signal::Overloaded {
+            state.overloaded += 1;
+        }
+
+        // Evaluate once per window; the window scales with the limit so that a
+        // high limit isn't judged on a handful of requests
+        let window = state.limit.max(10);
+        if state.latencies.len() < window && state.overloaded == 0 {
+            return;
+        }
+
+        let p95 = percentile(&mut state.latencies, 95);
+        let breached = p95 > state.settings.target_latency || state.overloaded > 0;
+        let old_limit = state.limit;
+        if breached {
+            let new_limit = (old_limit / 2).max(state.settings.floor);
+            let shrink = old_limit - new_limit;
+            let forgotten = self.semaphore.forget_permits(shrink);
+            state.debt += shrink - forgotten;
+            state.limit = new_limit;
+        } else if old_limit < state.settings.ceiling {
+            if state.debt > 0 {
+                state.debt -= 1;
+            } else {
+                self.semaphore.add_permits(1);
+            }
+            state.limit = old_limit + 1;
+        }
+        state.latencies.clear();
+        state.overloaded = 0;
+
+        if state.limit != old_limit {
+            let message = format!(
+                "Adaptive concurrency limit {} -> {} (p95 latency {} ms{})",
+                old_limit,
+                state.limit,
+                p95.as_millis(),
+                if breached { ", backing off" } else { "" }
+            );
+            // Growing back happens every window, which would crowd out the rest
+            if breached {
+                notable!(Level::Info, Kind::ConcurrencyReduced, "{}", message);
+            } else {
+                info!("{}", message);
+            }
+        }
+    }
+}
+
+fn percentile(samples: &mut [Duration], pct: usize) -> Duration {
+    if samples.is_empty() {
+        return Duration::ZERO;
+    }
+    samples.sort_unstable();
+    let index = (samples.len() * pct).div_ceil(100).saturating_sub(1);
+    samples[index.min(samples.len() - 1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(10);
+
+    fn limiter(floor: usize, ceiling: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::adaptive(AdaptiveSettings {
+            floor,
+            ceiling,
+            target_latency: Duration::from_millis(100),
+        })
+    }
+
+    fn healthy_window(limiter: &ConcurrencyLimiter) {
+        for _ in 0..10 {
+            limiter.record(FAST, Signal::Healthy);
+        }
+    }
+
+    #[test]
+    fn percentile_picks_the_nearest_rank() {
+        let mut samples: Vec<Duration> = (1..=20).rev().map(Duration::from_millis).collect();
+        assert_eq!(percentile(&mut samples, 95), Duration::from_millis(19));
+        assert_eq!(percentile(&mut samples, 50), Duration::from_millis(10));
+        assert_eq!(percentile(&mut [Duration::from_millis(7)], 95), Duration::from_millis(7));
+        assert_eq!(percentile(&mut [], 95), Duration::ZERO);
+    }
+
+    #[test]
+    fn healthy_windows_grow_the_limit_up_to_the_ceiling() {
+        let limiter = limiter(2, 4);
+        for _ in 0..9 {
+            limiter.record(FAST, Signal::Healthy);
+        }
+        assert_eq!(limiter.current_limit(), Some(2));
+        limiter.record(FAST, Signal::Healthy);
+        assert_eq!(limiter.current_limit(), Some(3));
+        healthy_window(&limiter);
+        healthy_window(&limiter);
+        assert_eq!(limiter.current_limit(), Some(4));
+        assert_eq!(limiter.semaphore.available_permits(), 4);
+    }
+
+    #[test]
+    fn slow_windows_and_overloads_halve_the_limit_down_to_the_floor() {
+        let limiter = limiter(1, 8);
+        for _ in 0..5 {
+            healthy_window(&limiter);
+        }
+        assert_eq!(limiter.current_limit(), Some(6));
+
+        for _ in 0..10 {
+            limiter.record(Duration::from_millis(500), Signal::Healthy);
+        }
+        assert_eq!(limiter.current_limit(), Some(3));
+        // An overload is acted on without waiting for the window to fill
+        limiter.record(FAST, Signal::Overloaded);
+        assert_eq!(limiter.current_limit(), Some(1));
+        limiter.record(FAST, Signal::Overloaded);
+        assert_eq!(limiter.current_limit(), Some(1));
+        assert_eq!(limiter.semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn permits_in_use_pay_back_the_decrease_when_released() {
+        let limiter = limiter(2, 8);
+        healthy_window(&limiter);
+        healthy_window(&limiter);
+        assert_eq!(limiter.current_limit(), Some(4));
+
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(limiter.acquire().await);
+        }
+        limiter.record(FAST, Signal::Overloaded);
+        assert_eq!(limiter.current_limit(), Some(2));
+        assert_eq!(limiter.adaptive.as_ref().unwrap().lock().unwrap().debt, 2);
+
+        // Growing while in debt cancels some of it instead of adding a permit
+        healthy_window(&limiter);
+        assert_eq!(limiter.current_limit(), Some(3));
+        assert_eq!(limiter.adaptive.as_ref().unwrap().lock().unwrap().debt, 1);
+
+        drop(permits);
+        assert_eq!(limiter.adaptive.as_ref().unwrap().lock().unwrap().debt, 0);
+        assert_eq!(limiter.semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn fixed_limiters_ignore_observations() {
+        let limiter = ConcurrencyLimiter::fixed(3);
+        limiter.record(FAST, Signal::Overloaded);
+        assert_eq!(limiter.current_limit(), None);
+        assert_eq!(limiter.semaphore.available_permits(), 3);
+    }
+}
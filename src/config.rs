@@ -0,0 +1,1283 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::archive::ExtractionLimits;
+use crate::audit_syslog::SyslogTarget;
+use crate::batch::BatchPolicy;
+use crate::body_match::BodyMatch;
+use crate::catchup::CatchupPolicy;
+use crate::concurrency::ConcurrencyMode;
+use crate::content_headers::{ContentHeaders, MissingHeader, WebhookHeaders};
+use crate::content_server::ContentMode;
+use crate::digest::DigestAlgorithm;
+use crate::encoding::{EncodingFallback, InvalidUtf8, TargetEncoding, Utf16Handling};
+use crate::events::WatchEvents;
+use crate::hardlinks::HardlinkPolicy;
+use crate::hooks::PreHookFailure;
+use crate::jws::{JwsSigner, SignMode};
+use crate::path_case::PathCase;
+use crate::pending_ack::AckTokenSource;
+use crate::presigned::PresignedDelivery;
+use crate::reorder::DeliveryOrder;
+use crate::retry::{RetryPolicy, RetryableErrors};
+use crate::sanitize::Sanitize;
+use crate::sensitive::SensitiveString;
+use crate::settle::AdaptivePolicy;
+use crate::size_policy::{SizeAction, SizePolicy};
+use crate::stability::StabilityCheck;
+use crate::tls::{SpkiPin, TlsBackend, TlsOptions};
+use crate::watch::WatchBackend;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Name of the `[[watcher]]` profile this configuration came from, if any
+    pub profile: Option<String>,
+    pub watch_dir: PathBuf,
+    // Let profiles watch overlapping directories, each file going to the
+    // most specific one
+    pub allow_overlapping_roots: bool,
+    pub webhook_url: SensitiveString,
+    pub webhook_method: String,
+    // Limit on each webhook request, from connecting until the response is read
+    pub webhook_timeout_secs: u64,
+    // Fixed headers of every webhook request, WEBHOOK_HEADERS
+    pub webhook_headers: WebhookHeaders,
+    // Retries of requests that failed on the way or with a 429 or 5xx
+    pub webhook_retry: RetryPolicy,
+    // Failures and statuses that are retried, RETRYABLE_ERRORS
+    pub retryable_errors: RetryableErrors,
+    // Presign, upload and confirm instead of one request, DELIVERY_MODE=presigned
+    pub presigned: Option<PresignedDelivery>,
+    // Second endpoint that gets a copy of every request, without affecting outcomes
+    pub shadow_webhook_url: Option<SensitiveString>,
+    pub shadow_max_concurrent: usize,
+    pub shadow_report_interval_secs: u64,
+    // Endpoint told about the terminal outcome of every file
+    pub outcome_webhook_url: Option<SensitiveString>,
+    pub outcome_flush_secs: u64,
+    // Syslog server also told about every outcome, with its facility
+    pub syslog_target: Option<SyslogTarget>,
+    pub syslog_facility: syslog::Facility,
+    // Also report files skipped before delivery to those, and to the database
+    pub audit_skips: bool,
+    // SQLite database keeping the spill queue, pending acknowledgements and
+    // every outcome, instead of QUEUE_SPILL_DIR and ASYNC_ACK_DIR
+    pub storage_db_path: Option<PathBuf>,
+    // Source address for outgoing webhook connections
+    pub bind_local_address: Option<IpAddr>,
+    pub include_content: bool,
+    pub overwrite_with_response: bool,
+    // Ask for gzip or deflate responses and decompress them before use
+    pub accept_compressed_responses: bool,
+    pub detect_content_type_from_doc: bool,
+    pub unsafe_log_secrets: bool,
+    pub require_tls: bool,
+    pub tls: TlsOptions,
+    pub concurrency_mode: ConcurrencyMode,
+    // Unset leaves fixed mode unbounded, every file being sent as soon as it
+    // is ready
+    pub max_concurrent_webhooks: Option<usize>,
+    pub concurrency_floor: usize,
+    pub concurrency_ceiling: usize,
+    pub target_latency_ms: u64,
+    pub include_detection_latency: bool,
+    // Number payloads, continuing from a file in the directory when set
+    pub include_sequence: bool,
+    pub sequence_dir: Option<PathBuf>,
+    pub numeric_fields_as_string: bool,
+    // Request bodies are sent as a compact JWS signed with this, with SIGN_MODE=jws
+    pub jws_signer: Option<JwsSigner>,
+    // Algorithm of the `Digest` header, when SEND_DIGEST_HEADER is enabled
+    pub digest_algorithm: Option<DigestAlgorithm>,
+    // Check a 2xx response body must also pass to count as delivered
+    pub success_body_match: Option<BodyMatch>,
+    // Where a 202 response carries the token of a delivery acknowledged later
+    pub async_ack_token: Option<AckTokenSource>,
+    pub async_ack_dir: Option<PathBuf>,
+    pub async_ack_max_pending: usize,
+    pub async_ack_timeout_secs: u64,
+    // Request headers whose values are taken from each delivered document
+    pub content_headers: Option<ContentHeaders>,
+    // Extensions of zip files whose XML entries are delivered one by one,
+    // BUNDLE_EXTENSIONS (or EXTRACT_ARCHIVES=zip); lower case, without dots
+    pub bundle_extensions: Vec<String>,
+    // Every file is delivered, not just XML files, with its detected type
+    pub watch_all_files: bool,
+    pub archive_limits: ExtractionLimits,
+    pub read_only: bool,
+    pub ignore_list_max_entries: usize,
+    pub backup_before_overwrite: bool,
+    pub backup_dir: Option<PathBuf>,
+    // OVERWRITE_TARGET_ENCODING; unset writes responses as they arrive
+    pub overwrite_target_encoding: Option<TargetEncoding>,
+    pub encoding_fallback: EncodingFallback,
+    pub watch_backend: WatchBackend,
+    pub watch_poll_interval_secs: u64,
+    pub watch_retry_secs: u64,
+    // Interval of the re-stat of the watch root that keeps flaky mounts reporting events
+    pub watch_keepalive_secs: Option<u64>,
+    // Files renamed into place are delivered without the settle delay
+    pub skip_delay_on_rename: bool,
+    // Which events lead to deliveries; modified files with WATCH_EVENTS=modify
+    pub watch_events: WatchEvents,
+    // Which of their deliveries carry content, CONTENT_EVENTS; files found by
+    // SCAN_ON_STARTUP count as created
+    pub content_events: WatchEvents,
+    // Quiet time after a file's last modification before it is delivered
+    pub modify_debounce: Duration,
+    // Deliver the files already in the watch directory at startup, those
+    // modified within SCAN_MAX_AGE_SECS when it is set
+    pub scan_on_startup: bool,
+    pub scan_max_age: Option<Duration>,
+    // Top-level directories delivered at once by the startup scan, each in
+    // order, SCAN_DIR_CONCURRENCY; otherwise all files are queued together
+    pub scan_dir_concurrency: Option<usize>,
+    // Caps on what the startup scan delivers, CATCHUP_MAX_FILES and
+    // CATCHUP_MAX_AGE_SECS, with where to report and move what they leave out
+    pub catchup: Option<CatchupPolicy>,
+    // Serve GET /catchup, the progress of the startup scan's deliveries
+    pub catchup_endpoint: bool,
+    // Settle delays learned per directory, with SETTLE_MODE=adaptive
+    pub adaptive_settle: Option<AdaptivePolicy>,
+    // Where they are kept across restarts
+    pub settle_state_dir: Option<PathBuf>,
+    pub settle_endpoint: bool,
+    // Files are processed by one task at a time, however many events they get
+    pub lock_per_path: bool,
+    pub ordering: DeliveryOrder,
+    // How long files are held to sort them, with ORDERING=mtime
+    pub ordering_window_ms: u64,
+    // PATH_CASE_SENSITIVE; detected from the watch directory when unset
+    pub path_case: Option<PathCase>,
+    // Read-based check that files are complete, for NFS_SAFE_MODE
+    pub nfs_safe_mode: Option<StabilityCheck>,
+    // Deepest directory level below the watch root whose files are processed
+    pub max_watch_depth: Option<usize>,
+    // Only subdirectories of the watch root with a matching name are watched
+    pub auto_watch_pattern: Option<Regex>,
+    // Files waiting in memory before new ones are spilled to QUEUE_SPILL_DIR
+    pub max_queued_files: usize,
+    pub queue_spill_dir: Option<PathBuf>,
+    // Files per second whose processing may start, unlimited when unset
+    pub max_files_per_sec: Option<u32>,
+    // XML files smaller than this are ignored, e.g. marker files
+    pub min_content_bytes: Option<u64>,
+    pub utf16_xml: Utf16Handling,
+    pub hardlink_policy: HardlinkPolicy,
+    // Don't send a file again while it holds what was last delivered from it
+    pub skip_redundant_delivery: bool,
+    pub filename_rewrite: Option<FilenameRewrite>,
+    // Local name of the element each XML file is split into deliveries by
+    pub split_on_element: Option<String>,
+    // Whole files sent several to a request, with BATCH_SIZE above 1
+    pub batch: Option<BatchPolicy>,
+    // Only the first N bytes of the content are included when set
+    pub content_preview_bytes: Option<usize>,
+    // Content is sent in Canonical XML 1.0 form
+    pub xml_c14n: bool,
+    // What happens to characters XML 1.0 doesn't allow in inline content
+    pub sanitize_content: Sanitize,
+    // What happens to bytes that aren't valid UTF-8 in inline content
+    pub content_invalid_utf8: InvalidUtf8,
+    pub content_mode: ContentMode,
+    // Decides per file size instead of INCLUDE_CONTENT and CONTENT_MODE
+    pub size_policy: Option<SizePolicy>,
+    pub content_serve_addr: SocketAddr,
+    // Base of the `content_url` handed to receivers, without a trailing slash
+    pub content_url_base: String,
+    pub content_url_ttl_secs: u64,
+    // Directories the content server may read files from, CONTENT_ROOTS;
+    // the watch directory unless set
+    pub content_roots: Vec<PathBuf>,
+    pub content_ref_template: Option<UrlTemplate>,
+    // Serve `GET /throttles` on CONTENT_SERVE_ADDR
+    pub throttles_endpoint: bool,
+    // Serve `POST /preview` on CONTENT_SERVE_ADDR
+    pub preview_endpoint: bool,
+    // Serve `GET /skips/recent` on CONTENT_SERVE_ADDR
+    pub skips_endpoint: bool,
+    // Serve `GET /events/internal` on CONTENT_SERVE_ADDR, from a history of
+    // this many entries
+    pub events_endpoint: bool,
+    pub events_history_size: usize,
+    // Accept events from `POST /control/inject-event`, for testing
+    pub debug_inject_events: bool,
+    // Every payload sent is also written there as JSON, for inspection
+    pub debug_payload_dir: Option<PathBuf>,
+    pub pre_delivery_command: Option<String>,
+    pub post_delivery_command: Option<String>,
+    pub pre_delivery_failure: PreHookFailure,
+    pub quarantine_dir: Option<PathBuf>,
+    // Where files that failed delivery are moved
+    pub retry_later_dir: Option<PathBuf>,
+    // Age after which they are moved back and delivered again
+    pub retry_later_after_secs: Option<u64>,
+    pub hook_timeout_secs: u64,
+    pub hook_max_concurrent: usize,
+}
+
+/// A `regex=>replacement` rule applied to file names reported in payloads.
+#[derive(Debug, Clone)]
+pub struct FilenameRewrite {
+    regex: Regex,
+    replacement: String,
+}
+
+impl FilenameRewrite {
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let (pattern, replacement) = rule
+            .split_once("=>")
+            .ok_or_else(|| format!("FILENAME_REWRITE must have the form 'regex=>replacement', got '{}'", rule))?;
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("Invalid FILENAME_REWRITE regex '{}': {}", pattern, e))?;
+        Ok(FilenameRewrite {
+            regex,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    pub fn apply(&self, filename: &str) -> String {
+        self.regex
+            .replace_all(filename, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+impl std::fmt::Display for FilenameRewrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}=>{}", self.regex.as_str(), self.replacement)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    // Name of the file on disk
+    Filename,
+    // Path below the watch directory, `/`-separated
+    Relpath,
+    Profile,
+}
+
+/// A URL with `{filename}`, `{relpath}` and `{profile}` placeholders, filled
+/// in per file: CONTENT_REF_TEMPLATE, PRESIGN_URL and CONFIRM_URL.
+#[derive(Debug, Clone)]
+pub struct UrlTemplate {
+    template: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl UrlTemplate {
+    // `name` is the option the template comes from, for error messages
+    pub fn parse(name: &str, template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in {} '{}'", name, template))?;
+            parts.push(match &rest[start + 1..start + end] {
+                "filename" => TemplatePart::Filename,
+                "relpath" => TemplatePart::Relpath,
+                "profile" => TemplatePart::Profile,
+                other => {
+                    return Err(format!(
+                        "Unknown placeholder '{{{}}}' in {}: expected {{filename}}, {{relpath}} or {{profile}}",
+                        other, name
+                    ))
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        Ok(UrlTemplate {
+            template: template.to_string(),
+            parts,
+        })
+    }
+
+    /// Fill in the placeholders, percent-encoding the values. The `/` between
+    /// the components of `relpath` is kept.
+    pub fn render(&self, relpath: &str, filename: &str, profile: Option<&str>) -> String {
+        let mut url = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(text) => url.push_str(text),
+                TemplatePart::Filename => url.push_str(&percent_encode(filename)),
+                TemplatePart::Relpath => {
+                    let segments: Vec<String> = relpath.split('/').map(percent_encode).collect();
+                    url.push_str(&segments.join("/"));
+                }
+                TemplatePart::Profile => url.push_str(&percent_encode(profile.unwrap_or("default"))),
+            }
+        }
+        url
+    }
+}
+
+impl std::fmt::Display for UrlTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+// Everything but RFC 3986 unreserved characters is encoded
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Where configuration values are looked up.
+///
+/// Profile tables use the lowercase option names (`watch_dir`, `webhook_url`, ...);
+/// options a profile doesn't set fall back to the environment variable.
+pub enum ConfigSource<'a> {
+    Env,
+    Profile(&'a toml::Table),
+}
+
+impl ConfigSource<'_> {
+    fn var(&self, name: &str) -> Option<String> {
+        if let ConfigSource::Profile(table) = self {
+            if let Some(value) = table.get(&name.to_lowercase()) {
+                return toml_value_to_string(value);
+            }
+        }
+        env::var(name).ok()
+    }
+
+    fn bool(&self, name: &str) -> bool {
+        self.var(name)
+            .unwrap_or_else(|| "false".to_string())
+            .to_lowercase() == "true"
+    }
+
+    // Like `bool`, with a default for when the option is unset or empty, and
+    // refusing anything but `true` and `false` (in any case)
+    fn bool_or(&self, name: &str, default: bool) -> Result<bool, String> {
+        match self.var(name).filter(|value| !value.trim().is_empty()) {
+            Some(value) => match value.trim().to_lowercase().as_str() {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => Err(format!("Invalid value for {}: '{}', expected 'true' or 'false'", name, value)),
+            },
+            None => Ok(default),
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.var(name) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: '{}'", name, value)),
+            None => Ok(default),
+        }
+    }
+
+    // Like `parse`, for options without a default; empty counts as unset
+    fn parse_optional<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.var(name).filter(|value| !value.trim().is_empty()) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid value for {}: '{}'", name, value)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Profile values are handed to the same parsers as environment variables, so
+// they are flattened to the string an operator would have exported
+fn toml_value_to_string(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(d) => Some(d.to_string()),
+        toml::Value::Array(items) => Some(
+            items
+                .iter()
+                .filter_map(toml_value_to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        toml::Value::Table(_) => None,
+    }
+}
+
+pub fn is_https(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .map(|url| url.scheme() == "https")
+        .unwrap_or(false)
+}
+
+impl Config {
+    /// Load every configured profile: the `[[watcher]]` tables of `CONFIG_FILE`
+    /// when it is set, otherwise a single unnamed profile from the environment.
+    pub fn load_all() -> Result<Vec<Config>, String> {
+        match env::var("CONFIG_FILE") {
+            Ok(path) if !path.is_empty() => Self::from_file(&path),
+            _ => Ok(vec![Self::from_env()?]),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_source(&ConfigSource::Env, None)
+    }
+
+    fn from_file(path: &str) -> Result<Vec<Config>, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read CONFIG_FILE '{}': {}", path, e))?;
+        let document: toml::Table = text
+            .parse()
+            .map_err(|e| format!("Failed to parse CONFIG_FILE '{}': {}", path, e))?;
+
+        let profiles = match document.get("watcher") {
+            Some(toml::Value::Array(profiles)) if !profiles.is_empty() => profiles,
+            _ => return Err(format!("CONFIG_FILE '{}' defines no [[watcher]] profiles", path)),
+        };
+
+        let mut names = HashSet::new();
+        let mut configs = Vec::new();
+        for (index, profile) in profiles.iter().enumerate() {
+            let table = profile
+                .as_table()
+                .ok_or_else(|| format!("[[watcher]] entry {} is not a table", index + 1))?;
+            let name = match table.get("name") {
+                Some(toml::Value::String(name)) if !name.is_empty() => name.clone(),
+                _ => return Err(format!("[[watcher]] entry {} needs a 'name'", index + 1)),
+            };
+            if !names.insert(name.clone()) {
+                return Err(format!("Duplicate watcher profile name '{}'", name));
+            }
+            let config = Self::from_source(&ConfigSource::Profile(table), Some(name.clone()))
+                .map_err(|e| format!("Profile '{}': {}", name, e))?;
+            configs.push(config);
+        }
+        Ok(configs)
+    }
+
+    /// Parse and validate one profile from `source`.
+    ///
+    /// This is also how to build a `Config` in code: put the options in a
+    /// `toml::Table` under their lowercase names, as in a `[[watcher]]` table,
+    /// and pass `ConfigSource::Profile(&table)`. Options the table leaves out
+    /// are read from the environment, as for profiles.
+    pub fn from_source(source: &ConfigSource, profile: Option<String>) -> Result<Self, String> {
+        let watch_dir: PathBuf = source.var("WATCH_DIR")
+            .unwrap_or_else(|| "/watch".to_string())
+            .into();
+        let allow_overlapping_roots = source.bool("ALLOW_OVERLAPPING_ROOTS");
+
+        let presigned = match source.var("DELIVERY_MODE").unwrap_or_else(|| "webhook".to_string()).to_lowercase().as_str() {
+            "webhook" => None,
+            "presigned" => {
+                let presign_url = source.var("PRESIGN_URL")
+                    .filter(|url| !url.is_empty())
+                    .ok_or_else(|| "DELIVERY_MODE=presigned requires PRESIGN_URL".to_string())?;
+                let confirm_url = source.var("CONFIRM_URL")
+                    .filter(|url| !url.is_empty())
+                    .map(|url| UrlTemplate::parse("CONFIRM_URL", &url))
+                    .transpose()?;
+                let presign_timeout_secs = source.parse("PRESIGN_TIMEOUT_SECS", 30u64)?;
+                let upload_timeout_secs = source.parse("UPLOAD_TIMEOUT_SECS", 300u64)?;
+                let confirm_timeout_secs = source.parse("CONFIRM_TIMEOUT_SECS", 30u64)?;
+                if presign_timeout_secs == 0 || upload_timeout_secs == 0 || confirm_timeout_secs == 0 {
+                    return Err("PRESIGN_TIMEOUT_SECS, UPLOAD_TIMEOUT_SECS and CONFIRM_TIMEOUT_SECS must be at least 1".to_string());
+                }
+                Some(PresignedDelivery {
+                    presign_url: UrlTemplate::parse("PRESIGN_URL", &presign_url)?,
+                    confirm_url,
+                    presign_timeout: Duration::from_secs(presign_timeout_secs),
+                    upload_timeout: Duration::from_secs(upload_timeout_secs),
+                    confirm_timeout: Duration::from_secs(confirm_timeout_secs),
+                })
+            }
+            other => return Err(format!("Invalid DELIVERY_MODE '{}': expected 'webhook' or 'presigned'", other)),
+        };
+
+        let webhook_url = match (&presigned, source.var("WEBHOOK_URL")) {
+            (None, Some(url)) => SensitiveString::url(url),
+            (None, None) => return Err("WEBHOOK_URL environment variable is required".to_string()),
+            (Some(_), Some(_)) => {
+                return Err("WEBHOOK_URL isn't used with DELIVERY_MODE=presigned, which starts at PRESIGN_URL".to_string())
+            }
+            // Throttling and the startup log go by the presign endpoint
+            (Some(presigned), None) => SensitiveString::url(presigned.presign_url.to_string()),
+        };
+
+        let webhook_method = source.var("WEBHOOK_METHOD")
+            .unwrap_or_else(|| "POST".to_string());
+        let webhook_timeout_secs = source.parse("WEBHOOK_TIMEOUT_SECS", 30u64)?;
+        if webhook_timeout_secs == 0 {
+            return Err("WEBHOOK_TIMEOUT_SECS must be at least 1".to_string());
+        }
+        // Invalid entries are logged and left out at startup, not fatal
+        let webhook_headers = source.var("WEBHOOK_HEADERS")
+            .map(|value| WebhookHeaders::parse(&value))
+            .unwrap_or_default();
+        let webhook_max_retries = source.parse("WEBHOOK_MAX_RETRIES", 3u32)?;
+        let webhook_retry_base_ms = source.parse("WEBHOOK_RETRY_BASE_MS", 500u64)?;
+        if webhook_retry_base_ms == 0 {
+            return Err("WEBHOOK_RETRY_BASE_MS must be at least 1".to_string());
+        }
+        let mut retryable_errors = match source.var("RETRYABLE_ERRORS").filter(|value| !value.trim().is_empty()) {
+            Some(value) => RetryableErrors::parse(&value)?,
+            None => RetryableErrors::default(),
+        };
+        if let Some(codes) = source.var("EXTRA_RETRY_CODES") {
+            retryable_errors.add_extra_codes(&codes)?;
+        }
+
+        let shadow_webhook_url = source.var("SHADOW_WEBHOOK_URL")
+            .filter(|url| !url.is_empty())
+            .map(SensitiveString::url);
+        let shadow_max_concurrent = source.parse("SHADOW_MAX_CONCURRENT", 2usize)?;
+        let shadow_report_interval_secs = source.parse("SHADOW_REPORT_INTERVAL_SECS", 300u64)?;
+        if shadow_max_concurrent == 0 || shadow_report_interval_secs == 0 {
+            return Err("SHADOW_MAX_CONCURRENT and SHADOW_REPORT_INTERVAL_SECS must be at least 1".to_string());
+        }
+
+        let storage_db_path = source.var("STORAGE_DB_PATH")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
+        let outcome_webhook_url = source.var("OUTCOME_WEBHOOK_URL")
+            .filter(|url| !url.is_empty())
+            .map(SensitiveString::url);
+        let outcome_flush_secs = source.parse("OUTCOME_FLUSH_SECS", 5u64)?;
+
+        let syslog_target = source.var("SYSLOG_ADDR")
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| SyslogTarget::parse(addr.trim()))
+            .transpose()?;
+        let syslog_facility = source.var("SYSLOG_FACILITY").unwrap_or_else(|| "daemon".to_string());
+        let syslog_facility = syslog_facility
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid SYSLOG_FACILITY '{}': expected e.g. daemon, user or local0..local7", syslog_facility))?;
+        let audit_skips = source.bool("AUDIT_SKIPS");
+        if audit_skips && outcome_webhook_url.is_none() && syslog_target.is_none() && storage_db_path.is_none() {
+            return Err("AUDIT_SKIPS requires OUTCOME_WEBHOOK_URL, SYSLOG_ADDR or STORAGE_DB_PATH".to_string());
+        }
+
+        let bind_local_address = match source.var("BIND_LOCAL_ADDRESS").filter(|a| !a.is_empty()) {
+            Some(address) => Some(
+                address
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid BIND_LOCAL_ADDRESS '{}': expected an IP address", address))?,
+            ),
+            None => None,
+        };
+
+        let include_content = source.bool("INCLUDE_CONTENT");
+
+        let overwrite_with_response = source.bool("OVERWRITE_WITH_RESPONSE");
+        let accept_compressed_responses = source.bool("ACCEPT_COMPRESSED_RESPONSES");
+
+        let detect_content_type_from_doc = source.bool("DETECT_CONTENT_TYPE_FROM_DOC");
+
+        let unsafe_log_secrets = source.bool("UNSAFE_LOG_SECRETS");
+
+        let require_tls = source.bool("REQUIRE_TLS");
+        if require_tls && !is_https(webhook_url.expose()) {
+            return Err(format!("REQUIRE_TLS is enabled but WEBHOOK_URL {} is not https", webhook_url));
+        }
+        if let Some(url) = presigned.as_ref().and_then(|p| p.confirm_url.as_ref()).filter(|url| require_tls && !is_https(&url.to_string())) {
+            return Err(format!("REQUIRE_TLS is enabled but CONFIRM_URL {} is not https", SensitiveString::url(url.to_string())));
+        }
+        if let Some(url) = shadow_webhook_url.as_ref().filter(|url| require_tls && !is_https(url.expose())) {
+            return Err(format!("REQUIRE_TLS is enabled but SHADOW_WEBHOOK_URL {} is not https", url));
+        }
+        if let Some(url) = outcome_webhook_url.as_ref().filter(|url| require_tls && !is_https(url.expose())) {
+            return Err(format!("REQUIRE_TLS is enabled but OUTCOME_WEBHOOK_URL {} is not https", url));
+        }
+
+        let tls_backend = TlsBackend::parse(&source.var("TLS_BACKEND").unwrap_or_else(|| "native".to_string()))?;
+        let ca_bundle = source.var("TLS_CA_BUNDLE").filter(|path| !path.is_empty()).map(PathBuf::from);
+        let client_certificate = match (
+            source.var("TLS_CLIENT_CERT").filter(|path| !path.is_empty()),
+            source.var("TLS_CLIENT_KEY").filter(|path| !path.is_empty()),
+        ) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => return Err("Set both TLS_CLIENT_CERT and TLS_CLIENT_KEY, or neither".to_string()),
+        };
+        let pinned_spki = source
+            .var("WEBHOOK_PINNED_SPKI_SHA256")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|pin| !pin.trim().is_empty())
+            .map(SpkiPin::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        // native-tls offers no say in verification, so a pin could only be
+        // checked once the request had been sent
+        if !pinned_spki.is_empty() && tls_backend != TlsBackend::Rustls {
+            return Err("WEBHOOK_PINNED_SPKI_SHA256 requires TLS_BACKEND=rustls".to_string());
+        }
+        let tls = TlsOptions {
+            backend: tls_backend,
+            ca_bundle,
+            client_certificate,
+            pinned_spki,
+        };
+
+        let concurrency_mode = ConcurrencyMode::parse(
+            &source.var("CONCURRENCY_MODE").unwrap_or_else(|| "fixed".to_string()),
+        )?;
+        let max_concurrent_webhooks = source.parse_optional("MAX_CONCURRENT_WEBHOOKS")?;
+        let concurrency_floor = source.parse("CONCURRENCY_FLOOR", 1usize)?;
+        let concurrency_ceiling = source.parse("CONCURRENCY_CEILING", max_concurrent_webhooks.unwrap_or(10))?;
+        let target_latency_ms = source.parse("TARGET_LATENCY_MS", 1000u64)?;
+        if max_concurrent_webhooks == Some(0) || concurrency_floor == 0 {
+            return Err("MAX_CONCURRENT_WEBHOOKS and CONCURRENCY_FLOOR must be at least 1".to_string());
+        }
+        if concurrency_floor > concurrency_ceiling {
+            return Err("CONCURRENCY_FLOOR must not exceed CONCURRENCY_CEILING".to_string());
+        }
+
+        let include_detection_latency = source.bool("INCLUDE_DETECTION_LATENCY");
+        let include_sequence = source.bool("INCLUDE_SEQUENCE");
+        let sequence_dir = source.var("SEQUENCE_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        let numeric_fields_as_string = source.bool("NUMERIC_FIELDS_AS_STRING");
+
+        let sign_mode = SignMode::parse(&source.var("SIGN_MODE").unwrap_or_else(|| "none".to_string()))?;
+        let jws_signer = match sign_mode {
+            SignMode::None => None,
+            SignMode::Jws => {
+                let key = match (
+                    source.var("JWS_KEY").filter(|key| !key.is_empty()),
+                    source.var("JWS_KEY_FILE").filter(|path| !path.is_empty()),
+                ) {
+                    (Some(key), None) => key.into_bytes(),
+                    (None, Some(path)) => std::fs::read(&path)
+                        .map_err(|e| format!("Failed to read JWS_KEY_FILE '{}': {}", path, e))?,
+                    (Some(_), Some(_)) => return Err("Set only one of JWS_KEY and JWS_KEY_FILE".to_string()),
+                    (None, None) => return Err("SIGN_MODE=jws requires JWS_KEY or JWS_KEY_FILE".to_string()),
+                };
+                let algorithm = source.var("JWS_ALGORITHM").unwrap_or_else(|| "HS256".to_string());
+                let key_id = source.var("JWS_KEY_ID").filter(|id| !id.is_empty());
+                Some(JwsSigner::new(algorithm.trim(), &key, key_id)?)
+            }
+        };
+
+        let digest_algorithm = DigestAlgorithm::parse(
+            &source.var("DIGEST_ALGORITHM").unwrap_or_else(|| "sha-256".to_string()),
+        )?;
+        let digest_algorithm = source.bool("SEND_DIGEST_HEADER").then_some(digest_algorithm);
+
+        let success_body_match = source.var("SUCCESS_BODY_MATCH")
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| BodyMatch::parse(&rule))
+            .transpose()?;
+
+        let async_ack_token = source.var("ASYNC_ACK_TOKEN")
+            .filter(|token| !token.is_empty())
+            .map(|token| AckTokenSource::parse(&token))
+            .transpose()?;
+        let async_ack_dir = source.var("ASYNC_ACK_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        if async_ack_dir.is_some() && storage_db_path.is_some() {
+            return Err("ASYNC_ACK_DIR can't be combined with STORAGE_DB_PATH, which keeps pending acknowledgements".to_string());
+        }
+        if async_ack_token.is_some() && async_ack_dir.is_none() && storage_db_path.is_none() {
+            return Err("ASYNC_ACK_TOKEN requires ASYNC_ACK_DIR or STORAGE_DB_PATH to keep pending acknowledgements in".to_string());
+        }
+        let async_ack_max_pending = source.parse("ASYNC_ACK_MAX_PENDING", 10_000usize)?;
+        let async_ack_timeout_secs = source.parse("ASYNC_ACK_TIMEOUT_SECS", 86_400u64)?;
+        if async_ack_max_pending == 0 || async_ack_timeout_secs == 0 {
+            return Err("ASYNC_ACK_MAX_PENDING and ASYNC_ACK_TIMEOUT_SECS must be at least 1".to_string());
+        }
+
+        let content_header_missing = MissingHeader::parse(
+            &source.var("CONTENT_HEADER_MISSING").unwrap_or_else(|| "omit".to_string()),
+        )?;
+        let content_headers = source.var("CONTENT_HEADERS")
+            .filter(|rules| !rules.is_empty())
+            .map(|rules| ContentHeaders::parse(&rules, content_header_missing))
+            .transpose()?;
+
+        let extract_zip_archives = match source.var("EXTRACT_ARCHIVES") {
+            Some(value) if value.eq_ignore_ascii_case("zip") => true,
+            Some(value) if value.is_empty() || value.eq_ignore_ascii_case("none") => false,
+            Some(value) => {
+                return Err(format!("Unsupported EXTRACT_ARCHIVES value '{}': only 'zip' is supported", value));
+            }
+            None => false,
+        };
+        let bundle_extensions = match source.var("BUNDLE_EXTENSIONS").filter(|list| !list.is_empty()) {
+            Some(_) if extract_zip_archives => {
+                return Err("Set either BUNDLE_EXTENSIONS or EXTRACT_ARCHIVES, not both".to_string());
+            }
+            Some(list) => {
+                let extensions: Vec<String> = list
+                    .split(',')
+                    .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|ext| !ext.is_empty())
+                    .collect();
+                if extensions.iter().any(|ext| ext == "xml") {
+                    return Err("BUNDLE_EXTENSIONS can't include 'xml'".to_string());
+                }
+                extensions
+            }
+            None if extract_zip_archives => vec!["zip".to_string()],
+            None => Vec::new(),
+        };
+        let watch_all_files = source.bool("WATCH_ALL_FILES");
+        let archive_limits = ExtractionLimits {
+            max_entries: source.parse("ARCHIVE_MAX_ENTRIES", 1000usize)?,
+            max_total_bytes: source.parse("ARCHIVE_MAX_TOTAL_BYTES", 100 * 1024 * 1024u64)?,
+        };
+
+        let read_only = source.bool("READ_ONLY");
+
+        let ignore_list_max_entries = source.parse("IGNORE_LIST_MAX_ENTRIES", 100_000usize)?;
+        if ignore_list_max_entries == 0 {
+            return Err("IGNORE_LIST_MAX_ENTRIES must be at least 1".to_string());
+        }
+
+        let backup_before_overwrite = source.bool("BACKUP_BEFORE_OVERWRITE");
+        let backup_dir = source.var("BACKUP_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        let overwrite_target_encoding = source.var("OVERWRITE_TARGET_ENCODING")
+            .filter(|value| !value.is_empty())
+            .map(|value| TargetEncoding::parse(&value))
+            .transpose()?;
+        let encoding_fallback =
+            EncodingFallback::parse(&source.var("ENCODING_FALLBACK").unwrap_or_else(|| "fail".to_string()))?;
+
+        let watch_backend = WatchBackend::parse(&source.var("WATCH_BACKEND").unwrap_or_else(|| "native".to_string()))?;
+        let watch_poll_interval_secs = source.parse("WATCH_POLL_INTERVAL_SECS", 30u64)?;
+        let watch_retry_secs = source.parse("WATCH_RETRY_SECS", 300u64)?;
+        let nfs_safe_sample_kb = source.parse("NFS_SAFE_SAMPLE_KB", 64u64)?;
+        let nfs_safe_interval_ms = source.parse("NFS_SAFE_INTERVAL_MS", 1000u64)?;
+        if nfs_safe_sample_kb == 0 || nfs_safe_interval_ms == 0 {
+            return Err("NFS_SAFE_SAMPLE_KB and NFS_SAFE_INTERVAL_MS must be at least 1".to_string());
+        }
+        let nfs_safe_mode = source.bool("NFS_SAFE_MODE").then_some(StabilityCheck {
+            sample_bytes: nfs_safe_sample_kb * 1024,
+            interval: Duration::from_millis(nfs_safe_interval_ms),
+        });
+        let watch_keepalive_secs = Some(source.parse("WATCH_KEEPALIVE_SECS", 0u64)?).filter(|secs| *secs > 0);
+        let skip_delay_on_rename = source.bool("SKIP_DELAY_ON_RENAME");
+        let watch_events = WatchEvents::parse(&source.var("WATCH_EVENTS").unwrap_or_else(|| "create".to_string()))?;
+        let modify_debounce_ms = source.parse("MODIFY_DEBOUNCE_MS", 1000u64)?;
+        if modify_debounce_ms == 0 {
+            return Err("MODIFY_DEBOUNCE_MS must be at least 1".to_string());
+        }
+        let scan_on_startup = source.bool("SCAN_ON_STARTUP");
+        let scan_max_age = Some(source.parse("SCAN_MAX_AGE_SECS", 0u64)?)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        if scan_max_age.is_some() && !scan_on_startup {
+            return Err("SCAN_MAX_AGE_SECS requires SCAN_ON_STARTUP=true".to_string());
+        }
+        let scan_dir_concurrency = source.parse_optional::<usize>("SCAN_DIR_CONCURRENCY")?;
+        if scan_dir_concurrency == Some(0) {
+            return Err("SCAN_DIR_CONCURRENCY must be at least 1".to_string());
+        }
+        if scan_dir_concurrency.is_some() && !scan_on_startup {
+            return Err("SCAN_DIR_CONCURRENCY requires SCAN_ON_STARTUP=true".to_string());
+        }
+        let catchup_max_files = source.parse_optional::<usize>("CATCHUP_MAX_FILES")?;
+        let catchup_max_age_secs = source.parse_optional::<u64>("CATCHUP_MAX_AGE_SECS")?;
+        if catchup_max_files == Some(0) || catchup_max_age_secs == Some(0) {
+            return Err("CATCHUP_MAX_FILES and CATCHUP_MAX_AGE_SECS must be at least 1".to_string());
+        }
+        let catchup_report = source.var("CATCHUP_REPORT_FILE").filter(|path| !path.is_empty()).map(PathBuf::from);
+        let catchup_defer_dir = source.var("CATCHUP_DEFER_DIR").filter(|dir| !dir.is_empty()).map(PathBuf::from);
+        let catchup_endpoint = source.bool("CATCHUP_ENDPOINT");
+        let catchup = match (catchup_max_files, catchup_max_age_secs, catchup_report) {
+            (None, None, None) if catchup_defer_dir.is_none() && !catchup_endpoint => None,
+            (None, None, _) => {
+                return Err("CATCHUP_REPORT_FILE, CATCHUP_DEFER_DIR and CATCHUP_ENDPOINT require CATCHUP_MAX_FILES or CATCHUP_MAX_AGE_SECS".to_string());
+            }
+            (_, _, None) => return Err("CATCHUP_MAX_FILES and CATCHUP_MAX_AGE_SECS require CATCHUP_REPORT_FILE".to_string()),
+            (max_files, max_age_secs, Some(report)) => Some(CatchupPolicy {
+                max_files,
+                max_age: max_age_secs.map(Duration::from_secs),
+                report,
+                defer_dir: catchup_defer_dir,
+            }),
+        };
+        if catchup.is_some() && !scan_on_startup {
+            return Err("CATCHUP_MAX_FILES and CATCHUP_MAX_AGE_SECS require SCAN_ON_STARTUP=true".to_string());
+        }
+        let adaptive_settle = match source.var("SETTLE_MODE").unwrap_or_default().to_lowercase().as_str() {
+            "" | "fixed" => None,
+            "adaptive" => {
+                let percentile = source.parse("SETTLE_PERCENTILE", 90u8)?;
+                if !(1..=100).contains(&percentile) {
+                    return Err("SETTLE_PERCENTILE must be between 1 and 100".to_string());
+                }
+                let min_ms = source.parse("SETTLE_MIN_MS", 100u64)?;
+                let max_ms = source.parse("SETTLE_MAX_MS", 30_000u64)?;
+                if min_ms > max_ms {
+                    return Err("SETTLE_MIN_MS can't be more than SETTLE_MAX_MS".to_string());
+                }
+                let poll_ms = source.parse("SETTLE_POLL_MS", 100u64)?;
+                if poll_ms == 0 {
+                    return Err("SETTLE_POLL_MS must be at least 1".to_string());
+                }
+                Some(AdaptivePolicy {
+                    percentile,
+                    min: Duration::from_millis(min_ms),
+                    max: Duration::from_millis(max_ms),
+                    poll: Duration::from_millis(poll_ms),
+                })
+            }
+            other => return Err(format!("Invalid SETTLE_MODE '{}': expected 'fixed' or 'adaptive'", other)),
+        };
+        let settle_state_dir = source.var("SETTLE_STATE_DIR").filter(|dir| !dir.is_empty()).map(PathBuf::from);
+        let settle_endpoint = source.bool("SETTLE_ENDPOINT");
+        if adaptive_settle.is_none() && (settle_state_dir.is_some() || settle_endpoint) {
+            return Err("SETTLE_STATE_DIR and SETTLE_ENDPOINT require SETTLE_MODE=adaptive".to_string());
+        }
+        let lock_per_path = source.bool_or("LOCK_PER_PATH", true)?;
+        let ordering = DeliveryOrder::parse(&source.var("ORDERING").unwrap_or_else(|| "arrival".to_string()))?;
+        let ordering_window_ms = source.parse("ORDERING_WINDOW_MS", 1000u64)?;
+        if !(1..=60_000).contains(&ordering_window_ms) {
+            return Err("ORDERING_WINDOW_MS must be between 1 and 60000".to_string());
+        }
+        let path_case = match source.var("PATH_CASE_SENSITIVE").unwrap_or_default().to_lowercase().as_str() {
+            "" | "auto" => None,
+            "true" => Some(PathCase::Sensitive),
+            "false" => Some(PathCase::Insensitive),
+            other => {
+                return Err(format!("Invalid PATH_CASE_SENSITIVE '{}': expected 'true', 'false' or 'auto'", other));
+            }
+        };
+        if watch_poll_interval_secs == 0 || watch_retry_secs == 0 {
+            return Err("WATCH_POLL_INTERVAL_SECS and WATCH_RETRY_SECS must be at least 1".to_string());
+        }
+
+        let max_watch_depth = source.parse_optional("MAX_WATCH_DEPTH")?;
+
+        let auto_watch_pattern = match source.var("AUTO_WATCH_PATTERN").filter(|p| !p.is_empty()) {
+            Some(pattern) => Some(
+                Regex::new(&pattern)
+                    .map_err(|e| format!("Invalid AUTO_WATCH_PATTERN regex '{}': {}", pattern, e))?,
+            ),
+            None => None,
+        };
+        if auto_watch_pattern.is_some() && watch_backend == WatchBackend::Poll {
+            return Err("AUTO_WATCH_PATTERN requires WATCH_BACKEND=native".to_string());
+        }
+
+        let max_queued_files = source.parse("MAX_QUEUED_FILES", 10_000usize)?;
+        if max_queued_files == 0 {
+            return Err("MAX_QUEUED_FILES must be at least 1".to_string());
+        }
+        let queue_spill_dir = source.var("QUEUE_SPILL_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        if queue_spill_dir.is_some() && storage_db_path.is_some() {
+            return Err("QUEUE_SPILL_DIR can't be combined with STORAGE_DB_PATH, which keeps the spill queue".to_string());
+        }
+        let max_files_per_sec = Some(source.parse("MAX_FILES_PER_SEC", 0u32)?).filter(|rate| *rate > 0);
+        let min_content_bytes = Some(source.parse("MIN_CONTENT_BYTES", 0u64)?).filter(|bytes| *bytes > 0);
+        let utf16_xml = Utf16Handling::parse(
+            &source.var("UTF16_XML").unwrap_or_else(|| "transcode".to_string()),
+        )?;
+
+        let hardlink_policy = HardlinkPolicy::parse(
+            &source.var("HARDLINK_POLICY").unwrap_or_else(|| "deliver".to_string()),
+        )?;
+        let skip_redundant_delivery = source.bool("SKIP_REDUNDANT_DELIVERY");
+
+        let filename_rewrite = source.var("FILENAME_REWRITE")
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| FilenameRewrite::parse(&rule))
+            .transpose()?;
+
+        let split_on_element = source.var("SPLIT_ON_ELEMENT")
+            .map(|element| element.trim().to_string())
+            .filter(|element| !element.is_empty());
+        if let Some(element) = split_on_element.as_ref().filter(|element| element.contains([':', '/', ' ', '<', '>'])) {
+            return Err(format!("Invalid SPLIT_ON_ELEMENT '{}': expected a local element name without prefix", element));
+        }
+
+        let content_preview_bytes = match source.parse("CONTENT_PREVIEW_BYTES", 0usize)? {
+            0 => None,
+            bytes => Some(bytes),
+        };
+        let xml_c14n = source.bool("XML_C14N");
+        if xml_c14n && content_preview_bytes.is_some() {
+            return Err("XML_C14N can't be combined with CONTENT_PREVIEW_BYTES: a truncated document can't be canonicalized".to_string());
+        }
+
+        let sanitize_content = Sanitize::parse(&source.var("SANITIZE_CONTENT").unwrap_or_else(|| "reject".to_string()))?;
+        let content_invalid_utf8 =
+            InvalidUtf8::parse(&source.var("CONTENT_INVALID_UTF8").unwrap_or_else(|| "replace".to_string()))?;
+        let content_mode = ContentMode::parse(
+            &source.var("CONTENT_MODE").unwrap_or_else(|| "inline".to_string()),
+        )?;
+        let size_policy = source.var("SIZE_POLICY")
+            .filter(|policy| !policy.is_empty())
+            .map(|policy| SizePolicy::parse(&policy))
+            .transpose()?;
+        if size_policy.is_some() && (include_content || source.var("CONTENT_MODE").is_some()) {
+            return Err("SIZE_POLICY decides how content is sent; it can't be combined with INCLUDE_CONTENT or CONTENT_MODE".to_string());
+        }
+        let content_events = match source.var("CONTENT_EVENTS").filter(|events| !events.trim().is_empty()) {
+            Some(_) if !include_content && size_policy.is_none() => {
+                return Err("CONTENT_EVENTS requires INCLUDE_CONTENT=true or SIZE_POLICY".to_string());
+            }
+            Some(events) => WatchEvents::parse_option("CONTENT_EVENTS", &events)?,
+            None => WatchEvents { create: true, modify: true },
+        };
+        let batch_size = source.parse("BATCH_SIZE", 1usize)?;
+        let batch_idle_ms = source.parse("BATCH_IDLE_MS", 1000u64)?;
+        let batch_max_wait_ms = source.parse("BATCH_MAX_WAIT_MS", 10_000u64)?;
+        if batch_size == 0 || batch_idle_ms == 0 || batch_max_wait_ms == 0 {
+            return Err("BATCH_SIZE, BATCH_IDLE_MS and BATCH_MAX_WAIT_MS must be at least 1".to_string());
+        }
+        let batch = (batch_size > 1).then_some(BatchPolicy {
+            size: batch_size,
+            idle: Duration::from_millis(batch_idle_ms),
+            max_wait: Duration::from_millis(batch_max_wait_ms),
+        });
+        if batch.is_some() {
+            // Each of these needs a request, or a response, of its own per file
+            let conflicts = [
+                ("OVERWRITE_WITH_RESPONSE", overwrite_with_response),
+                ("ASYNC_ACK_TOKEN", async_ack_token.is_some()),
+                ("CONTENT_HEADERS", content_headers.is_some()),
+                ("SPLIT_ON_ELEMENT", split_on_element.is_some()),
+                ("ORDERING=mtime", ordering == DeliveryOrder::Mtime),
+            ];
+            if let Some((name, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(format!("BATCH_SIZE can't be combined with {}", name));
+            }
+        }
+        if presigned.is_some() {
+            // The file's bytes are the upload, sent once whole, and only the
+            // status of each phase decides the outcome
+            let conflicts = [
+                ("INCLUDE_CONTENT", include_content),
+                ("SIZE_POLICY", size_policy.is_some()),
+                ("SPLIT_ON_ELEMENT", split_on_element.is_some()),
+                ("BUNDLE_EXTENSIONS", !bundle_extensions.is_empty()),
+                ("BATCH_SIZE", batch.is_some()),
+                ("OVERWRITE_WITH_RESPONSE", overwrite_with_response),
+                ("ASYNC_ACK_TOKEN", async_ack_token.is_some()),
+                ("SUCCESS_BODY_MATCH", success_body_match.is_some()),
+                ("SHADOW_WEBHOOK_URL", shadow_webhook_url.is_some()),
+                ("CONTENT_HEADERS", content_headers.is_some()),
+            ];
+            if let Some((name, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(format!("DELIVERY_MODE=presigned can't be combined with {}", name));
+            }
+        }
+        let content_serve_addr = source.parse("CONTENT_SERVE_ADDR", SocketAddr::from(([0, 0, 0, 0], 8080)))?;
+        let content_url_base = source.var("CONTENT_URL_BASE")
+            .filter(|base| !base.is_empty())
+            .unwrap_or_else(|| format!("http://{}", content_serve_addr))
+            .trim_end_matches('/')
+            .to_string();
+        // The content server itself speaks plain HTTP, so with REQUIRE_TLS it has
+        // to sit behind a TLS-terminating proxy that CONTENT_URL_BASE points at
+        let serves_content = match &size_policy {
+            Some(policy) => policy.uses(SizeAction::Reference),
+            None => include_content && content_mode == ContentMode::Reference,
+        };
+        if require_tls && serves_content && !is_https(&content_url_base) {
+            return Err(format!("REQUIRE_TLS is enabled but CONTENT_URL_BASE {} is not https", content_url_base));
+        }
+        let content_ref_template = source.var("CONTENT_REF_TEMPLATE")
+            .filter(|template| !template.is_empty())
+            .map(|template| UrlTemplate::parse("CONTENT_REF_TEMPLATE", &template))
+            .transpose()?;
+        let content_url_ttl_secs = source.parse("CONTENT_URL_TTL_SECS", 3600u64)?;
+        if content_url_ttl_secs == 0 {
+            return Err("CONTENT_URL_TTL_SECS must be at least 1".to_string());
+        }
+        let content_roots = match source.var("CONTENT_ROOTS").filter(|roots| !roots.is_empty()) {
+            Some(roots) => roots
+                .split(',')
+                .map(str::trim)
+                .filter(|root| !root.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+            None => vec![watch_dir.clone()],
+        };
+        let throttles_endpoint = source.bool("THROTTLES_ENDPOINT");
+
+        let preview_endpoint = source.bool("PREVIEW_ENDPOINT");
+        let skips_endpoint = source.bool("SKIPS_ENDPOINT");
+        let events_endpoint = source.bool("EVENTS_ENDPOINT");
+        let events_history_size = source.parse("EVENTS_HISTORY_SIZE", 100usize)?;
+        if events_history_size == 0 {
+            return Err("EVENTS_HISTORY_SIZE must be at least 1".to_string());
+        }
+        let debug_inject_events = source.bool("DEBUG_INJECT_EVENTS");
+        let debug_payload_dir = source.var("DEBUG_PAYLOAD_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+
+        let pre_delivery_command = source.var("PRE_DELIVERY_COMMAND").filter(|c| !c.trim().is_empty());
+        let post_delivery_command = source.var("POST_DELIVERY_COMMAND").filter(|c| !c.trim().is_empty());
+        let pre_delivery_failure = PreHookFailure::parse(
+            &source.var("PRE_DELIVERY_FAILURE").unwrap_or_else(|| "skip".to_string()),
+        )?;
+        let quarantine_dir = source.var("QUARANTINE_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        if pre_delivery_failure == PreHookFailure::Quarantine && quarantine_dir.is_none() {
+            return Err("PRE_DELIVERY_FAILURE=quarantine requires QUARANTINE_DIR".to_string());
+        }
+        let retry_later_dir = source.var("RETRY_LATER_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        let retry_later_after_secs = Some(source.parse("RETRY_LATER_AFTER_SECS", 0u64)?).filter(|secs| *secs > 0);
+        if retry_later_after_secs.is_some() && retry_later_dir.is_none() {
+            return Err("RETRY_LATER_AFTER_SECS requires RETRY_LATER_DIR".to_string());
+        }
+        let hook_timeout_secs = source.parse("HOOK_TIMEOUT_SECS", 30u64)?;
+        let hook_max_concurrent = source.parse("HOOK_MAX_CONCURRENT", 4usize)?;
+        if hook_timeout_secs == 0 || hook_max_concurrent == 0 {
+            return Err("HOOK_TIMEOUT_SECS and HOOK_MAX_CONCURRENT must be at least 1".to_string());
+        }
+
+        Ok(Config {
+            profile,
+            watch_dir,
+            allow_overlapping_roots,
+            webhook_url,
+            webhook_method,
+            webhook_timeout_secs,
+            webhook_headers,
+            webhook_retry: RetryPolicy {
+                max_retries: webhook_max_retries,
+                base: Duration::from_millis(webhook_retry_base_ms),
+            },
+            retryable_errors,
+            presigned,
+            shadow_webhook_url,
+            shadow_max_concurrent,
+            shadow_report_interval_secs,
+            outcome_webhook_url,
+            outcome_flush_secs,
+            syslog_target,
+            syslog_facility,
+            audit_skips,
+            storage_db_path,
+            bind_local_address,
+            include_content,
+            overwrite_with_response,
+            accept_compressed_responses,
+            detect_content_type_from_doc,
+            unsafe_log_secrets,
+            require_tls,
+            tls,
+            concurrency_mode,
+            max_concurrent_webhooks,
+            concurrency_floor,
+            concurrency_ceiling,
+            target_latency_ms,
+            include_detection_latency,
+            include_sequence,
+            sequence_dir,
+            numeric_fields_as_string,
+            jws_signer,
+            digest_algorithm,
+            success_body_match,
+            async_ack_token,
+            async_ack_dir,
+            async_ack_max_pending,
+            async_ack_timeout_secs,
+            content_headers,
+            bundle_extensions,
+            watch_all_files,
+            archive_limits,
+            read_only,
+            ignore_list_max_entries,
+            backup_before_overwrite,
+            backup_dir,
+            overwrite_target_encoding,
+            encoding_fallback,
+            watch_backend,
+            watch_poll_interval_secs,
+            watch_retry_secs,
+            watch_keepalive_secs,
+            skip_delay_on_rename,
+            watch_events,
+            content_events,
+            modify_debounce: Duration::from_millis(modify_debounce_ms),
+            scan_on_startup,
+            scan_max_age,
+            scan_dir_concurrency,
+            catchup,
+            catchup_endpoint,
+            adaptive_settle,
+            settle_state_dir,
+            settle_endpoint,
+            lock_per_path,
+            ordering,
+            ordering_window_ms,
+            path_case,
+            nfs_safe_mode,
+            max_watch_depth,
+            auto_watch_pattern,
+            max_queued_files,
+            queue_spill_dir,
+            max_files_per_sec,
+            min_content_bytes,
+            utf16_xml,
+            hardlink_policy,
+            skip_redundant_delivery,
+            filename_rewrite,
+            split_on_element,
+            batch,
+            content_preview_bytes,
+            xml_c14n,
+            sanitize_content,
+            content_invalid_utf8,
+            content_mode,
+            size_policy,
+            content_serve_addr,
+            content_url_base,
+            content_url_ttl_secs,
+            content_roots,
+            content_ref_template,
+            throttles_endpoint,
+            preview_endpoint,
+            skips_endpoint,
+            events_endpoint,
+            events_history_size,
+            debug_inject_events,
+            debug_payload_dir,
+            pre_delivery_command,
+            post_delivery_command,
+            pre_delivery_failure,
+            quarantine_dir,
+            retry_later_dir,
+            retry_later_after_secs,
+            hook_timeout_secs,
+            hook_max_concurrent,
+        })
+    }
+
+    /// Whether any delivery may carry content, inline or by reference.
+    pub fn sends_content(&self) -> bool {
+        match &self.size_policy {
+            Some(policy) => policy.uses(SizeAction::Inline) || policy.uses(SizeAction::Reference),
+            None => self.include_content,
+        }
+    }
+
+    /// Whether any delivery may carry content by reference.
+    pub fn serves_content(&self) -> bool {
+        match &self.size_policy {
+            Some(policy) => policy.uses(SizeAction::Reference),
+            None => self.include_content && self.content_mode == ContentMode::Reference,
+        }
+    }
+
+    /// Prefix for log lines that belong to this profile.
+    pub fn log_prefix(&self) -> String {
+        match &self.profile {
+            Some(name) => format!("[{}] ", name),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_rewrites_replace_every_match() {
+        let rewrite = FilenameRewrite::parse(r"^(\d{4})-(\d{2})_(.*)\.XML$=>$3-$1$2.xml").unwrap();
+        assert_eq!(rewrite.apply("2026-10_order.XML"), "order-202610.xml");
+        assert_eq!(rewrite.apply("order.xml"), "order.xml");
+        assert_eq!(FilenameRewrite::parse(" =>_").unwrap().apply("a b c.xml"), "a_b_c.xml");
+        // Only the first `=>` separates the two
+        assert_eq!(FilenameRewrite::parse("a=>b=>c").unwrap().apply("a.xml"), "b=>c.xml");
+        assert_eq!(rewrite.to_string(), r"^(\d{4})-(\d{2})_(.*)\.XML$=>$3-$1$2.xml");
+    }
+
+    #[test]
+    fn invalid_filename_rewrites_are_refused() {
+        assert!(FilenameRewrite::parse("order.xml").unwrap_err().contains("'regex=>replacement'"));
+        assert!(FilenameRewrite::parse("(unclosed=>x").unwrap_err().contains("Invalid FILENAME_REWRITE regex"));
+    }
+
+    #[test]
+    fn url_templates_encode_what_they_fill_in() {
+        let template = UrlTemplate::parse("CONTENT_REF_TEMPLATE", "s3://bucket/{profile}/{relpath}?name={filename}").unwrap();
+        assert_eq!(
+            template.render("in/2026 Q1/order #1.xml", "order #1.xml", Some("edi")),
+            "s3://bucket/edi/in/2026%20Q1/order%20%231.xml?name=order%20%231.xml"
+        );
+        assert_eq!(template.render("ä.xml", "ä.xml", None), "s3://bucket/default/%C3%A4.xml?name=%C3%A4.xml");
+        assert_eq!(template.to_string(), "s3://bucket/{profile}/{relpath}?name={filename}");
+        let literal = UrlTemplate::parse("PRESIGN_URL", "https://api.example/presign").unwrap();
+        assert_eq!(literal.render("a.xml", "a.xml", None), "https://api.example/presign");
+    }
+
+    #[test]
+    fn url_templates_refuse_unknown_and_unclosed_placeholders() {
+        let unknown = UrlTemplate::parse("CONFIRM_URL", "https://x/{path}").unwrap_err();
+        assert!(unknown.contains("'{path}' in CONFIRM_URL"), "{}", unknown);
+        let unclosed = UrlTemplate::parse("CONFIRM_URL", "https://x/{filename").unwrap_err();
+        assert!(unclosed.starts_with("Unclosed placeholder in CONFIRM_URL"), "{}", unclosed);
+    }
+
+    #[test]
+    fn optional_options_treat_empty_as_unset() {
+        let mut table = toml::Table::new();
+        table.insert("max_watch_depth".to_string(), toml::Value::String(" 3 ".to_string()));
+        table.insert("max_concurrent_webhooks".to_string(), toml::Value::String(String::new()));
+        table.insert("max_queued_files".to_string(), toml::Value::String("many".to_string()));
+        let source = ConfigSource::Profile(&table);
+        assert_eq!(source.parse_optional::<usize>("MAX_WATCH_DEPTH"), Ok(Some(3)));
+        assert_eq!(source.parse_optional::<usize>("MAX_CONCURRENT_WEBHOOKS"), Ok(None));
+        assert_eq!(
+            source.parse_optional::<usize>("MAX_QUEUED_FILES"),
+            Err("Invalid value for MAX_QUEUED_FILES: 'many'".to_string())
+        );
+    }
+
+    #[test]
+    fn switches_that_default_to_on_accept_any_case() {
+        let mut table = toml::Table::new();
+        table.insert("lock_per_path".to_string(), toml::Value::String("FALSE".to_string()));
+        table.insert("skip_redundant_delivery".to_string(), toml::Value::Boolean(true));
+        table.insert("scan_on_startup".to_string(), toml::Value::String(String::new()));
+        table.insert("xml_c14n".to_string(), toml::Value::String("yes".to_string()));
+        let source = ConfigSource::Profile(&table);
+        assert_eq!(source.bool_or("LOCK_PER_PATH", true), Ok(false));
+        assert_eq!(source.bool_or("SKIP_REDUNDANT_DELIVERY", false), Ok(true));
+        assert_eq!(source.bool_or("SCAN_ON_STARTUP", true), Ok(true));
+        assert_eq!(
+            source.bool_or("XML_C14N", true),
+            Err("Invalid value for XML_C14N: 'yes', expected 'true' or 'false'".to_string())
+        );
+    }
+}
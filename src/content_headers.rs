@@ -0,0 +1,175 @@
+use reqwest::header::{HeaderName, HeaderValue};
+
+use crate::xml_path::{self, XmlPath};
+
+// Headers the watcher sets itself, which rules must not replace
+const RESERVED_HEADERS: &[&str] = &["content-type", "content-length", "digest", "host"];
+
+/// Fixed request headers sent with every webhook request, WEBHOOK_HEADERS,
+/// written as `X-Api-Key: abc123;X-Tenant: acme`.
+///
+/// A `Content-Type` among them replaces the watcher's own. Entries that
+/// aren't valid headers, or name one the watcher must set itself, are left
+/// out and kept as problems for the startup log.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookHeaders {
+    headers: Vec<(HeaderName, String)>,
+    problems: Vec<String>,
+}
+
+impl WebhookHeaders {
+    pub fn parse(value: &str) -> Self {
+        let mut parsed = WebhookHeaders::default();
+        for entry in value.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let Some((name, header_value)) = entry.split_once(':') else {
+                parsed.problems.push(format!("expected <name>: <value>, got '{}'", entry));
+                continue;
+            };
+            let (name, header_value) = (name.trim(), header_value.trim());
+            let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+                parsed.problems.push(format!("'{}' is not a valid header name", name));
+                continue;
+            };
+            if name.as_str() != "content-type" && RESERVED_HEADERS.contains(&name.as_str()) {
+                parsed.problems.push(format!("{} is set by the watcher itself", name));
+                continue;
+            }
+            // The value isn't repeated, it may be a secret
+            if HeaderValue::from_str(header_value).is_err() {
+                parsed.problems.push(format!("the value of {} is not a valid header value", name));
+                continue;
+            }
+            parsed.headers.push((name, header_value.to_string()));
+        }
+        parsed
+    }
+
+    /// The Content-Type to send instead of the watcher's, if one is given.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.as_str() == "content-type")
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The other headers, in the order given.
+    pub fn others(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name.as_str() != "content-type")
+            .map(|(name, value)| (name.to_string(), value.clone()))
+    }
+
+    /// Whether `name` is one of the headers, in any case.
+    pub fn contains(&self, name: &str) -> bool {
+        self.headers.iter().any(|(header, _)| header.as_str().eq_ignore_ascii_case(name))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// The names of the headers, for the startup log; the values may be secrets.
+    pub fn names(&self) -> Vec<String> {
+        self.headers.iter().map(|(name, _)| name.to_string()).collect()
+    }
+
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+}
+
+/// What to send for a header whose path matches nothing in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingHeader {
+    Omit,
+    Empty,
+}
+
+impl MissingHeader {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "omit" => Ok(MissingHeader::Omit),
+            "empty" => Ok(MissingHeader::Empty),
+            other => Err(format!(
+                "Invalid CONTENT_HEADER_MISSING '{}': expected 'omit' or 'empty'",
+                other
+            )),
+        }
+    }
+}
+
+/// Request headers whose values are taken from each delivered XML document,
+/// written as `Header=/path,Other=/path/@attribute`.
+#[derive(Debug, Clone)]
+pub struct ContentHeaders {
+    rules: Vec<(HeaderName, XmlPath)>,
+    missing: MissingHeader,
+}
+
+impl ContentHeaders {
+    pub fn parse(value: &str, missing: MissingHeader) -> Result<Self, String> {
+        let invalid = |reason: String| format!("Invalid CONTENT_HEADERS '{}': {}", value, reason);
+        let mut rules = Vec::new();
+        for rule in value.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (name, path) = rule
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected <header>=<path>, got '{}'", rule)))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| invalid(format!("'{}' is not a valid header name", name.trim())))?;
+            if RESERVED_HEADERS.contains(&name.as_str()) {
+                return Err(invalid(format!("{} is set by the watcher itself", name)));
+            }
+            let path = XmlPath::parse(path.trim()).map_err(invalid)?;
+            rules.push((name, path));
+        }
+        if rules.is_empty() {
+            return Err(invalid("no rules".to_string()));
+        }
+        Ok(ContentHeaders { rules, missing })
+    }
+
+    /// The headers for one document. Values that can't be sent as a header,
+    /// such as text spanning several lines, count as missing; problems are
+    /// returned alongside for the log.
+    pub fn evaluate(&self, data: &[u8]) -> (Vec<(String, String)>, Vec<String>) {
+        let mut problems = Vec::new();
+        let document = match std::str::from_utf8(data)
+            .map_err(|e| format!("not valid UTF-8: {}", e))
+            .and_then(xml_path::parse_document)
+        {
+            Ok(document) => Some(document),
+            Err(e) => {
+                problems.push(format!("document is {}", e));
+                None
+            }
+        };
+
+        let mut headers = Vec::new();
+        for (name, path) in &self.rules {
+            let value = match document.as_ref().and_then(|document| path.find(document)) {
+                Some(value) if HeaderValue::from_str(&value).is_ok() => Some(value),
+                Some(_) => {
+                    problems.push(format!("{} of {} is not a valid header value", name, path));
+                    None
+                }
+                None => None,
+            };
+            match (value, self.missing) {
+                (Some(value), _) => headers.push((name.to_string(), value)),
+                (None, MissingHeader::Empty) => headers.push((name.to_string(), String::new())),
+                (None, MissingHeader::Omit) => {}
+            }
+        }
+        (headers, problems)
+    }
+
+    pub fn describe(&self) -> String {
+        let rules: Vec<String> = self.rules.iter().map(|(name, path)| format!("{}={}", name, path)).collect();
+        let missing = match self.missing {
+            MissingHeader::Omit => "omitted",
+            MissingHeader::Empty => "sent empty",
+        };
+        format!("{} (missing values {})", rules.join(", "), missing)
+    }
+}
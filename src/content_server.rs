@@ -0,0 +1,543 @@
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+
+use crate::events::{FileEvent, FileEventKind};
+
+// Size of the chunks a registered file is streamed in
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+// Largest request body accepted by `POST /preview` and `POST /control/inject-event`
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// Renders the request the watcher would send for a path, for `POST /preview`.
+pub type PreviewHandler =
+    Arc<dyn Fn(PathBuf) -> Pin<Box<dyn Future<Output = Result<serde_json::Value, PreviewError>> + Send>> + Send + Sync>;
+
+/// Why `POST /preview` rendered nothing.
+#[derive(Debug)]
+pub enum PreviewError {
+    // Outside every watch directory with previews, answered with 403
+    Forbidden(String),
+    // Anything else, answered with 400
+    Invalid(String),
+}
+
+/// Completes deliveries the receiver accepted with a token, for
+/// `POST /ack/<token>`, and lists those still waiting for `GET /pending`.
+pub trait AckHandler: Send + Sync {
+    /// Acknowledge the delivery with `token`. Returns the file's path, or
+    /// `None` for unknown tokens.
+    fn ack(&self, token: String) -> Pin<Box<dyn Future<Output = Option<String>> + Send>>;
+    fn pending(&self) -> serde_json::Value;
+}
+
+/// Lists the throttles receivers asked for that are in force, for
+/// `GET /throttles`.
+pub type ThrottlesHandler = Arc<dyn Fn() -> serde_json::Value + Send + Sync>;
+
+/// Lists recently skipped files for `GET /skips/recent`.
+pub type SkipsHandler = Arc<dyn Fn() -> serde_json::Value + Send + Sync>;
+
+/// Lists the settle delays learned per directory for `GET /settle`.
+pub type SettleHandler = Arc<dyn Fn() -> serde_json::Value + Send + Sync>;
+
+/// Reports how far the startup scan's deliveries have got, for `GET /catchup`.
+pub type CatchupHandler = Arc<dyn Fn() -> serde_json::Value + Send + Sync>;
+
+/// Lists the watcher's recent notable occurrences for `GET /events/internal`.
+pub type EventsHandler = Arc<dyn Fn() -> serde_json::Value + Send + Sync>;
+
+/// Hands an event to the profiles watching its paths, for
+/// `POST /control/inject-event`, and describes where it went.
+pub type InjectHandler = Arc<dyn Fn(FileEvent) -> Result<serde_json::Value, String> + Send + Sync>;
+
+#[derive(Deserialize)]
+struct PreviewRequest {
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct InjectRequest {
+    // Created when not given
+    kind: Option<FileEventKind>,
+    paths: Vec<PathBuf>,
+}
+
+/// How the content of a delivered file reaches the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentMode {
+    // Embedded in the payload's `content` field
+    Inline,
+    // Fetched by the receiver from the watcher's content server via `content_url`
+    Reference,
+}
+
+impl ContentMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "inline" => Ok(ContentMode::Inline),
+            "reference" => Ok(ContentMode::Reference),
+            other => Err(format!(
+                "Invalid CONTENT_MODE '{}': expected 'inline' or 'reference'",
+                other
+            )),
+        }
+    }
+}
+
+enum ContentSource {
+    // A file inside one of the content roots, read at request time
+    File { path: PathBuf, root: PathBuf },
+    // An archive entry, already extracted
+    Bytes(Arc<Vec<u8>>),
+}
+
+struct Registration {
+    source: ContentSource,
+    content_type: String,
+    expires_at: Instant,
+    // Kept until then so that late fetches get 410 instead of 404
+    forget_at: Instant,
+}
+
+/// Content that can currently be fetched, keyed by an unguessable per-event token.
+///
+/// Only registered content is ever served; request paths are never mapped onto
+/// the filesystem. Files are registered only from inside a content root, and
+/// checked again on every request.
+#[derive(Default)]
+pub struct ContentRegistry {
+    entries: Mutex<HashMap<String, Registration>>,
+}
+
+enum Lookup {
+    Unknown,
+    Gone,
+    File { path: PathBuf, root: PathBuf, content_type: String },
+    Bytes { data: Arc<Vec<u8>>, content_type: String },
+}
+
+impl ContentRegistry {
+    /// Register a file below one of `roots`; returns the token that addresses it.
+    pub fn register_file(&self, path: &Path, roots: &[PathBuf], content_type: &str, ttl: Duration) -> Result<String, String> {
+        let resolved = path
+            .canonicalize()
+            .map_err(|e| format!("failed to resolve {}: {}", path.display(), e))?;
+        // Roots that can't be resolved allow nothing
+        let root = roots
+            .iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .find(|root| resolved.starts_with(root))
+            .ok_or_else(|| format!("{} is outside the content roots", resolved.display()))?;
+        Ok(self.register(ContentSource::File { path: resolved, root }, content_type, ttl))
+    }
+
+    /// Register extracted bytes; returns the token that addresses them.
+    pub fn register_bytes(&self, data: Vec<u8>, content_type: &str, ttl: Duration) -> String {
+        self.register(ContentSource::Bytes(Arc::new(data)), content_type, ttl)
+    }
+
+    fn register(&self, source: ContentSource, content_type: &str, ttl: Duration) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, registration| registration.forget_at > now);
+        entries.insert(
+            token.clone(),
+            Registration {
+                source,
+                content_type: content_type.to_string(),
+                expires_at: now + ttl,
+                forget_at: now + ttl * 2,
+            },
+        );
+        token
+    }
+
+    fn lookup(&self, token: &str) -> Lookup {
+        let entries = self.entries.lock().unwrap();
+        let Some(registration) = entries.get(token) else {
+            return Lookup::Unknown;
+        };
+        if registration.expires_at <= Instant::now() {
+            return Lookup::Gone;
+        }
+        let content_type = registration.content_type.clone();
+        match &registration.source {
+            ContentSource::File { path, root } => Lookup::File {
+                path: path.clone(),
+                root: root.clone(),
+                content_type,
+            },
+            ContentSource::Bytes(data) => Lookup::Bytes {
+                data: Arc::clone(data),
+                content_type,
+            },
+        }
+    }
+}
+
+enum Confined {
+    Inside(PathBuf),
+    Outside(PathBuf),
+    Missing,
+}
+
+// Resolve `path` and check that it still lies below the canonical `root`, so
+// that a symlink swapped in after registration can't expose files outside
+// the tree
+fn confined_path(path: &Path, root: &Path) -> Confined {
+    match path.canonicalize() {
+        Ok(resolved) if resolved.starts_with(root) => Confined::Inside(resolved),
+        Ok(resolved) => Confined::Outside(resolved),
+        Err(_) => Confined::Missing,
+    }
+}
+
+/// Serve `GET /content/<token>` for registered content, `POST /preview` when a
+/// preview handler is given, `POST /ack/<token>` and `GET /pending` when an
+/// ack handler is, `GET /skips/recent` when a skips handler is, `GET /settle`
+/// when a settle handler is, `GET /catchup` when a catch-up handler is,
+/// `GET /events/internal` when an events handler is, `GET /throttles` when a
+/// throttles handler is and `POST /control/inject-event` when an inject
+/// handler is, until the process exits.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    addr: SocketAddr,
+    registry: Arc<ContentRegistry>,
+    preview: Option<PreviewHandler>,
+    acks: Option<Arc<dyn AckHandler>>,
+    skips: Option<SkipsHandler>,
+    settle: Option<SettleHandler>,
+    catchup: Option<CatchupHandler>,
+    events: Option<EventsHandler>,
+    throttles: Option<ThrottlesHandler>,
+    inject: Option<InjectHandler>,
+) -> Result<(), String> {
+    let make_service = make_service_fn(move |_| {
+        let registry = Arc::clone(&registry);
+        let preview = preview.clone();
+        let acks = acks.clone();
+        let skips = skips.clone();
+        let settle = settle.clone();
+        let catchup = catchup.clone();
+        let events = events.clone();
+        let throttles = throttles.clone();
+        let inject = inject.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let registry = Arc::clone(&registry);
+                let preview = preview.clone();
+                let acks = acks.clone();
+                let skips = skips.clone();
+                let settle = settle.clone();
+                let catchup = catchup.clone();
+                let events = events.clone();
+                let throttles = throttles.clone();
+                let inject = inject.clone();
+                async move {
+                    let path = request.uri().path();
+                    let response = match (&preview, &acks, &skips, &settle, &catchup, &events, &throttles, &inject) {
+                        (Some(preview), ..) if path == "/preview" => respond_preview(preview, request).await,
+                        (_, Some(acks), ..) if path == "/pending" || path.starts_with("/ack/") => {
+                            respond_ack(acks.as_ref(), request).await
+                        }
+                        (_, _, Some(skips), ..) if path == "/skips/recent" => respond_listing(skips, request),
+                        (_, _, _, Some(settle), ..) if path == "/settle" => respond_listing(settle, request),
+                        (_, _, _, _, Some(catchup), ..) if path == "/catchup" => respond_listing(catchup, request),
+                        (.., Some(events), _, _) if path == "/events/internal" => respond_listing(events, request),
+                        (.., Some(throttles), _) if path == "/throttles" => respond_listing(throttles, request),
+                        (.., Some(inject)) if path == "/control/inject-event" => {
+                            respond_inject(inject, request).await
+                        }
+                        _ => respond(&registry, request).await,
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("Failed to bind content server to {}: {}", addr, e))?
+        .serve(make_service);
+    info!("Content server listening on {}", addr);
+    server
+        .await
+        .map_err(|e| format!("Content server on {} failed: {}", addr, e))
+}
+
+async fn respond(registry: &ContentRegistry, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let Some(token) = request.uri().path().strip_prefix("/content/") else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+
+    match registry.lookup(token) {
+        Lookup::Unknown => status_response(StatusCode::NOT_FOUND),
+        Lookup::Gone => status_response(StatusCode::GONE),
+        Lookup::Bytes { data, content_type } => {
+            let body = if request.method() == Method::HEAD {
+                Body::empty()
+            } else {
+                Body::from(data.as_ref().clone())
+            };
+            content_response(&content_type, data.len() as u64, body)
+        }
+        Lookup::File { path, root, content_type } => {
+            // The file may have been moved or deleted since it was delivered
+            let path = match confined_path(&path, &root) {
+                Confined::Inside(path) => path,
+                Confined::Outside(resolved) => {
+                    warn!("Refusing to serve {}: it now resolves outside {}", resolved.display(), root.display());
+                    return status_response(StatusCode::FORBIDDEN);
+                }
+                Confined::Missing => return status_response(StatusCode::GONE),
+            };
+            let file = match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(_) => return status_response(StatusCode::GONE),
+            };
+            let length = match file.metadata().await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => return status_response(StatusCode::GONE),
+            };
+            let body = if request.method() == Method::HEAD {
+                Body::empty()
+            } else {
+                stream_file(file, path)
+            };
+            content_response(&content_type, length, body)
+        }
+    }
+}
+
+// The body of a POST request, or the response refusing it
+async fn read_post_body(request: Request<Body>) -> Result<Vec<u8>, Response<Body>> {
+    if request.method() != Method::POST {
+        return Err(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return Err(status_response(StatusCode::BAD_REQUEST));
+        };
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > MAX_REQUEST_BYTES {
+            return Err(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+    }
+    Ok(bytes)
+}
+
+async fn respond_preview(preview: &PreviewHandler, request: Request<Body>) -> Response<Body> {
+    let bytes = match read_post_body(request).await {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
+    };
+    let request: PreviewRequest = match serde_json::from_slice(&bytes) {
+        Ok(request) => request,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("expected {{\"path\": \"...\"}}: {}", e)),
+    };
+
+    match preview(request.path).await {
+        Ok(rendered) => {
+            let body = serde_json::to_vec_pretty(&rendered).unwrap_or_default();
+            content_response("application/json", body.len() as u64, Body::from(body))
+        }
+        Err(PreviewError::Forbidden(e)) => text_response(StatusCode::FORBIDDEN, e),
+        Err(PreviewError::Invalid(e)) => text_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn respond_ack(acks: &dyn AckHandler, request: Request<Body>) -> Response<Body> {
+    if request.uri().path() == "/pending" {
+        if request.method() != Method::GET {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let body = serde_json::to_vec_pretty(&acks.pending()).unwrap_or_default();
+        return content_response("application/json", body.len() as u64, Body::from(body));
+    }
+
+    if request.method() != Method::POST {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let token = request.uri().path().trim_start_matches("/ack/").to_string();
+    match acks.ack(token).await {
+        Some(path) => text_response(StatusCode::OK, format!("acknowledged {}", path)),
+        None => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn respond_inject(inject: &InjectHandler, request: Request<Body>) -> Response<Body> {
+    let bytes = match read_post_body(request).await {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
+    };
+    let request: InjectRequest = match serde_json::from_slice(&bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            return text_response(StatusCode::BAD_REQUEST, format!("expected {{\"kind\": \"...\", \"paths\": [...]}}: {}", e))
+        }
+    };
+
+    let event = FileEvent::new(request.kind.unwrap_or(FileEventKind::Created), request.paths);
+    match inject(event) {
+        Ok(injected) => {
+            let body = serde_json::to_vec_pretty(&injected).unwrap_or_default();
+            let mut response = content_response("application/json", body.len() as u64, Body::from(body));
+            *response.status_mut() = StatusCode::ACCEPTED;
+            response
+        }
+        Err(e) => text_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+// `GET /skips/recent`, `GET /settle`, `GET /catchup`, `GET /events/internal` and
+// `GET /throttles`
+fn respond_listing(listing: &Arc<dyn Fn() -> serde_json::Value + Send + Sync>, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let body = serde_json::to_vec_pretty(&listing()).unwrap_or_default();
+    content_response("application/json", body.len() as u64, Body::from(body))
+}
+
+fn stream_file(mut file: tokio::fs::File, path: PathBuf) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; STREAM_CHUNK_BYTES];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender.send_data(buffer[..n].to_vec().into()).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to stream {}: {}", path.display(), e);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    body
+}
+
+fn content_response(content_type: &str, length: u64, body: Body) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Length", length)
+        .body(body)
+        .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+fn text_response(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    async fn get(registry: &ContentRegistry, path: &str) -> (StatusCode, Vec<u8>) {
+        let response = respond(registry, Request::get(path).body(Body::empty()).unwrap()).await;
+        let status = response.status();
+        (status, hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec())
+    }
+
+    #[test]
+    fn files_outside_the_roots_are_never_registered() {
+        let tree = tempfile::tempdir().unwrap();
+        let root = tree.path().join("watch");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.xml"), "<a/>").unwrap();
+        std::fs::write(tree.path().join("secret.xml"), "<secret/>").unwrap();
+        std::os::unix::fs::symlink(tree.path().join("secret.xml"), root.join("link.xml")).unwrap();
+        let registry = ContentRegistry::default();
+        let roots = [root.clone()];
+
+        for path in [root.join("../secret.xml"), root.join("sub/../../secret.xml"), root.join("link.xml")] {
+            let error = registry.register_file(&path, &roots, "application/xml", TTL).unwrap_err();
+            assert!(error.ends_with("is outside the content roots"), "{}", error);
+        }
+        // `..` that stays inside is only another way to name the file
+        assert!(registry.register_file(&root.join("sub/../a.xml"), &roots, "application/xml", TTL).is_ok());
+        assert!(registry.register_file(&root.join("missing.xml"), &roots, "application/xml", TTL).is_err());
+    }
+
+    #[tokio::test]
+    async fn request_paths_are_never_mapped_onto_files() {
+        let tree = tempfile::tempdir().unwrap();
+        std::fs::write(tree.path().join("a.xml"), "<a/>").unwrap();
+        let registry = ContentRegistry::default();
+        let token = registry
+            .register_file(&tree.path().join("a.xml"), &[tree.path().to_path_buf()], "application/xml", TTL)
+            .unwrap();
+
+        assert_eq!(get(&registry, &format!("/content/{}", token)).await, (StatusCode::OK, b"<a/>".to_vec()));
+        for path in [
+            "/content/../a.xml".to_string(),
+            "/content/..%2F..%2Fetc%2Fpasswd".to_string(),
+            format!("/content/{}/../a.xml", token),
+            format!("/content/../content/{}x", token),
+            "/a.xml".to_string(),
+        ] {
+            assert_eq!(get(&registry, &path).await.0, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_swapped_for_links_out_of_the_root_are_refused() {
+        let tree = tempfile::tempdir().unwrap();
+        let root = tree.path().join("watch");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("a.xml"), "<a/>").unwrap();
+        std::fs::write(tree.path().join("secret.xml"), "<secret/>").unwrap();
+        let registry = ContentRegistry::default();
+        let token = registry.register_file(&root.join("a.xml"), std::slice::from_ref(&root), "application/xml", TTL).unwrap();
+
+        std::fs::remove_file(root.join("a.xml")).unwrap();
+        assert_eq!(get(&registry, &format!("/content/{}", token)).await.0, StatusCode::GONE);
+        std::os::unix::fs::symlink(tree.path().join("secret.xml"), root.join("a.xml")).unwrap();
+        assert_eq!(get(&registry, &format!("/content/{}", token)).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn expired_content_is_gone() {
+        let registry = ContentRegistry::default();
+        let token = registry.register_bytes(b"<a/>".to_vec(), "application/xml", Duration::ZERO);
+        assert_eq!(get(&registry, &format!("/content/{}", token)).await.0, StatusCode::GONE);
+        let token = registry.register_bytes(b"<a/>".to_vec(), "application/xml", TTL);
+        let response = respond(&registry, Request::head(format!("/content/{}", token)).body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,650 @@
+use chrono::Utc;
+use log::{debug, error, info, warn, Level};
+use reqwest::Client;
+use std::borrow::Cow;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::batch::FlushTrigger;
+use crate::concurrency::Signal;
+use crate::config::{Config, UrlTemplate};
+use crate::encoding::{DocumentEncoding, TargetEncoding};
+use crate::failure::FailureKind;
+use crate::history::notable;
+use crate::pending_ack::PendingAck;
+use crate::presigned::{PresignProgress, PresignedDelivery, PresignedUpload, Stage};
+use crate::retry::{RetryPolicy, RetryableErrors};
+use crate::throttle::THROTTLE_HEADER;
+use crate::write_guard::WriteCapability;
+use crate::{config, encoding, history, redundant, retry, sensitive};
+use crate::{
+    backup_path_for, is_xml_file, read_file_start, render_url, request_body, request_headers, write_debug_payload, AppState,
+    BatchPayload, BatchedFile, Outcome, PayloadNumber, WebhookPayload, DOC_SNIFF_BYTES, IGNORE_DURATION_SECS,
+};
+
+// Send a payload to the webhook with `content_headers` on top of the usual
+// ones, and a copy to the shadow webhook if there is one. `filepath` is set
+// when the file is delivered whole: a suitable response body may then replace
+// it, and the receiver may accept it for a later acknowledgement. Returns the
+// outcome and the HTTP status, if any.
+pub async fn send_webhook(
+    state: &Arc<AppState>,
+    mut payload: WebhookPayload,
+    content_headers: Vec<(String, String)>,
+    detected_at: Instant,
+    filepath: Option<&Path>,
+) -> (Outcome, Option<u16>) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    info!("{}Sending webhook...", prefix);
+    
+    let host = wait_for_throttle(state).await;
+    stamp_payload(state, &mut payload, detected_at);
+    
+    // Serialized once so that the digest covers exactly the bytes sent
+    let body = match request_body(config, &payload) {
+        Ok(body) => hyper::body::Bytes::from(body),
+        Err(e) => {
+            error!("{}  Failed to serialize payload: {}", prefix, e);
+            return (Outcome::Failed(FailureKind::Internal), None);
+        }
+    };
+    if let Some(dir) = &config.debug_payload_dir {
+        write_debug_payload(config, dir, &payload).await;
+    }
+    send_body(state, &host, body, content_headers, detected_at, filepath).await
+}
+
+// BATCH_SIZE: send batches as they fill up or their time comes, each with a
+// concurrency permit of its own
+pub async fn send_batches(state: Arc<AppState>) {
+    let Some(batcher) = &state.batcher else {
+        return;
+    };
+    loop {
+        let (files, trigger) = batcher.next().await;
+        tokio::spawn(send_batch(Arc::clone(&state), files, trigger));
+    }
+}
+
+// Send several files in one request; each of them gets its outcome
+async fn send_batch(state: Arc<AppState>, files: Vec<BatchedFile>, trigger: FlushTrigger) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let _permit = state.limiter.acquire().await;
+    info!("{}Sending batch of {} files (flushed by {})...", prefix, files.len(), trigger.as_str());
+    
+    let host = wait_for_throttle(&state).await;
+    let mut payloads = Vec::with_capacity(files.len());
+    let mut waiting = Vec::with_capacity(files.len());
+    for file in files {
+        let mut payload = file.payload;
+        stamp_payload(&state, &mut payload, file.detected_at);
+        if let Some(dir) = &config.debug_payload_dir {
+            write_debug_payload(config, dir, &payload).await;
+        }
+        payloads.push(payload);
+        waiting.push((file.detected_at, file.done));
+    }
+    let oldest = waiting.iter().map(|(detected_at, _)| *detected_at).min().unwrap_or_else(Instant::now);
+    let batch = BatchPayload {
+        event: "batch",
+        batch_id: uuid::Uuid::new_v4().to_string(),
+        flushed_by: trigger.as_str(),
+        files: payloads,
+    };
+    let result = match request_body(config, &batch) {
+        Ok(body) => send_body(&state, &host, hyper::body::Bytes::from(body), Vec::new(), oldest, None).await,
+        Err(e) => {
+            error!("{}  Failed to serialize batch: {}", prefix, e);
+            (Outcome::Failed(FailureKind::Internal), None)
+        }
+    };
+    for (_, done) in waiting {
+        done.send(result).ok();
+    }
+}
+
+// DELIVERY_MODE=presigned: ask PRESIGN_URL where to upload the file, PUT its
+// bytes there, then send the payload to CONFIRM_URL. The file counts as
+// delivered only when every phase succeeded. A delivery that fails remembers
+// the phase it reached, and the next attempt with the same content starts
+// there, as long as the upload URL hasn't expired.
+pub async fn deliver_presigned(
+    state: &Arc<AppState>,
+    presigned: &PresignedDelivery,
+    progress: &PresignProgress,
+    filepath: &Path,
+    mut payload: WebhookPayload,
+    detected_at: Instant,
+) -> (Outcome, Option<u16>) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let data = match tokio::fs::read(filepath).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("{}  Failed to read {} for upload: {}", prefix, filepath.display(), e);
+            return (Outcome::Failed(FailureKind::Internal), None);
+        }
+    };
+    let hash = redundant::content_hash(&data);
+    let data = hyper::body::Bytes::from(data);
+    let render = |template: &UrlTemplate| render_url(config, template, filepath);
+    
+    let host = wait_for_throttle(state).await;
+    stamp_payload(state, &mut payload, detected_at);
+    let body = match request_body(config, &payload) {
+        Ok(body) => hyper::body::Bytes::from(body),
+        Err(e) => {
+            error!("{}  Failed to serialize payload: {}", prefix, e);
+            return (Outcome::Failed(FailureKind::Internal), None);
+        }
+    };
+    if let Some(dir) = &config.debug_payload_dir {
+        write_debug_payload(config, dir, &payload).await;
+    }
+    let headers = request_headers(config, &body, Vec::new());
+    let presign = || async {
+        info!("{}Presigning upload...", prefix);
+        let mut request = state.client.post(render(&presigned.presign_url));
+        for (name, value) in &headers {
+            request = request.header(name, value);
+        }
+        let (status, response) = presigned_phase(state, &host, "Presign", presigned.presign_timeout, request.body(body.clone())).await?;
+        let upload = PresignedUpload::from_response(&response).map_err(|e| {
+            error!("{}  Presign response (HTTP {}) is not usable: {}", prefix, status, e);
+            (Outcome::Rejected, Some(status))
+        })?;
+        if config.require_tls && !config::is_https(upload.url.expose()) {
+            error!("{}  REQUIRE_TLS is enabled but the upload URL {} is not https", prefix, upload.url);
+            return Err((Outcome::Rejected, Some(status)));
+        }
+        Ok(upload)
+    };
+    
+    let mut stage = progress.resume(filepath, &hash);
+    match &stage {
+        Some(Stage::Presigned(upload)) if upload.has_expired() => {
+            info!("{}  The upload URL from the last attempt has expired, presigning again", prefix);
+            stage = None;
+        }
+        Some(Stage::Presigned(_)) => info!("{}  Resuming at the upload, with the URL from the last attempt", prefix),
+        Some(Stage::Uploaded) => info!("{}  Resuming at the confirmation, the file was already uploaded", prefix),
+        None => {}
+    }
+    
+    let mut status = None;
+    if !matches!(stage, Some(Stage::Uploaded)) {
+        // A URL from an earlier attempt that is refused may have expired
+        // without saying when; it is replaced once
+        let (mut upload, mut reused) = match stage {
+            Some(Stage::Presigned(upload)) => (upload, true),
+            _ => match presign().await {
+                Ok(upload) => (upload, false),
+                Err(result) => return result,
+            },
+        };
+        loop {
+            info!("{}Uploading {} bytes to {}...", prefix, data.len(), upload.url);
+            let mut request = state.client.put(upload.url.expose());
+            for (name, value) in &upload.headers {
+                request = request.header(name, value);
+            }
+            let result = presigned_phase(state, &host, "Upload", presigned.upload_timeout, request.body(data.clone())).await;
+            match result {
+                Ok((uploaded, _)) => {
+                    status = Some(uploaded);
+                    break;
+                }
+                Err((_, Some(403))) if reused => {
+                    info!("{}  The upload URL from the last attempt was refused, presigning again", prefix);
+                    upload = match presign().await {
+                        Ok(upload) => upload,
+                        Err(result) => return result,
+                    };
+                    reused = false;
+                }
+                Err(result) => {
+                    progress.record(filepath, hash, Stage::Presigned(upload));
+                    return result;
+                }
+            }
+        }
+    }
+    
+    if let Some(confirm_url) = &presigned.confirm_url {
+        info!("{}Confirming upload...", prefix);
+        let mut request = state.client.post(render(confirm_url));
+        for (name, value) in &headers {
+            request = request.header(name, value);
+        }
+        match presigned_phase(state, &host, "Confirm", presigned.confirm_timeout, request.body(body.clone())).await {
+            Ok((confirmed, _)) => status = Some(confirmed),
+            Err(result) => {
+                progress.record(filepath, hash, Stage::Uploaded);
+                return result;
+            }
+        }
+    }
+    info!("{}  Uploaded successfully", prefix);
+    (Outcome::Delivered, status)
+}
+
+// Send the request of one phase of a presigned delivery, within its own
+// timeout. Returns the status and body of a 2xx response, or the outcome of
+// the delivery if the phase failed.
+async fn presigned_phase(
+    state: &AppState,
+    host: &str,
+    phase: &str,
+    timeout: Duration,
+    request: reqwest::RequestBuilder,
+) -> Result<(u16, hyper::body::Bytes), (Outcome, Option<u16>)> {
+    let prefix = state.config.log_prefix();
+    let started = Instant::now();
+    let result = request.timeout(timeout).send().await;
+    let signal = match &result {
+        Ok(response) if response.status().as_u16() == 429 || response.status().is_server_error() => {
+            Signal::Overloaded
+        }
+        Ok(_) => Signal::Healthy,
+        Err(_) => Signal::Overloaded,
+    };
+    state.limiter.record(started.elapsed(), signal);
+    
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let kind = FailureKind::of(&e);
+            alert_on_pin_mismatch(&prefix, kind, &e);
+            error!("{}  {} request failed ({}): {}", prefix, phase, kind, sensitive::redact_error(e));
+            return Err((Outcome::Failed(kind), None));
+        }
+    };
+    let header = response.headers().get(THROTTLE_HEADER).and_then(|v| v.to_str().ok());
+    state.throttles.observe(host, header);
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        error!("{}  {} failed (HTTP {}): {}", prefix, phase, status.as_u16(), body);
+        return Err((Outcome::Rejected, Some(status.as_u16())));
+    }
+    match response.bytes().await {
+        Ok(body) => Ok((status.as_u16(), body)),
+        Err(e) => {
+            let kind = FailureKind::of(&e);
+            error!("{}  Failed to read the {} response body ({}): {}", prefix, phase.to_lowercase(), kind, e);
+            Err((Outcome::Failed(kind), Some(status.as_u16())))
+        }
+    }
+}
+
+// A pinned key that stops matching is more than a failed delivery: someone may
+// be intercepting the connection, or the receiver rotated its certificate
+// without saying so. Either way it needs a person, not a retry.
+fn alert_on_pin_mismatch(prefix: &str, kind: FailureKind, error: &reqwest::Error) {
+    if kind != FailureKind::TlsPinMismatch {
+        return;
+    }
+    let host = error.url().and_then(|url| url.host_str()).unwrap_or("the receiver");
+    notable!(
+        Level::Error,
+        history::Kind::TlsPinMismatch,
+        "{}  ALERT: no certificate presented by {} matches WEBHOOK_PINNED_SPKI_SHA256; \
+         the connection may be intercepted, or the certificate was rotated without notice",
+        prefix, host
+    );
+}
+
+// Wait out any throttle the receiver asked for. Returns the host it applies
+// to, which receiver-requested throttles are kept per, across profiles.
+async fn wait_for_throttle(state: &AppState) -> String {
+    let host = reqwest::Url::parse(state.config.webhook_url.expose())
+        .ok()
+        .and_then(|url| Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?)))
+        .unwrap_or_default();
+    state.throttles.wait(&host).await;
+    host
+}
+
+// Fill in the fields of a payload that are set as it is sent
+fn stamp_payload(state: &AppState, payload: &mut WebhookPayload, detected_at: Instant) {
+    let config = &state.config;
+    if config.include_detection_latency {
+        payload.detection_to_send_ms = Some(PayloadNumber::new(config, detected_at.elapsed().as_millis() as u64));
+    }
+    if let Some(sequence) = &state.sequence {
+        payload.sequence = Some(PayloadNumber::new(config, sequence.next()));
+    }
+}
+
+// Send a serialized body to the webhook, and a copy to the shadow webhook if
+// there is one, and decide the outcome as `handle_response` does
+async fn send_body(
+    state: &Arc<AppState>,
+    host: &str,
+    body: hyper::body::Bytes,
+    content_headers: Vec<(String, String)>,
+    detected_at: Instant,
+    filepath: Option<&Path>,
+) -> (Outcome, Option<u16>) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let client = &state.client;
+    let headers = request_headers(config, &body, content_headers);
+    
+    if let Some(shadow) = &state.shadow {
+        let mut shadow_request = webhook_request(client, config, shadow.url().expose());
+        for (name, value) in &headers {
+            shadow_request = shadow_request.header(name, value);
+        }
+        shadow.send(shadow_request.body(body.clone()), config.success_body_match.clone(), prefix.clone());
+    }
+    
+    let (headers, body, prefix) = (&headers, &body, &prefix);
+    let (result, latency) = retry::retrying(|retries| async move {
+        if retries > 0 {
+            state.throttles.wait(host).await;
+        }
+        let mut request_builder = webhook_request(client, config, config.webhook_url.expose());
+        for (name, value) in headers {
+            request_builder = request_builder.header(name, value);
+        }
+        let started = Instant::now();
+        let result = request_builder
+            .body(body.clone())
+            .send()
+            .await;
+        let latency = started.elapsed();
+        
+        let signal = match &result {
+            Ok(response) if response.status().as_u16() == 429 || response.status().is_server_error() => {
+                Signal::Overloaded
+            }
+            Ok(_) => Signal::Healthy,
+            Err(_) => Signal::Overloaded,
+        };
+        state.limiter.record(latency, signal);
+        if let Ok(response) = &result {
+            let header = response.headers().get(THROTTLE_HEADER).and_then(|v| v.to_str().ok());
+            state.throttles.observe(host, header);
+        }
+        
+        let Some((reason, delay)) = retry_delay(&config.webhook_retry, &config.retryable_errors, &result, retries) else {
+            return ControlFlow::Break((result, latency));
+        };
+        warn!(
+            "{}  Webhook attempt {} of {} failed ({}), retrying in {} ms",
+            prefix, retries + 1, config.webhook_retry.max_retries + 1, reason, delay.as_millis()
+        );
+        ControlFlow::Continue(delay)
+    })
+    .await;
+    
+    let (outcome, status) = handle_response(state, result, filepath, detected_at).await;
+    if let Some(shadow) = &state.shadow {
+        shadow.record_primary(matches!(outcome, Outcome::Delivered | Outcome::Accepted), status, latency);
+    }
+    (outcome, status)
+}
+
+// Whether a webhook request is sent again after `retries` retries, and why
+// and when: after the failures and statuses in RETRYABLE_ERRORS, by default
+// failures on the way to the receiver that may pass and a 429 or a 5xx, and
+// the EXTRA_RETRY_CODES. A 429 or an extra code waits as long as its
+// Retry-After asks, if it does.
+fn retry_delay(
+    policy: &RetryPolicy,
+    retryable: &RetryableErrors,
+    result: &reqwest::Result<reqwest::Response>,
+    retries: u32,
+) -> Option<(String, Duration)> {
+    if retries >= policy.max_retries {
+        return None;
+    }
+    let backoff = policy.delay(retries + 1);
+    match result {
+        Ok(response) if !retryable.status(response.status().as_u16()) => None,
+        Ok(response) if retryable.honours_retry_after(response.status().as_u16()) => {
+            let requested = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(retry::retry_after);
+            let delay = requested.unwrap_or(backoff);
+            (delay <= retry::MAX_DELAY).then(|| (format!("HTTP {}", response.status().as_u16()), delay))
+        }
+        Ok(response) => Some((format!("HTTP {}", response.status().as_u16()), backoff)),
+        Err(e) => {
+            let kind = FailureKind::of(e);
+            retryable.kind(kind).then(|| (kind.to_string(), backoff))
+        }
+    }
+}
+
+fn webhook_request(client: &Client, config: &Config, url: &str) -> reqwest::RequestBuilder {
+    match config.webhook_method.to_uppercase().as_str() {
+        "GET" => client.get(url),
+        "PUT" => client.put(url),
+        "PATCH" => client.patch(url),
+        "DELETE" => client.delete(url),
+        _ => client.post(url),
+    }
+}
+
+// Whether a suitable response may replace `filepath` once it was delivered
+// whole. An XML response only replaces an XML file.
+pub fn overwrites_with_response(state: &AppState, filepath: &Path) -> bool {
+    let config = &state.config;
+    config.overwrite_with_response
+        && config.sends_content()
+        && state.write_guard.allows(WriteCapability::Overwrite)
+        && is_xml_file(filepath)
+}
+
+// Decide the outcome of a webhook request. For a file delivered whole, a
+// suitable response body overwrites `filepath` when the feature is enabled, and
+// a 202 with an ASYNC_ACK_TOKEN leaves it waiting for acknowledgement.
+async fn handle_response(
+    state: &Arc<AppState>,
+    result: reqwest::Result<reqwest::Response>,
+    filepath: Option<&Path>,
+    detected_at: Instant,
+) -> (Outcome, Option<u16>) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            if status.is_success() {
+                // Handle overwriting the file with response if enabled
+                let overwrite_target = filepath.filter(|path| overwrites_with_response(state, path));
+                
+                // Only files delivered whole can wait for an acknowledgement
+                let ack = match (&config.async_ack_token, &state.pending_acks, filepath) {
+                    (Some(source), Some(pending), Some(filepath)) if status == reqwest::StatusCode::ACCEPTED => {
+                        Some((source, pending, filepath, response.headers().clone()))
+                    }
+                    _ => None,
+                };
+                
+                let content_type = response.headers()
+                    .get("content-type")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("")
+                    .to_string();
+                
+                // Read once, for the body check, the ack token and the overwrite
+                let reads_token = ack.as_ref().is_some_and(|(source, ..)| source.reads_body());
+                let response_body = if config.success_body_match.is_some() || overwrite_target.is_some() || reads_token {
+                    match response.bytes().await {
+                        Ok(bytes) => Some(bytes),
+                        Err(e) => {
+                            let kind = FailureKind::of(&e);
+                            error!("{}  Failed to read response body ({}): {}", prefix, kind, e);
+                            if config.success_body_match.is_some() {
+                                return (Outcome::Failed(kind), Some(status.as_u16()));
+                            }
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                
+                if let (Some(rule), Some(body)) = (&config.success_body_match, &response_body) {
+                    match rule.check(&content_type, body) {
+                        Ok(detail) => info!("{}  Response body check passed: {}", prefix, detail),
+                        Err(detail) => {
+                            error!("{}  Webhook failed (HTTP {}), response body check failed: {}", prefix, status.as_u16(), detail);
+                            return (Outcome::Rejected, Some(status.as_u16()));
+                        }
+                    }
+                }
+                info!("{}  Webhook sent successfully (HTTP {})", prefix, status.as_u16());
+                
+                if let Some((source, pending, filepath, headers)) = ack {
+                    match source.find(&headers, response_body.as_deref()) {
+                        Some(token) => {
+                            let accepted_at = Utc::now();
+                            let entry = PendingAck {
+                                token,
+                                path: filepath.to_path_buf(),
+                                status: status.as_u16(),
+                                detected_at: accepted_at - chrono::Duration::from_std(detected_at.elapsed()).unwrap_or_default(),
+                                accepted_at,
+                            };
+                            match pending.insert(entry) {
+                                // The response carries the token, not a document to overwrite with
+                                Ok(()) => return (Outcome::Accepted, Some(status.as_u16())),
+                                Err(e) => warn!("{}  Counting the accepted delivery as delivered: {}", prefix, e),
+                            }
+                        }
+                        None => warn!("{}  Response has no {} ack token, counting the delivery as delivered", prefix, source),
+                    }
+                }
+
+                if let (Some(filepath), Some(body)) = (overwrite_target, &response_body) {
+                    // Check if content type is appropriate (text/xml or application/xml)
+                    // Accept content types that start with these prefixes (may include charset parameter)
+                    let is_xml = content_type.starts_with("text/xml") 
+                        || content_type.starts_with("application/xml");
+                    
+                    if is_xml && body.is_empty() {
+                        warn!("{}  Response body is empty, not overwriting file", prefix);
+                    } else if is_xml {
+                        match overwrite_content(config, filepath, body, &content_type).await {
+                            Ok(content) => {
+                                if config.skip_redundant_delivery
+                                    && tokio::fs::read(filepath).await.is_ok_and(|data| data == *content)
+                                {
+                                    info!("{}  Response matches the file, not overwriting it", prefix);
+                                } else {
+                                    overwrite_file(state, filepath, &content).await;
+                                }
+                            }
+                            Err(e) => error!("{}  {}", prefix, e),
+                        }
+                    } else {
+                        warn!("{}  Response content-type '{}' is not XML, not overwriting file", prefix, content_type);
+                    }
+                }
+                (Outcome::Delivered, Some(status.as_u16()))
+            } else {
+                let body = response.text().await.unwrap_or_default();
+                error!("{}  Webhook failed (HTTP {}): {}", prefix, status.as_u16(), body);
+                (Outcome::Rejected, Some(status.as_u16()))
+            }
+        }
+        Err(e) => {
+            let kind = FailureKind::of(&e);
+            alert_on_pin_mismatch(&prefix, kind, &e);
+            match kind {
+                FailureKind::ConnectTimeout | FailureKind::ReadTimeout => error!(
+                    "{}  Webhook request timed out after {}s ({}): {}",
+                    prefix, config.webhook_timeout_secs, kind, sensitive::redact_error(e)
+                ),
+                _ => error!("{}  Webhook request failed ({}): {}", prefix, kind, sensitive::redact_error(e)),
+            }
+            (Outcome::Failed(kind), None)
+        }
+    }
+}
+
+// What an XML response body overwrites `filepath` with: the body as it is, or
+// with OVERWRITE_TARGET_ENCODING, re-encoded
+async fn overwrite_content<'a>(
+    config: &Config,
+    filepath: &Path,
+    body: &'a [u8],
+    content_type: &str,
+) -> Result<Cow<'a, [u8]>, String> {
+    let Some(target) = config.overwrite_target_encoding else {
+        return match std::str::from_utf8(body) {
+            Ok(_) => Ok(Cow::Borrowed(body)),
+            Err(e) => Err(format!("Failed to read response body: {}", e)),
+        };
+    };
+    let target = match target {
+        TargetEncoding::Fixed(charset) => DocumentEncoding::of(charset),
+        // Read now, while the file still is the one that was delivered
+        TargetEncoding::Preserve => {
+            let head = read_file_start(filepath, DOC_SNIFF_BYTES)
+                .await
+                .map_err(|e| format!("Failed to read the file's encoding, not overwriting it: {}", e))?;
+            DocumentEncoding::detect(&head)
+                .map_err(|e| format!("Not overwriting the file, {}", e))?
+        }
+    };
+    let text = encoding::decode_document(body, content_type)
+        .map_err(|e| format!("Failed to read response body, {}", e))?;
+    let content = encoding::encode_document(&text, &target, config.encoding_fallback)
+        .map_err(|e| format!("Not overwriting the file: {}", e))?;
+    debug!("{}  Response re-encoded in {}", config.log_prefix(), target.label);
+    Ok(Cow::Owned(content))
+}
+
+async fn overwrite_file(state: &Arc<AppState>, filepath: &Path, response_body: &[u8]) {
+    let prefix = state.config.log_prefix();
+    let backup_path = if state.config.backup_before_overwrite {
+        let backup_path = backup_path_for(&state.config, filepath);
+        match state.write_guard.copy(WriteCapability::Backup, filepath, &backup_path).await {
+            Ok(_) => {
+                info!("{}  Original file backed up to {}", prefix, backup_path.display());
+                Some(backup_path)
+            }
+            Err(e) => {
+                error!("{}  Failed to back up file, not overwriting: {}", prefix, e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    
+    // Add file to ignore list before writing
+    state.ignore_list.insert(filepath);
+    
+    match state.write_guard.write(WriteCapability::Overwrite, filepath, response_body).await {
+        Ok(_) => {
+            info!("{}  File overwritten with response content", prefix);
+            // Keep file in ignore list for a short time
+            state.ignore_list.release(filepath, Duration::from_secs(IGNORE_DURATION_SECS));
+        }
+        Err(e) => {
+            error!("{}  Failed to overwrite file: {}", prefix, e);
+            // A failed write may have truncated the file, so put the original back
+            if let Some(backup_path) = backup_path {
+                match state.write_guard.copy(WriteCapability::Overwrite, &backup_path, filepath).await {
+                    Ok(_) => info!("{}  Original file restored from {}", prefix, backup_path.display()),
+                    Err(e) => error!("{}  Failed to restore file from backup: {}", prefix, e),
+                }
+            }
+            // Remove from ignore list on failure
+            state.ignore_list.remove(filepath);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256, Sha512};
+
+/// Algorithm used for the RFC 3230 `Digest` request header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "sha-256" => Ok(DigestAlgorithm::Sha256),
+            "sha-512" => Ok(DigestAlgorithm::Sha512),
+            other => Err(format!(
+                "Invalid DIGEST_ALGORITHM '{}': expected 'sha-256' or 'sha-512'",
+                other
+            )),
+        }
+    }
+
+    // Name registered for the algorithm in the HTTP digest algorithm registry
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha512 => "SHA-512",
+        }
+    }
+
+    /// Value of the `Digest` header for a request with this exact body.
+    pub fn header_value(&self, body: &[u8]) -> String {
+        let digest = match self {
+            DigestAlgorithm::Sha256 => BASE64.encode(Sha256::digest(body)),
+            DigestAlgorithm::Sha512 => BASE64.encode(Sha512::digest(body)),
+        };
+        format!("{}={}", self.name(), digest)
+    }
+}
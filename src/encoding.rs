@@ -0,0 +1,640 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+/// What to do with XML files encoded in UTF-16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf16Handling {
+    // Send the content converted to UTF-8
+    Transcode,
+    // Don't deliver them
+    Skip,
+}
+
+impl Utf16Handling {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "transcode" => Ok(Utf16Handling::Transcode),
+            "skip" => Ok(Utf16Handling::Skip),
+            other => Err(format!("Invalid UTF16_XML '{}': expected 'transcode' or 'skip'", other)),
+        }
+    }
+}
+
+/// What is done with bytes that aren't valid UTF-8 in the content of a
+/// payload, CONTENT_INVALID_UTF8. JSON strings can't carry them, so they
+/// are never sent as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    // Each invalid sequence becomes U+FFFD
+    Replace,
+    // Each invalid byte becomes a character reference to the Latin-1
+    // character it would be, e.g. `&#xE9;`
+    Escape,
+    // The whole content is sent base64-encoded instead
+    Base64,
+}
+
+impl InvalidUtf8 {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "replace" => Ok(InvalidUtf8::Replace),
+            "escape" => Ok(InvalidUtf8::Escape),
+            "base64-fallback" => Ok(InvalidUtf8::Base64),
+            other => Err(format!(
+                "Invalid CONTENT_INVALID_UTF8 '{}': expected 'replace', 'escape' or 'base64-fallback'",
+                other
+            )),
+        }
+    }
+}
+
+/// The sequences of a content that weren't valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSequences {
+    pub count: usize,
+    // Byte offset of the first one
+    pub first: usize,
+}
+
+/// `data` as text, with what isn't valid UTF-8 handled as `mode` says, and
+/// the invalid sequences if there were any. Valid data is returned without
+/// copying. Sequences are counted like `String::from_utf8_lossy` replaces
+/// them: a truncated character is one, a stray byte another.
+pub fn decode_utf8(data: Vec<u8>, mode: InvalidUtf8) -> (String, Option<InvalidSequences>) {
+    let data = match String::from_utf8(data) {
+        Ok(text) => return (text, None),
+        Err(e) => e.into_bytes(),
+    };
+    let mut text = String::new();
+    let mut invalid = InvalidSequences { count: 0, first: 0 };
+    // Offset in `data` of the chunk's invalid bytes
+    let mut offset = 0;
+    for chunk in data.utf8_chunks() {
+        if mode != InvalidUtf8::Base64 {
+            text.push_str(chunk.valid());
+        }
+        offset += chunk.valid().len();
+        if chunk.invalid().is_empty() {
+            continue;
+        }
+        if invalid.count == 0 {
+            invalid.first = offset;
+        }
+        offset += chunk.invalid().len();
+        invalid.count += 1;
+        match mode {
+            InvalidUtf8::Replace => text.push(char::REPLACEMENT_CHARACTER),
+            InvalidUtf8::Escape => {
+                for byte in chunk.invalid() {
+                    text.push_str(&format!("&#x{:X};", byte));
+                }
+            }
+            InvalidUtf8::Base64 => {}
+        }
+    }
+    if mode == InvalidUtf8::Base64 {
+        use base64::Engine;
+        text = base64::engine::general_purpose::STANDARD.encode(&data);
+    }
+    (text, Some(invalid))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf16 {
+    Le,
+    Be,
+}
+
+impl Utf16 {
+    /// The UTF-16 flavour of a document, from its byte order mark or, without
+    /// one, from how its XML declaration is encoded (XML 1.0 appendix F).
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            [0xFF, 0xFE, ..] | [b'<', 0, b'?', 0, ..] => Some(Utf16::Le),
+            [0xFE, 0xFF, ..] | [0, b'<', 0, b'?', ..] => Some(Utf16::Be),
+            _ => None,
+        }
+    }
+
+    /// Name reported as `original_encoding`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Utf16::Le => "UTF-16LE",
+            Utf16::Be => "UTF-16BE",
+        }
+    }
+
+    /// Decode `data` without its byte order mark. A trailing odd byte or
+    /// unpaired surrogate, as left when a read stops early, is dropped;
+    /// elsewhere invalid sequences become U+FFFD.
+    pub fn decode(&self, data: &[u8]) -> String {
+        let data = match (self, data) {
+            (Utf16::Le, [0xFF, 0xFE, rest @ ..]) | (Utf16::Be, [0xFE, 0xFF, rest @ ..]) => rest,
+            _ => data,
+        };
+        let units = data.chunks_exact(2).map(|pair| match self {
+            Utf16::Le => u16::from_le_bytes([pair[0], pair[1]]),
+            Utf16::Be => u16::from_be_bytes([pair[0], pair[1]]),
+        });
+        let mut text = String::with_capacity(data.len() / 2);
+        let mut chars = char::decode_utf16(units).peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                Ok(c) => text.push(c),
+                Err(_) if chars.peek().is_none() => {}
+                Err(_) => text.push(char::REPLACEMENT_CHARACTER),
+            }
+        }
+        declare_utf8(text)
+    }
+}
+
+/// `data` as UTF-8, converted when it is UTF-16, and the original encoding
+/// in that case. Other encodings are passed through unchanged.
+pub fn to_utf8(data: &[u8]) -> (Cow<'_, [u8]>, Option<Utf16>) {
+    match Utf16::detect(data) {
+        Some(utf16) => (Cow::Owned(utf16.decode(data).into_bytes()), Some(utf16)),
+        None => (Cow::Borrowed(data), None),
+    }
+}
+
+// The XML declaration still names UTF-16 after decoding; a parser reading the
+// converted bytes would trust it, so it is changed to UTF-8
+fn declare_utf8(mut text: String) -> String {
+    if let Some(value) = declared_value(&text, "encoding") {
+        text.replace_range(value, "UTF-8");
+    }
+    text
+}
+
+// Where in `text` the value of a pseudo-attribute of its XML declaration is,
+// e.g. of `encoding`, without the quotes
+fn declared_value(text: &str, name: &str) -> Option<Range<usize>> {
+    let rest = text.strip_prefix("<?xml").filter(|rest| rest.starts_with(|c: char| c.is_ascii_whitespace()))?;
+    let declaration = &rest[..rest.find("?>")?];
+    let start = declaration.find(name)?;
+    let after = declaration[start + name.len()..].trim_start();
+    let value = after.strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let length = value[1..].find(quote)?;
+    // Offsets into `text`, which starts with "<?xml"
+    let value_start = "<?xml".len() + declaration.len() - value.len() + 1;
+    Some(value_start..value_start + length)
+}
+
+/// The encoding OVERWRITE_TARGET_ENCODING writes responses in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetEncoding {
+    // That of the file being overwritten
+    Preserve,
+    Fixed(Charset),
+}
+
+impl TargetEncoding {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "preserve" => Ok(TargetEncoding::Preserve),
+            label => Charset::for_label(label).map(TargetEncoding::Fixed).ok_or_else(|| {
+                format!(
+                    "Invalid OVERWRITE_TARGET_ENCODING '{}': expected 'preserve', 'utf8' or the name of an encoding",
+                    value
+                )
+            }),
+        }
+    }
+}
+
+impl fmt::Display for TargetEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetEncoding::Preserve => write!(f, "that of the original file"),
+            TargetEncoding::Fixed(charset) => write!(f, "{}", charset.name()),
+        }
+    }
+}
+
+/// What is done with characters the target encoding has no bytes for,
+/// ENCODING_FALLBACK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingFallback {
+    // The file is left as it is
+    Fail,
+    // Each becomes a numeric character reference, e.g. `&#x20AC;`
+    Ncr,
+}
+
+impl EncodingFallback {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "fail" => Ok(EncodingFallback::Fail),
+            "ncr" => Ok(EncodingFallback::Ncr),
+            other => Err(format!("Invalid ENCODING_FALLBACK '{}': expected 'fail' or 'ncr'", other)),
+        }
+    }
+}
+
+// Labels of ISO-8859-1 itself. encoding_rs reads them as windows-1252, like
+// browsers do, which has other characters at 0x80-0x9F; a consumer that
+// really expects ISO-8859-1 would misread those.
+const LATIN1_LABELS: &[&str] = &[
+    "iso-8859-1", "iso8859-1", "iso_8859-1", "iso_8859-1:1987", "latin1", "l1", "iso-ir-100", "cp819", "ibm819",
+    "csisolatin1",
+];
+
+/// A character encoding documents are read and written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Latin1,
+    Utf16(Utf16),
+    Other(&'static encoding_rs::Encoding),
+}
+
+impl Charset {
+    /// The encoding a name such as `ISO-8859-1` or `windows-1252` stands for.
+    pub fn for_label(label: &str) -> Option<Self> {
+        let label = label.trim();
+        if LATIN1_LABELS.iter().any(|latin1| latin1.eq_ignore_ascii_case(label)) {
+            return Some(Charset::Latin1);
+        }
+        match encoding_rs::Encoding::for_label(label.as_bytes())? {
+            encoding if encoding == encoding_rs::UTF_16LE => Some(Charset::Utf16(Utf16::Le)),
+            encoding if encoding == encoding_rs::UTF_16BE => Some(Charset::Utf16(Utf16::Be)),
+            // Stands for encodings such as ISO-2022-KR, which can't be read safely
+            encoding if encoding == encoding_rs::REPLACEMENT => None,
+            encoding => Some(Charset::Other(encoding)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Charset::Latin1 => "ISO-8859-1",
+            Charset::Utf16(_) => "UTF-16",
+            Charset::Other(encoding) => encoding.name(),
+        }
+    }
+
+    // XML parsers recognise these without a declaration
+    fn is_unicode(&self) -> bool {
+        matches!(self, Charset::Utf16(_)) || *self == Charset::Other(encoding_rs::UTF_8)
+    }
+
+    // `data`, without a byte order mark, as text; None when it isn't valid in
+    // this encoding
+    fn decode(&self, data: &[u8]) -> Option<String> {
+        match self {
+            Charset::Latin1 => Some(data.iter().map(|&byte| char::from(byte)).collect()),
+            Charset::Utf16(utf16) => Some(utf16.decode(data)),
+            Charset::Other(encoding) => encoding
+                .decode_without_bom_handling_and_without_replacement(data)
+                .map(Cow::into_owned),
+        }
+    }
+
+    // `text` in this encoding; fails with the first character it has no bytes
+    // for and its byte offset, unless `fallback` says otherwise
+    fn encode(&self, text: &str, fallback: EncodingFallback) -> Result<Vec<u8>, (char, usize)> {
+        let mut data = Vec::with_capacity(text.len());
+        match self {
+            Charset::Utf16(utf16) => {
+                for unit in text.encode_utf16() {
+                    data.extend(match utf16 {
+                        Utf16::Le => unit.to_le_bytes(),
+                        Utf16::Be => unit.to_be_bytes(),
+                    });
+                }
+            }
+            Charset::Latin1 => {
+                for (offset, c) in text.char_indices() {
+                    match u8::try_from(c) {
+                        Ok(byte) => data.push(byte),
+                        Err(_) => unrepresentable(c, offset, fallback, &mut data)?,
+                    }
+                }
+            }
+            Charset::Other(encoding) => {
+                let mut encoder = encoding.new_encoder();
+                let mut rest = text;
+                loop {
+                    let room = encoder
+                        .max_buffer_length_from_utf8_without_replacement(rest.len())
+                        .unwrap_or(rest.len());
+                    data.reserve(room);
+                    let (result, read) = encoder.encode_from_utf8_to_vec_without_replacement(rest, &mut data, true);
+                    rest = &rest[read..];
+                    match result {
+                        encoding_rs::EncoderResult::InputEmpty => break,
+                        encoding_rs::EncoderResult::OutputFull => {}
+                        // `read` includes the character
+                        encoding_rs::EncoderResult::Unmappable(c) => {
+                            let offset = text.len() - rest.len() - c.len_utf8();
+                            unrepresentable(c, offset, fallback, &mut data)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+fn unrepresentable(c: char, offset: usize, fallback: EncodingFallback, data: &mut Vec<u8>) -> Result<(), (char, usize)> {
+    match fallback {
+        EncodingFallback::Fail => Err((c, offset)),
+        // Encoders that switch between character sets are back in ASCII here
+        EncodingFallback::Ncr => {
+            data.extend_from_slice(format!("&#x{:X};", c as u32).as_bytes());
+            Ok(())
+        }
+    }
+}
+
+/// How a document is encoded: the charset, the name its XML declaration
+/// gives it and whether it starts with a byte order mark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentEncoding {
+    pub charset: Charset,
+    pub label: String,
+    pub bom: bool,
+}
+
+impl DocumentEncoding {
+    /// `charset` under its own name, with the byte order mark UTF-16 needs.
+    pub fn of(charset: Charset) -> Self {
+        DocumentEncoding {
+            charset,
+            label: charset.name().to_string(),
+            bom: matches!(charset, Charset::Utf16(_)),
+        }
+    }
+
+    /// The encoding of a document, from its first bytes: its byte order mark,
+    /// or else the encoding its XML declaration names, or else UTF-8.
+    pub fn detect(head: &[u8]) -> Result<Self, String> {
+        if let Some(utf16) = Utf16::detect(head) {
+            let bom = matches!(head, [0xFF, 0xFE, ..] | [0xFE, 0xFF, ..]);
+            return Ok(DocumentEncoding { bom, ..DocumentEncoding::of(Charset::Utf16(utf16)) });
+        }
+        let (head, bom) = match head.strip_prefix(UTF8_BOM) {
+            Some(rest) => (rest, true),
+            None => (head, false),
+        };
+        // The declaration is ASCII in every encoding it can name here
+        let text = String::from_utf8_lossy(head);
+        let utf8 = Charset::Other(encoding_rs::UTF_8);
+        let Some(label) = declared_value(&text, "encoding").map(|value| &text[value]) else {
+            return Ok(DocumentEncoding { bom, ..DocumentEncoding::of(utf8) });
+        };
+        let charset = Charset::for_label(label).ok_or_else(|| format!("its encoding '{}' is unknown", label))?;
+        if bom && charset != utf8 {
+            // The byte order mark wins, as in XML 1.0 appendix F
+            return Ok(DocumentEncoding { bom, ..DocumentEncoding::of(utf8) });
+        }
+        Ok(DocumentEncoding { charset, label: label.to_string(), bom })
+    }
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+/// A document received as text: decoded as its byte order mark says, or else
+/// as the `charset` of its `content_type`, or else as its XML declaration
+/// says.
+pub fn decode_document(data: &[u8], content_type: &str) -> Result<String, String> {
+    let declared = content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("charset").then(|| value.trim().trim_matches('"'))
+    });
+    let utf8 = Charset::Other(encoding_rs::UTF_8);
+    let (charset, data) = match (Utf16::detect(data), data.strip_prefix(UTF8_BOM), declared) {
+        (Some(utf16), ..) => (Charset::Utf16(utf16), data),
+        (None, Some(rest), _) => (utf8, rest),
+        (None, None, Some(label)) => {
+            let charset = Charset::for_label(label).ok_or_else(|| format!("its charset '{}' is unknown", label))?;
+            (charset, data)
+        }
+        (None, None, None) => (DocumentEncoding::detect(data)?.charset, data),
+    };
+    charset.decode(data).ok_or_else(|| format!("it isn't valid {}", charset.name()))
+}
+
+/// `text`, an XML document, written in `target`. Its XML declaration is
+/// changed to name `target`, and one without an encoding gets one unless
+/// `target` is UTF-8 or UTF-16.
+pub fn encode_document(text: &str, target: &DocumentEncoding, fallback: EncodingFallback) -> Result<Vec<u8>, String> {
+    let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+    let declared = declare_encoding(text, &target.label, !target.charset.is_unicode());
+    let mut data = match (target.bom, target.charset) {
+        (false, _) => Vec::new(),
+        (true, Charset::Utf16(Utf16::Le)) => vec![0xFF, 0xFE],
+        (true, Charset::Utf16(Utf16::Be)) => vec![0xFE, 0xFF],
+        (true, _) => UTF8_BOM.to_vec(),
+    };
+    let encoded = target.charset.encode(&declared, fallback).map_err(|(c, offset)| {
+        // The declaration is ASCII, so the character comes after it, in
+        // `text` as much further ahead as the declaration grew
+        let offset = (offset + text.len()).saturating_sub(declared.len());
+        let position = text[..offset].chars().count() + 1;
+        format!(
+            "U+{:04X} '{}', character {} of the response, can't be written in {}",
+            c as u32, c, position, target.charset.name()
+        )
+    })?;
+    data.extend(encoded);
+    Ok(data)
+}
+
+// `text` with the encoding in its XML declaration replaced by `label`, and
+// added when `required`
+fn declare_encoding<'a>(text: &'a str, label: &str, required: bool) -> Cow<'a, str> {
+    if let Some(value) = declared_value(text, "encoding") {
+        let mut declared = text.to_string();
+        declared.replace_range(value, label);
+        return Cow::Owned(declared);
+    }
+    if !required {
+        return Cow::Borrowed(text);
+    }
+    match declared_value(text, "version") {
+        // Right after the version, where XML expects it
+        Some(version) => {
+            let end = version.end + 1;
+            Cow::Owned(format!("{} encoding=\"{}\"{}", &text[..end], label, &text[end..]))
+        }
+        None if text.starts_with("<?xml") => Cow::Borrowed(text),
+        None => Cow::Owned(format!("<?xml version=\"1.0\" encoding=\"{}\"?>\n{}", label, text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UTF16_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/order-utf16le.xml");
+    const FIXTURE_TEXT: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<order id=\"42\">\n  \
+        <customer>Zoë Müller</customer>\n  <note>Größe: 10 € — 𝄞 ok</note>\n</order>\n";
+
+    #[test]
+    fn utf16_files_are_transcoded_and_declared_utf8() {
+        let (data, original) = to_utf8(UTF16_FIXTURE);
+        assert_eq!(original, Some(Utf16::Le));
+        assert_eq!(std::str::from_utf8(&data).unwrap(), FIXTURE_TEXT);
+        assert!(roxmltree::Document::parse(std::str::from_utf8(&data).unwrap()).is_ok());
+
+        // Big-endian, and without a byte order mark
+        let big_endian: Vec<u8> = UTF16_FIXTURE[2..].chunks_exact(2).flat_map(|pair| [pair[1], pair[0]]).collect();
+        let (data, original) = to_utf8(&big_endian);
+        assert_eq!(original, Some(Utf16::Be));
+        assert_eq!(std::str::from_utf8(&data).unwrap(), FIXTURE_TEXT);
+    }
+
+    #[test]
+    fn utf8_files_are_passed_through() {
+        let (data, original) = to_utf8(FIXTURE_TEXT.as_bytes());
+        assert!(matches!(data, Cow::Borrowed(_)));
+        assert_eq!(original, None);
+        assert_eq!(Utf16::detect(b"<order/>"), None);
+    }
+
+    #[test]
+    fn truncated_utf16_loses_only_the_cut_character() {
+        // Cut in the middle of the surrogate pair of U+1D11E
+        let clef = UTF16_FIXTURE.windows(4).position(|bytes| bytes == [0x34, 0xD8, 0x1E, 0xDD]).unwrap();
+        let text = Utf16::Le.decode(&UTF16_FIXTURE[..clef + 3]);
+        assert!(text.ends_with("— "), "{:?}", text);
+        let text = Utf16::Le.decode(&UTF16_FIXTURE[..clef + 2]);
+        assert!(text.ends_with("— "), "{:?}", text);
+
+        // An unpaired surrogate elsewhere is replaced
+        let mut data = UTF16_FIXTURE.to_vec();
+        data.drain(clef + 2..clef + 4);
+        assert!(Utf16::Le.decode(&data).contains("— \u{FFFD} ok"));
+    }
+
+    #[test]
+    fn utf16_handling_parses() {
+        assert_eq!(Utf16Handling::parse("Transcode").unwrap(), Utf16Handling::Transcode);
+        assert_eq!(Utf16Handling::parse("skip").unwrap(), Utf16Handling::Skip);
+        assert!(Utf16Handling::parse("utf8").is_err());
+    }
+
+    // Byte sequences that aren't UTF-8, each with the number of sequences
+    // String::from_utf8_lossy would replace
+    const PATHOLOGICAL: &[(&[u8], usize)] = &[
+        (b"\xFF", 1),
+        (b"\xC0\xAF", 2),         // Overlong '/'
+        (b"\xE0\x80\xAF", 3),     // Overlong, three bytes
+        (b"\xED\xA0\x80", 3),     // A surrogate, as CESU-8 writes them
+        (b"\xF4\x90\x80\x80", 4), // Beyond U+10FFFF
+        (b"\x80\x80", 2),         // Continuation bytes on their own
+        (b"\xE2\x82", 1),         // Truncated '€'
+        (b"\xF0\x9D\x84", 1),     // Truncated U+1D11E
+    ];
+
+    #[test]
+    fn valid_utf8_is_left_alone_in_every_mode() {
+        for mode in [InvalidUtf8::Replace, InvalidUtf8::Escape, InvalidUtf8::Base64] {
+            assert_eq!(decode_utf8(b"<a>\0\x01 \xE2\x82\xAC</a>".to_vec(), mode), ("<a>\0\x01 €</a>".to_string(), None));
+        }
+    }
+
+    #[test]
+    fn invalid_sequences_are_counted_and_located() {
+        for (bytes, count) in PATHOLOGICAL {
+            let data = [b"<a>".as_slice(), bytes, b"</a>"].concat();
+            let (text, invalid) = decode_utf8(data.clone(), InvalidUtf8::Replace);
+            assert_eq!(text, String::from_utf8_lossy(&data), "{:X?}", bytes);
+            assert_eq!(invalid, Some(InvalidSequences { count: *count, first: 3 }), "{:X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn escaping_keeps_every_invalid_byte() {
+        let (text, invalid) = decode_utf8(b"<a>\xC3\xA9\xE9\xC0\xAF</a>\xE2\x82".to_vec(), InvalidUtf8::Escape);
+        assert_eq!(text, "<a>é&#xE9;&#xC0;&#xAF;</a>&#xE2;&#x82;");
+        assert_eq!(invalid, Some(InvalidSequences { count: 4, first: 5 }));
+
+        for (bytes, _) in PATHOLOGICAL {
+            let (text, _) = decode_utf8(bytes.to_vec(), InvalidUtf8::Escape);
+            let expected: String = bytes.iter().map(|byte| format!("&#x{:X};", byte)).collect();
+            assert_eq!(text, expected);
+        }
+    }
+
+    #[test]
+    fn base64_fallback_sends_the_bytes_as_they_are() {
+        use base64::Engine;
+        for (bytes, count) in PATHOLOGICAL {
+            let data = [b"<a>".as_slice(), bytes].concat();
+            let (text, invalid) = decode_utf8(data.clone(), InvalidUtf8::Base64);
+            assert_eq!(base64::engine::general_purpose::STANDARD.decode(text).unwrap(), data);
+            assert_eq!(invalid.unwrap().count, *count);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_modes_parse() {
+        assert_eq!(InvalidUtf8::parse("Replace").unwrap(), InvalidUtf8::Replace);
+        assert_eq!(InvalidUtf8::parse("escape").unwrap(), InvalidUtf8::Escape);
+        assert_eq!(InvalidUtf8::parse("base64-fallback").unwrap(), InvalidUtf8::Base64);
+        assert!(InvalidUtf8::parse("base64").is_err());
+    }
+
+    // A response for `original`, as a receiver answering in UTF-8 sends it
+    fn utf8_response(original: &[u8]) -> String {
+        let text = decode_document(original, "application/xml").unwrap();
+        let response = declare_encoding(&text, "UTF-8", true).into_owned();
+        decode_document(response.as_bytes(), "application/xml; charset=utf-8").unwrap()
+    }
+
+    #[test]
+    fn latin1_files_round_trip_through_utf8_responses() {
+        let original = b"<?xml version=\"1.0\" encoding=\"latin1\"?>\n<a>Zo\xEB M\xFCller \xA9 \x85</a>\n";
+        let target = DocumentEncoding::detect(original).unwrap();
+        assert_eq!(target, DocumentEncoding { charset: Charset::Latin1, label: "latin1".to_string(), bom: false });
+
+        let response = utf8_response(original);
+        // 0x85 is a control character in ISO-8859-1, not windows-1252's '…'
+        assert_eq!(response, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<a>Zoë Müller © \u{85}</a>\n");
+        assert_eq!(encode_document(&response, &target, EncodingFallback::Fail).unwrap(), original);
+    }
+
+    #[test]
+    fn windows_1252_files_round_trip_through_utf8_responses() {
+        let original = b"<?xml version='1.0' encoding='windows-1252'?><a>\x80 10 \x96 caf\xE9 \x93ok\x94</a>";
+        let target = DocumentEncoding::detect(original).unwrap();
+        assert_eq!(target.charset, Charset::Other(encoding_rs::WINDOWS_1252));
+
+        let response = utf8_response(original);
+        assert_eq!(response, "<?xml version='1.0' encoding='UTF-8'?><a>€ 10 – café “ok”</a>");
+        assert_eq!(encode_document(&response, &target, EncodingFallback::Fail).unwrap(), original);
+    }
+
+    #[test]
+    fn characters_the_target_lacks_fail_or_become_references() {
+        let target = DocumentEncoding::of(Charset::Latin1);
+        let response = "<?xml version=\"1.0\"?><a>10 € ok</a>";
+        let error = encode_document(response, &target, EncodingFallback::Fail).unwrap_err();
+        assert_eq!(error, "U+20AC '€', character 28 of the response, can't be written in ISO-8859-1");
+
+        let written = encode_document(response, &target, EncodingFallback::Ncr).unwrap();
+        assert_eq!(written, b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><a>10 &#x20AC; ok</a>");
+
+        let target = DocumentEncoding::of(Charset::for_label("windows-1252").unwrap());
+        let written = encode_document("<a>\u{1D11E}</a>", &target, EncodingFallback::Ncr).unwrap();
+        assert_eq!(written, b"<?xml version=\"1.0\" encoding=\"windows-1252\"?>\n<a>&#x1D11E;</a>");
+    }
+
+    #[test]
+    fn target_encodings_parse() {
+        assert_eq!(TargetEncoding::parse("preserve").unwrap(), TargetEncoding::Preserve);
+        assert_eq!(TargetEncoding::parse("ISO-8859-1").unwrap(), TargetEncoding::Fixed(Charset::Latin1));
+        assert_eq!(
+            TargetEncoding::parse("cp1252").unwrap(),
+            TargetEncoding::Fixed(Charset::Other(encoding_rs::WINDOWS_1252))
+        );
+        assert!(TargetEncoding::parse("iso-2022-kr").is_err());
+        assert!(TargetEncoding::parse("klingon").is_err());
+        assert_eq!(EncodingFallback::parse("NCR").unwrap(), EncodingFallback::Ncr);
+        assert!(EncodingFallback::parse("replace").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use notify::event::{MetadataKind, ModifyKind, RenameMode};
+use notify::EventKind;
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// What happened to the paths of an event, whichever source reported it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileEventKind {
+    Created,
+    // Renamed within the tree; the paths are the old and the new name
+    Renamed,
+    // Moved in from outside the tree, so only the new name is known
+    MovedIn,
+    // Moved out of the tree, so only the old name is known
+    MovedOut,
+    // The content changed; permission and ownership changes are Other
+    Modified,
+    Removed,
+    // The source lost events, e.g. on an inotify queue overflow
+    Rescan,
+    // Accesses, and renames or changes the backend can't describe
+    Other,
+}
+
+impl FileEventKind {
+    /// The kind of a `notify` event.
+    pub fn of(event: &notify::Event) -> Self {
+        if event.need_rescan() {
+            return FileEventKind::Rescan;
+        }
+        match event.kind {
+            EventKind::Create(_) => FileEventKind::Created,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => FileEventKind::Renamed,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => FileEventKind::MovedIn,
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileEventKind::MovedOut,
+            // Backends that report renames one path at a time without a side
+            EventKind::Modify(ModifyKind::Name(_)) => FileEventKind::Other,
+            // The poll backend reports a write it only saw in the mtime as WriteTime
+            EventKind::Modify(ModifyKind::Metadata(kind)) if kind != MetadataKind::WriteTime => FileEventKind::Other,
+            EventKind::Modify(_) => FileEventKind::Modified,
+            EventKind::Remove(_) => FileEventKind::Removed,
+            EventKind::Access(_) | EventKind::Any | EventKind::Other => FileEventKind::Other,
+        }
+    }
+}
+
+/// The kinds of event that lead to deliveries, WATCH_EVENTS: files created
+/// (or renamed into place), and files whose content changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchEvents {
+    pub create: bool,
+    pub modify: bool,
+}
+
+impl WatchEvents {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        Self::parse_option("WATCH_EVENTS", value)
+    }
+
+    /// Parse the events of the option `name`, for messages that name it.
+    pub fn parse_option(name: &str, value: &str) -> Result<Self, String> {
+        let mut events = WatchEvents { create: false, modify: false };
+        for event in value.split(',').map(str::trim).filter(|event| !event.is_empty()) {
+            match event.to_lowercase().as_str() {
+                "create" => events.create = true,
+                "modify" => events.modify = true,
+                other => {
+                    return Err(format!(
+                        "Invalid {} '{}': unknown event '{}', expected 'create' or 'modify'",
+                        name, value, other
+                    ))
+                }
+            }
+        }
+        if !events.create && !events.modify {
+            return Err(format!("{} must name at least one of 'create' and 'modify'", name));
+        }
+        Ok(events)
+    }
+}
+
+impl fmt::Display for WatchEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.create, self.modify) {
+            (true, true) => write!(f, "create,modify"),
+            (false, true) => write!(f, "modify"),
+            _ => write!(f, "create"),
+        }
+    }
+}
+
+/// A filesystem event as the delivery pipeline sees it.
+#[derive(Debug, Clone)]
+pub struct FileEvent {
+    pub kind: FileEventKind,
+    pub paths: Vec<PathBuf>,
+    // When the source reported it
+    pub at: Instant,
+}
+
+impl FileEvent {
+    pub fn new(kind: FileEventKind, paths: Vec<PathBuf>) -> Self {
+        FileEvent { kind, paths, at: Instant::now() }
+    }
+}
+
+impl From<notify::Event> for FileEvent {
+    fn from(event: notify::Event) -> Self {
+        FileEvent::new(FileEventKind::of(&event), event.paths)
+    }
+}
+
+/// Receives the events of a source, on whichever thread the source runs.
+pub type EventSink = Arc<dyn Fn(FileEvent) + Send + Sync>;
+
+/// Keeps a started source running until it is dropped.
+pub type RunningSource = Box<dyn Send>;
+
+/// Produces the events of one watch root.
+pub trait EventSource {
+    fn start(self: Box<Self>, sink: EventSink) -> Result<RunningSource, String>;
+}
+
+/// Events pushed in by hand, for `POST /control/inject-event` with
+/// DEBUG_INJECT_EVENTS. Clones share the sink.
+#[derive(Clone, Default)]
+pub struct ManualSource {
+    sink: Arc<Mutex<Option<EventSink>>>,
+}
+
+impl ManualSource {
+    /// Hand `event` to the pipeline. Returns false before the source is started.
+    pub fn inject(&self, event: FileEvent) -> bool {
+        let sink = self.sink.lock().unwrap().clone();
+        match sink {
+            Some(sink) => {
+                sink(event);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl EventSource for ManualSource {
+    fn start(self: Box<Self>, sink: EventSink) -> Result<RunningSource, String> {
+        *self.sink.lock().unwrap() = Some(sink);
+        Ok(Box::new(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, AccessMode, CreateKind, DataChange, Flag, RemoveKind};
+    use notify::Event;
+
+    fn kind_of(kind: EventKind, paths: usize) -> FileEventKind {
+        let event = (0..paths).fold(Event::new(kind), |event, i| {
+            event.add_path(PathBuf::from(format!("/watch/{}.xml", i)))
+        });
+        FileEventKind::of(&event)
+    }
+
+    #[test]
+    fn every_notify_kind_has_an_internal_kind() {
+        use FileEventKind::*;
+        let cases = [
+            (EventKind::Create(CreateKind::Any), Created),
+            (EventKind::Create(CreateKind::File), Created),
+            (EventKind::Create(CreateKind::Folder), Created),
+            (EventKind::Create(CreateKind::Other), Created),
+            (EventKind::Modify(ModifyKind::Any), Modified),
+            (EventKind::Modify(ModifyKind::Data(DataChange::Any)), Modified),
+            (EventKind::Modify(ModifyKind::Data(DataChange::Size)), Modified),
+            (EventKind::Modify(ModifyKind::Data(DataChange::Content)), Modified),
+            (EventKind::Modify(ModifyKind::Data(DataChange::Other)), Modified),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)), Modified),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)), Other),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)), Other),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)), Other),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership)), Other),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::Extended)), Other),
+            (EventKind::Modify(ModifyKind::Metadata(MetadataKind::Other)), Other),
+            (EventKind::Modify(ModifyKind::Name(RenameMode::To)), MovedIn),
+            (EventKind::Modify(ModifyKind::Name(RenameMode::From)), MovedOut),
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Any)), Other),
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Other)), Other),
+            (EventKind::Modify(ModifyKind::Other), Modified),
+            (EventKind::Access(AccessKind::Any), Other),
+            (EventKind::Access(AccessKind::Read), Other),
+            (EventKind::Access(AccessKind::Open(AccessMode::Write)), Other),
+            (EventKind::Access(AccessKind::Close(AccessMode::Write)), Other),
+            (EventKind::Access(AccessKind::Other), Other),
+            (EventKind::Remove(RemoveKind::Any), Removed),
+            (EventKind::Remove(RemoveKind::File), Removed),
+            (EventKind::Remove(RemoveKind::Folder), Removed),
+            (EventKind::Remove(RemoveKind::Other), Removed),
+            (EventKind::Any, Other),
+            (EventKind::Other, Other),
+        ];
+        for (kind, expected) in cases {
+            assert_eq!(kind_of(kind, 1), expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn renames_need_both_paths() {
+        let both = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        assert_eq!(kind_of(both, 2), FileEventKind::Renamed);
+        assert_eq!(kind_of(both, 1), FileEventKind::Other);
+        assert_eq!(kind_of(both, 3), FileEventKind::Other);
+    }
+
+    #[test]
+    fn lost_events_are_a_rescan_whatever_their_kind() {
+        for kind in [EventKind::Any, EventKind::Other, EventKind::Create(CreateKind::File)] {
+            let event = Event::new(kind).set_flag(Flag::Rescan);
+            assert_eq!(FileEventKind::of(&event), FileEventKind::Rescan);
+        }
+    }
+
+    #[test]
+    fn watch_events_parse() {
+        let both = WatchEvents::parse(" Modify, create ").unwrap();
+        assert_eq!(both, WatchEvents { create: true, modify: true });
+        assert_eq!(both.to_string(), "create,modify");
+        assert_eq!(WatchEvents::parse("modify").unwrap().to_string(), "modify");
+        assert!(WatchEvents::parse("").is_err());
+        assert!(WatchEvents::parse("create,delete").unwrap_err().contains("'delete'"));
+    }
+
+    #[test]
+    fn manual_sources_deliver_once_started() {
+        let source = ManualSource::default();
+        let event = || FileEvent::new(FileEventKind::Created, vec![PathBuf::from("/watch/a.xml")]);
+        assert!(!source.inject(event()));
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink: EventSink = {
+            let received = Arc::clone(&received);
+            Arc::new(move |event: FileEvent| received.lock().unwrap().push(event.paths))
+        };
+        let _running = Box::new(source.clone()).start(sink).unwrap();
+        // Clones share the sink, like the one the control API keeps
+        assert!(source.inject(event()));
+        assert_eq!(*received.lock().unwrap(), [vec![PathBuf::from("/watch/a.xml")]]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a delivery could not be completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    // The webhook's host name didn't resolve
+    DnsResolution,
+    ConnectTimeout,
+    ConnectionRefused,
+    // Any other failure to open the connection, e.g. no route to the host
+    Connect,
+    // The receiver's certificate was rejected: expired, self-signed or for
+    // another host
+    TlsCertificate,
+    // No certificate of the receiver's chain has a WEBHOOK_PINNED_SPKI_SHA256
+    // hash: someone in between, or a rotation nobody announced
+    TlsPinMismatch,
+    // The TLS handshake failed otherwise, e.g. with an alert from the peer
+    TlsHandshake,
+    // The connection was closed or reset before the response was complete
+    ConnectionReset,
+    // No response, or not all of it, within the request timeout
+    ReadTimeout,
+    // The response couldn't be read or decoded
+    ResponseBody,
+    // Any other failure of the request, e.g. a redirect loop
+    Request,
+    // A 202 was never followed by POST /ack, with ASYNC_ACK_TOKEN
+    AckTimeout,
+    // Nothing was sent because the watcher couldn't prepare the delivery, e.g.
+    // a file that failed to split
+    Internal,
+}
+
+impl FailureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::DnsResolution => "dns_resolution",
+            FailureKind::ConnectTimeout => "connect_timeout",
+            FailureKind::ConnectionRefused => "connection_refused",
+            FailureKind::Connect => "connect",
+            FailureKind::TlsCertificate => "tls_certificate",
+            FailureKind::TlsPinMismatch => "tls_pin_mismatch",
+            FailureKind::TlsHandshake => "tls_handshake",
+            FailureKind::ConnectionReset => "connection_reset",
+            FailureKind::ReadTimeout => "read_timeout",
+            FailureKind::ResponseBody => "response_body",
+            FailureKind::Request => "request",
+            FailureKind::AckTimeout => "ack_timeout",
+            FailureKind::Internal => "internal",
+        }
+    }
+
+    /// The kind of a failed request or response read.
+    ///
+    /// reqwest only tells connect errors, timeouts and body errors apart, so
+    /// the errors it wraps are looked at first: I/O error kinds, then the
+    /// messages of hyper, the resolver and the TLS library, in the order of
+    /// `PATTERNS`.
+    pub fn of(error: &reqwest::Error) -> Self {
+        let mut sources = Vec::new();
+        let mut source = error.source();
+        while let Some(error) = source {
+            sources.push(error);
+            source = error.source();
+        }
+        let messages: Vec<String> = sources.iter().map(|error| error.to_string().to_lowercase()).collect();
+
+        // Messages first: a DNS or TLS failure also carries an I/O error
+        if let Some(kind) = kind_in_messages(&messages) {
+            return kind;
+        }
+        let io_kind = sources
+            .iter()
+            .find_map(|error| error.downcast_ref::<io::Error>())
+            .map(io::Error::kind);
+        match io_kind {
+            Some(io::ErrorKind::ConnectionRefused) => return FailureKind::ConnectionRefused,
+            Some(io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe) => {
+                return FailureKind::ConnectionReset;
+            }
+            _ => {}
+        }
+        match error {
+            error if error.is_timeout() && error.is_connect() => FailureKind::ConnectTimeout,
+            error if error.is_timeout() => FailureKind::ReadTimeout,
+            error if error.is_connect() => FailureKind::Connect,
+            error if error.is_body() || error.is_decode() => FailureKind::ResponseBody,
+            _ => FailureKind::Request,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// The kind of the first of `PATTERNS` that one of the lower-case `messages`
+// contains
+fn kind_in_messages(messages: &[String]) -> Option<FailureKind> {
+    PATTERNS
+        .iter()
+        .find(|(pattern, _)| messages.iter().any(|message| message.contains(pattern)))
+        .map(|(_, kind)| *kind)
+}
+
+// Lower-case fragments of the messages of the errors reqwest wraps, from
+// hyper, the system resolver, OpenSSL (native-tls) and rustls, and the kind
+// they indicate. The first match wins, so certificate problems come before
+// other handshake failures, which also mention the handshake.
+const PATTERNS: &[(&str, FailureKind)] = &[
+    // hyper's resolver, and getaddrinfo's messages
+    ("dns error", FailureKind::DnsResolution),
+    ("failed to lookup address", FailureKind::DnsResolution),
+    ("name or service not known", FailureKind::DnsResolution),
+    ("no address associated with hostname", FailureKind::DnsResolution),
+    ("temporary failure in name resolution", FailureKind::DnsResolution),
+    // The pinning verifier's, which rustls also reports as InvalidCertificate
+    ("webhook_pinned_spki_sha256", FailureKind::TlsPinMismatch),
+    // OpenSSL's verify errors, and rustls' InvalidCertificate
+    ("certificate verify failed", FailureKind::TlsCertificate),
+    ("invalid peer certificate", FailureKind::TlsCertificate),
+    ("self signed certificate", FailureKind::TlsCertificate),
+    ("self-signed certificate", FailureKind::TlsCertificate),
+    ("certificate has expired", FailureKind::TlsCertificate),
+    ("unable to get local issuer certificate", FailureKind::TlsCertificate),
+    ("hostname mismatch", FailureKind::TlsCertificate),
+    // Alerts from the peer and other handshake errors
+    ("alert", FailureKind::TlsHandshake),
+    ("handshake", FailureKind::TlsHandshake),
+    ("wrong version number", FailureKind::TlsHandshake),
+    ("ssl routines", FailureKind::TlsHandshake),
+    // hyper, when the connection goes away mid-request or mid-response
+    ("connection reset", FailureKind::ConnectionReset),
+    ("connection closed before message completed", FailureKind::ConnectionReset),
+    ("incomplete message", FailureKind::ConnectionReset),
+    ("broken pipe", FailureKind::ConnectionReset),
+    ("connection refused", FailureKind::ConnectionRefused),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    // Messages as the libraries under reqwest word them
+    const MESSAGES: &[(&str, FailureKind)] = &[
+        ("dns error: failed to lookup address information: Name or service not known", FailureKind::DnsResolution),
+        ("failed to lookup address information: Temporary failure in name resolution", FailureKind::DnsResolution),
+        ("failed to lookup address information: No address associated with hostname", FailureKind::DnsResolution),
+        (
+            "invalid peer certificate: Other(OtherError(no certificate matches WEBHOOK_PINNED_SPKI_SHA256))",
+            FailureKind::TlsPinMismatch,
+        ),
+        (
+            "error:0A000086:SSL routines:tls_post_process_server_certificate:certificate verify failed:\
+             ../ssl/statem/statem_clnt.c:1889: (self-signed certificate)",
+            FailureKind::TlsCertificate,
+        ),
+        ("invalid peer certificate: Expired", FailureKind::TlsCertificate),
+        ("invalid peer certificate: NotValidForName", FailureKind::TlsCertificate),
+        ("invalid peer certificate: UnknownIssuer", FailureKind::TlsCertificate),
+        ("The certificate was not trusted. (certificate has expired)", FailureKind::TlsCertificate),
+        ("unable to get local issuer certificate", FailureKind::TlsCertificate),
+        ("received fatal alert: HandshakeFailure", FailureKind::TlsHandshake),
+        (
+            "error:0A00010B:SSL routines:ssl3_get_record:wrong version number:../ssl/record/ssl3_record.c:354:",
+            FailureKind::TlsHandshake,
+        ),
+        ("peer is incompatible: NoCipherSuitesInCommon during handshake", FailureKind::TlsHandshake),
+        ("Connection reset by peer (os error 104)", FailureKind::ConnectionReset),
+        ("connection closed before message completed", FailureKind::ConnectionReset),
+        ("error reading a body from connection: end of file before message length reached (incomplete message)", FailureKind::ConnectionReset),
+        ("Broken pipe (os error 32)", FailureKind::ConnectionReset),
+        ("tcp connect error: Connection refused (os error 111)", FailureKind::ConnectionRefused),
+    ];
+
+    #[test]
+    fn messages_map_to_their_kinds() {
+        for (message, kind) in MESSAGES {
+            assert_eq!(kind_in_messages(&[message.to_lowercase()]), Some(*kind), "{}", message);
+        }
+        assert_eq!(kind_in_messages(&["tcp connect error: No route to host (os error 113)".to_string()]), None);
+        assert_eq!(kind_in_messages(&[]), None);
+    }
+
+    #[test]
+    fn the_first_pattern_wins_across_the_chain() {
+        // rustls reports a pin mismatch as an invalid certificate
+        let messages = ["invalid peer certificate: unknownissuer".to_string(), "webhook_pinned_spki_sha256".to_string()];
+        assert_eq!(kind_in_messages(&messages), Some(FailureKind::TlsPinMismatch));
+        let messages = ["handshake failed".to_string(), "certificate verify failed".to_string()];
+        assert_eq!(kind_in_messages(&messages), Some(FailureKind::TlsCertificate));
+    }
+
+    async fn failure_of(url: &str, timeout: Duration) -> FailureKind {
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+        FailureKind::of(&client.get(url).send().await.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn real_connection_failures_are_classified() {
+        // Nothing listens on a port that was just freed
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{}/", port);
+        assert_eq!(failure_of(&url, Duration::from_secs(5)).await, FailureKind::ConnectionRefused);
+
+        // Accepts, reads the request and hangs up without answering
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+        });
+        assert_eq!(failure_of(&url, Duration::from_secs(5)).await, FailureKind::ConnectionReset);
+
+        // Accepts and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let _held = tokio::spawn(async move {
+            let _socket = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        assert_eq!(failure_of(&url, Duration::from_millis(200)).await, FailureKind::ReadTimeout);
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+
+/// Bytes read from the start of a file to recognise its type.
+pub const SNIFF_BYTES: usize = 8192;
+
+// Used when neither the content nor the name tells what a file is
+const BINARY_TYPE: &str = "application/octet-stream";
+const TEXT_TYPE: &str = "text/plain";
+
+// Text formats have no magic bytes, so they are told apart by extension
+const EXTENSION_TYPES: &[(&str, &str)] = &[
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("json", "application/json"),
+    ("ndjson", "application/x-ndjson"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("md", "text/markdown"),
+    ("edi", "application/edi-x12"),
+];
+
+// Suffixes of files still being written, renamed to their real name when
+// complete
+const TEMPORARY_SUFFIXES: &[&str] = &[".tmp", ".part", ".partial", ".crdownload"];
+
+/// The media type of a file that starts with `head`: from its magic bytes
+/// (using the `infer` crate), then its extension, then whether it is text.
+pub fn detect(path: &Path, head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+    let by_extension = path.extension().and_then(|ext| ext.to_str()).and_then(|ext| {
+        EXTENSION_TYPES
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+            .map(|(_, media_type)| *media_type)
+    });
+    match by_extension {
+        Some(media_type) => media_type,
+        None if is_text(head, head.len() == SNIFF_BYTES) => TEXT_TYPE,
+        None => BINARY_TYPE,
+    }
+}
+
+/// Whether `data` is UTF-8 text. With `cut`, `data` is the start of a longer
+/// file and may end inside a character.
+pub fn is_text(data: &[u8], cut: bool) -> bool {
+    match std::str::from_utf8(data) {
+        Ok(text) => !text.contains('\0'),
+        Err(e) => cut && e.error_len().is_none() && !data[..e.valid_up_to()].contains(&0),
+    }
+}
+
+/// Hidden files and names like `report.pdf.part`, which WATCH_ALL_FILES
+/// leaves alone until they are renamed.
+pub fn is_temporary_name(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let lower = name.to_lowercase();
+    name.starts_with('.') || name.ends_with('~') || TEMPORARY_SUFFIXES.iter().any(|suffix| lower.ends_with(suffix))
+}
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::Metadata;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// Number of delivered files remembered; the oldest are forgotten first
+const DELIVERED_INODES_CAPACITY: usize = 10_000;
+
+/// What to do with a new path that is a hard link to an already delivered file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardlinkPolicy {
+    // Deliver it like any other file
+    Deliver,
+    // Don't deliver it
+    Suppress,
+    // Deliver it with `duplicate_of` naming the path delivered first
+    Annotate,
+}
+
+impl HardlinkPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "deliver" => Ok(HardlinkPolicy::Deliver),
+            "suppress" => Ok(HardlinkPolicy::Suppress),
+            "annotate" => Ok(HardlinkPolicy::Annotate),
+            other => Err(format!(
+                "Invalid HARDLINK_POLICY '{}': expected 'deliver', 'suppress' or 'annotate'",
+                other
+            )),
+        }
+    }
+}
+
+// (device, inode)
+type InodeKey = (u64, u64);
+
+struct Delivered {
+    // Path of the first delivered link, relative to the watch directory
+    relative_path: String,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Inodes of recently delivered files, used to recognise hard links.
+///
+/// Only available on Unix; elsewhere nothing is ever reported as a link.
+#[derive(Default)]
+pub struct DeliveredInodes {
+    state: Mutex<(HashMap<InodeKey, Delivered>, VecDeque<InodeKey>)>,
+}
+
+impl DeliveredInodes {
+    /// The relative path under which the file behind `metadata` was already
+    /// delivered, if it is a hard link to it and its content hasn't changed.
+    pub fn delivered_as(&self, metadata: &Metadata) -> Option<String> {
+        // A file with a single link can't be a second path to anything, which
+        // also keeps a reused inode number from matching a deleted file
+        if link_count(metadata) < 2 {
+            return None;
+        }
+        let key = inode_key(metadata)?;
+        let (entries, _) = &*self.state.lock().unwrap();
+        let delivered = entries.get(&key)?;
+        let unchanged = delivered.len == metadata.len() && delivered.modified == metadata.modified().ok();
+        unchanged.then(|| delivered.relative_path.clone())
+    }
+
+    pub fn record(&self, metadata: &Metadata, relative_path: String) {
+        let Some(key) = inode_key(metadata) else {
+            return;
+        };
+        let (entries, order) = &mut *self.state.lock().unwrap();
+        let delivered = Delivered {
+            relative_path,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        };
+        if entries.insert(key, delivered).is_none() {
+            order.push_back(key);
+        }
+        while order.len() > DELIVERED_INODES_CAPACITY {
+            if let Some(oldest) = order.pop_front() {
+                entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode_key(metadata: &Metadata) -> Option<InodeKey> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn inode_key(_metadata: &Metadata) -> Option<InodeKey> {
+    None
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &Metadata) -> u64 {
+    1
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn policies_parse_case_insensitively() {
+        assert_eq!(HardlinkPolicy::parse("Deliver").unwrap(), HardlinkPolicy::Deliver);
+        assert_eq!(HardlinkPolicy::parse("suppress").unwrap(), HardlinkPolicy::Suppress);
+        assert_eq!(HardlinkPolicy::parse("ANNOTATE").unwrap(), HardlinkPolicy::Annotate);
+        assert!(HardlinkPolicy::parse("skip").is_err());
+    }
+
+    #[test]
+    fn hard_links_to_delivered_files_are_recognised() {
+        let root = tempfile::tempdir().unwrap();
+        let original = root.path().join("a.xml");
+        fs::write(&original, "<a/>").unwrap();
+        let inodes = DeliveredInodes::default();
+        // Not a link yet, and not delivered
+        assert_eq!(inodes.delivered_as(&fs::metadata(&original).unwrap()), None);
+        inodes.record(&fs::metadata(&original).unwrap(), "a.xml".to_string());
+
+        let link = root.path().join("in/b.xml");
+        fs::create_dir(root.path().join("in")).unwrap();
+        fs::hard_link(&original, &link).unwrap();
+        assert_eq!(inodes.delivered_as(&fs::metadata(&link).unwrap()).as_deref(), Some("a.xml"));
+
+        let copy = root.path().join("c.xml");
+        fs::copy(&original, &copy).unwrap();
+        assert_eq!(inodes.delivered_as(&fs::metadata(&copy).unwrap()), None);
+    }
+
+    #[test]
+    fn links_whose_content_changed_are_new_files() {
+        let root = tempfile::tempdir().unwrap();
+        let original = root.path().join("a.xml");
+        fs::write(&original, "<a/>").unwrap();
+        let inodes = DeliveredInodes::default();
+        inodes.record(&fs::metadata(&original).unwrap(), "a.xml".to_string());
+
+        let link = root.path().join("b.xml");
+        fs::hard_link(&original, &link).unwrap();
+        fs::write(&link, "<a>changed</a>").unwrap();
+        assert_eq!(inodes.delivered_as(&fs::metadata(&link).unwrap()), None);
+
+        // Once the other path is gone, the inode is a file of its own again
+        inodes.record(&fs::metadata(&link).unwrap(), "b.xml".to_string());
+        fs::remove_file(&original).unwrap();
+        assert_eq!(inodes.delivered_as(&fs::metadata(&link).unwrap()), None);
+    }
+}
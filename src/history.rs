@@ -0,0 +1,91 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// Longer messages are cut off, so that an entry stays small whatever it names
+const MAX_MESSAGE_CHARS: usize = 200;
+
+// Notable occurrences of the whole process, across profiles. None until
+// `enable`, so that nothing is kept without EVENTS_ENDPOINT.
+static HISTORY: Mutex<Option<History>> = Mutex::new(None);
+
+struct History {
+    capacity: usize,
+    // Oldest first
+    entries: VecDeque<Entry>,
+}
+
+/// Something that happened to the watcher itself, for `GET /events/internal`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    pub kind: Kind,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    // A watch root or AUTO_WATCH_PATTERN directory was registered
+    WatchRegistered,
+    // Subtrees fell back to polling at the watch limit
+    WatchLimited,
+    WatchRestored,
+    // A directory couldn't be watched, or the backend reported an error
+    WatchFailed,
+    // The delivery queue overflowed to QUEUE_SPILL_DIR
+    QueueSpilled,
+    QueueDrained,
+    ThrottleRequested,
+    ThrottleExpired,
+    // CONCURRENCY_MODE=adaptive backed off
+    ConcurrencyReduced,
+    TlsPinMismatch,
+}
+
+/// Start keeping the last `capacity` notable occurrences.
+pub fn enable(capacity: usize) {
+    *HISTORY.lock().unwrap() = Some(History {
+        capacity,
+        entries: VecDeque::with_capacity(capacity),
+    });
+}
+
+/// Keep `message` in the history, when it is enabled. Use `notable!`, which
+/// logs the same message.
+pub fn record(kind: Kind, message: &str) {
+    let mut history = HISTORY.lock().unwrap();
+    let Some(history) = history.as_mut() else {
+        return;
+    };
+    let message = message.trim_start();
+    let message = match message.char_indices().nth(MAX_MESSAGE_CHARS) {
+        Some((end, _)) => format!("{}...", &message[..end]),
+        None => message.to_string(),
+    };
+    if history.entries.len() == history.capacity {
+        history.entries.pop_front();
+    }
+    history.entries.push_back(Entry { at: Utc::now(), kind, message });
+}
+
+/// The entries kept, newest first.
+pub fn recent() -> Vec<Entry> {
+    match HISTORY.lock().unwrap().as_ref() {
+        Some(history) => history.entries.iter().rev().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Log a notable occurrence at `level` and keep it in the history, from the
+/// same message, so that the log and the history always agree.
+macro_rules! notable {
+    ($level:expr, $kind:expr, $($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        log::log!($level, "{}", message);
+        $crate::history::record($kind, &message);
+    }};
+}
+
+pub(crate) use notable;
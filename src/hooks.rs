@@ -0,0 +1,103 @@
+use log::info;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+// Longest stretch of a hook's stdout or stderr that is copied into the log
+const HOOK_OUTPUT_LOG_BYTES: usize = 4096;
+
+/// What happens to a file whose pre-delivery hook fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreHookFailure {
+    // Leave the file where it is and don't deliver it
+    Skip,
+    // Move the file to QUARANTINE_DIR and don't deliver it
+    Quarantine,
+}
+
+impl PreHookFailure {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "skip" => Ok(PreHookFailure::Skip),
+            "quarantine" => Ok(PreHookFailure::Quarantine),
+            other => Err(format!(
+                "Invalid PRE_DELIVERY_FAILURE '{}': expected 'skip' or 'quarantine'",
+                other
+            )),
+        }
+    }
+}
+
+/// Runs the external pre- and post-delivery commands of a profile.
+///
+/// Commands are run through `sh -c` with the file path as their last argument.
+/// They are bounded both in number and in running time; a command that exceeds
+/// the timeout is killed.
+pub struct HookRunner {
+    slots: Semaphore,
+    timeout: Duration,
+}
+
+impl HookRunner {
+    pub fn new(max_concurrent: usize, timeout: Duration) -> Self {
+        HookRunner {
+            slots: Semaphore::new(max_concurrent),
+            timeout,
+        }
+    }
+
+    /// Run `command` for `path` with the extra environment `env`.
+    ///
+    /// Succeeds when the command exits with status 0. Its output is logged either way.
+    pub async fn run(&self, command: &str, path: &Path, env: &[(&str, String)], prefix: &str) -> Result<(), String> {
+        let _slot = self.slots.acquire().await.expect("hook semaphore is never closed");
+
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", command))
+            .arg("sh")
+            .arg(path)
+            .envs(env.iter().map(|(key, value)| (*key, value.as_str())))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to start '{}': {}", command, e))?;
+
+        // Dropping the future on timeout drops the child, which kills it
+        let output = match tokio::time::timeout(self.timeout, child.wait_with_output()).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return Err(format!("failed to run '{}': {}", command, e)),
+            Err(_) => return Err(format!("'{}' timed out after {}s", command, self.timeout.as_secs())),
+        };
+
+        log_output(prefix, "stdout", &output.stdout);
+        log_output(prefix, "stderr", &output.stderr);
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!("'{}' exited with {}", command, output.status))
+        }
+    }
+}
+
+fn log_output(prefix: &str, stream: &str, bytes: &[u8]) {
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.trim_end();
+    if text.is_empty() {
+        return;
+    }
+    if text.len() > HOOK_OUTPUT_LOG_BYTES {
+        let mut end = HOOK_OUTPUT_LOG_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        info!("{}  Hook {} ({} bytes, truncated): {}", prefix, stream, text.len(), &text[..end]);
+    } else {
+        info!("{}  Hook {}: {}", prefix, stream, text);
+    }
+}
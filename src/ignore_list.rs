@@ -0,0 +1,183 @@
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::path_case::PathCase;
+
+struct Entry {
+    inserted: Instant,
+    // Unset while the write that caused the entry is still in progress
+    expires: Option<Instant>,
+}
+
+/// Paths the watcher is writing itself, whose events must not be delivered.
+///
+/// Entries are added before a write and given an expiry once it has finished.
+/// The list holds at most `capacity` entries: when it is full, expired entries
+/// are dropped first and then the oldest ones. Dropping an entry that hasn't
+/// expired can let a self-triggered event through, so those evictions are
+/// counted and logged. Paths are compared as the file system of the watch
+/// root compares them.
+///
+/// A panic while the lock is held doesn't disable the list: every entry
+/// stands on its own, so the next caller takes the entries over as they are.
+pub struct IgnoreList {
+    entries: RwLock<HashMap<PathBuf, Entry>>,
+    capacity: usize,
+    case: PathCase,
+    early_evictions: AtomicU64,
+}
+
+impl IgnoreList {
+    pub fn new(capacity: usize, case: PathCase) -> Self {
+        IgnoreList {
+            entries: RwLock::new(HashMap::new()),
+            capacity,
+            case,
+            early_evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Ignore `path` until `release` is called for it.
+    pub fn insert(&self, path: &Path) {
+        let key = self.case.key(path);
+        let now = Instant::now();
+        let mut entries = self.write();
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires.map(|expires| expires > now).unwrap_or(true));
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.inserted)
+                    .map(|(path, _)| path.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                    let evicted = self.early_evictions.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!(
+                        "Ignore list full ({} entries): dropped {} before it expired ({} early evictions so far; consider raising IGNORE_LIST_MAX_ENTRIES)",
+                        self.capacity,
+                        oldest.display(),
+                        evicted
+                    );
+                }
+            }
+        }
+        entries.insert(key, Entry { inserted: now, expires: None });
+    }
+
+    /// Keep ignoring `path` for `linger`, then forget it.
+    pub fn release(&self, path: &Path, linger: Duration) {
+        if let Some(entry) = self.write().get_mut(&self.case.key(path)) {
+            entry.expires = Some(Instant::now() + linger);
+        }
+    }
+
+    /// Stop ignoring `path` immediately.
+    pub fn remove(&self, path: &Path) {
+        self.write().remove(&self.case.key(path));
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.read()
+            .get(&self.case.key(path))
+            .map(|entry| entry.expires.map(|expires| expires > Instant::now()).unwrap_or(true))
+            .unwrap_or(false)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<PathBuf, Entry>> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<PathBuf, Entry>> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn released_paths_are_ignored_until_they_expire() {
+        let list = IgnoreList::new(10, PathCase::Sensitive);
+        let path = Path::new("/watch/a.xml");
+        list.insert(path);
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        // Writes in progress never expire
+        assert!(list.contains(path));
+
+        list.release(path, Duration::from_secs(2));
+        tokio::time::advance(Duration::from_millis(1999)).await;
+        assert!(list.contains(path));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(!list.contains(path));
+    }
+
+    #[test]
+    fn a_panic_while_the_list_is_locked_leaves_it_working() {
+        let list = IgnoreList::new(10, PathCase::Sensitive);
+        list.insert(Path::new("/watch/before.xml"));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _entries = list.write();
+            panic!("while holding the lock");
+        }));
+        assert!(result.is_err());
+        assert!(list.entries.is_poisoned());
+
+        assert!(list.contains(Path::new("/watch/before.xml")));
+        list.insert(Path::new("/watch/after.xml"));
+        assert!(list.contains(Path::new("/watch/after.xml")));
+        list.remove(Path::new("/watch/before.xml"));
+        assert!(!list.contains(Path::new("/watch/before.xml")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_list_drops_expired_entries_before_the_oldest() {
+        let list = IgnoreList::new(2, PathCase::Insensitive);
+        list.insert(Path::new("/watch/Old.xml"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        list.insert(Path::new("/watch/expired.xml"));
+        list.release(Path::new("/watch/expired.xml"), Duration::from_secs(1));
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        list.insert(Path::new("/watch/new.xml"));
+        assert!(list.contains(Path::new("/watch/old.XML")));
+        assert_eq!(list.early_evictions.load(Ordering::Relaxed), 0);
+
+        list.insert(Path::new("/watch/newer.xml"));
+        assert!(!list.contains(Path::new("/watch/Old.xml")));
+        assert!(list.contains(Path::new("/watch/new.xml")));
+        assert!(list.contains(Path::new("/watch/newer.xml")));
+        assert_eq!(list.early_evictions.load(Ordering::Relaxed), 1);
+
+        list.remove(Path::new("/watch/NEW.xml"));
+        assert!(!list.contains(Path::new("/watch/new.xml")));
+    }
+
+    #[test]
+    fn inserting_a_listed_path_into_a_full_list_evicts_nothing() {
+        let list = IgnoreList::new(2, PathCase::Sensitive);
+        list.insert(Path::new("/watch/a.xml"));
+        list.insert(Path::new("/watch/b.xml"));
+        list.release(Path::new("/watch/a.xml"), Duration::from_secs(60));
+
+        // A second write to a.xml starts over, without an expiry
+        list.insert(Path::new("/watch/a.xml"));
+        assert!(list.contains(Path::new("/watch/a.xml")));
+        assert!(list.contains(Path::new("/watch/b.xml")));
+        assert!(list.read()[Path::new("/watch/a.xml")].expires.is_none());
+        assert_eq!(list.early_evictions.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn releasing_an_unlisted_path_does_nothing() {
+        let list = IgnoreList::new(2, PathCase::Sensitive);
+        list.release(Path::new("/watch/a.xml"), Duration::from_secs(60));
+        assert!(!list.contains(Path::new("/watch/a.xml")));
+        assert!(list.read().is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use log::info;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+struct Bucket {
+    // Negative when files are already waiting for tokens that haven't accrued
+    tokens: f64,
+    refilled: Instant,
+    throttling: bool,
+}
+
+/// Token bucket limiting how many files per second start being processed.
+///
+/// Up to one second's worth of files can start at once; beyond that each file
+/// waits for its turn, in arrival order. Nothing is ever dropped.
+pub struct IntakeLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl IntakeLimiter {
+    pub fn new(files_per_sec: u32) -> Self {
+        IntakeLimiter {
+            rate: files_per_sec as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: files_per_sec as f64,
+                refilled: Instant::now(),
+                throttling: false,
+            }),
+        }
+    }
+
+    /// Wait until one more file may be processed.
+    pub async fn acquire(&self, prefix: &str) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+            bucket.refilled = now;
+
+            let throttling = bucket.tokens < 1.0;
+            if throttling != bucket.throttling {
+                bucket.throttling = throttling;
+                if throttling {
+                    info!("{}Intake limit of {} files/s reached, new files are waiting", prefix, self.rate);
+                } else {
+                    info!("{}Intake back below {} files/s", prefix, self.rate);
+                }
+            }
+
+            // Reserve a token, possibly one that is yet to accrue
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64(-bucket.tokens / self.rate)
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_seconds_worth_starts_at_once_and_the_rest_waits() {
+        let limiter = IntakeLimiter::new(20);
+        let started = Instant::now();
+        for _ in 0..20 {
+            limiter.acquire("").await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(40));
+        assert!(!limiter.bucket.lock().unwrap().throttling);
+
+        limiter.acquire("").await;
+        limiter.acquire("").await;
+        // Two more files at 20 files/s, less what accrued meanwhile
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert!(limiter.bucket.lock().unwrap().throttling);
+    }
+}
@@ -0,0 +1,120 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use sha2::Sha256;
+use std::fmt;
+
+/// Media type of a compact JWS (RFC 7515 section 9.2.1).
+pub const JWS_CONTENT_TYPE: &str = "application/jose";
+
+// Smallest keys RFC 7518 allows: a 256-bit HMAC secret, a 2048-bit RSA modulus
+const MIN_HMAC_KEY_BYTES: usize = 32;
+const MIN_RSA_KEY_BITS: usize = 2048;
+
+/// How the request body is sent, SIGN_MODE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignMode {
+    // The JSON payload as it is
+    None,
+    // The JSON payload as a compact JWS
+    Jws,
+}
+
+impl SignMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(SignMode::None),
+            "jws" => Ok(SignMode::Jws),
+            other => Err(format!("Invalid SIGN_MODE '{}': expected 'none' or 'jws'", other)),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Key {
+    Hs256(Vec<u8>),
+    Rs256(Box<SigningKey<Sha256>>),
+}
+
+/// Signs payloads as compact JWS with HS256 or RS256 (RFC 7515, RFC 7518).
+#[derive(Clone)]
+pub struct JwsSigner {
+    key: Key,
+    key_id: Option<String>,
+}
+
+impl JwsSigner {
+    /// `key` is the shared secret for HS256 and a PEM private key (PKCS#8 or
+    /// PKCS#1) for RS256.
+    pub fn new(algorithm: &str, key: &[u8], key_id: Option<String>) -> Result<Self, String> {
+        let key = match algorithm.to_uppercase().as_str() {
+            "HS256" => {
+                if key.len() < MIN_HMAC_KEY_BYTES {
+                    return Err(format!("The JWS key must be at least {} bytes for HS256", MIN_HMAC_KEY_BYTES));
+                }
+                Key::Hs256(key.to_vec())
+            }
+            "RS256" => {
+                let pem = std::str::from_utf8(key).map_err(|_| "The JWS key must be a PEM private key for RS256".to_string())?;
+                let private = RsaPrivateKey::from_pkcs8_pem(pem)
+                    .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+                    .map_err(|e| format!("The JWS key is not a PEM RSA private key: {}", e))?;
+                if private.size() * 8 < MIN_RSA_KEY_BITS {
+                    return Err(format!("The JWS key must have at least {} bits for RS256", MIN_RSA_KEY_BITS));
+                }
+                Key::Rs256(Box::new(SigningKey::new(private)))
+            }
+            other => return Err(format!("Invalid JWS_ALGORITHM '{}': expected 'HS256' or 'RS256'", other)),
+        };
+        Ok(JwsSigner { key, key_id })
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self.key {
+            Key::Hs256(_) => "HS256",
+            Key::Rs256(_) => "RS256",
+        }
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// The compact serialization `header.payload.signature` of `payload`.
+    pub fn sign(&self, payload: &[u8]) -> String {
+        let mut header = serde_json::json!({ "alg": self.algorithm() });
+        if let Some(key_id) = &self.key_id {
+            header["kid"] = serde_json::json!(key_id);
+        }
+        let signing_input = format!(
+            "{}.{}",
+            BASE64URL.encode(header.to_string()),
+            BASE64URL.encode(payload)
+        );
+        let signature = match &self.key {
+            Key::Hs256(secret) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+                mac.update(signing_input.as_bytes());
+                mac.finalize().into_bytes().to_vec()
+            }
+            Key::Rs256(key) => key.sign(signing_input.as_bytes()).to_vec(),
+        };
+        format!("{}.{}", signing_input, BASE64URL.encode(signature))
+    }
+}
+
+// The key never appears in the log
+impl fmt::Debug for JwsSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwsSigner")
+            .field("algorithm", &self.algorithm())
+            .field("key_id", &self.key_id)
+            .finish()
+    }
+}
@@ -1,19 +1,121 @@
+mod archive;
+mod audit_syslog;
+mod batch;
+mod body_match;
+mod c14n;
+mod catchup;
+mod concurrency;
+mod config;
+mod content_headers;
+mod content_server;
+mod delivery;
+mod digest;
+mod encoding;
+mod events;
+mod failure;
+mod file_type;
+mod hardlinks;
+mod history;
+mod hooks;
+mod ignore_list;
+mod intake;
+mod jws;
+mod modifications;
+mod outcomes;
+mod path_case;
+mod path_lock;
+mod preview;
+mod pending_ack;
+mod presigned;
+mod redundant;
+mod reorder;
+mod retry;
+mod retry_later;
+mod roots;
+mod sanitize;
+mod sensitive;
+mod sequence;
+mod settle;
+mod size_policy;
+mod shadow;
+mod skips;
+mod spill;
+mod split;
+mod stability;
+mod startup_scan;
+mod state;
+mod storage;
+mod throttle;
+mod tls;
+mod watch;
+mod write_guard;
+mod xml_path;
+
 use chrono::Utc;
-use log::{error, info, warn};
-use notify::{Event, RecursiveMode, Result as NotifyResult, Watcher};
+use log::{debug, error, info, warn, Level};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::env;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc::channel, Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
 use tokio::time::sleep;
 
+use archive::BundleProgress;
+use audit_syslog::SyslogAudit;
+use batch::Batcher;
+use catchup::{CatchupPolicy, CatchupProgress};
+use concurrency::ConcurrencyLimiter;
+use config::{Config, UrlTemplate};
+use content_server::{
+    AckHandler, CatchupHandler, ContentMode, ContentRegistry, EventsHandler, InjectHandler, PreviewError, PreviewHandler,
+    SettleHandler, SkipsHandler, ThrottlesHandler,
+};
+use delivery::{deliver_presigned, overwrites_with_response, send_batches, send_webhook};
+use encoding::{InvalidSequences, InvalidUtf8, Utf16, Utf16Handling};
+use events::{EventSink, EventSource, FileEvent, FileEventKind, ManualSource, WatchEvents};
+use failure::FailureKind;
+use hardlinks::{DeliveredInodes, HardlinkPolicy};
+use history::notable;
+use hooks::{HookRunner, PreHookFailure};
+use ignore_list::IgnoreList;
+use intake::IntakeLimiter;
+use outcomes::{OutcomeNotifier, OutcomeRecord};
+use path_case::PathCase;
+use path_lock::PathLocks;
+use modifications::Modifications;
+use pending_ack::PendingAcks;
+use presigned::PresignProgress;
+use redundant::DeliveredContent;
+use reorder::{DeliveryOrder, Reorder};
+use roots::{HandledElsewhere, WatchRoots};
+use sensitive::SensitiveString;
+use sanitize::Sanitize;
+use sequence::Sequence;
+use settle::{AdaptivePolicy, SettleLearner};
+use size_policy::SizeAction;
+use shadow::Shadow;
+use skips::{SkipReason, Skips};
+use stability::StabilityCheck;
+use startup_scan::ExistingFiles;
+use state::build_state;
+use storage::{AuditStore, QueueStore};
+use throttle::Throttles;
+use watch::{FallbackSettings, NotifySource, PollSource, WatchBackend};
+use write_guard::{WriteCapability, WriteGuard};
+
 // Duration to keep files in the ignore list after overwriting them
 // This prevents triggering new webhook events when we modify the file
 const IGNORE_DURATION_SECS: u64 = 2;
 
+// Exit code used when the filesystem watcher stops delivering events, so that
+// the orchestrator restarts the container instead of leaving it hung
+const EXIT_WATCHER_DISCONNECTED: i32 = 3;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct WebhookPayload {
     event: String,
@@ -21,45 +123,206 @@ struct WebhookPayload {
     filename: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_truncated: Option<bool>,
+    // Set when characters XML 1.0 doesn't allow were removed or replaced,
+    // with SANITIZE_CONTENT, along with how many
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_sanitized: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sanitized_characters: Option<usize>,
+    // How many sequences weren't valid UTF-8, with CONTENT_INVALID_UTF8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalid_utf8_sequences: Option<usize>,
+    // "base64" for files that aren't text, with WATCH_ALL_FILES, and for
+    // content that isn't UTF-8 with CONTENT_INVALID_UTF8=base64-fallback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<String>,
+    // Set when the content was converted to UTF-8 from UTF-16
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    // Media type of any file, with WATCH_ALL_FILES
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detected_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive_source: Option<String>,
+    // File name of the bundle and path of the entry inside it, with BUNDLE_EXTENSIONS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bundle_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bundle_entry: Option<String>,
+    // The SIZE_POLICY action that shaped the payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_policy: Option<String>,
+    // CONTENT_REF_TEMPLATE filled in for the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_ref: Option<String>,
+    // Position of the element within the file, with SPLIT_ON_ELEMENT
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fragment_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<String>,
+    // Set when a file with a newer mtime was delivered before it, with ORDERING=mtime
+    #[serde(skip_serializing_if = "Option::is_none")]
+    out_of_order: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
     timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detection_to_send_ms: Option<PayloadNumber>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<PayloadNumber>,
 }
 
-#[derive(Debug, Clone)]
-struct Config {
-    watch_dir: PathBuf,
-    webhook_url: String,
-    webhook_method: String,
-    include_content: bool,
-    overwrite_with_response: bool,
-}
-
-impl Config {
-    fn from_env() -> Result<Self, String> {
-        let watch_dir = env::var("WATCH_DIR")
-            .unwrap_or_else(|_| "/watch".to_string())
-            .into();
-        
-        let webhook_url = env::var("WEBHOOK_URL")
-            .map_err(|_| "WEBHOOK_URL environment variable is required".to_string())?;
-        
-        let webhook_method = env::var("WEBHOOK_METHOD")
-            .unwrap_or_else(|_| "POST".to_string());
-        
-        let include_content = env::var("INCLUDE_CONTENT")
-            .unwrap_or_else(|_| "false".to_string())
-            .to_lowercase() == "true";
-        
-        let overwrite_with_response = env::var("OVERWRITE_WITH_RESPONSE")
-            .unwrap_or_else(|_| "false".to_string())
-            .to_lowercase() == "true";
-        
-        Ok(Config {
-            watch_dir,
-            webhook_url,
-            webhook_method,
-            include_content,
-            overwrite_with_response,
-        })
+// The body of a request carrying several files, with BATCH_SIZE
+#[derive(Debug, Serialize)]
+struct BatchPayload {
+    event: &'static str,
+    batch_id: String,
+    // The trigger that sent the batch: size, idle or max_wait
+    flushed_by: &'static str,
+    files: Vec<WebhookPayload>,
+}
+
+// A file waiting for its batch to be sent
+struct BatchedFile {
+    payload: WebhookPayload,
+    detected_at: Instant,
+    // Receives the outcome of the batch's request
+    done: tokio::sync::oneshot::Sender<(Outcome, Option<u16>)>,
+}
+
+// A numeric payload field, sent as a JSON string when NUMERIC_FIELDS_AS_STRING is set
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum PayloadNumber {
+    Number(u64),
+    Text(String),
+}
+
+impl PayloadNumber {
+    fn new(config: &Config, value: u64) -> Self {
+        if config.numeric_fields_as_string {
+            PayloadNumber::Text(value.to_string())
+        } else {
+            PayloadNumber::Number(value)
+        }
+    }
+}
+
+// State shared by every delivery task
+struct AppState {
+    config: Config,
+    // Shared so that connections to the receiver are reused
+    client: Client,
+    // How the watch root's file system compares paths, for every map keyed on them
+    path_case: PathCase,
+    ignore_list: IgnoreList,
+    limiter: ConcurrencyLimiter,
+    write_guard: WriteGuard,
+    content_registry: Arc<ContentRegistry>,
+    hooks: HookRunner,
+    throttles: Arc<Throttles>,
+    delivered_inodes: DeliveredInodes,
+    // With SKIP_REDUNDANT_DELIVERY
+    delivered_content: DeliveredContent,
+    // Entries delivered from bundles that had failures, with BUNDLE_EXTENSIONS
+    bundle_progress: BundleProgress,
+    // XML files waiting for or in delivery
+    queued: AtomicUsize,
+    // Signalled whenever a queued file finishes
+    queue_space: Notify,
+    // QUEUE_SPILL_DIR, or the STORAGE_DB_PATH database
+    spill: Option<Box<dyn QueueStore>>,
+    shadow: Option<Shadow>,
+    // MAX_FILES_PER_SEC
+    intake: Option<IntakeLimiter>,
+    outcomes: Option<OutcomeNotifier>,
+    syslog: Option<SyslogAudit>,
+    // Every outcome, with STORAGE_DB_PATH
+    audit: Option<Box<dyn AuditStore>>,
+    // Files waiting for POST /ack, with ASYNC_ACK_TOKEN
+    pending_acks: Option<PendingAcks>,
+    // With INCLUDE_SEQUENCE
+    sequence: Option<Sequence>,
+    // Subdirectories delivered by other profiles, with ALLOW_OVERLAPPING_ROOTS
+    handled_elsewhere: Option<HandledElsewhere>,
+    skips: Skips,
+    // Events from POST /control/inject-event, with DEBUG_INJECT_EVENTS
+    injected_events: Option<ManualSource>,
+    // New files waiting to be sorted, with ORDERING=mtime
+    reorder: Option<Reorder>,
+    // Files waiting to be sent together, with BATCH_SIZE
+    batcher: Option<Batcher<BatchedFile>>,
+    // Phases reached by failed deliveries, with DELIVERY_MODE=presigned
+    presign_progress: Option<PresignProgress>,
+    // Paths being processed, with LOCK_PER_PATH
+    path_locks: Option<PathLocks>,
+    // SETTLE_MODE=adaptive
+    settle: Option<SettleLearner>,
+    // Modified files being debounced, with WATCH_EVENTS=modify
+    modifications: Option<Modifications>,
+    // Files found by SCAN_ON_STARTUP, until they are delivered
+    existing: Option<ExistingFiles>,
+    // How far their delivery has got, with CATCHUP_MAX_FILES or CATCHUP_MAX_AGE_SECS
+    catchup: Option<CatchupProgress>,
+}
+
+// Terminal result of processing one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    // The receiver answered with a 2xx status
+    Delivered,
+    // The receiver accepted the whole file with a 202 and a token, and will
+    // acknowledge it later; the final outcome comes with POST /ack
+    Accepted,
+    // The receiver answered with any other status
+    Rejected,
+    // The request could not be completed
+    Failed(FailureKind),
+    // Nothing was delivered and the file was left in place, e.g. because the
+    // pre-delivery hook failed
+    Skipped(SkipReason),
+    // The pre-delivery hook failed and the file was moved to QUARANTINE_DIR
+    Quarantined,
+    // A hard link to an already delivered file, with HARDLINK_POLICY=suppress
+    Suppressed,
+    // Identical to what was last delivered from the path, with
+    // SKIP_REDUNDANT_DELIVERY, so not sent again
+    Cached,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Delivered => "delivered",
+            Outcome::Accepted => "accepted",
+            Outcome::Rejected => "rejected",
+            Outcome::Failed(_) => "failed",
+            Outcome::Skipped(_) => "skipped",
+            Outcome::Quarantined => "quarantined",
+            Outcome::Suppressed => "suppressed",
+            Outcome::Cached => "cached",
+        }
+    }
+    
+    fn skip_reason(&self) -> Option<SkipReason> {
+        match self {
+            Outcome::Skipped(reason) => Some(*reason),
+            Outcome::Suppressed => Some(SkipReason::DuplicateHardlink),
+            _ => None,
+        }
+    }
+    
+    fn failure_kind(&self) -> Option<FailureKind> {
+        match self {
+            Outcome::Failed(kind) => Some(*kind),
+            _ => None,
+        }
     }
 }
 
@@ -70,208 +333,3053 @@ fn is_xml_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-async fn trigger_webhook(config: &Config, filepath: PathBuf, ignore_list: Arc<Mutex<HashSet<PathBuf>>>) {
-    let filename = filepath
+// Number of bytes read from the start of a file when sniffing its document type
+const DOC_SNIFF_BYTES: usize = 4096;
+
+// Content type used when the document doesn't match any known dialect
+const DEFAULT_XML_CONTENT_TYPE: &str = "application/xml";
+
+// Known root elements (local name, without namespace prefix) and their media types
+const ROOT_ELEMENT_CONTENT_TYPES: &[(&str, &str)] = &[
+    ("rss", "application/rss+xml"),
+    ("feed", "application/atom+xml"),
+    ("svg", "image/svg+xml"),
+    ("html", "application/xhtml+xml"),
+    ("RDF", "application/rdf+xml"),
+    ("Envelope", "application/soap+xml"),
+    ("kml", "application/vnd.google-earth.kml+xml"),
+    ("gpx", "application/gpx+xml"),
+    ("math", "application/mathml+xml"),
+    ("stylesheet", "application/xslt+xml"),
+    ("transform", "application/xslt+xml"),
+];
+
+// Well-known DOCTYPE public identifier fragments and their media types
+const DOCTYPE_CONTENT_TYPES: &[(&str, &str)] = &[
+    ("-//W3C//DTD XHTML", "application/xhtml+xml"),
+    ("-//W3C//DTD SVG", "image/svg+xml"),
+    ("-//W3C//DTD MathML", "application/mathml+xml"),
+    ("-//Netscape Communications//DTD RSS", "application/rss+xml"),
+];
+
+fn content_type_for_root(name: &str) -> Option<&'static str> {
+    let local_name = name.rsplit(':').next().unwrap_or(name);
+    ROOT_ELEMENT_CONTENT_TYPES
+        .iter()
+        .find(|(root, _)| *root == local_name)
+        .map(|(_, content_type)| *content_type)
+}
+
+// Derive a media type from the document itself: a recognised DOCTYPE public
+// identifier wins, then the DOCTYPE name, then the first element in the document.
+fn detect_document_content_type(head: &str) -> Option<&'static str> {
+    let mut rest = head.trim_start_matches('\u{feff}');
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix("<?") {
+            rest = &after[after.find("?>")? + 2..];
+        } else if let Some(after) = rest.strip_prefix("<!--") {
+            rest = &after[after.find("-->")? + 3..];
+        } else if let Some(after) = rest.strip_prefix("<!DOCTYPE") {
+            let end = after.find(['[', '>']).unwrap_or(after.len());
+            let declaration = &after[..end];
+            if let Some((_, content_type)) = DOCTYPE_CONTENT_TYPES
+                .iter()
+                .find(|(public_id, _)| declaration.contains(public_id))
+            {
+                return Some(content_type);
+            }
+            let name = declaration.split_whitespace().next()?;
+            return content_type_for_root(name);
+        } else if let Some(after) = rest.strip_prefix('<') {
+            let end = after
+                .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                .unwrap_or(after.len());
+            return content_type_for_root(&after[..end]);
+        } else {
+            return None;
+        }
+    }
+}
+
+// The files of a watch directory that are delivered
+#[derive(Debug, Clone)]
+struct WatchedFiles {
+    // WATCH_ALL_FILES: any file not under a temporary name
+    all: bool,
+    bundle_extensions: Vec<String>,
+    case: PathCase,
+    events: WatchEvents,
+}
+
+impl WatchedFiles {
+    fn of(state: &AppState) -> Self {
+        WatchedFiles {
+            all: state.config.watch_all_files,
+            bundle_extensions: state.config.bundle_extensions.clone(),
+            case: state.path_case,
+            events: state.config.watch_events,
+        }
+    }
+    
+    fn contains(&self, path: &Path) -> bool {
+        if self.all {
+            return !file_type::is_temporary_name(path);
+        }
+        is_xml_file(path) || archive::is_bundle(path, &self.bundle_extensions)
+    }
+    
+    // Why a file not contained was skipped
+    fn skip_reason(&self) -> SkipReason {
+        match self.all {
+            true => SkipReason::TemporaryName,
+            false => SkipReason::NotWatchedExtension,
+        }
+    }
+}
+
+// The paths of an event that may need delivering: those of a Create event, or
+// the destination of a rename that gives a file the extension it is watched
+// for, the usual way producers mark `file.tmp` complete as `file.xml`. Renames
+// between two watched names, or on a case-insensitive file system between two
+// spellings of one name, are not deliveries. None without WATCH_EVENTS=create.
+fn delivery_paths<'e>(event: &'e FileEvent, watched: &WatchedFiles) -> &'e [PathBuf] {
+    if !watched.events.create {
+        return &[];
+    }
+    match (event.kind, event.paths.as_slice()) {
+        (FileEventKind::Created, paths) => paths,
+        (FileEventKind::Renamed, [from, to])
+            if !watched.contains(from) && !watched.case.same_path(from, to) =>
+        {
+            std::slice::from_ref(to)
+        }
+        _ => &[],
+    }
+}
+
+// The paths of a Modify event that are redelivered once they stop changing,
+// with WATCH_EVENTS=modify. Bundles are only delivered when they appear.
+fn modified_paths<'e>(event: &'e FileEvent, watched: &WatchedFiles) -> Vec<&'e PathBuf> {
+    if !watched.events.modify || event.kind != FileEventKind::Modified {
+        return Vec::new();
+    }
+    event
+        .paths
+        .iter()
+        .filter(|path| watched.contains(path) && !archive::is_bundle(path, &watched.bundle_extensions))
+        .collect()
+}
+
+// Cheap check run inside the notify callback so that events we would never act
+// on don't cross the channel. Only Create events and renames (see
+// `delivery_paths`) touching an XML path (or a bundle, with BUNDLE_EXTENSIONS,
+// or any file with WATCH_ALL_FILES) qualify, and with WATCH_EVENTS=modify the
+// modifications of those files.
+fn is_relevant_event(event: &FileEvent, watched: &WatchedFiles) -> bool {
+    delivery_paths(event, watched)
+        .iter()
+        .any(|path| watched.contains(path))
+        || !modified_paths(event, watched).is_empty()
+}
+
+// Files of an event dropped by `is_relevant_event`: created with a name that
+// isn't watched, or renamed between two watched names or spellings. Directories and the
+// other events are not files being skipped.
+fn filtered_skips<'e>(event: &'e FileEvent, watched: &WatchedFiles) -> Vec<(&'e Path, SkipReason)> {
+    if !watched.events.create {
+        return Vec::new();
+    }
+    match (event.kind, event.paths.as_slice()) {
+        (FileEventKind::Created, paths) => paths
+            .iter()
+            .filter(|path| !path.is_dir())
+            .map(|path| (path.as_path(), watched.skip_reason()))
+            .collect(),
+        (FileEventKind::Renamed, [from, to])
+            if watched.contains(to) =>
+        {
+            let reason = match watched.case.same_path(from, to) {
+                true => SkipReason::CaseOnlyRename,
+                false => SkipReason::RenamedFromWatchedName,
+            };
+            vec![(to.as_path(), reason)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// At most the first `limit` bytes of a file
+async fn read_file_start(filepath: &Path, limit: usize) -> std::io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let file = tokio::fs::File::open(filepath).await?;
+    let mut buf = Vec::with_capacity(limit);
+    file.take(limit as u64).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn read_file_head(filepath: &Path, limit: usize) -> std::io::Result<String> {
+    let buf = read_file_start(filepath, limit).await?;
+    Ok(match Utf16::detect(&buf) {
+        Some(utf16) => utf16.decode(&buf),
+        None => String::from_utf8_lossy(&buf).into_owned(),
+    })
+}
+
+// Whether a file starts like a UTF-16 XML document
+async fn is_utf16_file(filepath: &Path) -> bool {
+    use tokio::io::AsyncReadExt;
+    
+    let mut head = Vec::with_capacity(4);
+    match tokio::fs::File::open(filepath).await {
+        Ok(file) => file.take(4).read_to_end(&mut head).await.is_ok() && Utf16::detect(&head).is_some(),
+        Err(_) => false,
+    }
+}
+
+// Read a whole file as text, converted to UTF-8 when it is UTF-16
+async fn read_file_text(filepath: &Path) -> std::io::Result<(String, Option<Utf16>)> {
+    let data = tokio::fs::read(filepath).await?;
+    match Utf16::detect(&data) {
+        Some(utf16) => Ok((utf16.decode(&data), Some(utf16))),
+        None => String::from_utf8(data)
+            .map(|text| (text, None))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+    }
+}
+
+// Read a whole file as the content of a payload: converted to UTF-8 when it
+// is UTF-16, and otherwise with what isn't valid UTF-8 handled as `mode` says
+async fn read_file_content(
+    filepath: &Path,
+    mode: InvalidUtf8,
+) -> std::io::Result<(String, Option<InvalidSequences>, Option<Utf16>)> {
+    let data = tokio::fs::read(filepath).await?;
+    if let Some(utf16) = Utf16::detect(&data) {
+        return Ok((utf16.decode(&data), None, Some(utf16)));
+    }
+    let (content, invalid) = encoding::decode_utf8(data, mode);
+    Ok((content, invalid, None))
+}
+
+// Read at most `limit` bytes of a file for CONTENT_PREVIEW_BYTES. Returns the
+// preview, its invalid UTF-8 sequences, whether the file is longer than that
+// and, for UTF-16 files, whose preview is `limit` bytes of the converted text,
+// their encoding.
+async fn read_file_preview(
+    filepath: &Path,
+    limit: usize,
+    mode: InvalidUtf8,
+) -> std::io::Result<(String, Option<InvalidSequences>, bool, Option<Utf16>)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(filepath).await?;
+    let mut buf = Vec::with_capacity(limit + 1);
+    // One byte past the limit tells whether anything was left out
+    (&mut file).take(limit as u64 + 1).read_to_end(&mut buf).await?;
+    let Some(utf16) = Utf16::detect(&buf) else {
+        let (preview, invalid, truncated) = preview_of(&buf, limit, mode);
+        return Ok((preview, invalid, truncated, None));
+    };
+    // UTF-16 takes at most twice the bytes of UTF-8, plus the byte order mark
+    // and what an early stop may cut off
+    file.take(limit as u64 + 8).read_to_end(&mut buf).await?;
+    let (preview, _, truncated) = preview_of(utf16.decode(&buf).as_bytes(), limit, mode);
+    Ok((preview, None, truncated, Some(utf16)))
+}
+
+// Inline content of a file that isn't XML, with WATCH_ALL_FILES: text as it
+// is, anything else base64-encoded. Returns the content, whether `limit` left
+// part of the file out and whether the content is base64.
+async fn read_other_content(filepath: &Path, limit: Option<usize>) -> std::io::Result<(String, bool, bool)> {
+    use base64::Engine;
+    
+    let data = match limit {
+        // One byte past the limit tells whether anything was left out
+        Some(limit) => read_file_start(filepath, limit + 1).await?,
+        None => tokio::fs::read(filepath).await?,
+    };
+    let limit = limit.unwrap_or(data.len());
+    if file_type::is_text(&data, data.len() > limit) {
+        // Text is valid UTF-8 up to where the limit cuts it
+        let (content, _, truncated) = preview_of(&data, limit, InvalidUtf8::Replace);
+        return Ok((content, truncated, false));
+    }
+    let end = data.len().min(limit);
+    Ok((base64::engine::general_purpose::STANDARD.encode(&data[..end]), data.len() > limit, true))
+}
+
+// WATCH_ALL_FILES: the media type of a file, from the document for XML files
+async fn detect_file_type(filepath: &Path) -> std::io::Result<String> {
+    let head = read_file_start(filepath, file_type::SNIFF_BYTES).await?;
+    if !is_xml_file(filepath) {
+        return Ok(file_type::detect(filepath, &head).to_string());
+    }
+    let (head, _) = encoding::to_utf8(&head);
+    Ok(detect_document_content_type(&String::from_utf8_lossy(&head))
+        .unwrap_or(DEFAULT_XML_CONTENT_TYPE)
+        .to_string())
+}
+
+// The first `limit` bytes of `data`, cut back so that no UTF-8 sequence is
+// split, with what isn't valid UTF-8 handled as `mode` says
+fn preview_of(data: &[u8], limit: usize, mode: InvalidUtf8) -> (String, Option<InvalidSequences>, bool) {
+    if data.len() <= limit {
+        let (preview, invalid) = encoding::decode_utf8(data.to_vec(), mode);
+        return (preview, invalid, false);
+    }
+    let mut end = limit;
+    // Back up to the start of a multi-byte sequence that the limit cuts through
+    if let Some(start) = (end.saturating_sub(3)..end).rev().find(|&i| data[i] & 0xC0 != 0x80) {
+        let width = match data[start] {
+            b if b >= 0xF0 => 4,
+            b if b >= 0xE0 => 3,
+            b if b >= 0xC0 => 2,
+            _ => 1,
+        };
+        if start + width > end {
+            end = start;
+        }
+    }
+    let (preview, invalid) = encoding::decode_utf8(data[..end].to_vec(), mode);
+    (preview, invalid, true)
+}
+
+// The `filepath` and `filename` reported for `path`, with FILENAME_REWRITE applied
+// to the file name. The file on disk keeps its name.
+fn payload_names(config: &Config, path: &Path) -> (String, String) {
+    let filename = path
         .file_name()
         .and_then(|f| f.to_str())
         .unwrap_or("")
         .to_string();
+    match &config.filename_rewrite {
+        Some(rewrite) => {
+            let rewritten = rewrite.apply(&filename);
+            let filepath = path.with_file_name(&rewritten).display().to_string();
+            (filepath, rewritten)
+        }
+        None => (path.display().to_string(), filename),
+    }
+}
+
+fn content_url_for(config: &Config, token: &str) -> String {
+    format!("{}/content/{}", config.content_url_base, token)
+}
+
+async fn trigger_webhook(state: Arc<AppState>, filepath: PathBuf, detected_at: Instant) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let size = tokio::fs::metadata(&filepath).await.ok().map(|metadata| metadata.len());
+    if let Some(reason) = first_failure(content_checks(&state, &filepath, size).await) {
+        skip_file(&state, &filepath, reason);
+        return;
+    }
+    match (is_modification(&state, &filepath), is_existing(&state, &filepath), is_xml_file(&filepath)) {
+        (true, _, true) => info!("{}Modified XML file detected: {}", prefix, filepath.display()),
+        (true, _, false) => info!("{}Modified file detected: {}", prefix, filepath.display()),
+        (false, true, true) => info!("{}Existing XML file found: {}", prefix, filepath.display()),
+        (false, true, false) => info!("{}Existing file found: {}", prefix, filepath.display()),
+        (false, false, true) => info!("{}New XML file detected: {}", prefix, filepath.display()),
+        (false, false, false) => info!("{}New file detected: {}", prefix, filepath.display()),
+    }
     
-    info!("New XML file detected: {}", filepath.display());
+    // SIZE_POLICY goes by the size alone, before anything is read
+    let tier = config.size_policy.as_ref().zip(size).map(|(policy, size)| policy.tier(size));
+    let size_action = tier.map(|tier| tier.action);
+    if let Some(tier) = tier.filter(|tier| tier.action == SizeAction::SkipAlert) {
+        error!(
+            "{}  Not delivering {}: its {} bytes are in SIZE_POLICY tier {}",
+            prefix, filepath.display(), size.unwrap_or_default(), tier
+        );
+        let detected_at = Utc::now() - chrono::Duration::from_std(detected_at.elapsed()).unwrap_or_default();
+        let outcome = Outcome::Skipped(SkipReason::SizePolicy);
+        finish_file_with(&state, &filepath, outcome, None, 0, detected_at, size_action, &[]).await;
+        return;
+    }
     
-    let content = if config.include_content {
-        match tokio::fs::read_to_string(&filepath).await {
-            Ok(c) => Some(c),
-            Err(e) => {
-                error!("Failed to read file content: {}", e);
-                None
-            }
-        }
-    } else {
-        None
+    let pre_hook_outcome = match &config.pre_delivery_command {
+        Some(command) => run_pre_delivery_hook(&state, command, &filepath).await,
+        None => None,
     };
-    
-    let payload = WebhookPayload {
-        event: "new_xml_file".to_string(),
-        filepath: filepath.display().to_string(),
-        filename,
-        content,
-        timestamp: Utc::now().to_rfc3339(),
+    let (outcome, status) = match pre_hook_outcome {
+        Some(outcome) => (outcome, None),
+        None => deliver_unless_duplicate(&state, &filepath, detected_at, size_action).await,
     };
     
-    info!("Sending webhook...");
-    
-    let client = Client::new();
-    let request_builder = match config.webhook_method.to_uppercase().as_str() {
-        "GET" => client.get(&config.webhook_url),
-        "PUT" => client.put(&config.webhook_url),
-        "PATCH" => client.patch(&config.webhook_url),
-        "DELETE" => client.delete(&config.webhook_url),
-        _ => client.post(&config.webhook_url),
-    };
-    
-    match request_builder
-        .header("Content-Type", "application/json")
-        .json(&payload)
-        .send()
-        .await
-    {
-        Ok(response) => {
-            let status = response.status();
-            if status.is_success() {
-                info!("  Webhook sent successfully (HTTP {})", status.as_u16());
-                
-                // Handle overwriting the file with response if enabled
-                let should_overwrite_with_response = |config: &Config| {
-                    config.overwrite_with_response && config.include_content
-                };
+    if outcome == Outcome::Accepted {
+        info!("{}  Waiting for the receiver to acknowledge {}", prefix, filepath.display());
+        return;
+    }
+    let detected_at = Utc::now() - chrono::Duration::from_std(detected_at.elapsed()).unwrap_or_default();
+    let attempted = matches!(outcome, Outcome::Delivered | Outcome::Rejected | Outcome::Failed(_));
+    finish_file_with(&state, &filepath, outcome, status, attempted as u32, detected_at, size_action, &[]).await;
+    if matches!(outcome, Outcome::Rejected | Outcome::Failed(_)) {
+        if let Some(dir) = &config.retry_later_dir {
+            move_to_retry_later(&state, dir, &filepath).await;
+        }
+    }
+}
 
-                if should_overwrite_with_response(&config) {
-                    let content_type = response.headers()
-                        .get("content-type")
-                        .and_then(|v| v.to_str().ok())
-                        .unwrap_or("");
-                    
-                    // Check if content type is appropriate (text/xml or application/xml)
-                    // Accept content types that start with these prefixes (may include charset parameter)
-                    let is_xml = content_type.starts_with("text/xml") 
-                        || content_type.starts_with("application/xml");
-                    
-                    if is_xml {
-                        match response.text().await {
-                            Ok(response_body) => {
-                                if !response_body.is_empty() {
-                                    // Add file to ignore list before writing
-                                    {
-                                        let mut ignore = ignore_list.lock().unwrap();
-                                        ignore.insert(filepath.clone());
-                                    }
-                                    
-                                    match tokio::fs::write(&filepath, &response_body).await {
-                                        Ok(_) => {
-                                            info!("  File overwritten with response content");
-                                            // Keep file in ignore list for a short time
-                                            let ignore_list_clone = Arc::clone(&ignore_list);
-                                            let filepath_clone = filepath.clone();
-                                            tokio::spawn(async move {
-                                                sleep(Duration::from_secs(IGNORE_DURATION_SECS)).await;
-                                                let mut ignore = ignore_list_clone.lock().unwrap();
-                                                ignore.remove(&filepath_clone);
-                                            });
-                                        }
-                                        Err(e) => {
-                                            error!("  Failed to overwrite file: {}", e);
-                                            // Remove from ignore list on failure
-                                            let mut ignore = ignore_list.lock().unwrap();
-                                            ignore.remove(&filepath);
-                                        }
-                                    }
-                                } else {
-                                    warn!("  Response body is empty, not overwriting file");
-                                }
-                            }
-                            Err(e) => {
-                                error!("  Failed to read response body: {}", e);
-                            }
-                        }
+// RETRY_LATER_DIR: move a file that failed delivery aside. The post-delivery
+// hook has already seen it in place.
+async fn move_to_retry_later(state: &AppState, dir: &Path, filepath: &Path) {
+    let prefix = state.config.log_prefix();
+    let target = mirrored_path(&state.config, dir, filepath);
+    match state.write_guard.rename(WriteCapability::RetryLater, filepath, &target).await {
+        Ok(_) => info!("{}  Moved {} to {} to be retried later", prefix, filepath.display(), target.display()),
+        Err(e) => error!("{}  Failed to move {} to the retry-later directory: {}", prefix, filepath.display(), e),
+    }
+}
+
+// RETRY_LATER_AFTER_SECS: move files that have waited long enough in
+// RETRY_LATER_DIR back to where they came from and deliver them again
+async fn retry_later_files(state: Arc<AppState>, dir: PathBuf, after: Duration) {
+    let prefix = state.config.log_prefix();
+    let every = (after / 2).clamp(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        sleep(every).await;
+        let scan_dir = dir.clone();
+        let Ok(due) = tokio::task::spawn_blocking(move || retry_later::due_files(&scan_dir, after)).await else {
+            continue;
+        };
+        for path in due {
+            let Ok(relative) = path.strip_prefix(&dir) else {
+                continue;
+            };
+            let target = state.config.watch_dir.join(relative);
+            if tokio::fs::symlink_metadata(&target).await.is_ok() {
+                warn!("{}{} exists again, leaving {} in the retry-later directory", prefix, target.display(), path.display());
+                continue;
+            }
+            // The move is delivered directly; backends that report it as a new
+            // file (WATCH_BACKEND=poll) must not deliver it a second time
+            state.ignore_list.insert(&target);
+            match state.write_guard.rename(WriteCapability::RetryLater, &path, &target).await {
+                Ok(_) => {
+                    info!("{}Retrying {}", prefix, target.display());
+                    if archive::is_bundle(&target, &state.config.bundle_extensions) {
+                        dispatch_bundle(&state, target.clone(), Instant::now(), true);
                     } else {
-                        warn!("  Response content-type '{}' is not XML, not overwriting file", content_type);
+                        dispatch_file(&state, target.clone(), Instant::now(), false, true, false);
                     }
                 }
-            } else {
-                let body = response.text().await.unwrap_or_default();
-                error!("  Webhook failed (HTTP {}): {}", status.as_u16(), body);
+                Err(e) => error!("{}Failed to move {} back for a retry: {}", prefix, path.display(), e),
             }
+            let settle = Duration::from_secs(state.config.watch_poll_interval_secs + IGNORE_DURATION_SECS);
+            state.ignore_list.release(&target, settle);
         }
-        Err(e) => {
-            error!("  Webhook request failed: {}", e);
+    }
+}
+
+// Count a file skipped before any delivery step and, with AUDIT_SKIPS, report
+// it like an outcome. Files skipped later are reported by `finish_file`.
+fn skip_file(state: &AppState, path: &Path, reason: SkipReason) {
+    state.skips.record(path, reason);
+    if !state.config.audit_skips {
+        return;
+    }
+    let now = Utc::now().to_rfc3339();
+    let record = OutcomeRecord {
+        event_id: uuid::Uuid::new_v4().to_string(),
+        profile: state.config.profile.clone(),
+        path: relative_display(&state.config, path),
+        outcome: Outcome::Skipped(reason).as_str(),
+        reason: Some(reason.as_str()),
+        failure_kind: None,
+        content_policy: None,
+        status: None,
+        attempts: 0,
+        duration_ms: 0,
+        detected_at: now.clone(),
+        completed_at: now,
+    };
+    report_outcome(state, record);
+}
+
+// Keep an outcome record in the database and send it to the outcome webhook
+// and syslog, whichever are configured
+fn report_outcome(state: &AppState, record: OutcomeRecord) {
+    if let Some(audit) = &state.audit {
+        if let Err(e) = audit.record(&record) {
+            warn!("{}Failed to keep outcome: {}", state.config.log_prefix(), e);
         }
     }
+    if let Some(syslog) = &state.syslog {
+        syslog.record(record.clone());
+    }
+    if let Some(outcomes) = &state.outcomes {
+        outcomes.notify(record);
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+// Report the final outcome of a file and run the post-delivery hook
+async fn finish_file(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    outcome: Outcome,
+    status: Option<u16>,
+    attempts: u32,
+    detected_at: chrono::DateTime<Utc>,
+) {
+    finish_file_with(state, filepath, outcome, status, attempts, detected_at, None, &[]).await;
+}
+
+// `finish_file` with the file's SIZE_POLICY action for the outcome record
+// and more variables for the post-delivery hook
+#[allow(clippy::too_many_arguments)]
+async fn finish_file_with(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    outcome: Outcome,
+    status: Option<u16>,
+    attempts: u32,
+    detected_at: chrono::DateTime<Utc>,
+    size_action: Option<SizeAction>,
+    extra_env: &[(&str, String)],
+) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let reason = outcome.skip_reason();
+    if let Some(reason) = reason {
+        state.skips.record(filepath, reason);
+    }
+    if state.outcomes.is_some() || state.syslog.is_some() || state.audit.is_some() {
+        let completed_at = Utc::now();
+        let duration = (completed_at - detected_at).to_std().unwrap_or_default();
+        let record = OutcomeRecord {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            profile: config.profile.clone(),
+            path: relative_display(config, filepath),
+            outcome: outcome.as_str(),
+            reason: reason.map(|reason| reason.as_str()),
+            failure_kind: outcome.failure_kind().map(|kind| kind.as_str()),
+            content_policy: size_action.map(|action| action.as_str()),
+            status,
+            attempts,
+            duration_ms: duration.as_millis() as u64,
+            detected_at: detected_at.to_rfc3339(),
+            completed_at: completed_at.to_rfc3339(),
+        };
+        report_outcome(state, record);
+    }
     
-    let config = match Config::from_env() {
-        Ok(c) => c,
+    if let Some(command) = &config.post_delivery_command {
+        let mut env = vec![
+            ("XMLW_OUTCOME", outcome.as_str().to_string()),
+            ("XMLW_HTTP_STATUS", status.map(|s| s.to_string()).unwrap_or_default()),
+            ("XMLW_FILEPATH", filepath.display().to_string()),
+            ("XMLW_FAILURE_KIND", outcome.failure_kind().map(|kind| kind.to_string()).unwrap_or_default()),
+        ];
+        env.extend(extra_env.iter().cloned());
+        if let Err(e) = state.hooks.run(command, filepath, &env, &prefix).await {
+            warn!("{}  Post-delivery hook failed: {}", prefix, e);
+        }
+    }
+}
+
+// Run PRE_DELIVERY_COMMAND for a file. Returns the outcome when the file must not
+// be delivered.
+async fn run_pre_delivery_hook(state: &Arc<AppState>, command: &str, filepath: &Path) -> Option<Outcome> {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    
+    // The hook may rewrite the file (e.g. format it and move the result over the
+    // original), which must not be picked up as a new file
+    state.ignore_list.insert(filepath);
+    let before = file_signature(filepath).await;
+    let result = state.hooks.run(command, filepath, &[], &prefix).await;
+    let after = file_signature(filepath).await;
+    state.ignore_list.release(filepath, Duration::from_secs(IGNORE_DURATION_SECS));
+    
+    match result {
+        Ok(_) => {
+            if before != after {
+                info!("{}  Pre-delivery hook modified the file", prefix);
+            }
+            None
+        }
+        Err(e) if config.pre_delivery_failure == PreHookFailure::Quarantine => {
+            let Some(dir) = &config.quarantine_dir else {
+                return Some(Outcome::Skipped(SkipReason::PreDeliveryHookFailed));
+            };
+            let target = mirrored_path(config, dir, filepath);
+            match state.write_guard.rename(WriteCapability::Quarantine, filepath, &target).await {
+                Ok(_) => {
+                    warn!("{}  Pre-delivery hook failed, file quarantined to {}: {}", prefix, target.display(), e);
+                    Some(Outcome::Quarantined)
+                }
+                Err(move_error) => {
+                    error!("{}  Pre-delivery hook failed ({}) and the file could not be quarantined: {}", prefix, e, move_error);
+                    Some(Outcome::Skipped(SkipReason::PreDeliveryHookFailed))
+                }
+            }
+        }
         Err(e) => {
-            eprintln!("ERROR: {}", e);
-            std::process::exit(1);
+            warn!("{}  Pre-delivery hook failed, skipping file: {}", prefix, e);
+            Some(Outcome::Skipped(SkipReason::PreDeliveryHookFailed))
         }
-    };
+    }
+}
+
+// Size and modification time, used to notice changes made by a hook
+async fn file_signature(path: &Path) -> Option<(u64, std::time::SystemTime)> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+// Apply HARDLINK_POLICY, then deliver the file
+async fn deliver_unless_duplicate(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    detected_at: Instant,
+    size_action: Option<SizeAction>,
+) -> (Outcome, Option<u16>) {
+    let config = &state.config;
+    if config.hardlink_policy == HardlinkPolicy::Deliver {
+        return deliver_file(state, filepath, detected_at, None, size_action).await;
+    }
     
-    if !config.watch_dir.exists() {
-        eprintln!("ERROR: Watch directory '{}' does not exist", config.watch_dir.display());
-        std::process::exit(1);
+    let prefix = config.log_prefix();
+    let relative_path = relative_display(config, filepath);
+    let metadata = tokio::fs::metadata(filepath).await.ok();
+    let duplicate_of = metadata
+        .as_ref()
+        .and_then(|metadata| state.delivered_inodes.delivered_as(metadata))
+        .filter(|original| *original != relative_path);
+    
+    if let Some(original) = &duplicate_of {
+        if config.hardlink_policy == HardlinkPolicy::Suppress {
+            info!("{}  Not delivering hard link to already delivered {}", prefix, original);
+            return (Outcome::Suppressed, None);
+        }
+        info!("{}  Hard link to already delivered {}", prefix, original);
     }
     
-    // Warn if overwrite is enabled without content inclusion
-    if config.overwrite_with_response && !config.include_content {
-        warn!("OVERWRITE_WITH_RESPONSE is enabled but INCLUDE_CONTENT is disabled. File overwrite will not work without including content in the webhook.");
+    let result = deliver_file(state, filepath, detected_at, duplicate_of, size_action).await;
+    if let (Outcome::Delivered | Outcome::Accepted, Some(metadata)) = (result.0, &metadata) {
+        state.delivered_inodes.record(metadata, relative_path);
+    }
+    result
+}
+
+// A URL template filled in for a file
+fn render_url(config: &Config, template: &UrlTemplate, filepath: &Path) -> String {
+    let relpath = relative_display(config, filepath).replace(std::path::MAIN_SEPARATOR, "/");
+    let name = filepath.file_name().and_then(|f| f.to_str()).unwrap_or("");
+    template.render(&relpath, name, config.profile.as_deref())
+}
+
+// `path` relative to the watch directory
+fn relative_display(config: &Config, path: &Path) -> String {
+    path.strip_prefix(&config.watch_dir)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+// Apply SKIP_REDUNDANT_DELIVERY, then deliver the file
+async fn deliver_file(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    detected_at: Instant,
+    duplicate_of: Option<String>,
+    size_action: Option<SizeAction>,
+) -> (Outcome, Option<u16>) {
+    if !state.config.skip_redundant_delivery {
+        return deliver_content(state, filepath, detected_at, duplicate_of, size_action).await;
     }
     
-    info!("Starting XML file watcher...");
-    info!("  Watch directory: {}", config.watch_dir.display());
-    info!("  Webhook URL: {}", config.webhook_url);
-    info!("  Webhook method: {}", config.webhook_method);
-    info!("  Include content: {}", config.include_content);
-    info!("  Overwrite with response: {}", config.overwrite_with_response);
+    let before = file_signature(filepath).await;
+    let hash = match tokio::fs::read(filepath).await {
+        Ok(data) => redundant::content_hash(&data),
+        // Reading it again for the delivery reports the error
+        Err(_) => return deliver_content(state, filepath, detected_at, duplicate_of, size_action).await,
+    };
+    if state.delivered_content.is_redundant(filepath, &hash) {
+        info!("{}  Content is unchanged since it was last delivered, not sending it again", state.config.log_prefix());
+        return (Outcome::Cached, None);
+    }
     
-    // Create an ignore list for files we've just modified
-    let ignore_list: Arc<Mutex<HashSet<PathBuf>>> = Arc::new(Mutex::new(HashSet::new()));
+    let result = deliver_content(state, filepath, detected_at, duplicate_of, size_action).await;
+    // A file overwritten with the response no longer holds what was sent
+    if result.0 == Outcome::Delivered && before.is_some() && file_signature(filepath).await == before {
+        state.delivered_content.record(filepath, hash);
+    } else {
+        state.delivered_content.forget(filepath);
+    }
+    result
+}
+
+async fn deliver_content(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    detected_at: Instant,
+    duplicate_of: Option<String>,
+    size_action: Option<SizeAction>,
+) -> (Outcome, Option<u16>) {
+    let out_of_order = state
+        .reorder
+        .as_ref()
+        .is_some_and(|reorder| reorder.take_out_of_order(filepath))
+        .then_some(true);
+    if let Some(element) = state.config.split_on_element.as_ref().filter(|_| is_xml_file(filepath)) {
+        return deliver_fragments(state, filepath, element, detected_at, duplicate_of, out_of_order).await;
+    }
+    let mut payload = build_file_payload(state, filepath, duplicate_of, true, size_action).await;
+    payload.out_of_order = out_of_order;
+    if let (Some(presigned), Some(progress)) = (&state.config.presigned, &state.presign_progress) {
+        return deliver_presigned(state, presigned, progress, filepath, payload, detected_at).await;
+    }
+    if let Some(batcher) = &state.batcher {
+        debug!("{}  Waiting for the next batch", state.config.log_prefix());
+        let (done, outcome) = tokio::sync::oneshot::channel();
+        batcher.add(BatchedFile { payload, detected_at, done });
+        return outcome.await.unwrap_or((Outcome::Failed(FailureKind::Internal), None));
+    }
+    let headers = file_content_headers(&state.config, filepath).await;
+    send_webhook(state, payload, headers, detected_at, Some(filepath)).await
+}
+
+// Deliver every `element` of a file as its own webhook, in order. The file is
+// streamed, so only one fragment is held in memory at a time. It counts as
+// delivered only when every fragment was; otherwise the outcome is that of the
+// last failed fragment.
+async fn deliver_fragments(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    element: &str,
+    detected_at: Instant,
+    duplicate_of: Option<String>,
+    out_of_order: Option<bool>,
+) -> (Outcome, Option<u16>) {
+    let prefix = state.config.log_prefix();
     
-    let (tx, rx) = channel();
+    let (sender, mut fragments) = tokio::sync::mpsc::channel(1);
+    let path = filepath.to_path_buf();
+    let name = element.to_string();
+    let splitter = tokio::task::spawn_blocking(move || {
+        split::split_file(&path, &name, |fragment| sender.blocking_send(fragment).is_ok())
+    });
     
-    let mut watcher = match notify::recommended_watcher(move |res: NotifyResult<Event>| {
-        if let Ok(event) = res {
-            tx.send(event).ok();
-        }
-    }) {
-        Ok(w) => w,
-        Err(e) => {
-            eprintln!("ERROR: Failed to create watcher: {}", e);
-            std::process::exit(1);
+    let mut result = (Outcome::Delivered, None);
+    let mut failed = Vec::new();
+    let mut index = 0;
+    while let Some(fragment) = fragments.recv().await {
+        let headers = content_headers(&state.config, &fragment);
+        let mut payload = extracted_payload(state, fragment, filepath, None, Some(index));
+        payload.duplicate_of = duplicate_of.clone();
+        payload.out_of_order = out_of_order;
+        let (outcome, status) = send_webhook(state, payload, headers, detected_at, None).await;
+        if outcome != Outcome::Delivered {
+            error!("{}  Fragment {} of {} was not delivered ({})", prefix, index, filepath.display(), outcome.as_str());
+            failed.push(index);
+            result = (outcome, status);
         }
+        index += 1;
+    }
+    
+    let split_error = match splitter.await {
+        Ok(result) => result.err(),
+        Err(e) => Some(e.to_string()),
     };
+    if let Some(e) = split_error {
+        error!("{}  Failed to split {} after {} <{}> fragments: {}", prefix, filepath.display(), index, element, e);
+        return (Outcome::Failed(FailureKind::Internal), result.1);
+    }
     
-    if let Err(e) = watcher.watch(&config.watch_dir, RecursiveMode::Recursive) {
-        eprintln!("ERROR: Failed to watch directory: {}", e);
-        std::process::exit(1);
+    if index == 0 {
+        warn!("{}  No <{}> elements in {}, nothing delivered", prefix, element, filepath.display());
+        (Outcome::Skipped(SkipReason::NoSplitElements), None)
+    } else if failed.is_empty() {
+        info!("{}  Delivered all {} <{}> fragments", prefix, index, element);
+        result
+    } else {
+        let failed: Vec<String> = failed.iter().map(|index| index.to_string()).collect();
+        error!("{}  {} of {} <{}> fragments not delivered: {}", prefix, failed.len(), index, element, failed.join(", "));
+        result
+    }
+}
+
+// The SIZE_POLICY action for a document of `size` bytes
+fn size_action(config: &Config, size: u64) -> Option<SizeAction> {
+    config.size_policy.as_ref().map(|policy| policy.tier(size).action)
+}
+
+// How the content of a document is sent, or `None` for not at all: as its
+// SIZE_POLICY action says, or as INCLUDE_CONTENT and CONTENT_MODE do, for the
+// events CONTENT_EVENTS names. `modification` is whether the delivery is for
+// a modified file rather than a new one.
+fn content_mode_for(config: &Config, size_action: Option<SizeAction>, modification: bool) -> Option<ContentMode> {
+    let events = config.content_events;
+    if !(if modification { events.modify } else { events.create }) {
+        return None;
+    }
+    match (&config.size_policy, size_action) {
+        (Some(_), Some(action)) => action.content_mode(),
+        (Some(_), None) => None,
+        (None, _) => config.include_content.then_some(config.content_mode),
+    }
+}
+
+// Whether the delivery of a file under way is for a modification, with
+// WATCH_EVENTS=modify, rather than for a new file
+fn is_modification(state: &AppState, filepath: &Path) -> bool {
+    state.modifications.as_ref().is_some_and(|modifications| modifications.is_modification(filepath))
+}
+
+// Whether a file being delivered was found by SCAN_ON_STARTUP rather than
+// by an event
+fn is_existing(state: &AppState, filepath: &Path) -> bool {
+    state.existing.as_ref().is_some_and(|existing| existing.contains(filepath))
+}
+
+// The `event` of a file's payload
+fn payload_event(state: &AppState, filepath: &Path, is_xml: bool) -> &'static str {
+    match (is_modification(state, filepath), is_existing(state, filepath), is_xml) {
+        (true, _, true) => "modified_xml_file",
+        (true, _, false) => "modified_file",
+        (false, true, true) => "existing_xml_file",
+        (false, true, false) => "existing_file",
+        (false, false, true) => "new_xml_file",
+        (false, false, false) => "new_file",
     }
+}
+
+// Build the payload for a file. Without `register_content` (previews) nothing
+// is registered with the content server and `content_url` is a placeholder.
+async fn build_file_payload(
+    state: &Arc<AppState>,
+    filepath: &Path,
+    duplicate_of: Option<String>,
+    register_content: bool,
+    size_action: Option<SizeAction>,
+) -> WebhookPayload {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let (payload_filepath, filename) = payload_names(config, filepath);
+    // Only XML files are read as documents; others come with WATCH_ALL_FILES
+    let is_xml = is_xml_file(filepath);
+    let detected_type = match config.watch_all_files {
+        true => match detect_file_type(filepath).await {
+            Ok(detected) => Some(detected),
+            Err(e) => {
+                warn!("{}Failed to read file for type detection: {}", prefix, e);
+                None
+            }
+        },
+        false => None,
+    };
     
-    loop {
-        match rx.recv() {
-            Ok(event) => {
-                // Only handle Create events to avoid duplicates (matches bash script behavior)
-                if matches!(event.kind, notify::EventKind::Create(_)) {
-                    for path in event.paths {
-                        if path.is_file() && is_xml_file(&path) {
-                            // Check if this file is in the ignore list
-                            let should_ignore = {
-                                let ignore = ignore_list.lock().unwrap();
-                                ignore.contains(&path)
-                            };
-                            
-                            if should_ignore {
-                                info!("Ignoring file event for recently modified file: {}", path.display());
-                                continue;
-                            }
-                            
-                            // Small delay to ensure file is fully written
-                            let config_clone = config.clone();
-                            let ignore_list_clone = Arc::clone(&ignore_list);
-                            tokio::spawn(async move {
-                                sleep(Duration::from_millis(500)).await;
-                                trigger_webhook(&config_clone, path, ignore_list_clone).await;
-                            });
-                        }
-                    }
+    let content_mode = content_mode_for(config, size_action, is_modification(state, filepath));
+    let inline_content = content_mode == Some(ContentMode::Inline);
+    let mut content_encoding = None;
+    let mut invalid_utf8 = None;
+    let (content, content_truncated, encoding) = match (inline_content, config.content_preview_bytes) {
+        (false, _) => (None, None, None),
+        (true, limit) if !is_xml => match read_other_content(filepath, limit).await {
+            Ok((c, truncated, base64)) => {
+                content_encoding = base64.then(|| "base64".to_string());
+                (Some(c), limit.map(|_| truncated), None)
+            }
+            Err(e) => {
+                error!("{}Failed to read file content: {}", prefix, e);
+                (None, None, None)
+            }
+        },
+        (true, None) => match read_file_content(filepath, config.content_invalid_utf8).await {
+            Ok((c, invalid, encoding)) => {
+                (content_encoding, invalid_utf8) = invalid_utf8_content(config, invalid);
+                match content_encoding {
+                    // Not text, so not a document to canonicalize
+                    Some(_) => (Some(c), None, encoding),
+                    None => (Some(canonical_content(config, c)), None, encoding),
                 }
             }
             Err(e) => {
-                error!("Watch error: {}", e);
+                error!("{}Failed to read file content: {}", prefix, e);
+                (None, None, None)
             }
-        }
+        },
+        (true, Some(limit)) => match read_file_preview(filepath, limit, config.content_invalid_utf8).await {
+            Ok((preview, invalid, truncated, encoding)) => {
+                (content_encoding, invalid_utf8) = invalid_utf8_content(config, invalid);
+                (Some(preview), Some(truncated), encoding)
+            }
+            Err(e) => {
+                error!("{}Failed to read file content: {}", prefix, e);
+                (None, None, None)
+            }
+        },
+    };
+    
+    let (content, sanitized) = match content {
+        Some(content) if is_xml && content_encoding.is_none() => {
+            let (content, sanitized) = sanitized_content(config, content);
+            (Some(content), sanitized)
+        }
+        content => (content, None),
+    };
+    
+    let content_type = if config.detect_content_type_from_doc && is_xml {
+        // Base64 content is no document to look into
+        let detected = match content.as_ref().filter(|_| content_encoding.is_none()) {
+            Some(c) => detect_document_content_type(c),
+            None => match read_file_head(filepath, DOC_SNIFF_BYTES).await {
+                Ok(head) => detect_document_content_type(&head),
+                Err(e) => {
+                    warn!("{}Failed to read file for content-type detection: {}", prefix, e);
+                    None
+                }
+            },
+        };
+        Some(detected.unwrap_or(DEFAULT_XML_CONTENT_TYPE).to_string())
+    } else {
+        None
+    };
+    
+    let by_reference = content_mode == Some(ContentMode::Reference);
+    let content_url = if by_reference && !register_content {
+        Some(content_url_for(config, "<token>"))
+    } else if by_reference {
+        let served_type = content_type
+            .as_deref()
+            .or(detected_type.as_deref())
+            .unwrap_or(DEFAULT_XML_CONTENT_TYPE);
+        let ttl = Duration::from_secs(config.content_url_ttl_secs);
+        let registered = if config.xml_c14n && is_xml {
+            // The canonical form only exists in memory
+            read_file_text(filepath)
+                .await
+                .map(|(c, _)| state.content_registry.register_bytes(canonical_content(config, c).into_bytes(), served_type, ttl))
+                .map_err(|e| e.to_string())
+        } else {
+            state.content_registry.register_file(filepath, &config.content_roots, served_type, ttl)
+        };
+        match registered {
+            Ok(token) => Some(content_url_for(config, &token)),
+            Err(e) => {
+                error!("{}Failed to register file content: {}", prefix, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    
+    // The file's real name, not the rewritten one, so that the reference
+    // resolves to the same object in shared storage
+    let content_ref = config.content_ref_template.as_ref().map(|template| render_url(config, template, filepath));
+    
+    WebhookPayload {
+        event: payload_event(state, filepath, is_xml).to_string(),
+        filepath: payload_filepath,
+        filename,
+        content,
+        content_truncated,
+        content_sanitized: sanitized.map(|_| true),
+        sanitized_characters: sanitized,
+        invalid_utf8_sequences: invalid_utf8,
+        content_encoding,
+        original_encoding: encoding.map(|utf16| utf16.name().to_string()),
+        content_url,
+        content_type,
+        detected_type,
+        archive_source: None,
+        bundle_name: None,
+        bundle_entry: None,
+        content_policy: size_action.map(|action| action.as_str().to_string()),
+        content_ref,
+        fragment_index: None,
+        duplicate_of,
+        out_of_order: None,
+        profile: config.profile.clone(),
+        timestamp: Utc::now().to_rfc3339(),
+        detection_to_send_ms: None,
+        sequence: None,
+    }
+}
+
+// XML_C14N: the canonical form of a document. Documents that can't be
+// canonicalized are sent as they are.
+fn canonical_content(config: &Config, text: String) -> String {
+    if !config.xml_c14n {
+        return text;
+    }
+    match c14n::canonicalize(&text) {
+        Ok(canonical) => canonical,
+        Err(e) => {
+            warn!("{}  Sending content as is, canonicalization failed: {}", config.log_prefix(), e);
+            text
+        }
+    }
+}
+
+// SANITIZE_CONTENT: the content of an XML document with the characters XML
+// 1.0 doesn't allow left in with a warning, removed or replaced. Returns how
+// many were removed or replaced, if any.
+fn sanitized_content(config: &Config, content: String) -> (String, Option<usize>) {
+    let prefix = config.log_prefix();
+    let (content, findings) = sanitize::sanitize(content, config.sanitize_content);
+    let Some((character, offset)) = findings.first else {
+        return (content, None);
+    };
+    match config.sanitize_content {
+        Sanitize::Reject => {
+            warn!(
+                "{}  Content has {} characters not allowed in XML 1.0, the first U+{:04X} at byte {}; the receiver may reject it (SANITIZE_CONTENT=reject)",
+                prefix, findings.count, character as u32, offset
+            );
+            (content, None)
+        }
+        Sanitize::Strip | Sanitize::Escape => {
+            let done = if config.sanitize_content == Sanitize::Strip { "Removed" } else { "Escaped" };
+            info!(
+                "{}  {} {} characters not allowed in XML 1.0 in the content, the first U+{:04X} at byte {}",
+                prefix, done, findings.count, character as u32, offset
+            );
+            (content, Some(findings.count))
+        }
+    }
+}
+
+// CONTENT_INVALID_UTF8: log what was done with the sequences of a content
+// that weren't valid UTF-8. Returns the payload's `content_encoding`, base64
+// when the content was encoded instead, and `invalid_utf8_sequences`.
+fn invalid_utf8_content(config: &Config, invalid: Option<InvalidSequences>) -> (Option<String>, Option<usize>) {
+    let Some(invalid) = invalid else {
+        return (None, None);
+    };
+    let done = match config.content_invalid_utf8 {
+        InvalidUtf8::Replace => "replaced them with U+FFFD",
+        InvalidUtf8::Escape => "escaped their bytes as character references",
+        InvalidUtf8::Base64 => "sending the content base64-encoded",
+    };
+    warn!(
+        "{}  Content has {} sequences that aren't valid UTF-8, the first at byte {}; {}",
+        config.log_prefix(), invalid.count, invalid.first, done
+    );
+    let encoding = (config.content_invalid_utf8 == InvalidUtf8::Base64).then(|| "base64".to_string());
+    (encoding, Some(invalid.count))
+}
+
+// The body of the request for a payload: its JSON, signed as a compact JWS
+// with SIGN_MODE=jws
+fn request_body<T: Serialize>(config: &Config, payload: &T) -> Result<Vec<u8>, String> {
+    let json = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    Ok(match &config.jws_signer {
+        Some(signer) => signer.sign(&json).into_bytes(),
+        None => json,
+    })
+}
+
+// Headers sent with a serialized payload, followed by those taken from the
+// document's content
+fn request_headers(config: &Config, body: &[u8], content_headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    let content_type = match (config.webhook_headers.content_type(), &config.jws_signer) {
+        (Some(content_type), _) => content_type,
+        (None, Some(_)) => jws::JWS_CONTENT_TYPE,
+        (None, None) => "application/json",
+    };
+    let mut headers = vec![("Content-Type".to_string(), content_type.to_string())];
+    if let Some(algorithm) = config.digest_algorithm {
+        headers.push(("Digest".to_string(), algorithm.header_value(body)));
+    }
+    headers.extend(config.webhook_headers.others());
+    headers.extend(content_headers);
+    headers
+}
+
+// The headers of a request as `xml-watcher preview` shows them, with the
+// values of WEBHOOK_HEADERS masked like the webhook URL
+fn preview_headers(config: &Config, headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    headers
+        .into_iter()
+        .map(|(name, value)| match config.webhook_headers.contains(&name) {
+            true => (name, sensitive::mask_secret(&value)),
+            false => (name, value),
+        })
+        .collect()
+}
+
+// CONTENT_HEADERS values for an XML document, if any are configured
+fn content_headers(config: &Config, data: &[u8]) -> Vec<(String, String)> {
+    let Some(rules) = &config.content_headers else {
+        return Vec::new();
+    };
+    let (data, _) = encoding::to_utf8(data);
+    let (headers, problems) = rules.evaluate(&data);
+    for problem in problems {
+        warn!("{}  Content header: {}", config.log_prefix(), problem);
+    }
+    headers
+}
+
+// CONTENT_HEADERS values for a file. The file is only read when rules are
+// configured and it is an XML file.
+async fn file_content_headers(config: &Config, filepath: &Path) -> Vec<(String, String)> {
+    if config.content_headers.is_none() || !is_xml_file(filepath) {
+        return Vec::new();
+    }
+    match tokio::fs::read(filepath).await {
+        Ok(data) => content_headers(config, &data),
+        Err(e) => {
+            error!("{}Failed to read file for content headers: {}", config.log_prefix(), e);
+            Vec::new()
+        }
+    }
+}
+
+// Render what would be sent for `path` by the first previewable profile whose
+// watch directory contains it. Nothing is delivered and no hooks are run.
+async fn preview_file(states: &[Arc<AppState>], path: PathBuf) -> Result<serde_json::Value, PreviewError> {
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| PreviewError::Invalid(format!("{}: {}", path.display(), e)))?;
+    
+    for state in states {
+        let config = &state.config;
+        let Some(filepath) = watched_path(config, &resolved) else {
+            continue;
+        };
+        if !resolved.is_file() {
+            return Err(PreviewError::Invalid(format!("{} is not a file", path.display())));
+        }
+        if !config.watch_all_files && !is_xml_file(&resolved) {
+            return Err(PreviewError::Invalid(format!("{} is not an XML file", path.display())));
+        }
+        
+        let size = tokio::fs::metadata(&filepath)
+            .await
+            .map_err(|e| PreviewError::Invalid(format!("{}: {}", path.display(), e)))?
+            .len();
+        let (requests, _) = preview_requests(state, &filepath, size).await.map_err(PreviewError::Invalid)?;
+        let Some(request) = requests.into_iter().next() else {
+            return Err(PreviewError::Invalid(format!("{} would not be sent", path.display())));
+        };
+        let headers: serde_json::Map<String, serde_json::Value> = request.headers
+            .into_iter()
+            .map(|(name, value)| (name, value.into()))
+            .collect();
+        return Ok(serde_json::json!({
+            "profile": config.profile,
+            "method": request.method,
+            "url": request.url,
+            "headers": headers,
+            "payload": request.payload,
+        }));
+    }
+    Err(PreviewError::Forbidden(format!(
+        "{} is not inside a watch directory with PREVIEW_ENDPOINT enabled",
+        path.display()
+    )))
+}
+
+// A resolved path as the watcher sees it below `config`'s watch directory, if
+// it is inside
+fn watched_path(config: &Config, resolved: &Path) -> Option<PathBuf> {
+    let root = config.watch_dir.canonicalize().ok()?;
+    let relative = resolved.strip_prefix(&root).ok()?;
+    Some(config.watch_dir.join(relative))
+}
+
+// The requests delivering a settled file of `size` bytes would send, built the
+// way deliveries build them, with notes on how they are sent. Nothing is
+// registered for `content_url`.
+async fn preview_requests(state: &Arc<AppState>, filepath: &Path, size: u64) -> Result<(Vec<preview::Request>, Vec<String>), String> {
+    let config = &state.config;
+    let mut notes = Vec::new();
+    let payload = match config.split_on_element.as_ref().filter(|_| is_xml_file(filepath)) {
+        Some(element) => {
+            let path = filepath.to_path_buf();
+            let name = element.clone();
+            let (count, first) = tokio::task::spawn_blocking(move || {
+                let mut first = None;
+                let count = split::split_file(&path, &name, |fragment| {
+                    first.get_or_insert(fragment);
+                    true
+                });
+                count.map(|count| (count, first))
+            })
+            .await
+            .map_err(|e| e.to_string())??;
+            let Some(first) = first else {
+                return Ok((Vec::new(), notes));
+            };
+            notes.push(format!("split on <{}>: {} fragments, each sent like the first, shown here", element, count));
+            extracted_payload(state, first, filepath, None, Some(0))
+        }
+        None => build_file_payload(state, filepath, None, false, size_action(config, size)).await,
+    };
+    if let Some(policy) = &config.batch {
+        notes.push(format!(
+            "sent in a `batch` request with up to {} files, whose body and headers cover all of them",
+            policy.size
+        ));
+    }
+    if config.ordering == DeliveryOrder::Mtime {
+        notes.push(format!("held up to {} ms to go out in mtime order", config.ordering_window_ms));
+    }
+    
+    let body = request_body(config, &payload)?;
+    let describe_body = |body: &[u8]| match config.jws_signer {
+        Some(_) => format!("{} bytes, the payload signed as a compact JWS", body.len()),
+        None => format!("{} bytes of JSON", body.len()),
+    };
+    let payload = serde_json::to_value(&payload).map_err(|e| e.to_string())?;
+    let Some(presigned) = &config.presigned else {
+        let content_headers = file_content_headers(config, filepath).await;
+        let request = preview::Request {
+            phase: "webhook".to_string(),
+            method: config.webhook_method.to_uppercase(),
+            url: config.webhook_url.to_string(),
+            headers: preview_headers(config, request_headers(config, &body, content_headers)),
+            body: describe_body(&body),
+            payload: Some(payload),
+        };
+        return Ok((vec![request], notes));
+    };
+    let headers = preview_headers(config, request_headers(config, &body, Vec::new()));
+    let mut requests = vec![
+        preview::Request {
+            phase: "presign".to_string(),
+            method: "POST".to_string(),
+            url: SensitiveString::url(render_url(config, &presigned.presign_url, filepath)).to_string(),
+            headers: headers.clone(),
+            body: describe_body(&body),
+            payload: Some(payload.clone()),
+        },
+        preview::Request {
+            phase: "upload".to_string(),
+            method: "PUT".to_string(),
+            url: "(from the presign response)".to_string(),
+            headers: Vec::new(),
+            body: format!("{} bytes, the file as it is", size),
+            payload: None,
+        },
+    ];
+    if let Some(confirm_url) = &presigned.confirm_url {
+        requests.push(preview::Request {
+            phase: "confirm".to_string(),
+            method: "POST".to_string(),
+            url: SensitiveString::url(render_url(config, confirm_url, filepath)).to_string(),
+            headers,
+            body: describe_body(&body),
+            payload: Some(payload),
+        });
+    }
+    Ok((requests, notes))
+}
+
+// `xml-watcher preview [--json] <path>`: load the configuration like the
+// daemon does and report what would happen to the file, without sending
+// anything. Exits 0 when it would be delivered, 2 when skipped and 1 on errors.
+async fn run_preview(args: &[String]) -> i32 {
+    let json = args.iter().any(|arg| arg == "--json");
+    let paths: Vec<&String> = args.iter().filter(|arg| arg.as_str() != "--json").collect();
+    let [path] = paths.as_slice() else {
+        eprintln!("Usage: xml-watcher preview [--json] <path>");
+        return 1;
+    };
+    if let Some(option) = path.strip_prefix("--") {
+        eprintln!("Unknown option --{}. Usage: xml-watcher preview [--json] <path>", option);
+        return 1;
+    }
+    let path = PathBuf::from(path);
+    // A file that is gone is still checked, by the path it would have
+    let resolved = path.canonicalize().unwrap_or_else(|_| match path.is_absolute() {
+        true => path.clone(),
+        false => std::env::current_dir().unwrap_or_default().join(&path),
+    });
+    
+    let (states, _) = load_states();
+    // The profile that delivers the file: the most specific one containing it
+    let candidates: Vec<(&Arc<AppState>, PathBuf)> = states
+        .iter()
+        .filter_map(|state| Some((state, watched_path(&state.config, &resolved)?)))
+        .collect();
+    let chosen = candidates
+        .iter()
+        .find(|(state, filepath)| !state.handled_elsewhere.as_ref().is_some_and(|handled| handled.contains(filepath)))
+        .or(candidates.first());
+    let Some((state, filepath)) = chosen else {
+        eprintln!("ERROR: {} is not inside the watch directory of any profile", path.display());
+        return 1;
+    };
+    
+    let report = match preview_report(state, filepath).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return 1;
+        }
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+    } else {
+        print!("{}", report.to_text());
+    }
+    match report.skip_reason {
+        Some(_) => 2,
+        None => 0,
+    }
+}
+
+// `xml-watcher db query <name>`: run a canned query against the
+// STORAGE_DB_PATH database of the profiles, each database once. Exits 1 on
+// errors.
+fn run_db(args: &[String]) -> i32 {
+    let usage = format!("Usage: xml-watcher db query <{}>", storage::QUERIES.join("|"));
+    let [command, name] = args else {
+        eprintln!("{}", usage);
+        return 1;
+    };
+    if command != "query" {
+        eprintln!("Unknown command '{}'. {}", command, usage);
+        return 1;
+    }
+    if !storage::QUERIES.contains(&name.as_str()) {
+        eprintln!("Unknown query '{}'. {}", name, usage);
+        return 1;
+    }
+    let configs = match Config::load_all() {
+        Ok(configs) => configs,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return 1;
+        }
+    };
+    let mut paths: Vec<PathBuf> = configs.into_iter().filter_map(|config| config.storage_db_path).collect();
+    paths.sort();
+    paths.dedup();
+    if paths.is_empty() {
+        eprintln!("ERROR: STORAGE_DB_PATH isn't set for any profile");
+        return 1;
+    }
+    for (index, path) in paths.iter().enumerate() {
+        match storage::run_query(path, name) {
+            Ok(table) => {
+                if paths.len() > 1 {
+                    if index > 0 {
+                        println!();
+                    }
+                    println!("{}:", path.display());
+                }
+                print!("{}", table);
+            }
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+// What `xml-watcher preview` reports for a file of a profile, going through
+// the checks of delivery in their order
+async fn preview_report(state: &Arc<AppState>, filepath: &Path) -> Result<preview::Report, String> {
+    let config = &state.config;
+    let watched = WatchedFiles::of(state);
+    let mut checks = path_checks(state, &watched, filepath);
+    let size = tokio::fs::metadata(filepath).await.ok().map(|metadata| metadata.len());
+    let bundle = archive::is_bundle(filepath, &config.bundle_extensions);
+    let mut size_tier = None;
+    if first_failure(checks.clone()).is_none() && !bundle {
+        checks.extend(content_checks(state, filepath, size).await);
+        let tier = config.size_policy.as_ref().zip(size).map(|(policy, size)| policy.tier(size));
+        size_tier = tier.map(|tier| tier.to_string());
+        let skipped = tier.is_some_and(|tier| tier.action == SizeAction::SkipAlert);
+        checks.push(("size policy", skipped.then_some(SkipReason::SizePolicy)));
+    }
+    
+    let mut report = preview::Report {
+        path: filepath.display().to_string(),
+        profile: config.profile.clone(),
+        checks: Vec::new(),
+        skip_reason: first_failure(checks.clone()),
+        size_bytes: size,
+        size_tier,
+        requests: Vec::new(),
+        notes: Vec::new(),
+        before_delivery: Vec::new(),
+        on_success: Vec::new(),
+        on_failure: Vec::new(),
+    };
+    if report.skip_reason.is_none() {
+        if bundle {
+            report.notes.push("a bundle: each entry is delivered as its own request".to_string());
+        } else {
+            let (requests, notes) = preview_requests(state, filepath, size.unwrap_or_default()).await?;
+            if requests.is_empty() {
+                checks.push(("split elements", Some(SkipReason::NoSplitElements)));
+                report.skip_reason = Some(SkipReason::NoSplitElements);
+            }
+            report.requests = requests;
+            report.notes = notes;
+        }
+        report.describe_actions(config, overwrites_with_response(state, filepath));
+    }
+    report.checks = checks.into_iter().map(|(name, failed)| preview::Check::new(name, failed)).collect();
+    Ok(report)
+}
+
+// Build the payload for an XML document that isn't a file of its own: an
+// entry of the bundle at `bundle` or a SPLIT_ON_ELEMENT fragment. `name` is
+// used for the filepath and filename fields.
+fn extracted_payload(
+    state: &AppState,
+    data: Vec<u8>,
+    name: &Path,
+    bundle: Option<&Path>,
+    fragment_index: Option<usize>,
+) -> WebhookPayload {
+    let config = &state.config;
+    // Parts of a file are never skipped, only sent without content
+    let size_action = size_action(config, data.len() as u64).map(|action| match action {
+        SizeAction::SkipAlert => SizeAction::Metadata,
+        action => action,
+    });
+    let (data, encoding) = match Utf16::detect(&data) {
+        Some(utf16) => (utf16.decode(&data).into_bytes(), Some(utf16)),
+        None => (data, None),
+    };
+    let data = match config.xml_c14n {
+        true => match String::from_utf8(data) {
+            Ok(text) => canonical_content(config, text).into_bytes(),
+            Err(e) => e.into_bytes(),
+        },
+        false => data,
+    };
+    let (payload_filepath, filename) = payload_names(config, name);
+    let document_type = || {
+        detect_document_content_type(&String::from_utf8_lossy(&data))
+            .unwrap_or(DEFAULT_XML_CONTENT_TYPE)
+            .to_string()
+    };
+    let content_type = config.detect_content_type_from_doc.then(document_type);
+    let detected_type = config.watch_all_files.then(document_type);
+    
+    let mut sanitized = None;
+    let mut truncated = false;
+    let (mut content_encoding, mut invalid_utf8) = (None, None);
+    let (content, content_url) = match content_mode_for(config, size_action, is_modification(state, bundle.unwrap_or(name))) {
+        None => (None, None),
+        Some(ContentMode::Inline) => {
+            let (content, invalid) = match config.content_preview_bytes {
+                Some(limit) => {
+                    let (content, invalid, cut) = preview_of(&data, limit, config.content_invalid_utf8);
+                    truncated = cut;
+                    (content, invalid)
+                }
+                None => encoding::decode_utf8(data, config.content_invalid_utf8),
+            };
+            (content_encoding, invalid_utf8) = invalid_utf8_content(config, invalid);
+            match content_encoding {
+                Some(_) => (Some(content), None),
+                None => {
+                    let (content, count) = sanitized_content(config, content);
+                    sanitized = count;
+                    (Some(content), None)
+                }
+            }
+        }
+        Some(ContentMode::Reference) => {
+            let served_type = content_type.as_deref().unwrap_or(DEFAULT_XML_CONTENT_TYPE);
+            let ttl = Duration::from_secs(config.content_url_ttl_secs);
+            let token = state.content_registry.register_bytes(data, served_type, ttl);
+            (None, Some(content_url_for(config, &token)))
+        }
+    };
+    let content_truncated = (content.is_some() && config.content_preview_bytes.is_some()).then_some(truncated);
+    WebhookPayload {
+        // Fragments and entries are XML, and count as new or modified like
+        // the file they came from
+        event: payload_event(state, bundle.unwrap_or(name), true).to_string(),
+        filepath: payload_filepath,
+        filename,
+        content_truncated,
+        content,
+        content_sanitized: sanitized.map(|_| true),
+        sanitized_characters: sanitized,
+        invalid_utf8_sequences: invalid_utf8,
+        content_encoding,
+        original_encoding: encoding.map(|utf16| utf16.name().to_string()),
+        content_url,
+        content_type,
+        detected_type,
+        archive_source: bundle.map(|bundle| bundle.display().to_string()),
+        bundle_name: bundle.and_then(|bundle| bundle.file_name()).map(|name| name.to_string_lossy().into_owned()),
+        bundle_entry: bundle.map(|_| name.display().to_string()),
+        content_policy: size_action.map(|action| action.as_str().to_string()),
+        content_ref: None,
+        fragment_index,
+        duplicate_of: None,
+        out_of_order: None,
+        profile: config.profile.clone(),
+        timestamp: Utc::now().to_rfc3339(),
+        detection_to_send_ms: None,
+        sequence: None,
+    }
+}
+
+// Deliver each XML document contained in a bundle as its own webhook. The
+// bundle is then finished like a file: delivered once every entry is,
+// otherwise rejected or failed, and a retry of the same bundle only sends the
+// entries that didn't get through.
+async fn trigger_bundle_webhooks(state: Arc<AppState>, bundle_path: PathBuf, detected_at: Instant) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    info!("{}New bundle detected: {}", prefix, bundle_path.display());
+    let started_at = Utc::now() - chrono::Duration::from_std(detected_at.elapsed()).unwrap_or_default();
+    
+    let limits = config.archive_limits;
+    let path_clone = bundle_path.clone();
+    let bundle = match tokio::task::spawn_blocking(move || archive::extract_xml_entries(&path_clone, limits)).await {
+        Ok(Ok(bundle)) => bundle,
+        Ok(Err(e)) => {
+            error!("{}  Failed to open bundle {}: {}", prefix, bundle_path.display(), e);
+            finish_bundle(&state, &bundle_path, Outcome::Failed(FailureKind::Internal), None, 0, started_at, &[]).await;
+            return;
+        }
+        Err(e) => {
+            error!("{}  Bundle extraction task failed: {}", prefix, e);
+            return;
+        }
+    };
+    
+    let mut delivered = state.bundle_progress.delivered(&bundle_path, bundle.digest);
+    let total = bundle.entries.len();
+    match delivered.len() {
+        0 => info!("{}  Found {} XML entries in bundle", prefix, total),
+        done => info!("{}  Found {} XML entries in bundle, {} delivered before", prefix, total, done),
+    }
+    
+    let mut failed = Vec::new();
+    let mut outcome = Outcome::Delivered;
+    let mut status = None;
+    let mut attempts = 0;
+    for entry in bundle.entries {
+        if delivered.contains(&entry.name) {
+            continue;
+        }
+        info!("{}Bundle entry: {}", prefix, entry.name);
+        if config.utf16_xml == Utf16Handling::Skip && Utf16::detect(&entry.data).is_some() {
+            info!("{}  Not delivering UTF-16 entry (UTF16_XML=skip)", prefix);
+            continue;
+        }
+        let headers = content_headers(config, &entry.data);
+        let payload = extracted_payload(&state, entry.data, Path::new(&entry.name), Some(&bundle_path), None);
+        
+        let _permit = state.limiter.acquire().await;
+        let (entry_outcome, entry_status) = send_webhook(&state, payload, headers, detected_at, None).await;
+        attempts += 1;
+        match entry_outcome {
+            Outcome::Delivered | Outcome::Accepted => {
+                delivered.insert(entry.name);
+            }
+            // A request that couldn't be completed outweighs a rejection
+            entry_outcome => {
+                if !matches!(outcome, Outcome::Failed(_)) {
+                    outcome = entry_outcome;
+                    status = entry_status;
+                }
+                failed.push(match entry_status {
+                    Some(code) => format!("{} ({})", entry.name, code),
+                    None => entry.name,
+                });
+            }
+        }
+    }
+    
+    if failed.is_empty() {
+        state.bundle_progress.forget(&bundle_path);
+    } else {
+        error!(
+            "{}  {} of {} bundle entries were not delivered: {}",
+            prefix, failed.len(), total, failed.join(", ")
+        );
+        state.bundle_progress.record(&bundle_path, bundle.digest, delivered);
+    }
+    finish_bundle(&state, &bundle_path, outcome, status, attempts, started_at, &failed).await;
+}
+
+// Finish a bundle like a file, with the entries that weren't delivered in
+// XMLW_FAILED_ENTRIES, one per line, for the post-delivery hook
+async fn finish_bundle(
+    state: &Arc<AppState>,
+    bundle_path: &Path,
+    outcome: Outcome,
+    status: Option<u16>,
+    attempts: u32,
+    detected_at: chrono::DateTime<Utc>,
+    failed: &[String],
+) {
+    let env = [("XMLW_FAILED_ENTRIES", failed.join("\n"))];
+    finish_file_with(state, bundle_path, outcome, status, attempts, detected_at, None, &env).await;
+    if matches!(outcome, Outcome::Rejected | Outcome::Failed(_)) {
+        if let Some(dir) = &state.config.retry_later_dir {
+            move_to_retry_later(state, dir, bundle_path).await;
+        }
+    }
+}
+
+// `filepath` moved under `dir`, keeping its path relative to the watch directory
+fn mirrored_path(config: &Config, dir: &Path, filepath: &Path) -> PathBuf {
+    let relative = filepath
+        .strip_prefix(&config.watch_dir)
+        .ok()
+        .map(Path::to_path_buf)
+        .or_else(|| filepath.file_name().map(PathBuf::from))
+        .unwrap_or_default();
+    dir.join(relative)
+}
+
+// Where the original of `filepath` is kept before it is overwritten: mirrored
+// under BACKUP_DIR when set, otherwise a sibling file with a `.bak` suffix.
+fn backup_path_for(config: &Config, filepath: &Path) -> PathBuf {
+    match &config.backup_dir {
+        Some(dir) => mirrored_path(config, dir, filepath),
+        None => {
+            let mut name = filepath.as_os_str().to_os_string();
+            name.push(".bak");
+            PathBuf::from(name)
+        }
+    }
+}
+
+// Number of directories between the watch root and `path`; files directly in
+// the root are at depth 0
+fn watch_depth(config: &Config, path: &Path) -> usize {
+    path.strip_prefix(&config.watch_dir)
+        .map(|relative| relative.components().count().saturating_sub(1))
+        .unwrap_or(0)
+}
+
+// Files the watcher moved or copied aside are never delivered again
+fn is_in_internal_dir(config: &Config, path: &Path) -> bool {
+    let catchup_defer_dir = config.catchup.as_ref().and_then(|policy| policy.defer_dir.as_ref());
+    [config.backup_dir.as_ref(), config.quarantine_dir.as_ref(), config.retry_later_dir.as_ref(), catchup_defer_dir]
+        .into_iter()
+        .flatten()
+        .any(|dir| path.starts_with(dir))
+}
+
+// The checks a path from an event goes through before it is queued, in order,
+// each with the reason it fails for, if it does
+fn path_checks(state: &AppState, watched: &WatchedFiles, path: &Path) -> Vec<(&'static str, Option<SkipReason>)> {
+    let config = &state.config;
+    let elsewhere = state.handled_elsewhere.as_ref().is_some_and(|handled| handled.contains(path));
+    let too_deep = config.max_watch_depth.is_some_and(|max_depth| watch_depth(config, path) > max_depth);
+    vec![
+        ("internal directory", is_in_internal_dir(config, path).then_some(SkipReason::InternalDirectory)),
+        ("profile", elsewhere.then_some(SkipReason::OtherProfile)),
+        ("watch depth", too_deep.then_some(SkipReason::MaxWatchDepth)),
+        ("watched name", (!watched.contains(path)).then(|| watched.skip_reason())),
+        ("regular file", (!path.is_file()).then_some(SkipReason::NotAFile)),
+    ]
+}
+
+// The checks of a settled file before anything is read for its delivery, like
+// `path_checks`
+async fn content_checks(state: &AppState, filepath: &Path, size: Option<u64>) -> Vec<(&'static str, Option<SkipReason>)> {
+    let config = &state.config;
+    let too_small = matches!((config.min_content_bytes, size), (Some(min), Some(size)) if size < min);
+    let skips_utf16 = config.utf16_xml == Utf16Handling::Skip && is_xml_file(filepath);
+    let utf16 = skips_utf16 && is_utf16_file(filepath).await;
+    vec![
+        ("minimum size", too_small.then_some(SkipReason::BelowMinContentBytes)),
+        ("encoding", utf16.then_some(SkipReason::Utf16Content)),
+    ]
+}
+
+fn first_failure(checks: Vec<(&'static str, Option<SkipReason>)>) -> Option<SkipReason> {
+    checks.into_iter().find_map(|(_, failed)| failed)
+}
+
+fn handle_event(state: &Arc<AppState>, event: FileEvent) {
+    let config = &state.config;
+    let detected_at = event.at;
+    
+    // New files are on their way: the batch isn't idle yet
+    if let Some(batcher) = &state.batcher {
+        batcher.touch();
+    }
+    
+    // A file renamed into place was complete before the rename
+    let settled = event.kind == FileEventKind::Renamed && config.skip_delay_on_rename;
+    
+    // Only handle Create events and renames into place to avoid duplicates
+    // (matches bash script behavior)
+    let watched = WatchedFiles::of(state);
+    for path in delivery_paths(&event, &watched).iter().cloned() {
+        if let Some(reason) = first_failure(path_checks(state, &watched, &path)) {
+            skip_file(state, &path, reason);
+            continue;
+        }
+        if state.existing.as_ref().is_some_and(|existing| existing.queued_by_scan(&path, detected_at)) {
+            debug!("{}Already queued by the startup scan: {}", config.log_prefix(), path.display());
+            continue;
+        }
+        queue_new_file(state, path, detected_at, settled);
+    }
+    
+    for path in modified_paths(&event, &watched) {
+        dispatch_modified(state, path.clone(), detected_at);
+    }
+}
+
+// Queue a new file that passed `path_checks` for delivery: spilled when the
+// queue is full, held for ORDERING=mtime, and otherwise dispatched
+fn queue_new_file(state: &Arc<AppState>, path: PathBuf, detected_at: Instant, settled: bool) {
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    if archive::is_bundle(&path, &config.bundle_extensions) {
+        dispatch_bundle(state, path, detected_at, settled);
+        return;
+    }
+    
+    // Check if this file is in the ignore list
+    if state.ignore_list.contains(&path) {
+        info!("{}Ignoring file event for recently modified file: {}", prefix, path.display());
+        skip_file(state, &path, SkipReason::WrittenByWatcher);
+        return;
+    }
+    
+    if let Some(spill) = &state.spill {
+        // Once anything is spilled, newer files queue up behind it
+        let backlog = spill.len();
+        if backlog > 0 || state.queued.load(Ordering::Relaxed) >= config.max_queued_files {
+            match spill.push(&path) {
+                Ok(_) if backlog == 0 => {
+                    notable!(
+                        Level::Warn,
+                        history::Kind::QueueSpilled,
+                        "{}Delivery queue is full ({} files); spilling new files to {}",
+                        prefix, config.max_queued_files, spill.location()
+                    );
+                    return;
+                }
+                Ok(_) => return,
+                Err(e) => warn!("{}Failed to spill file, keeping it in memory: {}", prefix, e),
+            }
+        }
+    }
+    
+    if state.reorder.is_none() {
+        dispatch_file(state, path, detected_at, false, settled, false);
+        return;
+    }
+    // Sorted once complete, when the modification time is final
+    let state_clone = Arc::clone(state);
+    tokio::spawn(async move {
+        if !settled {
+            wait_until_written(&state_clone, &path, detected_at).await;
+        }
+        if let Some(reorder) = &state_clone.reorder {
+            reorder.hold(path, detected_at);
+        }
+    });
+}
+
+// SCAN_ON_STARTUP: queue the files that were already in the watch directory
+// when its watch started, which arrived while the watcher wasn't running.
+// Files changed since have events of their own, and bundles aren't scanned.
+// Files not to be delivered, such as those of other profiles, are passed over
+// without being reported as skips.
+fn scan_existing_files(state: &Arc<AppState>, watch_started: SystemTime) {
+    let Some(existing) = &state.existing else {
+        return;
+    };
+    let config = &state.config;
+    let prefix = config.log_prefix();
+    let watched = WatchedFiles::of(state);
+    let oldest = config.scan_max_age.and_then(|age| watch_started.checked_sub(age));
+    // The files of a directory are one level deeper than the directory, and
+    // with AUTO_WATCH_PATTERN only matching top-level directories are watched
+    let descend = |dir: &Path| {
+        let unwatched = config.auto_watch_pattern.as_ref().is_some_and(|pattern| {
+            dir.parent() == Some(config.watch_dir.as_path()) && !watch::name_matches(pattern, dir)
+        });
+        !unwatched
+            && !is_in_internal_dir(config, dir)
+            && config.max_watch_depth.is_none_or(|max_depth| watch_depth(config, dir) < max_depth)
+    };
+    let (mut queued, mut too_old, mut changed) = (0, 0, 0);
+    let mut planned = Vec::new();
+    for path in startup_scan::files_under(&config.watch_dir, descend, &prefix) {
+        if archive::is_bundle(&path, &config.bundle_extensions) || first_failure(path_checks(state, &watched, &path)).is_some() {
+            continue;
+        }
+        let Ok(modified) = std::fs::metadata(&path).and_then(|metadata| metadata.modified()) else {
+            continue;
+        };
+        if modified >= watch_started {
+            changed += 1;
+            continue;
+        }
+        if oldest.is_some_and(|oldest| modified < oldest) {
+            too_old += 1;
+            continue;
+        }
+        if let Some(batcher) = &state.batcher {
+            batcher.touch();
+        }
+        existing.insert(&path);
+        if holds_back_scan(config) {
+            planned.push((path, modified));
+        } else {
+            queue_new_file(state, path, Instant::now(), true);
+        }
+        queued += 1;
+    }
+    existing.scan_finished(Instant::now());
+    info!(
+        "{}Startup scan: {} existing files queued, {} older than SCAN_MAX_AGE_SECS, {} changed since the watch started",
+        prefix, queued, too_old, changed
+    );
+    if holds_back_scan(config) {
+        tokio::spawn(deliver_scanned(Arc::clone(state), planned, watch_started));
+    }
+}
+
+// Whether the files of the startup scan are delivered by `deliver_scanned`
+// rather than queued like those of events
+fn holds_back_scan(config: &Config) -> bool {
+    config.scan_dir_concurrency.is_some() || config.catchup.is_some()
+}
+
+// Deliver the files of the startup scan, with their modification times, in
+// turn: those the catch-up caps let through, oldest first, or a top-level
+// directory at a time with SCAN_DIR_CONCURRENCY. Only one file, or one per
+// directory, waits for a concurrency permit at a time, so live events are sent
+// ahead of the rest of the backlog.
+async fn deliver_scanned(state: Arc<AppState>, mut files: Vec<(PathBuf, SystemTime)>, watch_started: SystemTime) {
+    let config = &state.config;
+    if let Some(policy) = &config.catchup {
+        files = catch_up(&state, policy, files, watch_started).await;
+    }
+    match config.scan_dir_concurrency {
+        Some(concurrency) => {
+            let units = startup_scan::plan_by_directory(&config.watch_dir, files);
+            deliver_by_directory(&state, units, concurrency).await;
+        }
+        None => {
+            for (path, _) in files {
+                if state.ignore_list.contains(&path) {
+                    skip_file(&state, &path, SkipReason::WrittenByWatcher);
+                } else {
+                    dispatch_file(&state, path, Instant::now(), false, true, false).started.await.ok();
+                }
+                if let Some(progress) = &state.catchup {
+                    progress.advance();
+                }
+            }
+        }
+    }
+}
+
+// CATCHUP_MAX_FILES and CATCHUP_MAX_AGE_SECS: leave out the files the caps
+// don't let through, moved to CATCHUP_DEFER_DIR when it is set, and list them
+// in the report. Returns the files to deliver.
+async fn catch_up(
+    state: &AppState,
+    policy: &CatchupPolicy,
+    files: Vec<(PathBuf, SystemTime)>,
+    watch_started: SystemTime,
+) -> Vec<(PathBuf, SystemTime)> {
+    let prefix = state.config.log_prefix();
+    let plan = catchup::plan(policy, files, watch_started);
+    let mut moved = Vec::new();
+    for (path, _, _) in &plan.left_out {
+        if let Some(existing) = &state.existing {
+            existing.remove(path);
+        }
+        let Some(dir) = &policy.defer_dir else {
+            continue;
+        };
+        let target = mirrored_path(&state.config, dir, path);
+        match state.write_guard.rename(WriteCapability::CatchupDefer, path, &target).await {
+            Ok(_) => moved.push(Some(target)),
+            Err(e) => {
+                error!("{}Failed to move {} to the catch-up directory: {}", prefix, path.display(), e);
+                moved.push(None);
+            }
+        }
+    }
+    if let Err(e) = catchup::write_report(policy, &plan, &moved) {
+        error!("{}Failed to write the catch-up report: {}", prefix, e);
+    }
+    if let Some(progress) = &state.catchup {
+        progress.planned(&plan);
+    }
+    let moved_count = moved.iter().flatten().count();
+    info!(
+        "{}Catch-up: delivering {} files, leaving out {} ({} moved aside), listed in {}",
+        prefix, plan.deliver.len(), plan.left_out.len(), moved_count, policy.report.display()
+    );
+    plan.deliver
+}
+
+// SCAN_DIR_CONCURRENCY: deliver the files of the startup scan a top-level
+// directory at a time, up to `concurrency` directories at once, and the files
+// of each one after the other, so that a receiver sees every directory in
+// order. Each delivery still waits for a concurrency permit, and for
+// MAX_FILES_PER_SEC, like those of events. Progress is logged per directory,
+// and a summary at the end.
+async fn deliver_by_directory(state: &Arc<AppState>, units: Vec<startup_scan::ScanUnit>, concurrency: usize) {
+    let prefix = state.config.log_prefix();
+    let started = Instant::now();
+    let directories = Arc::new(tokio::sync::Semaphore::new(concurrency));
+    let mut tasks = Vec::new();
+    for unit in units {
+        // Taken here, so that directories start in name order
+        let Ok(permit) = Arc::clone(&directories).acquire_owned().await else {
+            return;
+        };
+        let state = Arc::clone(state);
+        let prefix = prefix.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = permit;
+            let unit_started = Instant::now();
+            let total = unit.files.len();
+            // About every tenth of the directory
+            let every = (total / 10).max(1);
+            for (index, path) in unit.files.into_iter().enumerate() {
+                if state.ignore_list.contains(&path) {
+                    skip_file(&state, &path, SkipReason::WrittenByWatcher);
+                } else {
+                    dispatch_file(&state, path, Instant::now(), false, true, false).finished.await.ok();
+                }
+                if let Some(progress) = &state.catchup {
+                    progress.advance();
+                }
+                let done = index + 1;
+                if done % every == 0 || done == total {
+                    info!("{}Startup scan: {}: {}/{}", prefix, unit.name, done, total);
+                }
+            }
+            (unit.name, total, unit_started.elapsed())
+        }));
+    }
+    let mut summary = Vec::new();
+    for task in tasks {
+        if let Ok(row) = task.await {
+            summary.push(row);
+        }
+    }
+    let width = summary.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    info!(
+        "{}Startup scan: {} directories delivered in {:.1}s",
+        prefix, summary.len(), started.elapsed().as_secs_f64()
+    );
+    for (name, files, took) in summary {
+        info!("{}  {:<width$}  {:>6} files  {:>8.1}s", prefix, name, files, took.as_secs_f64(), width = width);
+    }
+}
+
+// WATCH_EVENTS=modify: deliver a modified file again once it has gone
+// unmodified for MODIFY_DEBOUNCE_MS, which takes the place of the settle
+// delay. Modifications a delivery already covers, or that the watcher made
+// itself, aren't delivered.
+fn dispatch_modified(state: &Arc<AppState>, path: PathBuf, detected_at: Instant) {
+    let Some(modifications) = &state.modifications else {
+        return;
+    };
+    if state.ignore_list.contains(&path) {
+        debug!("{}Ignoring modification of recently written file: {}", state.config.log_prefix(), path.display());
+        return;
+    }
+    if !modifications.modified(&path, detected_at) {
+        return;
+    }
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let Some(modifications) = &state.modifications else {
+            return;
+        };
+        let Some(modified_at) = modifications.quiet(&path).await else {
+            debug!("{}Modification of {} already delivered", state.config.log_prefix(), path.display());
+            return;
+        };
+        let watched = WatchedFiles::of(&state);
+        if let Some(reason) = first_failure(path_checks(&state, &watched, &path)) {
+            skip_file(&state, &path, reason);
+            return;
+        }
+        if state.ignore_list.contains(&path) {
+            return;
+        }
+        dispatch_file(&state, path, modified_at, false, true, true);
+    });
+}
+
+// Queue a bundle for delivery, the way `dispatch_file` does files. Its
+// entries take a concurrency permit each.
+fn dispatch_bundle(state: &Arc<AppState>, path: PathBuf, detected_at: Instant, settled: bool) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        if let Some(intake) = &state.intake {
+            intake.acquire(&state.config.log_prefix()).await;
+        }
+        if !settled {
+            wait_until_written(&state, &path, detected_at).await;
+        }
+        let (_lock, _) = lock_path(&state, &path).await;
+        trigger_bundle_webhooks(Arc::clone(&state), path, detected_at).await;
+    });
+}
+
+// What a caller of `dispatch_file` can wait for
+struct Dispatched {
+    // The delivery holds its concurrency permit
+    started: tokio::sync::oneshot::Receiver<()>,
+    // The delivery finished, whatever its outcome
+    finished: tokio::sync::oneshot::Receiver<()>,
+}
+
+// Queue a file for delivery. Unless the file is known to be `settled`, its
+// delivery waits briefly for the writer to finish. Files handed out by the
+// spill queue have already settled and are reported back to it when done.
+// A `modification` is delivered as a modified file.
+fn dispatch_file(
+    state: &Arc<AppState>,
+    path: PathBuf,
+    detected_at: Instant,
+    from_spill: bool,
+    settled: bool,
+    modification: bool,
+) -> Dispatched {
+    state.queued.fetch_add(1, Ordering::Relaxed);
+    if let Some(modifications) = &state.modifications {
+        modifications.begin(&path, modification);
+    }
+    let state_clone = Arc::clone(state);
+    let (started, started_rx) = tokio::sync::oneshot::channel();
+    let (finished, finished_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        if let Some(intake) = &state_clone.intake {
+            intake.acquire(&state_clone.config.log_prefix()).await;
+        }
+        if !from_spill && !settled {
+            wait_until_written(&state_clone, &path, detected_at).await;
+        }
+        {
+            // Taken before the permit, so that a file waiting for itself
+            // doesn't keep another from being sent
+            let (_lock, waited) = lock_path(&state_clone, &path).await;
+            // What the task before did with the file may leave nothing to do
+            if waited && state_clone.ignore_list.contains(&path) {
+                skip_file(&state_clone, &path, SkipReason::WrittenByWatcher);
+            } else if waited && !path.is_file() {
+                skip_file(&state_clone, &path, SkipReason::NotAFile);
+            } else {
+                // Batched files share the permit of their batch's request
+                let _permit = match &state_clone.batcher {
+                    Some(_) => None,
+                    None => Some(state_clone.limiter.acquire().await),
+                };
+                started.send(()).ok();
+                trigger_webhook(Arc::clone(&state_clone), path.clone(), detected_at).await;
+            }
+        }
+        if let Some(modifications) = &state_clone.modifications {
+            modifications.finished(&path);
+        }
+        if let Some(existing) = &state_clone.existing {
+            existing.remove(&path);
+        }
+        if let Some(limit) = state_clone.limiter.current_limit() {
+            debug!("Effective concurrency limit: {}", limit);
+        }
+        
+        state_clone.queued.fetch_sub(1, Ordering::Relaxed);
+        if from_spill {
+            if let Some(Err(e)) = state_clone.spill.as_ref().map(|spill| spill.complete()) {
+                error!("{}{}", state_clone.config.log_prefix(), e);
+            }
+        }
+        state_clone.queue_space.notify_one();
+        finished.send(()).ok();
+    });
+    Dispatched { started: started_rx, finished: finished_rx }
+}
+
+// LOCK_PER_PATH: wait until no other task is processing `path`. Returns the
+// lock, if there is one, and whether another task held it.
+async fn lock_path<'a>(state: &'a AppState, path: &Path) -> (Option<path_lock::PathGuard<'a>>, bool) {
+    let Some(locks) = &state.path_locks else {
+        return (None, false);
+    };
+    let (guard, waited) = locks.lock(path).await;
+    if waited {
+        info!("{}Waited for the previous processing of {} to finish", state.config.log_prefix(), path.display());
+    }
+    (Some(guard), waited)
+}
+
+// The settle delay that gives a new file's writer time to finish, followed by
+// the NFS_SAFE_MODE check
+async fn wait_until_written(state: &AppState, path: &Path, detected_at: Instant) {
+    let config = &state.config;
+    match &state.settle {
+        Some(learner) => wait_until_settled(config, learner, path, detected_at).await,
+        None => sleep(settle::FIXED_DELAY).await,
+    }
+    if let Some(check) = &config.nfs_safe_mode {
+        wait_until_stable(config, check, path).await;
+    }
+}
+
+// SETTLE_MODE=adaptive: wait at least the delay learned for the file's
+// directory, and after that until its size stayed the same from one poll to
+// the next, or until SETTLE_MAX_MS. The directory's delay is learned from
+// when the final size was first seen, counting from the event.
+async fn wait_until_settled(config: &Config, learner: &SettleLearner, path: &Path, detected_at: Instant) {
+    let size = || std::fs::metadata(path).map(|metadata| metadata.len()).ok();
+    let dir = path.parent().unwrap_or(path);
+    let delay = learner.delay(dir);
+    let AdaptivePolicy { max, poll, .. } = *learner.policy();
+    let mut last_size = size();
+    let mut settled_after = detected_at.elapsed();
+    let mut changed = true;
+    while changed || detected_at.elapsed() < delay {
+        if detected_at.elapsed() >= max {
+            warn!(
+                "{}  {} still changing after SETTLE_MAX_MS ({} ms), delivering anyway",
+                config.log_prefix(), path.display(), max.as_millis()
+            );
+            break;
+        }
+        sleep(poll).await;
+        let current = size();
+        changed = current != last_size;
+        if changed {
+            last_size = current;
+            settled_after = detected_at.elapsed();
+        }
+    }
+    debug!(
+        "{}{} settled {} ms after its event (delay {} ms)",
+        config.log_prefix(), path.display(), settled_after.as_millis(), delay.as_millis()
+    );
+    learner.observe(dir, settled_after);
+}
+
+// NFS_SAFE_MODE: read the file until it stops changing. Files that can't be
+// read are left to fail in delivery; files still changing are delivered anyway.
+async fn wait_until_stable(config: &Config, check: &StabilityCheck, path: &Path) {
+    let prefix = config.log_prefix();
+    match check.wait(path).await {
+        Ok(reads) => debug!("{}{} unchanged after {} reads", prefix, path.display(), reads),
+        Err(e) => warn!("{}  Stability check of {} gave up, delivering anyway: {}", prefix, path.display(), e),
+    }
+}
+
+// ORDERING=mtime: queue the files the reordering window lets through. Each
+// waits until the one before it holds a concurrency permit, so deliveries
+// start in order.
+async fn dispatch_in_mtime_order(state: Arc<AppState>) {
+    let Some(reorder) = &state.reorder else {
+        return;
+    };
+    loop {
+        for (path, detected_at) in reorder.next().await {
+            dispatch_file(&state, path, detected_at, false, true, false).started.await.ok();
+        }
+    }
+}
+
+// Move spilled files back into memory as deliveries finish
+async fn drain_spill_queue(state: Arc<AppState>) {
+    let Some(spill) = &state.spill else {
+        return;
+    };
+    let prefix = state.config.log_prefix();
+    loop {
+        let free = state.config.max_queued_files.saturating_sub(state.queued.load(Ordering::Relaxed));
+        if free == 0 || spill.len() == 0 {
+            state.queue_space.notified().await;
+            continue;
+        }
+        let paths = match spill.pop(free) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("{}Failed to read spill queue: {}", prefix, e);
+                state.queue_space.notified().await;
+                continue;
+            }
+        };
+        for path in paths {
+            if path.is_file() {
+                dispatch_file(&state, path, Instant::now(), true, true, false);
+            } else {
+                debug!("{}Spilled file no longer exists: {}", prefix, path.display());
+                if let Err(e) = spill.complete() {
+                    error!("{}{}", prefix, e);
+                }
+            }
+        }
+        if spill.len() == 0 {
+            notable!(Level::Info, history::Kind::QueueDrained, "{}Spill queue drained", prefix);
+        }
+    }
+}
+
+// Periodically stat the watch root. Some network file system clients only keep
+// delivering change notifications for directories that are accessed now and then.
+async fn keep_watch_root_alive(state: Arc<AppState>, every: Duration) {
+    let root = &state.config.watch_dir;
+    let prefix = state.config.log_prefix();
+    let mut interval = tokio::time::interval(every);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match tokio::fs::metadata(root).await {
+            Ok(_) => debug!("{}Watch keepalive: stat {}", prefix, root.display()),
+            Err(e) => warn!("{}Watch keepalive: failed to stat {}: {}", prefix, root.display(), e),
+        }
+    }
+}
+
+// Periodically log how the shadow webhook compares to the primary one
+async fn report_shadow_comparison(state: Arc<AppState>) {
+    let Some(shadow) = &state.shadow else {
+        return;
+    };
+    let prefix = state.config.log_prefix();
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.shadow_report_interval_secs));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Some(report) = shadow.report() {
+            info!("{}Shadow comparison: {}", prefix, report);
+        }
+    }
+}
+
+// Finish files whose acknowledgement didn't arrive within ASYNC_ACK_TIMEOUT_SECS
+async fn expire_pending_acks(state: Arc<AppState>) {
+    let Some(pending) = &state.pending_acks else {
+        return;
+    };
+    let prefix = state.config.log_prefix();
+    let mut interval = tokio::time::interval(pending.timeout().min(Duration::from_secs(60)));
+    loop {
+        interval.tick().await;
+        for entry in pending.take_expired() {
+            error!("{}No acknowledgement for {} within {}s, giving up", prefix, entry.path.display(), pending.timeout().as_secs());
+            finish_file(&state, &entry.path, Outcome::Failed(FailureKind::AckTimeout), Some(entry.status), 1, entry.detected_at).await;
+        }
+    }
+}
+
+// POST /ack and GET /pending for the profiles with ASYNC_ACK_TOKEN on one
+// content server
+struct Acknowledgements {
+    states: Vec<Arc<AppState>>,
+}
+
+impl AckHandler for Acknowledgements {
+    fn ack(&self, token: String) -> std::pin::Pin<Box<dyn std::future::Future<Output = Option<String>> + Send>> {
+        let found = self.states.iter().find_map(|state| {
+            let entry = state.pending_acks.as_ref()?.take(&token)?;
+            Some((Arc::clone(state), entry))
+        });
+        Box::pin(async move {
+            let (state, entry) = found?;
+            info!("{}Receiver acknowledged {}", state.config.log_prefix(), entry.path.display());
+            finish_file(&state, &entry.path, Outcome::Delivered, Some(entry.status), 1, entry.detected_at).await;
+            Some(relative_display(&state.config, &entry.path))
+        })
+    }
+    
+    fn pending(&self) -> serde_json::Value {
+        let entries: Vec<serde_json::Value> = self
+            .states
+            .iter()
+            .filter_map(|state| Some((state, state.pending_acks.as_ref()?)))
+            .flat_map(|(state, pending)| {
+                pending.entries().into_iter().map(move |entry| {
+                    serde_json::json!({
+                        "profile": state.config.profile,
+                        "path": relative_display(&state.config, &entry.path),
+                        "status": entry.status,
+                        "accepted_at": entry.accepted_at.to_rfc3339(),
+                    })
+                })
+            })
+            .collect();
+        entries.into()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "preview") {
+        std::process::exit(run_preview(&args[1..]).await);
+    }
+    if args.first().is_some_and(|arg| arg == "db") {
+        std::process::exit(run_db(&args[1..]));
+    }
+    
+    let (states, content_registry) = load_states();
+    start_http_servers(&states, &content_registry);
+    
+    for state in &states {
+        if state.spill.is_some() {
+            tokio::spawn(drain_spill_queue(Arc::clone(state)));
+        }
+        if state.shadow.is_some() {
+            tokio::spawn(report_shadow_comparison(Arc::clone(state)));
+        }
+        if state.pending_acks.is_some() {
+            tokio::spawn(expire_pending_acks(Arc::clone(state)));
+        }
+        if let Some(secs) = state.config.watch_keepalive_secs {
+            tokio::spawn(keep_watch_root_alive(Arc::clone(state), Duration::from_secs(secs)));
+        }
+        if state.reorder.is_some() {
+            tokio::spawn(dispatch_in_mtime_order(Arc::clone(state)));
+        }
+        if state.batcher.is_some() {
+            tokio::spawn(send_batches(Arc::clone(state)));
+        }
+        if let (Some(dir), Some(secs)) = (&state.config.retry_later_dir, state.config.retry_later_after_secs) {
+            tokio::spawn(retry_later_files(Arc::clone(state), dir.clone(), Duration::from_secs(secs)));
+        }
+    }
+    
+    let (tx, rx) = channel();
+    
+    // Count events discarded by the callback pre-filter
+    let filtered_events = Arc::new(AtomicU64::new(0));
+    
+    // Files changed from here on have events, the others are for the startup scan
+    let watch_started = SystemTime::now();
+    
+    // Each profile gets its own sources; events are tagged with the profile index
+    let mut watchers = Vec::new();
+    for (index, state) in states.iter().enumerate() {
+        let tx = tx.clone();
+        let filtered_events_clone = Arc::clone(&filtered_events);
+        let watched = WatchedFiles::of(state);
+        let handler_state = Arc::clone(state);
+        let prefix = state.config.log_prefix();
+        
+        let sink: EventSink = Arc::new(move |event: FileEvent| {
+            if is_relevant_event(&event, &watched) {
+                tx.send((index, event)).ok();
+            } else {
+                filtered_events_clone.fetch_add(1, Ordering::Relaxed);
+                for (path, reason) in filtered_skips(&event, &watched) {
+                    skip_file(&handler_state, path, reason);
+                }
+            }
+        });
+        
+        for source in event_sources(state) {
+            match source.start(Arc::clone(&sink)) {
+                Ok(running) => watchers.push(running),
+                Err(e) => {
+                    eprintln!("ERROR: {}{}", prefix, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    drop(tx);
+    
+    for state in &states {
+        scan_existing_files(state, watch_started);
+    }
+    
+    run_event_loop(&rx, &states, &filtered_events);
+    
+    // Only reached once every sender is gone, which means the watchers died
+    drop(watchers);
+    error!("FATAL: Filesystem watcher channel disconnected; no further events can be received");
+    std::process::exit(EXIT_WATCHER_DISCONNECTED);
+}
+
+// Load every profile's configuration and build its state, exiting with the
+// error when any is invalid. The daemon and `xml-watcher preview` share this.
+fn load_states() -> (Vec<Arc<AppState>>, Arc<ContentRegistry>) {
+    let mut configs = match Config::load_all() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    // Before anything notable can happen, such as the watches being registered
+    if let Some(size) = configs.iter().filter(|c| c.events_endpoint).map(|c| c.events_history_size).max() {
+        history::enable(size);
+    }
+    
+    let unsafe_log_secrets = configs.iter().any(|c| c.unsafe_log_secrets);
+    sensitive::set_unsafe_log_secrets(unsafe_log_secrets);
+    if unsafe_log_secrets {
+        warn!("**************************************************************");
+        warn!("UNSAFE_LOG_SECRETS is enabled: webhook URLs and secrets will be");
+        warn!("written to the log in full. Never use this outside local debugging.");
+        warn!("**************************************************************");
+    }
+    
+    info!("Starting XML file watcher...");
+    
+    for config in &mut configs {
+        use_real_paths(config);
+    }
+    
+    if let Err(e) = check_debug_payload_dirs(&configs) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+    
+    let roots = WatchRoots::resolve(configs.iter().map(|c| (c.watch_dir.as_path(), c.profile.as_deref())));
+    let overlaps = roots.overlaps();
+    if !overlaps.is_empty() {
+        if !configs.iter().any(|c| c.allow_overlapping_roots) {
+            eprintln!(
+                "ERROR: Watch directories overlap, so their files would be delivered more than once: {}. \
+                 Set ALLOW_OVERLAPPING_ROOTS=true to have each file delivered by the most specific profile only",
+                overlaps.join("; ")
+            );
+            std::process::exit(1);
+        }
+        for overlap in &overlaps {
+            info!("Overlapping watch directories: {}", overlap);
+        }
+    }
+    
+    // One registry serves every profile; tokens are unique across profiles
+    let content_registry = Arc::new(ContentRegistry::default());
+    let throttles = Arc::new(Throttles::default());
+    let mut states = Vec::new();
+    for (index, config) in configs.into_iter().enumerate() {
+        let profile = config.profile.clone();
+        let handled_elsewhere = roots.handled_elsewhere(index, &config.watch_dir);
+        match build_state(config, &content_registry, &throttles) {
+            Ok(mut state) => {
+                if let Some(handled) = &handled_elsewhere {
+                    let dirs: Vec<String> = handled.roots().iter().map(|dir| dir.display().to_string()).collect();
+                    info!("{}  Left to other profiles: {}", state.config.log_prefix(), dirs.join(", "));
+                }
+                state.handled_elsewhere = handled_elsewhere;
+                states.push(Arc::new(state));
+            }
+            Err(e) => {
+                match profile {
+                    Some(name) => eprintln!("ERROR: Profile '{}': {}", name, e),
+                    None => eprintln!("ERROR: {}", e),
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+    (states, content_registry)
+}
+
+// Replace the watch directory, and the directories compared with the paths
+// of its events, with the locations they resolve to. Events then carry the
+// same paths whether WATCH_DIR is a symlink or not, and payloads, relative
+// paths and the ignore list all agree on them.
+fn use_real_paths(config: &mut Config) {
+    let prefix = config.log_prefix();
+    let dirs = [
+        Some(&mut config.watch_dir),
+        config.backup_dir.as_mut(),
+        config.quarantine_dir.as_mut(),
+        config.retry_later_dir.as_mut(),
+        config.catchup.as_mut().and_then(|policy| policy.defer_dir.as_mut()),
+        config.debug_payload_dir.as_mut(),
+    ];
+    for dir in dirs.into_iter().flatten().chain(config.content_roots.iter_mut()) {
+        match write_guard::resolve(dir) {
+            Ok(resolved) if resolved != *dir => {
+                info!("{}{} resolves to {}, using that path", prefix, dir.display(), resolved.display());
+                *dir = resolved;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("{}Using {} as configured: {}", prefix, dir.display(), e),
+        }
+    }
+}
+
+// DEBUG_PAYLOAD_DIR must not be watched by any profile, or the payloads
+// written there would be delivered in turn
+fn check_debug_payload_dirs(configs: &[Config]) -> Result<(), String> {
+    for config in configs {
+        let Some(dir) = &config.debug_payload_dir else {
+            continue;
+        };
+        let resolved = write_guard::resolve(dir).map_err(|e| format!("DEBUG_PAYLOAD_DIR can't be used: {}", e))?;
+        if let Some(watcher) = configs.iter().find(|other| resolved.starts_with(&other.watch_dir)) {
+            return Err(format!(
+                "DEBUG_PAYLOAD_DIR {} is inside the watch directory {}",
+                dir.display(),
+                watcher.watch_dir.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// DEBUG_PAYLOAD_DIR: write a payload as `<source>.json`, where the source is
+// the file's path relative to the watch directory, followed by the fragment
+// index for SPLIT_ON_ELEMENT, or for a bundle entry the bundle's path and the
+// entry's. A later payload from the same source replaces the file.
+async fn write_debug_payload(config: &Config, dir: &Path, payload: &WebhookPayload) {
+    let prefix = config.log_prefix();
+    let mut source = match (&payload.archive_source, &payload.bundle_entry) {
+        (Some(bundle), Some(entry)) => format!("{}/{}", relative_display(config, Path::new(bundle)), entry),
+        _ => relative_display(config, Path::new(&payload.filepath)),
+    };
+    if let Some(index) = payload.fragment_index {
+        source = format!("{}.{}", source, index);
+    }
+    // Entry names come from the bundle: `..` and absolute names stay inside
+    let relative: PathBuf = Path::new(&format!("{}.json", source))
+        .components()
+        .filter(|component| matches!(component, std::path::Component::Normal(_)))
+        .collect();
+    let target = dir.join(relative);
+    
+    let json = match serde_json::to_vec_pretty(payload) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("{}  Failed to serialize payload for DEBUG_PAYLOAD_DIR: {}", prefix, e);
+            return;
+        }
+    };
+    if let Some(parent) = target.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            warn!("{}  Failed to create {}: {}", prefix, parent.display(), e);
+            return;
+        }
+    }
+    match tokio::fs::write(&target, json).await {
+        Ok(_) => debug!("{}  Payload written to {}", prefix, target.display()),
+        Err(e) => warn!("{}  Failed to write payload to {}: {}", prefix, target.display(), e),
+    }
+}
+
+// Start one HTTP server per address used for content by reference or previews
+fn start_http_servers(states: &[Arc<AppState>], content_registry: &Arc<ContentRegistry>) {
+    // The profiles each server handles previews and acknowledgements for
+    #[derive(Default)]
+    struct Served {
+        previewable: Vec<Arc<AppState>>,
+        acknowledging: Vec<Arc<AppState>>,
+        listing_skips: Vec<Arc<AppState>>,
+        listing_settle: Vec<Arc<AppState>>,
+        listing_catchup: Vec<Arc<AppState>>,
+        // The history is the process's, so any profile asking for it will do
+        listing_events: bool,
+        // Throttles are kept per host across profiles, so the same goes for them
+        listing_throttles: Option<Arc<Throttles>>,
+        injecting: Vec<Arc<AppState>>,
+    }
+    let mut servers: BTreeMap<SocketAddr, Served> = BTreeMap::new();
+    for state in states {
+        let config = &state.config;
+        let serves_content = config.serves_content();
+        let acknowledges = state.pending_acks.is_some();
+        let endpoints = [
+            config.preview_endpoint,
+            acknowledges,
+            config.skips_endpoint,
+            config.settle_endpoint,
+            config.catchup_endpoint,
+            config.events_endpoint,
+            config.throttles_endpoint,
+            config.debug_inject_events,
+        ];
+        if serves_content || endpoints.contains(&true) {
+            let served = servers.entry(config.content_serve_addr).or_default();
+            if config.preview_endpoint {
+                served.previewable.push(Arc::clone(state));
+            }
+            if acknowledges {
+                served.acknowledging.push(Arc::clone(state));
+            }
+            if config.skips_endpoint {
+                served.listing_skips.push(Arc::clone(state));
+            }
+            if config.settle_endpoint {
+                served.listing_settle.push(Arc::clone(state));
+            }
+            if config.catchup_endpoint {
+                served.listing_catchup.push(Arc::clone(state));
+            }
+            served.listing_events |= config.events_endpoint;
+            if config.throttles_endpoint {
+                served.listing_throttles = Some(Arc::clone(&state.throttles));
+            }
+            if config.debug_inject_events {
+                served.injecting.push(Arc::clone(state));
+            }
+        }
+    }
+    
+    for (addr, served) in servers {
+        let Served {
+            previewable,
+            acknowledging,
+            listing_skips,
+            listing_settle,
+            listing_catchup,
+            listing_events,
+            listing_throttles,
+            injecting,
+        } = served;
+        let preview = (!previewable.is_empty()).then(|| preview_handler(previewable));
+        let acks = (!acknowledging.is_empty())
+            .then(|| Arc::new(Acknowledgements { states: acknowledging }) as Arc<dyn AckHandler>);
+        let skips = (!listing_skips.is_empty()).then(|| skips_handler(listing_skips));
+        let settle = (!listing_settle.is_empty()).then(|| settle_handler(listing_settle));
+        let catchup = (!listing_catchup.is_empty()).then(|| catchup_handler(listing_catchup));
+        let events = listing_events.then(|| Arc::new(|| serde_json::json!(history::recent())) as EventsHandler);
+        let throttles = listing_throttles
+            .map(|throttles| Arc::new(move || throttles.summary()) as ThrottlesHandler);
+        let inject = (!injecting.is_empty()).then(|| inject_handler(injecting));
+        let registry = Arc::clone(content_registry);
+        tokio::spawn(async move {
+            if let Err(e) = content_server::serve(addr, registry, preview, acks, skips, settle, catchup, events, throttles, inject).await {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        });
+    }
+}
+
+// One summary per profile
+fn skips_handler(states: Vec<Arc<AppState>>) -> SkipsHandler {
+    Arc::new(move || {
+        let profiles: Vec<serde_json::Value> = states
+            .iter()
+            .map(|state| {
+                let mut summary = state.skips.summary();
+                summary["profile"] = serde_json::json!(state.config.profile);
+                summary
+            })
+            .collect();
+        serde_json::Value::Array(profiles)
+    })
+}
+
+// One summary per profile
+fn catchup_handler(states: Vec<Arc<AppState>>) -> CatchupHandler {
+    Arc::new(move || {
+        let profiles: Vec<serde_json::Value> = states
+            .iter()
+            .filter_map(|state| Some((state, state.catchup.as_ref()?)))
+            .map(|(state, progress)| {
+                let mut summary = progress.summary();
+                summary["profile"] = serde_json::json!(state.config.profile);
+                summary
+            })
+            .collect();
+        serde_json::Value::Array(profiles)
+    })
+}
+
+// One listing per profile
+fn settle_handler(states: Vec<Arc<AppState>>) -> SettleHandler {
+    Arc::new(move || {
+        let profiles: Vec<serde_json::Value> = states
+            .iter()
+            .filter_map(|state| Some((state, state.settle.as_ref()?)))
+            .map(|(state, learner)| {
+                let mut summary = learner.summary();
+                summary["profile"] = serde_json::json!(state.config.profile);
+                summary
+            })
+            .collect();
+        serde_json::Value::Array(profiles)
+    })
+}
+
+// Injected events go to every profile whose watch directory holds the first
+// path, as a watcher on each would report it
+fn inject_handler(states: Vec<Arc<AppState>>) -> InjectHandler {
+    Arc::new(move |event: FileEvent| {
+        let Some(first) = event.paths.first() else {
+            return Err("the event has no paths".to_string());
+        };
+        let profiles: Vec<serde_json::Value> = states
+            .iter()
+            .filter(|state| first.starts_with(&state.config.watch_dir))
+            .filter_map(|state| state.injected_events.as_ref().map(|injected| (state, injected)))
+            .filter(|(_, injected)| injected.inject(event.clone()))
+            .map(|(state, _)| serde_json::json!(state.config.profile.as_deref().unwrap_or("default")))
+            .collect();
+        if profiles.is_empty() {
+            return Err(format!(
+                "{} is not inside a watch directory with DEBUG_INJECT_EVENTS enabled",
+                first.display()
+            ));
+        }
+        info!("Injected {:?} event for {}", event.kind, first.display());
+        Ok(serde_json::json!({ "injected": profiles }))
+    })
+}
+
+fn preview_handler(states: Vec<Arc<AppState>>) -> PreviewHandler {
+    let states = Arc::new(states);
+    Arc::new(move |path| {
+        let states = Arc::clone(&states);
+        Box::pin(async move { preview_file(&states, path).await })
+    })
+}
+
+// The sources of a profile's events: its watch backend and, with
+// DEBUG_INJECT_EVENTS, the events injected over HTTP
+fn event_sources(state: &AppState) -> Vec<Box<dyn EventSource>> {
+    let config = &state.config;
+    let mut sources: Vec<Box<dyn EventSource>> = vec![match config.watch_backend {
+        WatchBackend::Native => Box::new(NotifySource {
+            root: config.watch_dir.clone(),
+            pattern: config.auto_watch_pattern.clone(),
+            fallback: FallbackSettings {
+                poll_interval: Duration::from_secs(config.watch_poll_interval_secs),
+                retry_interval: Duration::from_secs(config.watch_retry_secs),
+            },
+            prefix: config.log_prefix(),
+        }),
+        WatchBackend::Poll => Box::new(PollSource {
+            root: config.watch_dir.clone(),
+            interval: Duration::from_secs(config.watch_poll_interval_secs),
+            prefix: config.log_prefix(),
+        }),
+    }];
+    if let Some(injected) = &state.injected_events {
+        sources.push(Box::new(injected.clone()));
+    }
+    sources
+}
+
+// Dispatch events until the channel disconnects
+fn run_event_loop(rx: &Receiver<(usize, FileEvent)>, states: &[Arc<AppState>], filtered_events: &AtomicU64) {
+    while let Ok((index, event)) = rx.recv() {
+        debug!(
+            "Received {:?} event ({} events filtered before the channel so far)",
+            event.kind,
+            filtered_events.load(Ordering::Relaxed)
+        );
+        
+        handle_event(&states[index], event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_event_loop_ends_once_the_event_sources_are_dropped() {
+        let root = tempfile::tempdir().unwrap();
+        let (tx, rx) = channel();
+        let sink: EventSink = Arc::new(move |event| {
+            tx.send((0, event)).ok();
+        });
+        let source = Box::new(PollSource {
+            root: root.path().to_path_buf(),
+            interval: Duration::from_millis(10),
+            prefix: String::new(),
+        });
+        let running = source.start(sink).unwrap();
+        let dropper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(running);
+        });
+
+        // No profile to dispatch to: the empty directory has no events
+        run_event_loop(&rx, &[], &AtomicU64::new(0));
+        dropper.join().unwrap();
+    }
+
+    fn watched(case: PathCase) -> WatchedFiles {
+        WatchedFiles {
+            all: false,
+            bundle_extensions: Vec::new(),
+            case,
+            events: WatchEvents { create: true, modify: false },
+        }
+    }
+
+    fn renamed(from: &str, to: &str) -> FileEvent {
+        FileEvent::new(FileEventKind::Renamed, vec![PathBuf::from(from), PathBuf::from(to)])
+    }
+
+    #[test]
+    fn renaming_a_temporary_file_to_xml_delivers_it() {
+        let watched = watched(PathCase::Sensitive);
+        let event = renamed("/watch/order.tmp", "/watch/order.xml");
+        assert_eq!(delivery_paths(&event, &watched), [PathBuf::from("/watch/order.xml")]);
+        assert!(is_relevant_event(&event, &watched));
+    }
+
+    #[test]
+    fn renames_that_were_already_watched_or_leave_xml_are_not_deliveries() {
+        let sensitive = watched(PathCase::Sensitive);
+        let event = renamed("/watch/order.xml", "/watch/order-2.xml");
+        assert!(delivery_paths(&event, &sensitive).is_empty());
+        assert!(!is_relevant_event(&event, &sensitive));
+
+        let event = renamed("/watch/order.xml", "/watch/order.done");
+        assert!(!is_relevant_event(&event, &sensitive));
+
+        let case_insensitive = watched(PathCase::Insensitive);
+        let event = renamed("/watch/order.XML", "/watch/order.xml");
+        assert!(delivery_paths(&event, &case_insensitive).is_empty());
+        assert_eq!(
+            filtered_skips(&event, &case_insensitive),
+            [(Path::new("/watch/order.xml"), SkipReason::CaseOnlyRename)]
+        );
+    }
+
+    #[test]
+    fn renames_are_ignored_without_create_events() {
+        let mut watched = watched(PathCase::Sensitive);
+        watched.events = WatchEvents { create: false, modify: true };
+        assert!(!is_relevant_event(&renamed("/watch/order.tmp", "/watch/order.xml"), &watched));
+    }
+
+    #[test]
+    fn deletes_are_never_delivered_so_never_read() {
+        let mut watched = watched(PathCase::Sensitive);
+        watched.events = WatchEvents { create: true, modify: true };
+        let removed = FileEvent::new(FileEventKind::Removed, vec![PathBuf::from("/watch/order.xml")]);
+        assert!(delivery_paths(&removed, &watched).is_empty());
+        assert!(!is_relevant_event(&removed, &watched));
+        let moved_out = FileEvent::new(FileEventKind::MovedOut, vec![PathBuf::from("/watch/order.xml")]);
+        assert!(delivery_paths(&moved_out, &watched).is_empty());
+    }
+
+    #[test]
+    fn content_events_choose_which_deliveries_carry_content() {
+        let both = config(&[("webhook_url", "http://localhost/"), ("include_content", "true")]);
+        assert_eq!(content_mode_for(&both, None, false), Some(ContentMode::Inline));
+        assert_eq!(content_mode_for(&both, None, true), Some(ContentMode::Inline));
+
+        let creates = config(&[("webhook_url", "http://localhost/"), ("include_content", "true"), ("content_events", "create")]);
+        assert_eq!(content_mode_for(&creates, None, false), Some(ContentMode::Inline));
+        assert_eq!(content_mode_for(&creates, None, true), None);
+
+        let modifies = config(&[("webhook_url", "http://localhost/"), ("size_policy", "0-:inline"), ("content_events", "modify")]);
+        assert_eq!(content_mode_for(&modifies, size_action(&modifies, 10), false), None);
+        assert_eq!(content_mode_for(&modifies, size_action(&modifies, 10), true), Some(ContentMode::Inline));
+
+        let without_content: toml::Table = [("webhook_url", "http://localhost/"), ("content_events", "create")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), toml::Value::String(value.to_string())))
+            .collect();
+        let refused = Config::from_source(&config::ConfigSource::Profile(&without_content), None).err().unwrap();
+        assert!(refused.contains("CONTENT_EVENTS"), "{}", refused);
+    }
+
+    #[test]
+    fn previews_never_split_a_character() {
+        // 'é' is two bytes, '€' three and U+1D11E four
+        let data = "ab\u{E9}\u{20AC}\u{1D11E}z".as_bytes();
+        let previews: Vec<String> = (0..=data.len())
+            .map(|limit| preview_of(data, limit, InvalidUtf8::Replace).0)
+            .collect();
+        assert_eq!(
+            previews,
+            ["", "a", "ab", "ab", "abé", "abé", "abé", "abé€", "abé€", "abé€", "abé€", "abé€\u{1D11E}", "abé€\u{1D11E}z"]
+        );
+        for limit in 0..data.len() {
+            let (_, invalid, truncated) = preview_of(data, limit, InvalidUtf8::Replace);
+            assert_eq!((invalid, truncated), (None, true));
+        }
+        assert!(!preview_of(data, data.len(), InvalidUtf8::Replace).2);
+    }
+
+    #[test]
+    fn previews_cut_through_invalid_bytes_report_them() {
+        // Continuation bytes without a start, where backing up finds nothing to keep whole
+        let data = b"ab\x80\x80\x80\x80cd";
+        let (preview, invalid, truncated) = preview_of(data, 5, InvalidUtf8::Escape);
+        assert_eq!(preview, "ab&#x80;&#x80;&#x80;");
+        assert_eq!(invalid, Some(InvalidSequences { count: 3, first: 2 }));
+        assert!(truncated);
+
+        let (preview, invalid, _) = preview_of(b"a\xFF\xE2\x82\xAC", 4, InvalidUtf8::Replace);
+        assert_eq!(preview, "a\u{FFFD}");
+        assert_eq!(invalid.unwrap().count, 1);
+    }
+
+    // A profile with `options`, and otherwise defaults
+    fn config(options: &[(&str, &str)]) -> Config {
+        let table: toml::Table = options
+            .iter()
+            .map(|(name, value)| (name.to_string(), toml::Value::String(value.to_string())))
+            .collect();
+        Config::from_source(&config::ConfigSource::Profile(&table), None).unwrap()
+    }
+
+    // A presign API and the storage it presigns uploads to. Uploads and
+    // confirmations answer with the queued statuses, then 200.
+    #[derive(Default)]
+    struct PresignReceiver {
+        // Of the upload URLs handed out
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+        upload_statuses: std::collections::VecDeque<u16>,
+        confirm_statuses: std::collections::VecDeque<u16>,
+        requests: Vec<String>,
+    }
+
+    fn presign_receiver(receiver: PresignReceiver) -> (std::net::SocketAddr, Arc<std::sync::Mutex<PresignReceiver>>) {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let receiver = Arc::new(std::sync::Mutex::new(receiver));
+        let shared = Arc::clone(&receiver);
+        let make_service = make_service_fn(move |_| {
+            let receiver = Arc::clone(&shared);
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |request: hyper::Request<hyper::Body>| {
+                    let receiver = Arc::clone(&receiver);
+                    async move {
+                        let host = request.headers()["host"].to_str().unwrap().to_string();
+                        let mut receiver = receiver.lock().unwrap();
+                        let presigns = receiver.requests.iter().filter(|r| r.ends_with("/presign")).count();
+                        receiver.requests.push(format!("{} {}", request.method(), request.uri().path()));
+                        let (status, body) = match request.uri().path() {
+                            "/presign" => {
+                                let url = format!("http://{}/upload/{}", host, presigns + 1);
+                                (200, serde_json::json!({"url": url, "expires_at": receiver.expires_at}).to_string())
+                            }
+                            "/confirm" => (receiver.confirm_statuses.pop_front().unwrap_or(200), String::new()),
+                            _ => (receiver.upload_statuses.pop_front().unwrap_or(200), String::new()),
+                        };
+                        let response = hyper::Response::builder().status(status).body(hyper::Body::from(body)).unwrap();
+                        Ok::<_, std::convert::Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, receiver)
+    }
+
+    // Deliver one file with DELIVERY_MODE=presigned once per element of
+    // `attempts`, checking each outcome, and return the requests received
+    async fn deliver_presigned_file(receiver: PresignReceiver, attempts: &[Outcome]) -> Vec<String> {
+        let (addr, receiver) = presign_receiver(receiver);
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("order.xml");
+        std::fs::write(&file, "<order/>").unwrap();
+        let watch_dir = dir.path().to_str().unwrap();
+        let presign_url = format!("http://{}/presign", addr);
+        let confirm_url = format!("http://{}/confirm", addr);
+        let config = config(&[
+            ("watch_dir", watch_dir),
+            ("delivery_mode", "presigned"),
+            ("presign_url", &presign_url),
+            ("confirm_url", &confirm_url),
+        ]);
+        let state = build_state(config, &Arc::default(), &Arc::default()).unwrap();
+        let state = Arc::new(state);
+        for expected in attempts {
+            let (outcome, _) = deliver_content(&state, &file, Instant::now(), None, None).await;
+            assert_eq!(outcome, *expected);
+        }
+        let requests = receiver.lock().unwrap().requests.clone();
+        requests
+    }
+
+    #[tokio::test]
+    async fn a_failed_upload_is_retried_with_the_same_url() {
+        let receiver = PresignReceiver {
+            expires_at: Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+            upload_statuses: [500].into(),
+            ..Default::default()
+        };
+        let requests = deliver_presigned_file(receiver, &[Outcome::Rejected, Outcome::Delivered]).await;
+        assert_eq!(requests, ["POST /presign", "PUT /upload/1", "PUT /upload/1", "POST /confirm"]);
+    }
+
+    #[tokio::test]
+    async fn an_expired_upload_url_is_presigned_again() {
+        let receiver = PresignReceiver {
+            expires_at: Some(chrono::Utc::now() - chrono::Duration::seconds(1)),
+            upload_statuses: [500].into(),
+            ..Default::default()
+        };
+        let requests = deliver_presigned_file(receiver, &[Outcome::Rejected, Outcome::Delivered]).await;
+        assert_eq!(requests, ["POST /presign", "PUT /upload/1", "POST /presign", "PUT /upload/2", "POST /confirm"]);
+    }
+
+    #[tokio::test]
+    async fn a_refused_upload_url_from_the_last_attempt_is_presigned_again() {
+        // Without an expiry, a 403 is taken to mean it expired
+        let receiver = PresignReceiver { upload_statuses: [500, 403].into(), ..Default::default() };
+        let requests = deliver_presigned_file(receiver, &[Outcome::Rejected, Outcome::Delivered]).await;
+        assert_eq!(
+            requests,
+            ["POST /presign", "PUT /upload/1", "PUT /upload/1", "POST /presign", "PUT /upload/2", "POST /confirm"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_confirmation_is_retried_without_uploading_again() {
+        let receiver = PresignReceiver { confirm_statuses: [503].into(), ..Default::default() };
+        let requests = deliver_presigned_file(receiver, &[Outcome::Rejected, Outcome::Delivered]).await;
+        assert_eq!(requests, ["POST /presign", "PUT /upload/1", "POST /confirm", "POST /confirm"]);
+    }
+
+    #[test]
+    fn path_case_sensitive_overrides_detection() {
+        let base = [("webhook_url", "http://localhost/hook")];
+        assert_eq!(config(&base).path_case, None);
+        for (value, case) in [("true", Some(PathCase::Sensitive)), ("FALSE", Some(PathCase::Insensitive)), ("auto", None)] {
+            assert_eq!(config(&[base[0], ("path_case_sensitive", value)]).path_case, case);
+        }
+    }
+
+    #[test]
+    fn a_symlinked_watch_dir_is_watched_and_reported_as_its_target() {
+        let tree = tempfile::tempdir().unwrap();
+        let real = tree.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        let link = tree.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let real = real.canonicalize().unwrap();
+
+        let mut config = config(&[
+            ("watch_dir", link.to_str().unwrap()),
+            ("webhook_url", "http://127.0.0.1:1/hook"),
+            ("backup_dir", link.join("backups/new").to_str().unwrap()),
+        ]);
+        use_real_paths(&mut config);
+        assert_eq!(config.watch_dir, real);
+        // Directories that don't exist yet resolve through the link too
+        assert_eq!(config.backup_dir, Some(real.join("backups/new")));
+
+        let (tx, rx) = channel();
+        let sink: EventSink = Arc::new(move |event: FileEvent| {
+            tx.send(event).ok();
+        });
+        let source = Box::new(PollSource {
+            root: config.watch_dir.clone(),
+            interval: Duration::from_millis(20),
+            prefix: String::new(),
+        });
+        let _running = source.start(sink).unwrap();
+        std::fs::write(link.join("a.xml"), "<a/>").unwrap();
+
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.paths, [real.join("a.xml")]);
+        assert_eq!(relative_display(&config, &event.paths[0]), "a.xml");
     }
 }
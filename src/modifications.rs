@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::path_case::PathCase;
+
+/// Modified files waiting for their writers to go quiet, with
+/// WATCH_EVENTS=modify, and the deliveries they are measured against.
+///
+/// Each modification of a path restarts its debounce, so that the several
+/// events of one save lead to one delivery. A delivery covers the
+/// modifications made before it finished: a file still being written after
+/// it was created, or rewritten by a hook or an overwrite during its own
+/// delivery, isn't delivered again. A path is only kept while it is being
+/// debounced or delivered.
+pub struct Modifications {
+    debounce: Duration,
+    case: PathCase,
+    paths: Mutex<HashMap<PathBuf, Tracked>>,
+}
+
+#[derive(Default)]
+struct Tracked {
+    // The last modification, while the path is being debounced
+    modified_at: Option<Instant>,
+    // Deliveries of the path from their dispatch until they finish
+    delivering: usize,
+    // Whether the latest of them is for a modification
+    modification: bool,
+    delivered_at: Option<Instant>,
+}
+
+impl Modifications {
+    pub fn new(debounce: Duration, case: PathCase) -> Self {
+        Modifications {
+            debounce,
+            case,
+            paths: Mutex::default(),
+        }
+    }
+
+    /// Record a modification of `path`. Returns true when the path wasn't
+    /// being debounced yet, and the caller should wait on `quiet`.
+    pub fn modified(&self, path: &Path, at: Instant) -> bool {
+        let mut paths = self.paths.lock().unwrap();
+        let tracked = paths.entry(self.case.key(path)).or_default();
+        let first = tracked.modified_at.is_none();
+        tracked.modified_at = Some(tracked.modified_at.map_or(at, |last| last.max(at)));
+        first
+    }
+
+    /// Wait until `path` has gone unmodified for the debounce and no delivery
+    /// of it is under way. Returns its last modification, or None when a
+    /// delivery that finished since covers it.
+    pub async fn quiet(&self, path: &Path) -> Option<Instant> {
+        let key = self.case.key(path);
+        loop {
+            let wait = {
+                let mut paths = self.paths.lock().unwrap();
+                let tracked = paths.get(&key)?;
+                let modified_at = tracked.modified_at?;
+                let quiet_at = modified_at + self.debounce;
+                if tracked.delivering > 0 {
+                    self.debounce
+                } else if tracked.delivered_at.is_some_and(|delivered_at| delivered_at >= modified_at) {
+                    paths.remove(&key);
+                    return None;
+                } else if Instant::now() >= quiet_at {
+                    paths.remove(&key);
+                    return Some(modified_at);
+                } else {
+                    quiet_at - Instant::now()
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// A delivery of `path` was dispatched; `modification` tells whether it
+    /// is for a modification rather than a new file.
+    pub fn begin(&self, path: &Path, modification: bool) {
+        let mut paths = self.paths.lock().unwrap();
+        let tracked = paths.entry(self.case.key(path)).or_default();
+        tracked.delivering += 1;
+        tracked.modification = modification;
+    }
+
+    /// A delivery of `path` finished, delivered or not.
+    pub fn finished(&self, path: &Path) {
+        let key = self.case.key(path);
+        let mut paths = self.paths.lock().unwrap();
+        let Some(tracked) = paths.get_mut(&key) else {
+            return;
+        };
+        tracked.delivering = tracked.delivering.saturating_sub(1);
+        tracked.delivered_at = Some(Instant::now());
+        if tracked.delivering == 0 && tracked.modified_at.is_none() {
+            paths.remove(&key);
+        }
+    }
+
+    /// Whether the delivery of `path` under way is for a modification.
+    pub fn is_modification(&self, path: &Path) -> bool {
+        let paths = self.paths.lock().unwrap();
+        paths
+            .get(&self.case.key(path))
+            .is_some_and(|tracked| tracked.delivering > 0 && tracked.modification)
+    }
+}
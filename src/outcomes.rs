@@ -0,0 +1,112 @@
+use log::{debug, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::sensitive::{self, SensitiveString};
+
+// Records waiting to be sent; further ones are dropped
+const MAX_PENDING_RECORDS: usize = 10_000;
+
+// Largest number of records sent in one request
+const MAX_BATCH_RECORDS: usize = 500;
+
+// Requests per batch, including the first
+const ATTEMPTS: usize = 2;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The terminal state of one file, as sent to `OUTCOME_WEBHOOK_URL`.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutcomeRecord {
+    pub event_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    // Relative to the watch directory
+    pub path: String,
+    pub outcome: &'static str,
+    // Why a skipped or suppressed file wasn't delivered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    // What went wrong with a failed delivery
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_kind: Option<&'static str>,
+    // The SIZE_POLICY action of the file's tier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_policy: Option<&'static str>,
+    pub status: Option<u16>,
+    // Deliveries attempted; 0 when the file was never sent
+    pub attempts: u32,
+    pub duration_ms: u64,
+    pub detected_at: String,
+    pub completed_at: String,
+}
+
+/// Sends outcome records to a second endpoint in batches, in the background.
+///
+/// Each batch is a JSON array of records, tried twice. Failures are only
+/// logged: they never affect the outcome they report.
+pub struct OutcomeNotifier {
+    sender: mpsc::Sender<OutcomeRecord>,
+    prefix: String,
+}
+
+impl OutcomeNotifier {
+    /// Start the sender task. Records are collected for `flush_every` before
+    /// being sent; with a zero interval each is sent as soon as it arrives.
+    pub fn start(client: Client, url: SensitiveString, flush_every: Duration, prefix: String) -> Self {
+        let (sender, receiver) = mpsc::channel(MAX_PENDING_RECORDS);
+        tokio::spawn(send_batches(client, url, flush_every, receiver, prefix.clone()));
+        OutcomeNotifier { sender, prefix }
+    }
+
+    pub fn notify(&self, record: OutcomeRecord) {
+        if self.sender.try_send(record).is_err() {
+            warn!("{}Too many outcome notifications pending, dropping one", self.prefix);
+        }
+    }
+}
+
+async fn send_batches(
+    client: Client,
+    url: SensitiveString,
+    flush_every: Duration,
+    mut receiver: mpsc::Receiver<OutcomeRecord>,
+    prefix: String,
+) {
+    let mut batch = Vec::new();
+    while receiver.recv_many(&mut batch, MAX_BATCH_RECORDS).await > 0 {
+        if !flush_every.is_zero() {
+            // Gather what arrives until the next flush
+            tokio::time::sleep(flush_every).await;
+            while batch.len() < MAX_BATCH_RECORDS {
+                match receiver.try_recv() {
+                    Ok(record) => batch.push(record),
+                    Err(_) => break,
+                }
+            }
+        }
+        send_batch(&client, &url, &batch, &prefix).await;
+        batch.clear();
+    }
+}
+
+async fn send_batch(client: &Client, url: &SensitiveString, batch: &[OutcomeRecord], prefix: &str) {
+    for attempt in 1..=ATTEMPTS {
+        let error = match client.post(url.expose()).json(batch).send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("{}Sent {} outcome records to {}", prefix, batch.len(), url);
+                return;
+            }
+            Ok(response) => format!("HTTP {}", response.status().as_u16()),
+            Err(e) => sensitive::redact_error(e).to_string(),
+        };
+        if attempt < ATTEMPTS {
+            debug!("{}Sending outcome records to {} failed ({}), retrying", prefix, url, error);
+            tokio::time::sleep(RETRY_DELAY).await;
+        } else {
+            warn!("{}Failed to send {} outcome records to {}: {}", prefix, batch.len(), url, error);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// Entries of the watch directory tried when detecting, before its own name
+const DETECT_ENTRIES: usize = 100;
+
+/// Whether the file system of a watch root tells paths apart by case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathCase {
+    Sensitive,
+    // `Invoice.XML` and `invoice.xml` are the same file, as on macOS and
+    // Windows by default
+    Insensitive,
+}
+
+impl PathCase {
+    /// Detect the case sensitivity of the file system `dir` is on, by looking
+    /// up a name in it with the case of one letter swapped. When neither an
+    /// entry nor the directory's own name has a letter, the platform's usual
+    /// file system decides. Nothing is written.
+    pub fn detect(dir: &Path) -> Self {
+        let entries = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .take(DETECT_ENTRIES)
+            .map(|entry| entry.path());
+        // An entry's name is looked up on the watched file system itself, the
+        // directory's in its parent, which may be another mount
+        for path in entries.chain(std::iter::once(dir.to_path_buf())) {
+            let Some(swapped) = swap_case(&path) else {
+                continue;
+            };
+            let Ok(original) = std::fs::symlink_metadata(&path) else {
+                continue;
+            };
+            match std::fs::symlink_metadata(&swapped) {
+                Ok(other) if (other.dev(), other.ino()) == (original.dev(), original.ino()) => {
+                    return PathCase::Insensitive;
+                }
+                // Two files whose names differ in case
+                Ok(_) => return PathCase::Sensitive,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return PathCase::Sensitive,
+                Err(_) => continue,
+            }
+        }
+        if cfg!(any(target_os = "macos", target_os = "windows")) {
+            PathCase::Insensitive
+        } else {
+            PathCase::Sensitive
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PathCase::Sensitive => "case-sensitive",
+            PathCase::Insensitive => "case-insensitive",
+        }
+    }
+
+    /// The key `path` is remembered under: lower-cased on case-insensitive
+    /// file systems, so that every spelling of a file finds the same entry.
+    /// This is Unicode lower-casing, which covers the names producers use but
+    /// not every folding rule of APFS or NTFS. Names that aren't UTF-8 are
+    /// kept as they are.
+    pub fn key(&self, path: &Path) -> PathBuf {
+        match (self, path.to_str()) {
+            (PathCase::Insensitive, Some(path)) => PathBuf::from(path.to_lowercase()),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Whether `a` and `b` name the same file, differing at most in case.
+    pub fn same_path(&self, a: &Path, b: &Path) -> bool {
+        self.key(a) == self.key(b)
+    }
+}
+
+// `path` with the case of the first letter of its file name swapped
+fn swap_case(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let (index, letter) = name
+        .char_indices()
+        .find(|(_, c)| c.to_lowercase().ne(c.to_uppercase()))?;
+    let swapped: String = match letter.is_lowercase() {
+        true => letter.to_uppercase().collect(),
+        false => letter.to_lowercase().collect(),
+    };
+    let name = format!("{}{}{}", &name[..index], swapped, &name[index + letter.len_utf8()..]);
+    Some(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_fold_case_only_when_insensitive() {
+        let a = Path::new("/watch/In/Invoice.XML");
+        let b = Path::new("/watch/in/invoice.xml");
+        assert_eq!(PathCase::Sensitive.key(a), a);
+        assert!(!PathCase::Sensitive.same_path(a, b));
+        assert_eq!(PathCase::Insensitive.key(a), b);
+        assert!(PathCase::Insensitive.same_path(a, b));
+        assert!(!PathCase::Insensitive.same_path(a, Path::new("/watch/in/invoice-2.xml")));
+        // Beyond ASCII
+        assert!(PathCase::Insensitive.same_path(Path::new("/watch/ÄNDERUNG.xml"), Path::new("/watch/änderung.xml")));
+    }
+
+    #[test]
+    fn names_that_arent_utf8_are_kept() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/watch/A\xff.xml"));
+        assert_eq!(PathCase::Insensitive.key(path), path);
+    }
+
+    #[test]
+    fn the_first_letter_of_the_name_is_swapped() {
+        assert_eq!(swap_case(Path::new("/Watch/2024-order.xml")), Some(PathBuf::from("/Watch/2024-Order.xml")));
+        assert_eq!(swap_case(Path::new("/watch/Order.xml")), Some(PathBuf::from("/watch/order.xml")));
+        assert_eq!(swap_case(Path::new("/watch/ébauche.xml")), Some(PathBuf::from("/watch/Ébauche.xml")));
+        assert_eq!(swap_case(Path::new("/watch/2024-01")), None);
+    }
+
+    #[test]
+    fn detection_finds_a_case_sensitive_file_system() {
+        // Linux file systems under CI tell case apart
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("order.xml"), "<a/>").unwrap();
+        assert_eq!(PathCase::detect(dir.path()), PathCase::Sensitive);
+
+        // Two names differing only in case can only exist on one
+        std::fs::write(dir.path().join("Order.xml"), "<b/>").unwrap();
+        assert_eq!(PathCase::detect(dir.path()), PathCase::Sensitive);
+    }
+}
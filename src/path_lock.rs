@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::path_case::PathCase;
+
+/// One lock per path being processed, so that events overlapping for the
+/// same file are handled one after another instead of racing on reading and
+/// overwriting it.
+///
+/// A path's lock only exists while some task holds or waits for it: the last
+/// one to let go removes it, so the map never outgrows what is in flight.
+pub struct PathLocks {
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    case: PathCase,
+}
+
+/// Held while a path is processed; dropping it lets the next task in.
+pub struct PathGuard<'a> {
+    locks: &'a PathLocks,
+    key: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl PathLocks {
+    pub fn new(case: PathCase) -> Self {
+        PathLocks {
+            locks: Mutex::default(),
+            case,
+        }
+    }
+
+    /// Lock `path`, waiting for whoever holds it first. Returns whether it had
+    /// to wait.
+    pub async fn lock(&self, path: &Path) -> (PathGuard<'_>, bool) {
+        let key = self.case.key(path);
+        // Cloned under the map's lock, which is what lets `drop` tell that
+        // nobody else is waiting
+        let lock = Arc::clone(self.locks.lock().unwrap().entry(key.clone()).or_default());
+        let (guard, waited) = match Arc::clone(&lock).try_lock_owned() {
+            Ok(guard) => (guard, false),
+            Err(_) => (lock.lock_owned().await, true),
+        };
+        (PathGuard { locks: self, key, guard: Some(guard) }, waited)
+    }
+}
+
+impl Drop for PathGuard<'_> {
+    fn drop(&mut self) {
+        // Released first, so that only the map's reference is left when no
+        // other task has cloned it
+        drop(self.guard.take());
+        let mut locks = self.locks.locks.lock().unwrap();
+        if locks.get(&self.key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(locks: &PathLocks) -> usize {
+        locks.locks.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn locks_are_removed_once_nobody_holds_them() {
+        let locks = PathLocks::new(PathCase::Sensitive);
+        let (guard, waited) = locks.lock(Path::new("/watch/a.xml")).await;
+        assert!(!waited);
+        let (other, waited) = locks.lock(Path::new("/watch/b.xml")).await;
+        assert!(!waited);
+        assert_eq!(held(&locks), 2);
+        drop(guard);
+        drop(other);
+        assert_eq!(held(&locks), 0);
+    }
+
+    #[tokio::test]
+    async fn tasks_on_one_path_take_turns() {
+        let locks = Arc::new(PathLocks::new(PathCase::Insensitive));
+        let (guard, _) = locks.lock(Path::new("/watch/Order.xml")).await;
+        let waiter = tokio::spawn({
+            let locks = Arc::clone(&locks);
+            async move {
+                let (_guard, waited) = locks.lock(Path::new("/watch/order.XML")).await;
+                waited
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        // The waiter's clone keeps the entry when the first task lets go
+        drop(guard);
+        assert_eq!(held(&locks), 1);
+        assert!(waiter.await.unwrap());
+        assert_eq!(held(&locks), 0);
+    }
+}
@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderName;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::storage::AckStore;
+use crate::write_guard::write_atomically;
+
+/// Where the receiver puts the token of an accepted (202) delivery.
+#[derive(Debug, Clone)]
+pub enum AckTokenSource {
+    Header(HeaderName),
+    // RFC 6901 pointer into a JSON response body
+    Json(String),
+}
+
+impl AckTokenSource {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("Invalid ASYNC_ACK_TOKEN '{}': {}", value, reason);
+        match value.split_once(':') {
+            Some((kind, name)) if kind.eq_ignore_ascii_case("header") => HeaderName::from_bytes(name.trim().as_bytes())
+                .map(AckTokenSource::Header)
+                .map_err(|_| invalid("not a valid header name")),
+            Some((kind, pointer)) if kind.eq_ignore_ascii_case("json") => {
+                if !pointer.starts_with('/') {
+                    return Err(invalid("the JSON pointer must start with '/'"));
+                }
+                Ok(AckTokenSource::Json(pointer.to_string()))
+            }
+            _ => Err(invalid("expected 'header:<name>' or 'json:<pointer>'")),
+        }
+    }
+
+    pub fn reads_body(&self) -> bool {
+        matches!(self, AckTokenSource::Json(_))
+    }
+
+    /// The token in a response, if there is a non-empty one.
+    pub fn find(&self, headers: &reqwest::header::HeaderMap, body: Option<&[u8]>) -> Option<String> {
+        let token = match self {
+            AckTokenSource::Header(name) => headers.get(name)?.to_str().ok()?.to_string(),
+            AckTokenSource::Json(pointer) => {
+                let document: serde_json::Value = serde_json::from_slice(body?).ok()?;
+                match document.pointer(pointer)? {
+                    serde_json::Value::String(token) => token.clone(),
+                    serde_json::Value::Number(token) => token.to_string(),
+                    _ => return None,
+                }
+            }
+        };
+        Some(token.trim().to_string()).filter(|token| !token.is_empty())
+    }
+}
+
+impl fmt::Display for AckTokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckTokenSource::Header(name) => write!(f, "header:{}", name),
+            AckTokenSource::Json(pointer) => write!(f, "json:{}", pointer),
+        }
+    }
+}
+
+/// A file the receiver accepted but hasn't acknowledged yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingAck {
+    pub token: String,
+    pub path: PathBuf,
+    pub status: u16,
+    pub detected_at: DateTime<Utc>,
+    pub accepted_at: DateTime<Utc>,
+}
+
+/// Files waiting for their acknowledgement, kept in a store so that they
+/// survive restarts.
+///
+/// The store is rewritten on every change, which is why the number of entries
+/// is bounded.
+pub struct PendingAcks {
+    store: Box<dyn AckStore>,
+    max_entries: usize,
+    timeout: Duration,
+    entries: Mutex<Vec<PendingAck>>,
+}
+
+impl PendingAcks {
+    /// Open the store, picking up entries left by a previous run.
+    pub fn open(store: Box<dyn AckStore>, max_entries: usize, timeout: Duration) -> Result<Self, String> {
+        let entries = store.load()?;
+        Ok(PendingAcks {
+            store,
+            max_entries,
+            timeout,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn location(&self) -> String {
+        self.store.location()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Add an entry. Fails when the store is full; an entry that can't be
+    /// saved is still kept in memory.
+    pub fn insert(&self, entry: PendingAck) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries {
+            return Err(format!("{} deliveries are already waiting for acknowledgement", entries.len()));
+        }
+        entries.push(entry);
+        if let Err(e) = self.store.save(&entries) {
+            log::error!("{}", e);
+        }
+        Ok(())
+    }
+
+    /// Remove and return the entry with `token`.
+    pub fn take(&self, token: &str) -> Option<PendingAck> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|entry| entry.token == token)?;
+        let entry = entries.remove(index);
+        if let Err(e) = self.store.save(&entries) {
+            log::error!("{}", e);
+        }
+        Some(entry)
+    }
+
+    /// Remove and return the entries accepted more than the timeout ago.
+    pub fn take_expired(&self) -> Vec<PendingAck> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(self.timeout).unwrap_or_default();
+        let mut entries = self.entries.lock().unwrap();
+        let (expired, kept) = entries.drain(..).partition(|entry| entry.accepted_at < cutoff);
+        *entries = kept;
+        if !expired.is_empty() {
+            if let Err(e) = self.store.save(&entries) {
+                log::error!("{}", e);
+            }
+        }
+        expired
+    }
+
+    pub fn entries(&self) -> Vec<PendingAck> {
+        self.entries.lock().unwrap().clone()
+    }
+
+}
+
+/// Pending acknowledgements in a JSON file, ASYNC_ACK_DIR.
+pub struct AckFile {
+    path: PathBuf,
+}
+
+impl AckFile {
+    pub fn new(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+        }
+        Ok(AckFile { path: path.to_path_buf() })
+    }
+}
+
+impl AckStore for AckFile {
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn load(&self) -> Result<Vec<PendingAck>, String> {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("failed to read {}: {}", self.path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(format!("failed to read {}: {}", self.path.display(), e)),
+        }
+    }
+
+    fn save(&self, entries: &[PendingAck]) -> Result<(), String> {
+        let data = serde_json::to_vec(entries).map_err(|e| e.to_string())?;
+        write_atomically(&self.path, &data)
+    }
+}
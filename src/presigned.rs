@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::UrlTemplate;
+use crate::path_case::PathCase;
+use crate::redundant::ContentHash;
+use crate::sensitive::SensitiveString;
+
+// Files whose unfinished delivery is remembered at most
+const MAX_TRACKED_FILES: usize = 1000;
+
+/// The endpoints and timeouts of DELIVERY_MODE=presigned.
+#[derive(Debug, Clone)]
+pub struct PresignedDelivery {
+    pub presign_url: UrlTemplate,
+    pub confirm_url: Option<UrlTemplate>,
+    pub presign_timeout: Duration,
+    pub upload_timeout: Duration,
+    pub confirm_timeout: Duration,
+}
+
+/// Where to upload one file, as PRESIGN_URL answered:
+/// `{"url": "...", "headers": {...}, "expires_at": "..."}`, with only `url`
+/// required.
+#[derive(Debug, Clone)]
+pub struct PresignedUpload {
+    pub url: SensitiveString,
+    // Headers the upload must be sent with, e.g. those covered by the signature
+    pub headers: Vec<(String, String)>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct PresignResponse {
+    #[serde(alias = "upload_url")]
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl PresignedUpload {
+    pub fn from_response(body: &[u8]) -> Result<Self, String> {
+        let response: PresignResponse = serde_json::from_slice(body)
+            .map_err(|e| format!("expected a JSON object with the upload url: {}", e))?;
+        reqwest::Url::parse(&response.url).map_err(|e| format!("invalid upload url: {}", e))?;
+        Ok(PresignedUpload {
+            url: SensitiveString::url(response.url),
+            headers: response.headers.into_iter().collect(),
+            expires_at: response.expires_at,
+        })
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+/// How far the last delivery of a file got before it failed.
+#[derive(Debug, Clone)]
+pub enum Stage {
+    Presigned(PresignedUpload),
+    // Only the confirmation is missing
+    Uploaded,
+}
+
+/// The stage reached by presigned deliveries that failed, so that retrying
+/// one starts from the phase that failed.
+///
+/// A file is known by its path and content: anything else at the same path
+/// starts over. Kept in memory only, like bundle progress.
+pub struct PresignProgress {
+    files: Mutex<HashMap<PathBuf, (ContentHash, Stage)>>,
+    case: PathCase,
+}
+
+impl PresignProgress {
+    pub fn new(case: PathCase) -> Self {
+        PresignProgress {
+            files: Mutex::default(),
+            case,
+        }
+    }
+
+    /// The stage the last delivery of `path` with `hash` reached, forgetting it.
+    pub fn resume(&self, path: &Path, hash: &ContentHash) -> Option<Stage> {
+        match self.files.lock().unwrap().remove(&self.case.key(path)) {
+            Some((recorded, stage)) if recorded == *hash => Some(stage),
+            _ => None,
+        }
+    }
+
+    pub fn record(&self, path: &Path, hash: ContentHash, stage: Stage) {
+        let key = self.case.key(path);
+        let mut files = self.files.lock().unwrap();
+        if files.len() >= MAX_TRACKED_FILES && !files.contains_key(&key) {
+            // Any entry will do: the cost of losing one is a repeated phase
+            if let Some(evicted) = files.keys().next().cloned() {
+                files.remove(&evicted);
+            }
+        }
+        files.insert(key, (hash, stage));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redundant::content_hash;
+
+    #[test]
+    fn responses_need_a_valid_url() {
+        let upload = PresignedUpload::from_response(
+            br#"{"upload_url": "https://bucket.example/o?sig=1", "headers": {"x-amz-acl": "private"}, "expires_at": "2000-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(upload.url.expose(), "https://bucket.example/o?sig=1");
+        assert_eq!(upload.headers, [("x-amz-acl".to_string(), "private".to_string())]);
+        assert!(upload.has_expired());
+
+        let upload = PresignedUpload::from_response(br#"{"url": "https://bucket.example/o"}"#).unwrap();
+        assert!(upload.headers.is_empty() && !upload.has_expired());
+        assert!(PresignedUpload::from_response(br#"{"url": "not a url"}"#).is_err());
+        assert!(PresignedUpload::from_response(br#"{"headers": {}}"#).is_err());
+    }
+
+    #[test]
+    fn progress_is_resumed_once_and_only_for_the_same_content() {
+        let progress = PresignProgress::new(PathCase::Insensitive);
+        let path = Path::new("/watch/Order.xml");
+        progress.record(path, content_hash(b"<a/>"), Stage::Uploaded);
+        assert!(progress.resume(Path::new("/watch/order.xml"), &content_hash(b"<b/>")).is_none());
+
+        progress.record(path, content_hash(b"<a/>"), Stage::Uploaded);
+        assert!(matches!(progress.resume(path, &content_hash(b"<a/>")), Some(Stage::Uploaded)));
+        assert!(progress.resume(path, &content_hash(b"<a/>")).is_none());
+    }
+
+    #[test]
+    fn tracked_files_are_bounded() {
+        let progress = PresignProgress::new(PathCase::Sensitive);
+        for index in 0..MAX_TRACKED_FILES + 10 {
+            progress.record(Path::new(&format!("/watch/{}.xml", index)), content_hash(b""), Stage::Uploaded);
+        }
+        assert_eq!(progress.files.lock().unwrap().len(), MAX_TRACKED_FILES);
+    }
+}
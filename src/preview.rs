@@ -0,0 +1,164 @@
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::hardlinks::HardlinkPolicy;
+use crate::hooks::PreHookFailure;
+use crate::skips::SkipReason;
+
+/// What `xml-watcher preview <path>` found out about a file: the profile that
+/// would deliver it, the checks it goes through, the requests it would cause
+/// and what would be done before and after. Nothing is sent.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub path: String,
+    pub profile: Option<String>,
+    pub checks: Vec<Check>,
+    // The first failed check's, when the file would be skipped
+    pub skip_reason: Option<SkipReason>,
+    pub size_bytes: Option<u64>,
+    // With SIZE_POLICY, e.g. `0B-1MB:inline`
+    pub size_tier: Option<String>,
+    pub requests: Vec<Request>,
+    // How delivery differs from a request of its own per file, e.g. batching
+    pub notes: Vec<String>,
+    pub before_delivery: Vec<String>,
+    pub on_success: Vec<String>,
+    pub on_failure: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<SkipReason>,
+}
+
+/// One request a delivery would send.
+#[derive(Debug, Serialize)]
+pub struct Request {
+    // `webhook`, or the phase of a presigned delivery
+    pub phase: String,
+    pub method: String,
+    // Masked like in the log
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    // What the body is, e.g. `412 bytes of JSON`
+    pub body: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+}
+
+impl Check {
+    pub fn new(name: &'static str, failed: Option<SkipReason>) -> Self {
+        Check {
+            name,
+            passed: failed.is_none(),
+            reason: failed,
+        }
+    }
+}
+
+impl Report {
+    /// Fill in the steps around delivery from the configuration. `overwrites`
+    /// says whether a response may replace this file.
+    pub fn describe_actions(&mut self, config: &Config, overwrites: bool) {
+        if let Some(command) = &config.pre_delivery_command {
+            let failure = match config.pre_delivery_failure {
+                PreHookFailure::Skip => "skipped".to_string(),
+                PreHookFailure::Quarantine => match &config.quarantine_dir {
+                    Some(dir) => format!("moved to {}", dir.display()),
+                    None => "quarantined".to_string(),
+                },
+            };
+            self.before_delivery.push(format!("run PRE_DELIVERY_COMMAND `{}`; the file is {} if it fails", command, failure));
+        }
+        if config.hardlink_policy == HardlinkPolicy::Suppress {
+            self.before_delivery.push("not sent if it is a hard link to a file already delivered".to_string());
+        }
+        if config.skip_redundant_delivery {
+            self.before_delivery.push("not sent if identical to what was last delivered from this path".to_string());
+        }
+
+        if overwrites {
+            let backup = match (config.backup_before_overwrite, &config.backup_dir) {
+                (false, _) => String::new(),
+                (true, Some(dir)) => format!(", after a backup to {}", dir.display()),
+                (true, None) => ", after a backup to a sibling .bak file".to_string(),
+            };
+            let encoding = match config.overwrite_target_encoding {
+                Some(target) => format!(" re-encoded in {}", target),
+                None => String::new(),
+            };
+            self.on_success.push(format!("overwrite the file with an XML response body{}{}", encoding, backup));
+        }
+        if let Some(source) = &config.async_ack_token {
+            self.on_success.push(format!("a 202 with the {} ack token waits for POST /ack", source));
+        }
+        let mut reports = Vec::new();
+        if let Some(url) = &config.outcome_webhook_url {
+            reports.push(format!("report the outcome to {}", url));
+        }
+        if let Some(target) = &config.syslog_target {
+            reports.push(format!("log the outcome to syslog at {}", target));
+        }
+        if let Some(command) = &config.post_delivery_command {
+            reports.push(format!("run POST_DELIVERY_COMMAND `{}`", command));
+        }
+        self.on_success.extend(reports.iter().cloned());
+        self.on_failure.extend(reports);
+        if let Some(dir) = &config.retry_later_dir {
+            let back = match config.retry_later_after_secs {
+                Some(secs) => format!(", and back after {}s to be delivered again", secs),
+                None => String::new(),
+            };
+            self.on_failure.push(format!("move the file to {}{}", dir.display(), back));
+        }
+    }
+
+    /// The report as text for a terminal.
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!("File:    {}", self.path),
+            format!("Profile: {}", self.profile.as_deref().unwrap_or("(default)")),
+            "Checks:".to_string(),
+        ];
+        for check in &self.checks {
+            lines.push(match check.reason {
+                None => format!("  pass  {}", check.name),
+                Some(reason) => format!("  FAIL  {}: {}", check.name, reason),
+            });
+        }
+        match (self.size_bytes, &self.size_tier) {
+            (Some(size), Some(tier)) => lines.push(format!("Size:    {} bytes, SIZE_POLICY tier {}", size, tier)),
+            (Some(size), None) => lines.push(format!("Size:    {} bytes", size)),
+            (None, _) => {}
+        }
+        if let Some(reason) = self.skip_reason {
+            lines.push(format!("\nWould be skipped: {}", reason));
+            return lines.join("\n") + "\n";
+        }
+        for request in &self.requests {
+            lines.push(format!("\n{}: {} {}", request.phase, request.method, request.url));
+            lines.extend(request.headers.iter().map(|(name, value)| format!("  {}: {}", name, value)));
+            lines.push(format!("  Body: {}", request.body));
+            if let Some(payload) = &request.payload {
+                let json = serde_json::to_string_pretty(payload).unwrap_or_default();
+                lines.extend(json.lines().map(|line| format!("  {}", line)));
+            }
+        }
+        for (title, steps) in [
+            ("Notes", &self.notes),
+            ("Before delivery", &self.before_delivery),
+            ("On success", &self.on_success),
+            ("On failure", &self.on_failure),
+        ] {
+            if !steps.is_empty() {
+                lines.push(format!("\n{}:", title));
+                lines.extend(steps.iter().map(|step| format!("  - {}", step)));
+            }
+        }
+        lines.push("\nWould be delivered".to_string());
+        lines.join("\n") + "\n"
+    }
+}
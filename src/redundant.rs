@@ -0,0 +1,111 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::path_case::PathCase;
+
+// Number of paths remembered; the oldest are forgotten first
+const DELIVERED_CONTENT_CAPACITY: usize = 10_000;
+
+pub type ContentHash = [u8; 32];
+
+pub fn content_hash(data: &[u8]) -> ContentHash {
+    Sha256::digest(data).into()
+}
+
+/// The content last delivered from each path, for SKIP_REDUNDANT_DELIVERY.
+///
+/// Only files left as they were sent are remembered: a file overwritten with
+/// the response holds something else, and the same content showing up there
+/// again has to be delivered for the file to be rewritten.
+pub struct DeliveredContent {
+    state: Mutex<(HashMap<PathBuf, ContentHash>, VecDeque<PathBuf>)>,
+    case: PathCase,
+}
+
+impl DeliveredContent {
+    pub fn new(case: PathCase) -> Self {
+        DeliveredContent {
+            state: Mutex::default(),
+            case,
+        }
+    }
+    
+    /// Whether `hash` is what was last delivered from `path`.
+    pub fn is_redundant(&self, path: &Path, hash: &ContentHash) -> bool {
+        self.state.lock().unwrap().0.get(&self.case.key(path)) == Some(hash)
+    }
+
+    pub fn record(&self, path: &Path, hash: ContentHash) {
+        let key = self.case.key(path);
+        let mut state = self.state.lock().unwrap();
+        let (entries, order) = &mut *state;
+        if entries.insert(key.clone(), hash).is_none() {
+            order.push_back(key);
+        }
+        while order.len() > DELIVERED_CONTENT_CAPACITY {
+            if let Some(oldest) = order.pop_front() {
+                entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn forget(&self, path: &Path) {
+        let key = self.case.key(path);
+        let mut state = self.state.lock().unwrap();
+        let (entries, order) = &mut *state;
+        if entries.remove(&key).is_some() {
+            order.retain(|recorded| *recorded != key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replayed_content_is_redundant_until_the_file_changes() {
+        let delivered = DeliveredContent::new(PathCase::Sensitive);
+        let path = Path::new("/watch/a.xml");
+        let first = content_hash(b"<a>1</a>");
+        assert!(!delivered.is_redundant(path, &first));
+
+        delivered.record(path, first);
+        assert!(delivered.is_redundant(path, &first));
+        assert!(!delivered.is_redundant(Path::new("/watch/b.xml"), &first));
+
+        let second = content_hash(b"<a>2</a>");
+        assert!(!delivered.is_redundant(path, &second));
+        delivered.record(path, second);
+        assert!(!delivered.is_redundant(path, &first));
+    }
+
+    #[test]
+    fn overwritten_files_are_forgotten() {
+        let delivered = DeliveredContent::new(PathCase::Insensitive);
+        let hash = content_hash(b"<a/>");
+        delivered.record(Path::new("/watch/A.xml"), hash);
+        assert!(delivered.is_redundant(Path::new("/watch/a.XML"), &hash));
+
+        delivered.forget(Path::new("/watch/a.xml"));
+        assert!(!delivered.is_redundant(Path::new("/watch/A.xml"), &hash));
+        assert!(delivered.state.lock().unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn the_oldest_paths_are_forgotten_beyond_the_capacity() {
+        let delivered = DeliveredContent::new(PathCase::Sensitive);
+        let hash = content_hash(b"<a/>");
+        for i in 0..=DELIVERED_CONTENT_CAPACITY {
+            delivered.record(&PathBuf::from(format!("/watch/{}.xml", i)), hash);
+        }
+        // Recording a path again takes up no more room
+        delivered.record(Path::new("/watch/1.xml"), hash);
+        assert_eq!(delivered.state.lock().unwrap().0.len(), DELIVERED_CONTENT_CAPACITY);
+        assert!(!delivered.is_redundant(Path::new("/watch/0.xml"), &hash));
+        assert!(delivered.is_redundant(Path::new("/watch/1.xml"), &hash));
+        assert!(delivered.is_redundant(&PathBuf::from(format!("/watch/{}.xml", DELIVERED_CONTENT_CAPACITY)), &hash));
+    }
+}
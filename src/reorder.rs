@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
+
+use crate::path_case::PathCase;
+
+/// The order files are delivered in, ORDERING.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryOrder {
+    // As their events arrive
+    Arrival,
+    // By modification time, within ORDERING_WINDOW_MS
+    Mtime,
+}
+
+impl DeliveryOrder {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "arrival" => Ok(DeliveryOrder::Arrival),
+            "mtime" => Ok(DeliveryOrder::Mtime),
+            other => Err(format!("Invalid ORDERING '{}': expected 'arrival' or 'mtime'", other)),
+        }
+    }
+}
+
+struct Held {
+    path: PathBuf,
+    detected_at: Instant,
+    deadline: Instant,
+}
+
+/// Holds new files for a window and lets them through sorted by modification
+/// time, ties broken by path.
+///
+/// No file is held longer than the window, so one with an old timestamp that
+/// arrives late is delivered out of order instead of holding up the rest; it
+/// is remembered for the `out_of_order` payload flag.
+pub struct Reorder {
+    window: Duration,
+    held: Mutex<Vec<Held>>,
+    // Modification time of the newest file let through so far
+    newest: Mutex<Option<SystemTime>>,
+    out_of_order: Mutex<HashSet<PathBuf>>,
+    case: PathCase,
+    arrived: Notify,
+}
+
+impl Reorder {
+    pub fn new(window: Duration, case: PathCase) -> Self {
+        Reorder {
+            window,
+            held: Mutex::new(Vec::new()),
+            newest: Mutex::new(None),
+            out_of_order: Mutex::new(HashSet::new()),
+            case,
+            arrived: Notify::new(),
+        }
+    }
+
+    pub fn hold(&self, path: PathBuf, detected_at: Instant) {
+        let deadline = Instant::now() + self.window;
+        self.held.lock().unwrap().push(Held { path, detected_at, deadline });
+        self.arrived.notify_one();
+    }
+
+    /// Wait until files are due and return them, with the time they were
+    /// detected, in delivery order: those held for the whole window, along
+    /// with any others that sort before them.
+    pub async fn next(&self) -> Vec<(PathBuf, Instant)> {
+        loop {
+            let earliest = self.held.lock().unwrap().iter().map(|held| held.deadline).min();
+            match earliest {
+                Some(deadline) if deadline <= Instant::now() => return self.release(Instant::now()),
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline.into()) => {}
+                        _ = self.arrived.notified() => {}
+                    }
+                }
+                None => self.arrived.notified().await,
+            }
+        }
+    }
+
+    /// Whether `path` was let through after a newer file, forgetting it.
+    pub fn take_out_of_order(&self, path: &Path) -> bool {
+        self.out_of_order.lock().unwrap().remove(&self.case.key(path))
+    }
+
+    fn release(&self, now: Instant) -> Vec<(PathBuf, Instant)> {
+        let mut held = self.held.lock().unwrap();
+        // Files gone by now sort first and are reported as such on delivery
+        let key = |held: &Held| {
+            let modified = std::fs::metadata(&held.path).and_then(|metadata| metadata.modified()).ok();
+            (modified, held.path.clone())
+        };
+        let keyed: Vec<_> = held.drain(..).map(|entry| (key(&entry), entry)).collect();
+        let Some(cutoff) = keyed.iter().filter(|(_, entry)| entry.deadline <= now).map(|(key, _)| key.clone()).max() else {
+            held.extend(keyed.into_iter().map(|(_, entry)| entry));
+            return Vec::new();
+        };
+        let (mut due, waiting): (Vec<_>, Vec<_>) = keyed.into_iter().partition(|(key, _)| *key <= cutoff);
+        held.extend(waiting.into_iter().map(|(_, entry)| entry));
+        drop(held);
+
+        due.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut newest = self.newest.lock().unwrap();
+        let mut out_of_order = self.out_of_order.lock().unwrap();
+        due.into_iter()
+            .map(|((modified, _), entry)| {
+                match (modified, *newest) {
+                    (Some(modified), Some(latest)) if modified < latest => {
+                        out_of_order.insert(self.case.key(&entry.path));
+                    }
+                    (Some(modified), _) => *newest = Some(modified),
+                    (None, _) => {}
+                }
+                (entry.path, entry.detected_at)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,331 @@
+use crate::failure::FailureKind;
+use chrono::{DateTime, Utc};
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+// Longest wait between two attempts; a receiver asking for a longer one with
+// Retry-After isn't retried
+pub const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How often, and how far apart, a webhook request that failed for a
+/// reason that may pass is sent again.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Retries after the first attempt; 0 disables them
+    pub max_retries: u32,
+    // Wait before the first retry, doubled for each one after it
+    pub base: Duration,
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry`, counting from 1: the base
+    /// doubled for each retry before it, plus up to as much again at random,
+    /// so that deliveries failing together don't all come back at once.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.base.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        let jitter = backoff.mul_f64(random_fraction());
+        backoff.saturating_add(jitter).min(MAX_DELAY)
+    }
+}
+
+/// Which failures of a webhook request are retried, RETRYABLE_ERRORS: kinds
+/// of failure on the way to the receiver, and response statuses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryableErrors {
+    kinds: Vec<FailureKind>,
+    // Inclusive ranges, within 429 and 5xx
+    statuses: Vec<(u16, u16)>,
+    // EXTRA_RETRY_CODES, of any 4xx or 5xx, which wait for their Retry-After
+    // like a 429
+    extra_codes: Vec<u16>,
+}
+
+// Failures a request can end with, which can be named one by one
+const REQUEST_KINDS: [FailureKind; 10] = [
+    FailureKind::DnsResolution,
+    FailureKind::ConnectTimeout,
+    FailureKind::ConnectionRefused,
+    FailureKind::Connect,
+    FailureKind::TlsCertificate,
+    FailureKind::TlsPinMismatch,
+    FailureKind::TlsHandshake,
+    FailureKind::ConnectionReset,
+    FailureKind::ReadTimeout,
+    FailureKind::Request,
+];
+
+// The kinds each of reqwest's error categories covers; a connect timeout is
+// both
+fn category(name: &str) -> Option<&'static [FailureKind]> {
+    match name {
+        "connect" => Some(&[
+            FailureKind::DnsResolution,
+            FailureKind::ConnectTimeout,
+            FailureKind::ConnectionRefused,
+            FailureKind::Connect,
+        ]),
+        "timeout" => Some(&[FailureKind::ConnectTimeout, FailureKind::ReadTimeout]),
+        "request" => Some(&[FailureKind::ConnectionReset, FailureKind::Request]),
+        _ => None,
+    }
+}
+
+impl Default for RetryableErrors {
+    // Failures that may pass, and 429 and 5xx responses
+    fn default() -> Self {
+        RetryableErrors {
+            kinds: vec![
+                FailureKind::DnsResolution,
+                FailureKind::ConnectTimeout,
+                FailureKind::ConnectionRefused,
+                FailureKind::Connect,
+                FailureKind::ConnectionReset,
+                FailureKind::ReadTimeout,
+            ],
+            statuses: vec![(429, 429), (500, 599)],
+            extra_codes: Vec::new(),
+        }
+    }
+}
+
+impl RetryableErrors {
+    /// Parse a comma-separated list of error categories (`connect`, `timeout`,
+    /// `request`), failure kinds (`read_timeout`) and statuses (`503`, `5xx`,
+    /// `500-504`).
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut retryable = RetryableErrors { kinds: Vec::new(), statuses: Vec::new(), extra_codes: Vec::new() };
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let name = entry.to_lowercase();
+            let kinds = match category(&name) {
+                Some(kinds) => kinds.to_vec(),
+                None => match REQUEST_KINDS.iter().find(|kind| kind.as_str() == name) {
+                    Some(kind) => vec![*kind],
+                    None if name == "decode" || name == FailureKind::ResponseBody.as_str() => {
+                        return Err(format!(
+                            "RETRYABLE_ERRORS can't retry '{}': the response body is read after a successful status, which the receiver has acted on",
+                            entry
+                        ));
+                    }
+                    None => {
+                        retryable.statuses.push(status_range(entry)?);
+                        continue;
+                    }
+                },
+            };
+            for kind in kinds {
+                if !retryable.kinds.contains(&kind) {
+                    retryable.kinds.push(kind);
+                }
+            }
+        }
+        if retryable.kinds.is_empty() && retryable.statuses.is_empty() {
+            return Err("RETRYABLE_ERRORS must name at least one error or status; set WEBHOOK_MAX_RETRIES=0 to turn retries off".to_string());
+        }
+        Ok(retryable)
+    }
+
+    /// Whether a request that failed with `kind` is retried.
+    pub fn kind(&self, kind: FailureKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    /// Add the comma-separated statuses of EXTRA_RETRY_CODES, any 4xx or 5xx.
+    pub fn add_extra_codes(&mut self, value: &str) -> Result<(), String> {
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.parse::<u16>() {
+                Ok(code) if (400..=599).contains(&code) => self.extra_codes.push(code),
+                _ => return Err(format!("Invalid EXTRA_RETRY_CODES entry '{}', expected a 4xx or 5xx status", entry)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a response with `status` is retried.
+    pub fn status(&self, status: u16) -> bool {
+        self.extra_codes.contains(&status) || self.statuses.iter().any(|(low, high)| (*low..=*high).contains(&status))
+    }
+
+    /// Whether a retried response with `status` waits as long as its
+    /// Retry-After asks.
+    pub fn honours_retry_after(&self, status: u16) -> bool {
+        status == 429 || self.extra_codes.contains(&status)
+    }
+}
+
+// `503`, `5xx` or `500-504`, retrying only what may pass: a 429 or a 5xx
+fn status_range(entry: &str) -> Result<(u16, u16), String> {
+    let invalid = || {
+        format!(
+            "Invalid RETRYABLE_ERRORS entry '{}', expected connect, timeout, request, a failure kind or a status",
+            entry
+        )
+    };
+    let code = |value: &str| value.trim().parse::<u16>().map_err(|_| invalid());
+    let range = if let Some(class) = entry.strip_suffix("xx").or_else(|| entry.strip_suffix("XX")) {
+        let class = code(class)?;
+        if class > 9 {
+            return Err(invalid());
+        }
+        (class * 100, class * 100 + 99)
+    } else if let Some((low, high)) = entry.split_once('-') {
+        (code(low)?, code(high)?)
+    } else {
+        let status = code(entry)?;
+        (status, status)
+    };
+    if range.0 > range.1 {
+        return Err(invalid());
+    }
+    if !(range == (429, 429) || (500 <= range.0 && range.1 <= 599)) {
+        return Err(format!(
+            "RETRYABLE_ERRORS can only retry 429 and 5xx statuses, not '{}'; other statuses answer the request for good",
+            entry
+        ));
+    }
+    Ok(range)
+}
+
+/// The wait a Retry-After header asks for, in seconds or as an HTTP date.
+pub fn retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    Some((at - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Make attempts until one breaks off, sleeping for as long as each one that
+/// continues asks before the next. `attempt` is given the number of retries
+/// so far. The sleeps are tokio's, so tests can run them in virtual time.
+pub async fn retrying<T, F, Fut>(mut attempt: F) -> T
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = ControlFlow<T, Duration>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt(retries).await {
+            ControlFlow::Break(result) => return result,
+            ControlFlow::Continue(delay) => tokio::time::sleep(delay).await,
+        }
+        retries += 1;
+    }
+}
+
+// Between 0 and 1, from the last 53 bits of a v4 UUID, which are all random
+fn random_fraction() -> f64 {
+    const BITS: u32 = 53;
+    (uuid::Uuid::new_v4().as_u128() as u64 & ((1 << BITS) - 1)) as f64 / (1u64 << BITS) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[test]
+    fn delays_double_with_up_to_as_much_again_of_jitter() {
+        let policy = RetryPolicy { max_retries: 10, base: Duration::from_millis(100) };
+        for (retry, backoff) in [(1, 100), (2, 200), (3, 400), (4, 800)] {
+            let delay = policy.delay(retry);
+            assert!(delay >= Duration::from_millis(backoff), "{:?}", delay);
+            assert!(delay < Duration::from_millis(2 * backoff), "{:?}", delay);
+        }
+        assert_eq!(policy.delay(20), MAX_DELAY);
+    }
+
+    #[test]
+    fn retryable_errors_take_categories_kinds_and_statuses() {
+        let retryable = RetryableErrors::parse("timeout, 503").unwrap();
+        assert!(retryable.kind(FailureKind::ConnectTimeout));
+        assert!(retryable.kind(FailureKind::ReadTimeout));
+        assert!(!retryable.kind(FailureKind::DnsResolution));
+        assert!(retryable.status(503));
+        assert!(!retryable.status(502));
+        assert!(!retryable.status(429));
+
+        let retryable = RetryableErrors::parse("Connect,tls_handshake,5XX,429").unwrap();
+        assert!(retryable.kind(FailureKind::DnsResolution));
+        assert!(retryable.kind(FailureKind::TlsHandshake));
+        assert!(!retryable.kind(FailureKind::ReadTimeout));
+        assert!(retryable.status(429) && retryable.status(500) && retryable.status(599));
+
+        let retryable = RetryableErrors::parse("request,500-504").unwrap();
+        assert!(retryable.kind(FailureKind::ConnectionReset) && retryable.kind(FailureKind::Request));
+        assert!(retryable.status(504) && !retryable.status(505));
+    }
+
+    #[test]
+    fn retryable_errors_default_to_what_may_pass() {
+        let retryable = RetryableErrors::default();
+        assert!(retryable.kind(FailureKind::DnsResolution));
+        assert!(retryable.kind(FailureKind::ReadTimeout));
+        assert!(!retryable.kind(FailureKind::TlsCertificate));
+        assert!(!retryable.kind(FailureKind::Request));
+        assert!(retryable.status(429) && retryable.status(503));
+        assert!(!retryable.status(404) && !retryable.status(200));
+    }
+
+    #[test]
+    fn retryable_errors_refuse_what_cant_be_retried() {
+        for value in ["decode", "response_body", "ack_timeout", "4xx", "404", "400-503", "504-500", "fast", ","] {
+            assert!(RetryableErrors::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn extra_codes_are_retried_whatever_their_class() {
+        let mut retryable = RetryableErrors::default();
+        retryable.add_extra_codes("420, 409").unwrap();
+        assert!(retryable.status(420) && retryable.status(409) && retryable.status(503));
+        assert!(!retryable.status(404));
+        assert!(retryable.honours_retry_after(420) && retryable.honours_retry_after(429));
+        assert!(!retryable.honours_retry_after(503));
+
+        let mut retryable = RetryableErrors::parse("timeout").unwrap();
+        retryable.add_extra_codes("420").unwrap();
+        assert!(retryable.status(420) && !retryable.status(503));
+
+        for value in ["200", "302", "600", "4xx", "soon"] {
+            assert!(RetryableErrors::default().add_extra_codes(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_a_date() {
+        assert_eq!(retry_after(" 30 "), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let at = (Utc::now() + chrono::Duration::seconds(120)).to_rfc2822();
+        let wait = retry_after(&at).unwrap();
+        assert!(wait > Duration::from_secs(115) && wait <= Duration::from_secs(120), "{:?}", wait);
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(retry_after("-5"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attempts_are_spaced_out_by_their_backoff() {
+        let policy = RetryPolicy { max_retries: 3, base: Duration::from_secs(10) };
+        let started = Instant::now();
+        let mut sent_at = Vec::new();
+        let retries = retrying(|retries| {
+            sent_at.push(started.elapsed());
+            async move {
+                if retries < policy.max_retries {
+                    ControlFlow::Continue(policy.delay(retries + 1))
+                } else {
+                    ControlFlow::Break(retries)
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(retries, 3);
+        // Minutes of backoff in no time at all
+        let gaps: Vec<Duration> = sent_at.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        for (gap, backoff) in gaps.iter().zip([10, 20, 40]) {
+            assert!(*gap >= Duration::from_secs(backoff) && *gap < Duration::from_secs(2 * backoff), "{:?}", gaps);
+        }
+    }
+}
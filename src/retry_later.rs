@@ -0,0 +1,36 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Files below `dir` that were moved there at least `after` ago, oldest
+/// first.
+///
+/// A rename leaves the modification time alone but updates the inode change
+/// time, so that tells when a file was moved aside. Anything else that
+/// changes the file's metadata, such as a `chmod`, starts its wait over.
+pub fn due_files(dir: &Path, after: Duration) -> Vec<PathBuf> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let mut due = Vec::new();
+    collect(dir, now - after.as_secs() as i64, &mut due);
+    due.sort();
+    due.into_iter().map(|(_, path)| path).collect()
+}
+
+fn collect(dir: &Path, moved_before: i64, due: &mut Vec<(i64, PathBuf)>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            collect(&entry.path(), moved_before, due);
+        } else if metadata.is_file() && metadata.ctime() <= moved_before {
+            due.push((metadata.ctime(), entry.path()));
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::path::{Path, PathBuf};
+
+/// The watch directories of all profiles with symlinks resolved, to find
+/// profiles that would see the same files.
+pub struct WatchRoots {
+    // Resolved directory and profile name, in profile order
+    roots: Vec<(PathBuf, String)>,
+}
+
+impl WatchRoots {
+    /// Directories that don't exist yet are compared as configured.
+    pub fn resolve<'a>(roots: impl IntoIterator<Item = (&'a Path, Option<&'a str>)>) -> Self {
+        let roots = roots
+            .into_iter()
+            .map(|(dir, name)| {
+                let resolved = dir
+                    .canonicalize()
+                    .or_else(|_| std::env::current_dir().map(|cwd| cwd.join(dir)))
+                    .unwrap_or_else(|_| dir.to_path_buf());
+                (resolved, name.unwrap_or("default").to_string())
+            })
+            .collect();
+        WatchRoots { roots }
+    }
+
+    /// Every pair of profiles whose directories overlap, described for the log.
+    pub fn overlaps(&self) -> Vec<String> {
+        let mut overlaps = Vec::new();
+        for (index, (root, name)) in self.roots.iter().enumerate() {
+            for (other_root, other_name) in &self.roots[index + 1..] {
+                if root == other_root {
+                    overlaps.push(format!("'{}' and '{}' both watch {}", name, other_name, root.display()));
+                } else if other_root.starts_with(root) {
+                    overlaps.push(format!("'{}' ({}) is inside '{}' ({})", other_name, other_root.display(), name, root.display()));
+                } else if root.starts_with(other_root) {
+                    overlaps.push(format!("'{}' ({}) is inside '{}' ({})", name, root.display(), other_name, other_root.display()));
+                }
+            }
+        }
+        overlaps
+    }
+
+    /// The parts of profile `index`'s tree that other profiles handle: their
+    /// directories nested inside it, and the same directory when an earlier
+    /// profile watches it too. `watch_dir` is the directory as configured,
+    /// which event paths start with.
+    pub fn handled_elsewhere(&self, index: usize, watch_dir: &Path) -> Option<HandledElsewhere> {
+        let (root, _) = &self.roots[index];
+        let mut roots: Vec<PathBuf> = self
+            .roots
+            .iter()
+            .enumerate()
+            .filter(|(other, (other_root, _))| {
+                *other != index && other_root.starts_with(root) && (other_root != root || *other < index)
+            })
+            .map(|(_, (other_root, _))| other_root.clone())
+            .collect();
+        roots.sort();
+        roots.dedup();
+        if roots.is_empty() {
+            return None;
+        }
+        Some(HandledElsewhere {
+            watch_dir: watch_dir.to_path_buf(),
+            resolved: root.clone(),
+            roots,
+        })
+    }
+}
+
+/// Directories within a profile's tree whose files another, more specific
+/// profile delivers, with ALLOW_OVERLAPPING_ROOTS.
+pub struct HandledElsewhere {
+    watch_dir: PathBuf,
+    resolved: PathBuf,
+    roots: Vec<PathBuf>,
+}
+
+impl HandledElsewhere {
+    pub fn contains(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.watch_dir) else {
+            return false;
+        };
+        let resolved = self.resolved.join(relative);
+        self.roots.iter().any(|root| resolved.starts_with(root))
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_roots_are_left_to_the_more_specific_profile() {
+        let tree = tempfile::tempdir().unwrap();
+        let outer = tree.path().to_path_buf();
+        let inner = outer.join("invoices");
+        std::fs::create_dir(&inner).unwrap();
+        let roots = WatchRoots::resolve([(outer.as_path(), Some("all")), (inner.as_path(), Some("invoices"))]);
+
+        let resolved = outer.canonicalize().unwrap();
+        assert_eq!(
+            roots.overlaps(),
+            [format!("'invoices' ({}) is inside 'all' ({})", resolved.join("invoices").display(), resolved.display())]
+        );
+        let elsewhere = roots.handled_elsewhere(0, &outer).unwrap();
+        assert_eq!(elsewhere.roots(), [resolved.join("invoices")]);
+        assert!(elsewhere.contains(&inner.join("2024/a.xml")));
+        assert!(!elsewhere.contains(&outer.join("orders/a.xml")));
+        // A sibling whose name merely starts the same isn't inside
+        assert!(!elsewhere.contains(&outer.join("invoices-old/a.xml")));
+        assert!(roots.handled_elsewhere(1, &inner).is_none());
+    }
+
+    #[test]
+    fn identical_roots_belong_to_the_first_profile() {
+        let tree = tempfile::tempdir().unwrap();
+        let dir = tree.path();
+        let roots = WatchRoots::resolve([(dir, None), (dir, Some("copy"))]);
+        assert_eq!(
+            roots.overlaps(),
+            [format!("'default' and 'copy' both watch {}", dir.canonicalize().unwrap().display())]
+        );
+        assert!(roots.handled_elsewhere(0, dir).is_none());
+        assert!(roots.handled_elsewhere(1, dir).unwrap().contains(&dir.join("a.xml")));
+    }
+
+    #[test]
+    fn symlinked_roots_are_compared_where_they_lead() {
+        let tree = tempfile::tempdir().unwrap();
+        let real = tree.path().join("real");
+        std::fs::create_dir_all(real.join("nested")).unwrap();
+        let alias = tree.path().join("alias");
+        std::os::unix::fs::symlink(&real, &alias).unwrap();
+        let nested_alias = tree.path().join("nested-alias");
+        std::os::unix::fs::symlink(real.join("nested"), &nested_alias).unwrap();
+
+        let roots = WatchRoots::resolve([(real.as_path(), Some("a")), (alias.as_path(), Some("b"))]);
+        assert_eq!(roots.overlaps().len(), 1);
+        assert!(roots.handled_elsewhere(1, &alias).unwrap().contains(&alias.join("a.xml")));
+
+        // Events come with the configured path, which is mapped onto the resolved one
+        let roots = WatchRoots::resolve([(alias.as_path(), Some("all")), (nested_alias.as_path(), Some("nested"))]);
+        assert_eq!(roots.overlaps().len(), 1);
+        let elsewhere = roots.handled_elsewhere(0, &alias).unwrap();
+        assert!(elsewhere.contains(&alias.join("nested/a.xml")));
+        assert!(!elsewhere.contains(&alias.join("a.xml")));
+    }
+
+    #[test]
+    fn separate_roots_dont_overlap() {
+        let tree = tempfile::tempdir().unwrap();
+        let (a, b) = (tree.path().join("a"), tree.path().join("b"));
+        // Directories that don't exist yet are compared as configured
+        let roots = WatchRoots::resolve([(a.as_path(), Some("a")), (b.as_path(), Some("b"))]);
+        assert!(roots.overlaps().is_empty());
+        assert!(roots.handled_elsewhere(0, &a).is_none() && roots.handled_elsewhere(1, &b).is_none());
+    }
+}
@@ -0,0 +1,137 @@
+/// What is done with characters XML 1.0 doesn't allow in the content of a
+/// payload, SANITIZE_CONTENT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanitize {
+    // Sent as they are, with a warning
+    Reject,
+    Strip,
+    // Replaced with numeric character references
+    Escape,
+}
+
+impl Sanitize {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "reject" => Ok(Sanitize::Reject),
+            "strip" => Ok(Sanitize::Strip),
+            "escape" => Ok(Sanitize::Escape),
+            other => Err(format!("Invalid SANITIZE_CONTENT '{}': expected 'reject', 'strip' or 'escape'", other)),
+        }
+    }
+}
+
+/// The characters not allowed that were found in a text.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Findings {
+    pub count: usize,
+    // The first one, with its byte offset
+    pub first: Option<(char, usize)>,
+}
+
+/// Handle the characters of `text` that aren't XML 1.0 `Char`s as `mode`
+/// says: the C0 controls other than tab, line feed and carriage return, and
+/// U+FFFE and U+FFFF. Surrogates can't occur in a `String`.
+///
+/// One pass over the bytes: a control character is a single byte in UTF-8
+/// and never part of another character, and U+FFFE and U+FFFF are the only
+/// characters encoded as `EF BF BE` and `EF BF BF`. Text without any is
+/// returned as it is, without copying.
+pub fn sanitize(text: String, mode: Sanitize) -> (String, Findings) {
+    let bytes = text.as_bytes();
+    let mut findings = Findings::default();
+    let mut output: Option<String> = None;
+    // End of the text already copied to the output
+    let mut copied = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        let Some((character, len)) = disallowed_at(bytes, index) else {
+            index += 1;
+            continue;
+        };
+        findings.count += 1;
+        findings.first.get_or_insert((character, index));
+        if mode != Sanitize::Reject {
+            let output = output.get_or_insert_with(|| String::with_capacity(text.len()));
+            output.push_str(&text[copied..index]);
+            if mode == Sanitize::Escape {
+                output.push_str(&format!("&#x{:X};", character as u32));
+            }
+            copied = index + len;
+        }
+        index += len;
+    }
+    match output {
+        Some(mut output) => {
+            output.push_str(&text[copied..]);
+            (output, findings)
+        }
+        None => (text, findings),
+    }
+}
+
+// The character starting at `index` and its length in bytes, if XML 1.0
+// doesn't allow it
+fn disallowed_at(bytes: &[u8], index: usize) -> Option<(char, usize)> {
+    match bytes[index] {
+        byte @ (0x00..=0x08 | 0x0B | 0x0C | 0x0E..=0x1F) => Some((byte as char, 1)),
+        0xEF => match bytes.get(index + 1..index + 3) {
+            Some([0xBF, 0xBE]) => Some(('\u{FFFE}', 3)),
+            Some([0xBF, 0xBF]) => Some(('\u{FFFF}', 3)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every character XML 1.0 doesn't allow, in <raw>, after allowed ones
+    // that come close
+    const FIXTURE: &str = include_str!("../tests/fixtures/illegal-chars.xml");
+
+    fn disallowed() -> Vec<char> {
+        let controls = (0x00..=0x08).chain([0x0B, 0x0C]).chain(0x0E..=0x1F);
+        controls.map(|c| char::from_u32(c).unwrap()).chain(['\u{FFFE}', '\u{FFFF}']).collect()
+    }
+
+    #[test]
+    fn every_disallowed_character_is_found() {
+        let (text, findings) = sanitize(FIXTURE.to_string(), Sanitize::Reject);
+        assert_eq!(text, FIXTURE);
+        assert_eq!(findings.count, disallowed().len());
+        assert_eq!(findings.first, Some(('\0', FIXTURE.find("<raw>").unwrap() + "<raw>".len())));
+    }
+
+    #[test]
+    fn strip_removes_only_disallowed_characters() {
+        let (text, findings) = sanitize(FIXTURE.to_string(), Sanitize::Strip);
+        assert_eq!(findings.count, 31);
+        assert_eq!(text, FIXTURE.replace(|c| disallowed().contains(&c), ""));
+        assert!(text.contains("<raw></raw>"));
+        assert!(text.contains("\t<note>allowed: tab, CR\r, LF, \u{FFFD} \u{EFBF} \u{1F4E6}</note>"));
+    }
+
+    #[test]
+    fn escape_writes_character_references() {
+        let (text, findings) = sanitize(FIXTURE.to_string(), Sanitize::Escape);
+        assert_eq!(findings.count, 31);
+        let references: String = disallowed().iter().map(|c| format!("&#x{:X};", *c as u32)).collect();
+        assert!(text.contains(&format!("<raw>{}</raw>", references)));
+        assert!(references.starts_with("&#x0;&#x1;") && references.ends_with("&#x1F;&#xFFFE;&#xFFFF;"));
+    }
+
+    #[test]
+    fn clean_text_is_unchanged() {
+        let (text, findings) = sanitize("<a>\u{FFFD}\u{10FFFF}</a>".to_string(), Sanitize::Escape);
+        assert_eq!((text.as_str(), findings.count, findings.first), ("<a>\u{FFFD}\u{10FFFF}</a>", 0, None));
+    }
+
+    #[test]
+    fn modes_parse_in_any_case() {
+        assert_eq!(Sanitize::parse("STRIP"), Ok(Sanitize::Strip));
+        assert_eq!(Sanitize::parse("escape"), Ok(Sanitize::Escape));
+        assert!(Sanitize::parse("drop").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::write_guard::write_atomically;
+
+/// The `sequence` numbers given to payloads with INCLUDE_SEQUENCE, starting
+/// at 1.
+///
+/// With a state file the last number is saved before each new one is handed
+/// out, so numbers continue after a restart. Without one they start over.
+pub struct Sequence {
+    path: Option<PathBuf>,
+    last: Mutex<u64>,
+}
+
+impl Sequence {
+    /// Continue from the number saved in `path`, if there is one.
+    pub fn open(path: Option<&Path>) -> Result<Self, String> {
+        let last = match path {
+            Some(path) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+                }
+                match std::fs::read_to_string(path) {
+                    Ok(text) => text
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("{} does not contain a sequence number", path.display()))?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+                }
+            }
+            None => 0,
+        };
+        Ok(Sequence {
+            path: path.map(Path::to_path_buf),
+            last: Mutex::new(last),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn last(&self) -> u64 {
+        *self.last.lock().unwrap()
+    }
+
+    /// The next number. A number that can't be saved is still used, so a
+    /// restart after the failure may repeat it.
+    pub fn next(&self) -> u64 {
+        let mut last = self.last.lock().unwrap();
+        *last += 1;
+        if let Err(e) = self.save(*last) {
+            log::error!("{}", e);
+        }
+        *last
+    }
+
+    fn save(&self, number: u64) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_atomically(path, number.to_string().as_bytes())
+    }
+}
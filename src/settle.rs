@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::write_guard::write_atomically;
+
+// The settle delay of fixed mode, and of directories without observations
+pub const FIXED_DELAY: Duration = Duration::from_millis(500);
+
+// Observations kept per directory, the most recent ones
+const WINDOW: usize = 50;
+
+// Directories learned at most
+const MAX_TRACKED_DIRS: usize = 1000;
+
+/// The bounds and percentile of SETTLE_MODE=adaptive.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptivePolicy {
+    // 1 to 100
+    pub percentile: u8,
+    pub min: Duration,
+    pub max: Duration,
+    // How often a settling file's size is looked at; a file whose size stayed
+    // the same from one look to the next has stopped growing
+    pub poll: Duration,
+}
+
+/// Settle delays learned per directory, from how long files in it took
+/// after their event to stop growing.
+///
+/// A directory's delay is the policy's percentile of its last 50
+/// observations, within the policy's bounds. With a state file, the
+/// observations are saved after each new one and loaded at startup.
+pub struct SettleLearner {
+    policy: AdaptivePolicy,
+    path: Option<PathBuf>,
+    dirs: Mutex<HashMap<PathBuf, VecDeque<u64>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedObservations {
+    // Milliseconds, oldest first, per directory
+    directories: BTreeMap<PathBuf, Vec<u64>>,
+}
+
+impl SettleLearner {
+    pub fn new(policy: AdaptivePolicy) -> Self {
+        SettleLearner {
+            policy,
+            path: None,
+            dirs: Mutex::default(),
+        }
+    }
+
+    /// Continue from the observations saved in `path`, if there are any.
+    pub fn open(policy: AdaptivePolicy, path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+        }
+        let saved: SavedObservations = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SavedObservations {
+                directories: BTreeMap::new(),
+            },
+            Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+        };
+        let dirs = saved
+            .directories
+            .into_iter()
+            .take(MAX_TRACKED_DIRS)
+            .map(|(dir, observations)| {
+                let skip = observations.len().saturating_sub(WINDOW);
+                (dir, observations.into_iter().skip(skip).collect())
+            })
+            .collect();
+        Ok(SettleLearner {
+            policy,
+            path: Some(path.to_path_buf()),
+            dirs: Mutex::new(dirs),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn policy(&self) -> &AdaptivePolicy {
+        &self.policy
+    }
+
+    /// Number of directories with observations.
+    pub fn len(&self) -> usize {
+        self.dirs.lock().unwrap().len()
+    }
+
+    /// The settle delay of files in `dir`.
+    pub fn delay(&self, dir: &Path) -> Duration {
+        let dirs = self.dirs.lock().unwrap();
+        self.delay_of(dirs.get(dir))
+    }
+
+    fn delay_of(&self, observations: Option<&VecDeque<u64>>) -> Duration {
+        let learned = match observations {
+            Some(observations) => Duration::from_millis(percentile(observations, self.policy.percentile)),
+            None => FIXED_DELAY,
+        };
+        learned.clamp(self.policy.min, self.policy.max)
+    }
+
+    /// Record that a file in `dir` stopped growing `settled` after its event.
+    /// An observation that can't be saved is still used.
+    pub fn observe(&self, dir: &Path, settled: Duration) {
+        let mut dirs = self.dirs.lock().unwrap();
+        if dirs.len() >= MAX_TRACKED_DIRS && !dirs.contains_key(dir) {
+            // Any entry will do: the cost of losing one is relearning it
+            if let Some(evicted) = dirs.keys().next().cloned() {
+                dirs.remove(&evicted);
+            }
+        }
+        let observations = dirs.entry(dir.to_path_buf()).or_default();
+        if observations.len() == WINDOW {
+            observations.pop_front();
+        }
+        observations.push_back(settled.as_millis() as u64);
+        if let Err(e) = self.save(&dirs) {
+            log::error!("{}", e);
+        }
+    }
+
+    /// The learned delays, for `GET /settle`.
+    pub fn summary(&self) -> serde_json::Value {
+        let dirs = self.dirs.lock().unwrap();
+        let mut directories: Vec<_> = dirs
+            .iter()
+            .map(|(dir, observations)| {
+                serde_json::json!({
+                    "directory": dir.display().to_string(),
+                    "delay_ms": self.delay_of(Some(observations)).as_millis() as u64,
+                    "observations": observations.len(),
+                    "median_ms": percentile(observations, 50),
+                    "max_ms": observations.iter().max().copied().unwrap_or_default(),
+                })
+            })
+            .collect();
+        directories.sort_by(|a, b| a["directory"].as_str().cmp(&b["directory"].as_str()));
+        serde_json::json!({
+            "percentile": self.policy.percentile,
+            "min_ms": self.policy.min.as_millis() as u64,
+            "max_ms": self.policy.max.as_millis() as u64,
+            "poll_ms": self.policy.poll.as_millis() as u64,
+            "default_ms": self.delay_of(None).as_millis() as u64,
+            "directories": directories,
+        })
+    }
+
+    fn save(&self, dirs: &HashMap<PathBuf, VecDeque<u64>>) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved = SavedObservations {
+            directories: dirs
+                .iter()
+                .map(|(dir, observations)| (dir.clone(), observations.iter().copied().collect()))
+                .collect(),
+        };
+        let data = serde_json::to_vec(&saved).map_err(|e| e.to_string())?;
+        write_atomically(path, &data)
+    }
+}
+
+// The nearest-rank `p`th percentile of `observations`, 0 when there are none
+fn percentile(observations: &VecDeque<u64>, p: u8) -> u64 {
+    let mut sorted: Vec<u64> = observations.iter().copied().collect();
+    sorted.sort_unstable();
+    let rank = (sorted.len() * p as usize).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(percentile: u8) -> AdaptivePolicy {
+        AdaptivePolicy {
+            percentile,
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            poll: Duration::from_millis(100),
+        }
+    }
+
+    fn observe_all(learner: &SettleLearner, dir: &str, millis: impl IntoIterator<Item = u64>) {
+        for ms in millis {
+            learner.observe(Path::new(dir), Duration::from_millis(ms));
+        }
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let observations: VecDeque<u64> = (1..=10).rev().map(|n| n * 100).collect();
+        assert_eq!(percentile(&observations, 1), 100);
+        assert_eq!(percentile(&observations, 50), 500);
+        assert_eq!(percentile(&observations, 90), 900);
+        assert_eq!(percentile(&observations, 91), 1000);
+        assert_eq!(percentile(&observations, 100), 1000);
+        assert_eq!(percentile(&VecDeque::new(), 90), 0);
+    }
+
+    #[test]
+    fn each_directory_learns_its_own_delay() {
+        let learner = SettleLearner::new(policy(90));
+        assert_eq!(learner.delay(Path::new("/watch/edi")), FIXED_DELAY);
+
+        observe_all(&learner, "/watch/edi", [120, 150, 130]);
+        // Streams of 80 MB, with one slow outlier above the percentile
+        observe_all(&learner, "/watch/imaging", (0..9).map(|n| 18_000 + n * 100).chain([60_000]));
+        assert_eq!(learner.delay(Path::new("/watch/edi")), Duration::from_millis(150));
+        assert_eq!(learner.delay(Path::new("/watch/imaging")), Duration::from_millis(18_800));
+        assert_eq!(learner.len(), 2);
+    }
+
+    #[test]
+    fn delays_stay_within_the_bounds() {
+        let learner = SettleLearner::new(policy(50));
+        observe_all(&learner, "/watch/fast", [0, 5, 10]);
+        observe_all(&learner, "/watch/slow", [120_000]);
+        assert_eq!(learner.delay(Path::new("/watch/fast")), Duration::from_millis(100));
+        assert_eq!(learner.delay(Path::new("/watch/slow")), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn only_recent_observations_count() {
+        let learner = SettleLearner::new(policy(100));
+        observe_all(&learner, "/watch", [20_000]);
+        observe_all(&learner, "/watch", std::iter::repeat_n(200, WINDOW));
+        assert_eq!(learner.delay(Path::new("/watch")), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn observations_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/settle.json");
+        let learner = SettleLearner::open(policy(50), &path).unwrap();
+        observe_all(&learner, "/watch/a", [300, 400, 500]);
+        drop(learner);
+
+        let learner = SettleLearner::open(policy(100), &path).unwrap();
+        assert_eq!(learner.delay(Path::new("/watch/a")), Duration::from_millis(500));
+        let summary = learner.summary();
+        assert_eq!(summary["directories"][0]["observations"], 3);
+        assert_eq!(summary["directories"][0]["median_ms"], 400);
+        assert_eq!(summary["default_ms"], 500);
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(SettleLearner::open(policy(50), &path).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+use crate::body_match::BodyMatch;
+use crate::sensitive::{self, SensitiveString};
+
+#[derive(Default)]
+struct TargetStats {
+    attempts: u64,
+    successes: u64,
+    // Status code, or "error" for requests without a response
+    statuses: BTreeMap<String, u64>,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+impl TargetStats {
+    fn record(&mut self, success: bool, status: Option<u16>, latency: Duration) {
+        self.attempts += 1;
+        if success {
+            self.successes += 1;
+        }
+        let status = status.map(|s| s.to_string()).unwrap_or_else(|| "error".to_string());
+        *self.statuses.entry(status).or_default() += 1;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+}
+
+impl fmt::Display for TargetStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.attempts == 0 {
+            return write!(f, "no requests");
+        }
+        let statuses: Vec<String> = self.statuses.iter().map(|(status, n)| format!("{}={}", status, n)).collect();
+        write!(
+            f,
+            "{}/{} ok ({:.1}%), avg {} ms, max {} ms, statuses {}",
+            self.successes,
+            self.attempts,
+            self.successes as f64 * 100.0 / self.attempts as f64,
+            self.total_latency.as_millis() / self.attempts as u128,
+            self.max_latency.as_millis(),
+            statuses.join(" ")
+        )
+    }
+}
+
+#[derive(Default)]
+struct Comparison {
+    primary: TargetStats,
+    shadow: TargetStats,
+    // Shadow requests not sent because all shadow slots were busy
+    dropped: u64,
+    // Totals at the last report, to skip reports when nothing happened
+    reported: (u64, u64, u64),
+}
+
+/// A second webhook endpoint that gets a copy of every request, for trying out
+/// a new receiver before cutting over to it.
+///
+/// Shadow requests are sent once, in the background, with their own
+/// concurrency limit; when it is reached the copy is dropped rather than
+/// queued. Their results are only logged and counted, never acted upon.
+pub struct Shadow {
+    url: SensitiveString,
+    slots: Arc<Semaphore>,
+    stats: Arc<Mutex<Comparison>>,
+}
+
+impl Shadow {
+    pub fn new(url: SensitiveString, max_concurrent: usize) -> Self {
+        Shadow {
+            url,
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            stats: Arc::default(),
+        }
+    }
+
+    pub fn url(&self) -> &SensitiveString {
+        &self.url
+    }
+
+    /// Count the result of a request to the primary endpoint.
+    pub fn record_primary(&self, success: bool, status: Option<u16>, latency: Duration) {
+        self.stats.lock().unwrap().primary.record(success, status, latency);
+    }
+
+    /// Send `request`, already addressed to the shadow URL, in the background.
+    ///
+    /// It succeeds on a 2xx response that also passes `body_match`, if given.
+    pub fn send(&self, request: reqwest::RequestBuilder, body_match: Option<BodyMatch>, prefix: String) {
+        let Ok(slot) = Arc::clone(&self.slots).try_acquire_owned() else {
+            self.stats.lock().unwrap().dropped += 1;
+            debug!("{}  target: shadow, dropped (all shadow slots busy)", prefix);
+            return;
+        };
+        let stats = Arc::clone(&self.stats);
+        tokio::spawn(async move {
+            let _slot = slot;
+            let started = Instant::now();
+            let (success, status, detail) = match request.send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    let content_type = response
+                        .headers()
+                        .get("content-type")
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("")
+                        .to_string();
+                    let check = match (&body_match, status.is_success()) {
+                        (Some(rule), true) => match response.bytes().await {
+                            Ok(body) => rule.check(&content_type, &body).err(),
+                            Err(e) => Some(format!("failed to read response body: {}", e)),
+                        },
+                        _ => None,
+                    };
+                    match check {
+                        Some(detail) => (false, Some(status.as_u16()), format!(", response body check failed: {}", detail)),
+                        None => (status.is_success(), Some(status.as_u16()), String::new()),
+                    }
+                }
+                Err(e) => (false, None, format!(": {}", sensitive::redact_error(e))),
+            };
+            let latency = started.elapsed();
+            stats.lock().unwrap().shadow.record(success, status, latency);
+
+            let status = status.map(|s| format!("HTTP {}", s)).unwrap_or_else(|| "request failed".to_string());
+            if success {
+                info!("{}  target: shadow, {} in {} ms", prefix, status, latency.as_millis());
+            } else {
+                warn!("{}  target: shadow, {} in {} ms{}", prefix, status, latency.as_millis(), detail);
+            }
+        });
+    }
+
+    /// Primary and shadow results so far, or `None` if nothing happened since
+    /// the last report.
+    pub fn report(&self) -> Option<String> {
+        let mut stats = self.stats.lock().unwrap();
+        let totals = (stats.primary.attempts, stats.shadow.attempts, stats.dropped);
+        if totals == stats.reported {
+            return None;
+        }
+        stats.reported = totals;
+        Some(format!(
+            "primary: {}; shadow: {}; {} shadow requests dropped",
+            stats.primary, stats.shadow, stats.dropped
+        ))
+    }
+}
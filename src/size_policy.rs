@@ -0,0 +1,261 @@
+use crate::content_server::ContentMode;
+use std::fmt;
+
+// Units of tier bounds, largest first; binary multiples
+const UNITS: &[(&str, u64)] = &[
+    ("TB", 1 << 40),
+    ("GB", 1 << 30),
+    ("MB", 1 << 20),
+    ("KB", 1 << 10),
+    ("B", 1),
+];
+
+/// What is done with a file in a SIZE_POLICY tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeAction {
+    // The content in the payload
+    Inline,
+    // A `content_url` to fetch the content from
+    Reference,
+    // The payload without content
+    Metadata,
+    // Not delivered, reported as skipped with an error
+    SkipAlert,
+}
+
+impl SizeAction {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "inline" => Ok(SizeAction::Inline),
+            "reference" => Ok(SizeAction::Reference),
+            "metadata" => Ok(SizeAction::Metadata),
+            "skip_alert" => Ok(SizeAction::SkipAlert),
+            other => Err(format!(
+                "Invalid SIZE_POLICY action '{}': expected 'inline', 'reference', 'metadata' or 'skip_alert'",
+                other
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SizeAction::Inline => "inline",
+            SizeAction::Reference => "reference",
+            SizeAction::Metadata => "metadata",
+            SizeAction::SkipAlert => "skip_alert",
+        }
+    }
+
+    /// How the content is sent, `None` for not at all.
+    pub fn content_mode(&self) -> Option<ContentMode> {
+        match self {
+            SizeAction::Inline => Some(ContentMode::Inline),
+            SizeAction::Reference => Some(ContentMode::Reference),
+            SizeAction::Metadata | SizeAction::SkipAlert => None,
+        }
+    }
+}
+
+/// Sizes from `min` up to, but not including, `max` (unbounded when `None`).
+#[derive(Debug, Clone)]
+pub struct SizeTier {
+    pub min: u64,
+    pub max: Option<u64>,
+    pub action: SizeAction,
+}
+
+impl fmt::Display for SizeTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max = self.max.map(format_size).unwrap_or_default();
+        write!(f, "{}-{}:{}", format_size(self.min), max, self.action.as_str())
+    }
+}
+
+/// Tiers by file size, SIZE_POLICY, e.g.
+/// `0-1MB:inline,1MB-100MB:metadata,100MB-:skip_alert`.
+///
+/// The tiers cover every size exactly once: the first starts at 0, each
+/// starts where the previous one ends, and the last is open-ended.
+#[derive(Debug, Clone)]
+pub struct SizePolicy {
+    tiers: Vec<SizeTier>,
+}
+
+impl SizePolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut tiers: Vec<SizeTier> = Vec::new();
+        for spec in value.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+            let tier = parse_tier(spec)?;
+            let expected_min = match tiers.last() {
+                None => 0,
+                Some(SizeTier { max: None, .. }) => {
+                    return Err(format!("SIZE_POLICY tier '{}' comes after the open-ended tier, which must be last", spec));
+                }
+                Some(SizeTier { max: Some(max), .. }) => *max,
+            };
+            if tier.min != expected_min {
+                let problem = if tier.min < expected_min { "overlaps the previous tier" } else { "leaves a gap" };
+                return Err(format!(
+                    "SIZE_POLICY tier '{}' {}: it must start at {}",
+                    spec,
+                    problem,
+                    format_size(expected_min)
+                ));
+            }
+            tiers.push(tier);
+        }
+        match tiers.last() {
+            None => Err("SIZE_POLICY has no tiers".to_string()),
+            Some(SizeTier { max: Some(max), .. }) => Err(format!(
+                "SIZE_POLICY must cover every size: add a last tier such as '{}-:skip_alert'",
+                format_size(*max)
+            )),
+            Some(_) => Ok(SizePolicy { tiers }),
+        }
+    }
+
+    /// The tier a file of `size` bytes falls in.
+    pub fn tier(&self, size: u64) -> &SizeTier {
+        self.tiers
+            .iter()
+            .find(|tier| tier.max.is_none_or(|max| size < max))
+            .expect("the last tier is open-ended")
+    }
+
+    pub fn uses(&self, action: SizeAction) -> bool {
+        self.tiers.iter().any(|tier| tier.action == action)
+    }
+}
+
+impl fmt::Display for SizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tiers: Vec<String> = self.tiers.iter().map(|tier| tier.to_string()).collect();
+        f.write_str(&tiers.join(","))
+    }
+}
+
+fn parse_tier(spec: &str) -> Result<SizeTier, String> {
+    let malformed = || format!("Invalid SIZE_POLICY tier '{}': expected '<from>-<to>:<action>', e.g. '0-1MB:inline'", spec);
+    let (range, action) = spec.split_once(':').ok_or_else(malformed)?;
+    let (min, max) = range.split_once('-').ok_or_else(malformed)?;
+    let min = parse_size(min.trim(), spec)?;
+    let max = match max.trim() {
+        "" => None,
+        max => Some(parse_size(max, spec)?),
+    };
+    if max.is_some_and(|max| max <= min) {
+        return Err(format!("SIZE_POLICY tier '{}' ends before it starts", spec));
+    }
+    Ok(SizeTier {
+        min,
+        max,
+        action: SizeAction::parse(action.trim())?,
+    })
+}
+
+// A size such as `0`, `512KB` or `100MB`
+fn parse_size(value: &str, spec: &str) -> Result<u64, String> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let invalid = || format!("Invalid size '{}' in SIZE_POLICY tier '{}': expected a number of B, KB, MB, GB or TB", value, spec);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let multiplier = match unit.trim() {
+        "" => 1,
+        unit => UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(invalid)?,
+    };
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+// The largest unit that `bytes` is a whole number of
+fn format_size(bytes: u64) -> String {
+    if bytes == 0 {
+        return "0".to_string();
+    }
+    let (unit, multiplier) = UNITS
+        .iter()
+        .find(|(_, multiplier)| bytes.is_multiple_of(*multiplier))
+        .expect("every size is a whole number of bytes");
+    format!("{}{}", bytes / multiplier, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = "0-1MB:inline, 1MB-100MB:reference, 100MB-1GB:metadata, 1GB-:skip_alert";
+
+    #[test]
+    fn files_fall_in_the_tier_their_size_is_in() {
+        let policy = SizePolicy::parse(POLICY).unwrap();
+        let action = |size: u64| policy.tier(size).action;
+        assert_eq!(action(0), SizeAction::Inline);
+        assert_eq!(action((1 << 20) - 1), SizeAction::Inline);
+        assert_eq!(action(1 << 20), SizeAction::Reference);
+        assert_eq!(action(100 << 20), SizeAction::Metadata);
+        assert_eq!(action((1 << 30) - 1), SizeAction::Metadata);
+        assert_eq!(action(1 << 30), SizeAction::SkipAlert);
+        assert_eq!(action(u64::MAX), SizeAction::SkipAlert);
+        assert!(policy.uses(SizeAction::Reference));
+        assert!(!SizePolicy::parse("0-:inline").unwrap().uses(SizeAction::Reference));
+    }
+
+    #[test]
+    fn each_action_sends_its_own_content() {
+        assert_eq!(SizeAction::Inline.content_mode(), Some(ContentMode::Inline));
+        assert_eq!(SizeAction::Reference.content_mode(), Some(ContentMode::Reference));
+        assert_eq!(SizeAction::Metadata.content_mode(), None);
+        assert_eq!(SizeAction::SkipAlert.content_mode(), None);
+        for action in ["inline", "reference", "metadata", "skip_alert"] {
+            assert_eq!(SizeAction::parse(action).unwrap().as_str(), action);
+        }
+    }
+
+    #[test]
+    fn policies_are_shown_in_their_largest_units() {
+        let policy = SizePolicy::parse("0-1024:inline,1kb-1536KB:reference,1536KB-:metadata").unwrap();
+        assert_eq!(policy.to_string(), "0-1KB:inline,1KB-1536KB:reference,1536KB-:metadata");
+        assert_eq!(SizePolicy::parse(POLICY).unwrap().to_string(), POLICY.replace(", ", ","));
+    }
+
+    #[test]
+    fn tiers_must_cover_every_size_once() {
+        let error = |value: &str| SizePolicy::parse(value).unwrap_err();
+        assert_eq!(error(""), "SIZE_POLICY has no tiers");
+        assert_eq!(error("1KB-:inline"), "SIZE_POLICY tier '1KB-:inline' leaves a gap: it must start at 0");
+        assert_eq!(
+            error("0-2MB:inline,1MB-:metadata"),
+            "SIZE_POLICY tier '1MB-:metadata' overlaps the previous tier: it must start at 2MB"
+        );
+        assert_eq!(
+            error("0-1MB:inline,2MB-:metadata"),
+            "SIZE_POLICY tier '2MB-:metadata' leaves a gap: it must start at 1MB"
+        );
+        assert_eq!(
+            error("0-1MB:inline"),
+            "SIZE_POLICY must cover every size: add a last tier such as '1MB-:skip_alert'"
+        );
+        assert_eq!(
+            error("0-:inline,1MB-:metadata"),
+            "SIZE_POLICY tier '1MB-:metadata' comes after the open-ended tier, which must be last"
+        );
+    }
+
+    #[test]
+    fn malformed_tiers_are_rejected() {
+        let error = |value: &str| SizePolicy::parse(value).unwrap_err();
+        assert!(error("0-1MB").starts_with("Invalid SIZE_POLICY tier '0-1MB': expected"));
+        assert!(error("0:inline").starts_with("Invalid SIZE_POLICY tier '0:inline': expected"));
+        assert_eq!(
+            error("0-1MB:inline,1MB-1MB:metadata"),
+            "SIZE_POLICY tier '1MB-1MB:metadata' ends before it starts"
+        );
+        assert!(error("0-1XB:inline").starts_with("Invalid size '1XB'"));
+        assert!(error("0-1.5MB:inline").starts_with("Invalid size '1.5MB'"));
+        assert!(error("0-99999999TB:inline").starts_with("Invalid size '99999999TB'"));
+        assert!(error("0-:drop").starts_with("Invalid SIZE_POLICY action 'drop'"));
+    }
+}
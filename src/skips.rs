@@ -0,0 +1,119 @@
+use chrono::Utc;
+use log::debug;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Skips kept for `GET /skips/recent`; older ones are only counted
+const RECENT_SKIPS: usize = 500;
+
+/// Why a file the watcher saw was not delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    // Neither an XML file nor, with BUNDLE_EXTENSIONS, a bundle
+    NotWatchedExtension,
+    // Hidden, or still being written under a temporary name, with WATCH_ALL_FILES
+    TemporaryName,
+    // Renamed from one watched name to another, so not new
+    RenamedFromWatchedName,
+    // Renamed to another spelling of the same name on a case-insensitive
+    // file system, so still the same file
+    CaseOnlyRename,
+    // Gone, or a directory, by the time the event was handled
+    NotAFile,
+    // In BACKUP_DIR or QUARANTINE_DIR below the watch directory
+    InternalDirectory,
+    // Inside the watch directory of a more specific profile, which delivers it
+    OtherProfile,
+    MaxWatchDepth,
+    // Written by the watcher itself, e.g. a response overwriting the file
+    WrittenByWatcher,
+    BelowMinContentBytes,
+    // Encoded in UTF-16, with UTF16_XML=skip
+    Utf16Content,
+    PreDeliveryHookFailed,
+    // A hard link to an already delivered file, with HARDLINK_POLICY=suppress
+    DuplicateHardlink,
+    // SPLIT_ON_ELEMENT found nothing to deliver
+    NoSplitElements,
+    // In a SIZE_POLICY tier with the skip_alert action
+    SizePolicy,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::NotWatchedExtension => "not_watched_extension",
+            SkipReason::TemporaryName => "temporary_name",
+            SkipReason::RenamedFromWatchedName => "renamed_from_watched_name",
+            SkipReason::CaseOnlyRename => "case_only_rename",
+            SkipReason::NotAFile => "not_a_file",
+            SkipReason::InternalDirectory => "internal_directory",
+            SkipReason::OtherProfile => "other_profile",
+            SkipReason::MaxWatchDepth => "max_watch_depth",
+            SkipReason::WrittenByWatcher => "written_by_watcher",
+            SkipReason::BelowMinContentBytes => "below_min_content_bytes",
+            SkipReason::Utf16Content => "utf16_content",
+            SkipReason::PreDeliveryHookFailed => "pre_delivery_hook_failed",
+            SkipReason::DuplicateHardlink => "duplicate_hardlink",
+            SkipReason::NoSplitElements => "no_split_elements",
+            SkipReason::SizePolicy => "size_policy",
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Skip {
+    path: String,
+    reason: SkipReason,
+    skipped_at: String,
+}
+
+#[derive(Default)]
+struct Log {
+    counts: BTreeMap<SkipReason, u64>,
+    recent: VecDeque<Skip>,
+}
+
+/// Counts skipped files per reason and keeps the most recent ones.
+pub struct Skips {
+    prefix: String,
+    log: Mutex<Log>,
+}
+
+impl Skips {
+    pub fn new(prefix: String) -> Self {
+        Skips { prefix, log: Mutex::new(Log::default()) }
+    }
+
+    pub fn record(&self, path: &Path, reason: SkipReason) {
+        debug!("{}skipped path={} reason={}", self.prefix, path.display(), reason);
+        let mut log = self.log.lock().unwrap();
+        *log.counts.entry(reason).or_default() += 1;
+        if log.recent.len() == RECENT_SKIPS {
+            log.recent.pop_front();
+        }
+        log.recent.push_back(Skip {
+            path: path.display().to_string(),
+            reason,
+            skipped_at: Utc::now().to_rfc3339(),
+        });
+    }
+
+    /// Counts since startup and the recent skips, newest first.
+    pub fn summary(&self) -> serde_json::Value {
+        let log = self.log.lock().unwrap();
+        let counts: BTreeMap<&str, u64> = log.counts.iter().map(|(reason, count)| (reason.as_str(), *count)).collect();
+        let recent: Vec<&Skip> = log.recent.iter().rev().collect();
+        serde_json::json!({ "counts": counts, "recent": recent })
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::storage::QueueStore;
+
+/// Paths waiting for delivery that didn't fit in memory, one per line in a file.
+///
+/// Entries are only removed from the file once every one of them has been
+/// handed out and completed.
+pub struct SpillQueue {
+    path: PathBuf,
+    state: Mutex<SpillState>,
+}
+
+struct SpillState {
+    // Where the next entry to hand out starts
+    read_offset: u64,
+    // Entries in the file that haven't been handed out
+    pending: usize,
+    // Entries handed out and not yet completed
+    in_flight: usize,
+}
+
+impl SpillQueue {
+    /// Open the queue file, picking up entries left by a previous run.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+        }
+        let pending = match File::open(path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| !line.is_empty())
+                .count(),
+            Err(_) => 0,
+        };
+        Ok(SpillQueue {
+            path: path.to_path_buf(),
+            state: Mutex::new(SpillState { read_offset: 0, pending, in_flight: 0 }),
+        })
+    }
+
+    // Start the file over once everything in it has been processed
+    fn reset_if_done(&self, state: &mut SpillState) -> Result<(), String> {
+        if state.pending > 0 || state.in_flight > 0 || state.read_offset == 0 {
+            return Ok(());
+        }
+        File::create(&self.path).map_err(|e| format!("failed to truncate {}: {}", self.path.display(), e))?;
+        state.read_offset = 0;
+        Ok(())
+    }
+}
+
+impl QueueStore for SpillQueue {
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().pending
+    }
+
+    /// Append a path. Paths that can't be stored as a single line are refused.
+    fn push(&self, item: &Path) -> Result<(), String> {
+        let line = item
+            .to_str()
+            .filter(|line| !line.contains('\n') && !line.is_empty())
+            .ok_or_else(|| format!("{} can't be written to the spill queue", item.display()))?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("failed to open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{}", line).map_err(|e| format!("failed to write {}: {}", self.path.display(), e))?;
+        state.pending += 1;
+        Ok(())
+    }
+
+    fn pop(&self, max: usize) -> Result<Vec<PathBuf>, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending == 0 || max == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut file = File::open(&self.path).map_err(|e| format!("failed to open {}: {}", self.path.display(), e))?;
+        file.seek(SeekFrom::Start(state.read_offset))
+            .map_err(|e| format!("failed to read {}: {}", self.path.display(), e))?;
+        let mut reader = BufReader::new(file);
+
+        let mut items = Vec::new();
+        let mut line = String::new();
+        while items.len() < max {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .map_err(|e| format!("failed to read {}: {}", self.path.display(), e))?;
+            if read == 0 {
+                break;
+            }
+            state.read_offset += read as u64;
+            let entry = line.trim_end_matches('\n');
+            if !entry.is_empty() {
+                items.push(PathBuf::from(entry));
+            }
+        }
+        state.pending = state.pending.saturating_sub(items.len());
+        if items.is_empty() {
+            state.pending = 0;
+        }
+        state.in_flight += items.len();
+        self.reset_if_done(&mut state)?;
+        Ok(items)
+    }
+
+    fn complete(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        self.reset_if_done(&mut state)
+    }
+}
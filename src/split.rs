@@ -0,0 +1,116 @@
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::QName;
+use quick_xml::{Reader, Writer};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// Namespace declarations (`xmlns`, `xmlns:p`) as written on an element
+type Declarations = Vec<(String, String)>;
+
+/// Stream the XML file at `path` and pass every `element` (matched by local
+/// name) to `emit` as a standalone document, one at a time.
+///
+/// Only outermost matches are emitted; a match nested inside another stays
+/// part of it. Namespace declarations the fragment relies on from its
+/// ancestors are copied onto its root element. `emit` returns `false` to stop
+/// early. Returns the number of fragments emitted.
+pub fn split_file(path: &Path, element: &str, mut emit: impl FnMut(Vec<u8>) -> bool) -> Result<usize, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+    let mut reader = Reader::from_reader(BufReader::new(file));
+
+    let mut buf = Vec::new();
+    // Declarations of each open element above the current position
+    let mut ancestors: Vec<Declarations> = Vec::new();
+    // The fragment being copied and the depth of open elements inside it
+    let mut fragment: Option<(Writer<Vec<u8>>, usize)> = None;
+    let mut count = 0;
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("invalid XML at byte {}: {}", reader.error_position(), e))?;
+
+        match (&mut fragment, event) {
+            (_, Event::Eof) => break,
+            (None, Event::Start(start)) if start.local_name().as_ref() == element => {
+                let mut writer = Writer::new(Vec::new());
+                write(&mut writer, Event::Start(with_inherited_namespaces(start, &ancestors)))?;
+                fragment = Some((writer, 1));
+            }
+            (None, Event::Empty(start)) if start.local_name().as_ref() == element => {
+                let mut writer = Writer::new(Vec::new());
+                write(&mut writer, Event::Empty(with_inherited_namespaces(start, &ancestors)))?;
+                count += 1;
+                if !emit(writer.into_inner()) {
+                    return Ok(count);
+                }
+            }
+            (None, Event::Start(start)) => ancestors.push(declarations(&start)),
+            (None, Event::End(_)) => {
+                ancestors.pop();
+            }
+            (None, _) => {}
+            (Some((writer, depth)), event) => {
+                match &event {
+                    Event::Start(_) => *depth += 1,
+                    Event::End(_) => *depth -= 1,
+                    _ => {}
+                }
+                write(writer, event)?;
+                if *depth == 0 {
+                    let (writer, _) = fragment.take().expect("fragment is open");
+                    count += 1;
+                    if !emit(writer.into_inner()) {
+                        return Ok(count);
+                    }
+                }
+            }
+        }
+        buf.clear();
+    }
+
+    if fragment.is_some() {
+        return Err(format!("file ends inside fragment {} (<{}>)", count, element));
+    }
+    Ok(count)
+}
+
+fn write(writer: &mut Writer<Vec<u8>>, event: Event) -> Result<(), String> {
+    writer
+        .write_event(event)
+        .map_err(|e| format!("failed to copy fragment: {}", e))
+}
+
+fn declarations(start: &BytesStart) -> Declarations {
+    start
+        .attributes()
+        .flatten()
+        .filter(|attribute| is_namespace_declaration(attribute.key.as_ref()))
+        .map(|attribute| (attribute.key.as_ref().to_string(), attribute.value.into_owned()))
+        .collect()
+}
+
+fn is_namespace_declaration(key: &str) -> bool {
+    key == "xmlns" || key.starts_with("xmlns:")
+}
+
+// `start` with the innermost in-scope declaration of every namespace prefix
+// it doesn't declare itself
+fn with_inherited_namespaces(start: BytesStart, ancestors: &[Declarations]) -> BytesStart<'static> {
+    let mut declared: Vec<String> = declarations(&start).into_iter().map(|(key, _)| key).collect();
+    let mut start = start.into_owned();
+    for (key, value) in ancestors.iter().rev().flatten() {
+        if declared.contains(key) {
+            continue;
+        }
+        // The value is copied as written, so it must not be escaped again
+        start.push_attribute(Attribute {
+            key: QName(key),
+            value: value.as_str().into(),
+        });
+        declared.push(key.clone());
+    }
+    start
+}
@@ -0,0 +1,157 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Files are delivered anyway, with a warning, when they still change after this
+pub const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// How NFS_SAFE_MODE decides that a file is completely written.
+#[derive(Debug, Clone, Copy)]
+pub struct StabilityCheck {
+    // Bytes hashed at each end of the file; smaller files are hashed whole
+    pub sample_bytes: u64,
+    pub interval: Duration,
+}
+
+// Length and hash of the sampled bytes, as read by one check
+type Signature = (u64, [u8; 32]);
+
+impl StabilityCheck {
+    /// Read the file every `interval` until two reads in a row see the same
+    /// content. Returns the number of reads, or an error when the file can't
+    /// be read or still changes after `MAX_WAIT`.
+    ///
+    /// Each read opens the file afresh, which makes NFS clients revalidate
+    /// cached attributes (close-to-open consistency), and hashes what is
+    /// actually read instead of trusting the reported size.
+    pub async fn wait(&self, path: &Path) -> Result<u32, String> {
+        let started = Instant::now();
+        let mut previous = self.signature(path).await?;
+        let mut reads = 1;
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let current = self.signature(path).await?;
+            reads += 1;
+            if current == previous {
+                return Ok(reads);
+            }
+            if started.elapsed() >= MAX_WAIT {
+                return Err(format!("still changing after {} reads", reads));
+            }
+            previous = current;
+        }
+    }
+
+    async fn signature(&self, path: &Path) -> Result<Signature, String> {
+        let owned = path.to_path_buf();
+        let sample_bytes = self.sample_bytes;
+        tokio::task::spawn_blocking(move || sample(&owned, sample_bytes))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))
+    }
+}
+
+fn sample(path: &Path, sample_bytes: u64) -> std::io::Result<Signature> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = Vec::new();
+
+    let head = (&mut file).take(sample_bytes * 2).read_to_end(&mut buf)? as u64;
+    if head < sample_bytes * 2 {
+        hasher.update(&buf);
+        return Ok((head, hasher.finalize().into()));
+    }
+    hasher.update(&buf[..sample_bytes as usize]);
+
+    // The length is where reading stops, not what stat reports
+    let end = file.seek(SeekFrom::End(0))?;
+    let tail_start = end.saturating_sub(sample_bytes).max(sample_bytes);
+    file.seek(SeekFrom::Start(tail_start))?;
+    buf.clear();
+    let tail = file.read_to_end(&mut buf)? as u64;
+    hasher.update(&buf);
+    Ok((tail_start + tail, hasher.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, FileTimes, OpenOptions};
+    use std::io::Write;
+
+    // What a client with cached attributes sees: neither the size nor the
+    // modification time moves, only the content does
+    fn rewrite_keeping_metadata(path: &Path, offset: u64, bytes: &[u8]) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+        file.set_times(FileTimes::new().set_modified(modified)).unwrap();
+    }
+
+    #[test]
+    fn content_changes_behind_unchanged_metadata_change_the_signature() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("a.xml");
+        fs::write(&path, "<a>0000</a>").unwrap();
+        let before = sample(&path, 64).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+
+        rewrite_keeping_metadata(&path, 3, b"1111");
+        let after = fs::metadata(&path).unwrap();
+        assert_eq!((metadata.len(), metadata.modified().unwrap()), (after.len(), after.modified().unwrap()));
+        assert_ne!(sample(&path, 64).unwrap(), before);
+    }
+
+    #[test]
+    fn large_files_are_sampled_at_both_ends() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("a.xml");
+        fs::write(&path, vec![b'x'; 1000]).unwrap();
+        let whole = sample(&path, 100).unwrap();
+        assert_eq!(whole.0, 1000);
+
+        // The middle isn't read
+        rewrite_keeping_metadata(&path, 500, b"y");
+        assert_eq!(sample(&path, 100).unwrap(), whole);
+        rewrite_keeping_metadata(&path, 950, b"y");
+        assert_ne!(sample(&path, 100).unwrap(), whole);
+        rewrite_keeping_metadata(&path, 50, b"y");
+        assert_ne!(sample(&path, 100).unwrap(), whole);
+    }
+
+    #[tokio::test]
+    async fn waits_until_the_writer_stops() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("a.xml");
+        fs::write(&path, "").unwrap();
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+                for _ in 0..20 {
+                    file.write_all(b"<a/>").unwrap();
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            })
+        };
+
+        let check = StabilityCheck { sample_bytes: 4096, interval: Duration::from_millis(30) };
+        let reads = check.wait(&path).await.unwrap();
+        assert!(writer.is_finished());
+        assert!(reads >= 3, "{}", reads);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 80);
+        writer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_an_error() {
+        let root = tempfile::tempdir().unwrap();
+        let check = StabilityCheck { sample_bytes: 4096, interval: Duration::from_millis(1) };
+        let error = check.wait(&root.path().join("gone.xml")).await.unwrap_err();
+        assert!(error.starts_with("failed to read"), "{}", error);
+    }
+}
@@ -0,0 +1,216 @@
+use log::warn;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use crate::path_case::PathCase;
+
+/// Files found in the watch directory at startup, with SCAN_ON_STARTUP,
+/// from when they are queued until their delivery finishes, so that their
+/// payloads can say they were already there.
+///
+/// A file can also have an event of its own from the time between its watch
+/// being registered and the scan, e.g. when it was renamed into the tree and
+/// kept its older modification time. Everything the scan queued is kept until
+/// an event from after the scan comes along, so that such events can be
+/// dropped instead of delivering the file twice.
+pub struct ExistingFiles {
+    case: PathCase,
+    // Being delivered
+    paths: Mutex<HashSet<PathBuf>>,
+    scanned: Mutex<Option<Scanned>>,
+}
+
+struct Scanned {
+    // None while the scan runs
+    finished_at: Option<Instant>,
+    paths: HashSet<PathBuf>,
+}
+
+impl ExistingFiles {
+    pub fn new(case: PathCase) -> Self {
+        ExistingFiles {
+            case,
+            paths: Mutex::default(),
+            scanned: Mutex::new(Some(Scanned { finished_at: None, paths: HashSet::new() })),
+        }
+    }
+
+    /// The scan queued `path`.
+    pub fn insert(&self, path: &Path) {
+        let key = self.case.key(path);
+        if let Some(scanned) = self.scanned.lock().unwrap().as_mut() {
+            scanned.paths.insert(key.clone());
+        }
+        self.paths.lock().unwrap().insert(key);
+    }
+
+    pub fn scan_finished(&self, at: Instant) {
+        if let Some(scanned) = self.scanned.lock().unwrap().as_mut() {
+            scanned.finished_at = Some(at);
+        }
+    }
+
+    /// Whether the scan already queued `path`, for an event seen `at`. The
+    /// first event from after the scan ends this, since later ones are about
+    /// files the scan can't have seen.
+    pub fn queued_by_scan(&self, path: &Path, at: Instant) -> bool {
+        let mut scanned = self.scanned.lock().unwrap();
+        let Some(current) = scanned.as_ref() else {
+            return false;
+        };
+        if current.finished_at.is_some_and(|finished_at| at >= finished_at) {
+            *scanned = None;
+            return false;
+        }
+        current.paths.contains(&self.case.key(path))
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.lock().unwrap().contains(&self.case.key(path))
+    }
+
+    /// The delivery of `path` finished, delivered or not.
+    pub fn remove(&self, path: &Path) {
+        self.paths.lock().unwrap().remove(&self.case.key(path));
+    }
+}
+
+/// Everything but directories below `root`, in no particular order. Only the
+/// directories `descend` accepts are entered, and symlinked directories never
+/// are, like the watch itself doesn't. Directories that can't be read are
+/// logged and left out.
+pub fn files_under(root: &Path, descend: impl Fn(&Path) -> bool, prefix: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("{}Startup scan can't read {}: {}", prefix, dir.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    if descend(&path) {
+                        dirs.push(path);
+                    }
+                }
+                Ok(_) => files.push(path),
+                Err(e) => warn!("{}Startup scan can't read {}: {}", prefix, path.display(), e),
+            }
+        }
+    }
+    files
+}
+
+/// A top-level directory of the watch tree, or the files directly in it, as
+/// SCAN_DIR_CONCURRENCY delivers it: one file after the other, oldest first.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScanUnit {
+    // Relative to the watch directory; `.` for the files directly in it
+    pub name: String,
+    pub files: Vec<PathBuf>,
+}
+
+/// Group `files` found below `root`, with their modification times, by the
+/// top-level directory they are in. Units come in name order, and their files
+/// by modification time, then path.
+pub fn plan_by_directory(root: &Path, files: Vec<(PathBuf, SystemTime)>) -> Vec<ScanUnit> {
+    let mut units: BTreeMap<String, Vec<(SystemTime, PathBuf)>> = BTreeMap::new();
+    for (path, modified) in files {
+        let mut components = path.strip_prefix(root).unwrap_or(&path).components();
+        let first = components.next();
+        let name = match (first, components.next()) {
+            (Some(directory), Some(_)) => directory.as_os_str().to_string_lossy().into_owned(),
+            _ => ".".to_string(),
+        };
+        units.entry(name).or_default().push((modified, path));
+    }
+    units
+        .into_iter()
+        .map(|(name, mut files)| {
+            files.sort();
+            ScanUnit { name, files: files.into_iter().map(|(_, path)| path).collect() }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn events_from_before_the_end_of_the_scan_are_dropped() {
+        let existing = ExistingFiles::new(PathCase::Sensitive);
+        let before = Instant::now();
+        existing.insert(Path::new("/watch/a.xml"));
+        let finished_at = before + Duration::from_secs(1);
+        existing.scan_finished(finished_at);
+
+        assert!(existing.queued_by_scan(Path::new("/watch/a.xml"), before));
+        assert!(!existing.queued_by_scan(Path::new("/watch/b.xml"), before));
+        // Once an event from after the scan is seen, a.xml is a new file again
+        assert!(!existing.queued_by_scan(Path::new("/watch/b.xml"), finished_at));
+        assert!(!existing.queued_by_scan(Path::new("/watch/a.xml"), before));
+    }
+
+    #[test]
+    fn queued_files_stay_existing_until_delivered() {
+        let existing = ExistingFiles::new(PathCase::Insensitive);
+        existing.insert(Path::new("/watch/Order.xml"));
+        assert!(existing.contains(Path::new("/watch/order.XML")));
+        existing.remove(Path::new("/watch/order.xml"));
+        assert!(!existing.contains(Path::new("/watch/Order.xml")));
+    }
+
+    #[test]
+    fn walk_skips_refused_and_symlinked_directories() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir_all(root.join("sub/deep")).unwrap();
+        std::fs::create_dir(root.join("skipped")).unwrap();
+        for file in ["top.xml", "sub/deep/nested.xml", "skipped/left.xml"] {
+            std::fs::write(root.join(file), "<a/>").unwrap();
+        }
+        std::os::unix::fs::symlink(root.join("sub"), root.join("link")).unwrap();
+
+        let mut files = files_under(root, |dir| !dir.ends_with("skipped"), "");
+        files.sort();
+        // The symlink is listed like a file, and its directory isn't entered
+        assert_eq!(files, vec![root.join("link"), root.join("sub/deep/nested.xml"), root.join("top.xml")]);
+    }
+
+    #[test]
+    fn plans_group_files_by_top_level_directory_oldest_first() {
+        let root = Path::new("/watch");
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let files = vec![
+            (root.join("acme/b.xml"), at(20)),
+            (root.join("top.xml"), at(5)),
+            (root.join("zeta/z.xml"), at(1)),
+            (root.join("acme/deep/a.xml"), at(10)),
+            (root.join("acme/c.xml"), at(20)),
+            (root.join("acme/first.xml"), at(2)),
+        ];
+        let plan = plan_by_directory(root, files);
+        let units: Vec<(&str, Vec<&Path>)> = plan
+            .iter()
+            .map(|unit| (unit.name.as_str(), unit.files.iter().map(|path| path.strip_prefix(root).unwrap()).collect()))
+            .collect();
+        assert_eq!(
+            units,
+            [
+                (".", vec![Path::new("top.xml")]),
+                ("acme", vec![Path::new("acme/first.xml"), Path::new("acme/deep/a.xml"), Path::new("acme/b.xml"), Path::new("acme/c.xml")]),
+                ("zeta", vec![Path::new("zeta/z.xml")]),
+            ]
+        );
+        assert!(plan_by_directory(root, Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,556 @@
+use log::{info, warn};
+use reqwest::Client;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::archive::BundleProgress;
+use crate::audit_syslog::SyslogAudit;
+use crate::batch::Batcher;
+use crate::catchup::CatchupProgress;
+use crate::concurrency::{AdaptiveSettings, ConcurrencyLimiter, ConcurrencyMode};
+use crate::config::Config;
+use crate::content_server::{ContentMode, ContentRegistry};
+use crate::encoding::{EncodingFallback, Utf16Handling};
+use crate::events::{ManualSource, WatchEvents};
+use crate::hardlinks::{DeliveredInodes, HardlinkPolicy};
+use crate::hooks::{HookRunner, PreHookFailure};
+use crate::ignore_list::IgnoreList;
+use crate::intake::IntakeLimiter;
+use crate::outcomes::OutcomeNotifier;
+use crate::path_case::PathCase;
+use crate::path_lock::PathLocks;
+use crate::modifications::Modifications;
+use crate::pending_ack::{AckFile, PendingAcks};
+use crate::presigned::PresignProgress;
+use crate::redundant::DeliveredContent;
+use crate::reorder::{DeliveryOrder, Reorder};
+use crate::sensitive::SensitiveString;
+use crate::sequence::Sequence;
+use crate::settle::SettleLearner;
+use crate::shadow::Shadow;
+use crate::skips::Skips;
+use crate::spill::SpillQueue;
+use crate::startup_scan::ExistingFiles;
+use crate::storage::{self, AckStore, QueueStore};
+use crate::throttle::Throttles;
+use crate::tls::TlsBackend;
+use crate::watch::WatchBackend;
+use crate::write_guard::{WriteCapability, WriteGuard};
+use crate::AppState;
+
+fn build_client(config: &Config) -> Result<Client, String> {
+    // Responses are decompressed as they are read, so a response body is
+    // checked, compared and written as the receiver meant it
+    let mut builder = Client::builder()
+        .gzip(config.accept_compressed_responses)
+        .deflate(config.accept_compressed_responses)
+        // Presigned phases replace it with their own timeouts
+        .timeout(Duration::from_secs(config.webhook_timeout_secs));
+    builder = config.tls.configure(builder)?;
+    if let Some(address) = config.bind_local_address {
+        // Binding fails unless the address belongs to this host, which catches
+        // typos at startup instead of on every delivery
+        std::net::UdpSocket::bind((address, 0))
+            .map_err(|e| format!("BIND_LOCAL_ADDRESS {} is not usable on this host: {}", address, e))?;
+        builder = builder.local_address(address);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+// Validate a profile, log its startup banner and build the state shared by
+// its delivery tasks
+pub fn build_state(
+    config: Config,
+    content_registry: &Arc<ContentRegistry>,
+    throttles: &Arc<Throttles>,
+) -> Result<AppState, String> {
+    let prefix = config.log_prefix();
+    
+    if !config.watch_dir.exists() {
+        return Err(format!("Watch directory '{}' does not exist", config.watch_dir.display()));
+    }
+    // Resolved, so that `..` and symlinks can't lead out of the watch directory
+    for root in &config.content_roots {
+        let resolved = root
+            .canonicalize()
+            .map_err(|e| format!("CONTENT_ROOTS entry '{}' can't be resolved: {}", root.display(), e))?;
+        if !resolved.starts_with(&config.watch_dir) {
+            return Err(format!(
+                "CONTENT_ROOTS entry '{}' is outside the watch directory '{}'",
+                root.display(),
+                config.watch_dir.display()
+            ));
+        }
+    }
+    
+    // Warn if overwrite is enabled without content inclusion
+    if config.overwrite_with_response && !config.sends_content() {
+        warn!("{}OVERWRITE_WITH_RESPONSE is enabled but INCLUDE_CONTENT is disabled. File overwrite will not work without including content in the webhook.", prefix);
+    }
+    
+    let mut requested_writes = Vec::new();
+    if config.overwrite_with_response {
+        requested_writes.push(WriteCapability::Overwrite);
+        if config.backup_before_overwrite {
+            requested_writes.push(WriteCapability::Backup);
+        }
+    }
+    if config.pre_delivery_command.is_some() && config.pre_delivery_failure == PreHookFailure::Quarantine {
+        requested_writes.push(WriteCapability::Quarantine);
+    }
+    if config.retry_later_dir.is_some() {
+        requested_writes.push(WriteCapability::RetryLater);
+    }
+    let catchup_defer_dir = config.catchup.as_ref().and_then(|policy| policy.defer_dir.as_ref());
+    if catchup_defer_dir.is_some() {
+        requested_writes.push(WriteCapability::CatchupDefer);
+    }
+    let allowed_dirs = std::iter::once(&config.watch_dir)
+        .chain(config.backup_dir.as_ref())
+        .chain(config.quarantine_dir.as_ref())
+        .chain(config.retry_later_dir.as_ref())
+        .chain(catchup_defer_dir)
+        .cloned()
+        .collect();
+    let write_guard = WriteGuard::new(&requested_writes, config.read_only, allowed_dirs);
+    for capability in &requested_writes {
+        if !write_guard.allows(*capability) {
+            warn!("{}{} is enabled but READ_ONLY=true; the watcher will not modify any files.", prefix, capability.feature());
+        }
+    }
+    
+    info!("{}  Watch directory: {}", prefix, config.watch_dir.display());
+    match &config.presigned {
+        Some(presigned) => {
+            info!("{}  Presign URL: {}", prefix, config.webhook_url);
+            if let Some(url) = &presigned.confirm_url {
+                info!("{}  Confirm URL: {}", prefix, SensitiveString::url(url.to_string()));
+            }
+            info!(
+                "{}  Phase timeouts: presign {}s, upload {}s, confirm {}s",
+                prefix,
+                presigned.presign_timeout.as_secs(),
+                presigned.upload_timeout.as_secs(),
+                presigned.confirm_timeout.as_secs()
+            );
+        }
+        None => {
+            info!("{}  Webhook URL: {}", prefix, config.webhook_url);
+            info!("{}  Webhook method: {}", prefix, config.webhook_method);
+            info!("{}  Webhook timeout: {}s", prefix, config.webhook_timeout_secs);
+            match config.webhook_retry.max_retries {
+                0 => info!("{}  Webhook retries: off", prefix),
+                retries => info!(
+                    "{}  Webhook retries: up to {}, backing off from {} ms",
+                    prefix, retries, config.webhook_retry.base.as_millis()
+                ),
+            }
+        }
+    }
+    if !config.webhook_headers.is_empty() {
+        info!("{}  Webhook headers: {}", prefix, config.webhook_headers.names().join(", "));
+    }
+    for problem in config.webhook_headers.problems() {
+        warn!("{}  Ignoring WEBHOOK_HEADERS entry: {}", prefix, problem);
+    }
+    if let Some(address) = config.bind_local_address {
+        info!("{}  Local address: {}", prefix, address);
+    }
+    if config.require_tls {
+        info!("{}  TLS required for all URLs", prefix);
+    }
+    if config.tls.backend != TlsBackend::Native {
+        info!("{}  TLS backend: {}", prefix, config.tls.backend.as_str());
+    }
+    if let Some(path) = &config.tls.ca_bundle {
+        info!("{}  Trusted CA bundle: {}", prefix, path.display());
+    }
+    if let Some((cert, _)) = &config.tls.client_certificate {
+        info!("{}  Client certificate: {}", prefix, cert.display());
+    }
+    if !config.tls.pinned_spki.is_empty() {
+        info!("{}  Pinned public keys: {}", prefix, config.tls.pinned_spki.len());
+    }
+    match &config.size_policy {
+        Some(policy) => info!("{}  Size policy: {}", prefix, policy),
+        None => info!("{}  Include content: {}", prefix, config.include_content),
+    }
+    if config.content_events != (WatchEvents { create: true, modify: true }) {
+        info!("{}  Content for events: {}", prefix, config.content_events);
+    }
+    if let Some(limit) = config.content_preview_bytes.filter(|_| config.include_content && config.content_mode == ContentMode::Inline) {
+        info!("{}  Content preview: first {} bytes", prefix, limit);
+    }
+    if config.preview_endpoint {
+        info!("{}  Preview endpoint: POST http://{}/preview", prefix, config.content_serve_addr);
+    }
+    if config.skips_endpoint {
+        info!("{}  Skips endpoint: GET http://{}/skips/recent", prefix, config.content_serve_addr);
+    }
+    if config.debug_inject_events {
+        warn!("{}  Event injection enabled: POST http://{}/control/inject-event", prefix, config.content_serve_addr);
+    }
+    if let Some(dir) = &config.debug_payload_dir {
+        info!("{}  Payloads also written to: {}", prefix, dir.display());
+    }
+    if config.watch_backend == WatchBackend::Poll {
+        info!("{}  Watch backend: polling every {}s", prefix, config.watch_poll_interval_secs);
+    }
+    if config.serves_content() {
+        info!(
+            "{}  Content by reference: {}/content/<token> (valid {}s)",
+            prefix, config.content_url_base, config.content_url_ttl_secs
+        );
+        if config.content_roots != [config.watch_dir.clone()] {
+            let roots: Vec<String> = config.content_roots.iter().map(|root| root.display().to_string()).collect();
+            info!("{}  Content served only from: {}", prefix, roots.join(", "));
+        }
+    }
+    info!("{}  Overwrite with response: {}", prefix, config.overwrite_with_response);
+    if config.accept_compressed_responses {
+        info!("{}  Compressed responses accepted: gzip, deflate", prefix);
+    }
+    info!("{}  Write mode: {}", prefix, write_guard.describe());
+    if config.backup_before_overwrite {
+        match &config.backup_dir {
+            Some(dir) => info!("{}  Backup directory: {}", prefix, dir.display()),
+            None => info!("{}  Backups: sibling .bak files", prefix),
+        }
+    }
+    if let Some(target) = config.overwrite_target_encoding {
+        let fallback = match config.encoding_fallback {
+            EncodingFallback::Fail => "responses with characters it lacks aren't written",
+            EncodingFallback::Ncr => "characters it lacks become character references",
+        };
+        info!("{}  Overwrite encoding: {}; {}", prefix, target, fallback);
+    }
+    info!("{}  Detect content type from document: {}", prefix, config.detect_content_type_from_doc);
+    if config.watch_all_files {
+        info!("{}  Watching all files, with detected types", prefix);
+    }
+    if !config.bundle_extensions.is_empty() {
+        info!(
+            "{}  Bundles: .{} files, up to {} entries, {} bytes",
+            prefix,
+            config.bundle_extensions.join(", ."),
+            config.archive_limits.max_entries,
+            config.archive_limits.max_total_bytes
+        );
+    }
+    if let Some(command) = &config.pre_delivery_command {
+        let on_failure = match &config.quarantine_dir {
+            Some(dir) if config.pre_delivery_failure == PreHookFailure::Quarantine => {
+                format!("quarantine to {}", dir.display())
+            }
+            _ => "skip".to_string(),
+        };
+        info!("{}  Pre-delivery command: {} (on failure: {})", prefix, command, on_failure);
+    }
+    if let Some(command) = &config.post_delivery_command {
+        info!("{}  Post-delivery command: {}", prefix, command);
+    }
+    if let Some(dir) = &config.retry_later_dir {
+        match config.retry_later_after_secs {
+            Some(secs) => info!("{}  Failed files moved to {}, delivered again after {}s", prefix, dir.display(), secs),
+            None => info!("{}  Failed files moved to {}", prefix, dir.display()),
+        }
+    }
+    if config.hardlink_policy != HardlinkPolicy::Deliver {
+        info!("{}  Hard links to delivered files: {:?}", prefix, config.hardlink_policy);
+    }
+    if config.skip_redundant_delivery {
+        info!("{}  Skip redundant deliveries: yes", prefix);
+    }
+    if let Some(pattern) = &config.auto_watch_pattern {
+        info!("{}  Auto-watched subdirectories: {}", prefix, pattern);
+    }
+    if let Some(depth) = config.max_watch_depth {
+        info!("{}  Max watch depth: {}", prefix, depth);
+    }
+    if let Some(algorithm) = config.digest_algorithm {
+        info!("{}  Digest header: {}", prefix, algorithm.name());
+    }
+    if let Some(signer) = &config.jws_signer {
+        match signer.key_id() {
+            Some(key_id) => info!("{}  Request bodies signed as JWS: {} (key ID {})", prefix, signer.algorithm(), key_id),
+            None => info!("{}  Request bodies signed as JWS: {}", prefix, signer.algorithm()),
+        }
+    }
+    if let Some(rule) = &config.success_body_match {
+        info!("{}  Success body match: {}", prefix, rule);
+    }
+    if config.xml_c14n {
+        info!("{}  Content canonicalization: Canonical XML 1.0 (without comments)", prefix);
+    }
+    if let Some(rules) = &config.content_headers {
+        info!("{}  Content headers: {}", prefix, rules.describe());
+    }
+    if let Some(template) = &config.content_ref_template {
+        info!("{}  Content reference template: {}", prefix, template);
+    }
+    if let Some(rewrite) = &config.filename_rewrite {
+        info!("{}  Filename rewrite: {}", prefix, rewrite);
+    }
+    if let Some(element) = &config.split_on_element {
+        info!("{}  Split files on element: <{}>", prefix, element);
+        if !config.sends_content() {
+            warn!("{}SPLIT_ON_ELEMENT is enabled but INCLUDE_CONTENT is disabled. Fragment deliveries won't carry their content.", prefix);
+        }
+    }
+    let limiter = match config.concurrency_mode {
+        ConcurrencyMode::Fixed => match config.max_concurrent_webhooks {
+            Some(max) => {
+                info!("{}  Max concurrent webhooks: {}", prefix, max);
+                ConcurrencyLimiter::fixed(max)
+            }
+            None => {
+                info!("{}  Max concurrent webhooks: unlimited", prefix);
+                ConcurrencyLimiter::unbounded()
+            }
+        },
+        ConcurrencyMode::Adaptive => {
+            info!(
+                "{}  Adaptive concurrency: {}..{} (target p95 latency {} ms)",
+                prefix, config.concurrency_floor, config.concurrency_ceiling, config.target_latency_ms
+            );
+            ConcurrencyLimiter::adaptive(AdaptiveSettings {
+                floor: config.concurrency_floor,
+                ceiling: config.concurrency_ceiling,
+                target_latency: Duration::from_millis(config.target_latency_ms),
+            })
+        }
+    };
+    
+    let client = build_client(&config)?;
+    
+    let hooks = HookRunner::new(config.hook_max_concurrent, Duration::from_secs(config.hook_timeout_secs));
+    
+    // The spill queue, pending acknowledgements and outcomes all live in the
+    // database when there is one
+    let (database_queue, database_acks, audit) = match &config.storage_db_path {
+        Some(path) => {
+            let name = config.profile.as_deref().unwrap_or("default");
+            let stores = storage::open_database(path, name)?;
+            info!("{}  Storage database: {} (profile {})", prefix, path.display(), name);
+            (Some(stores.queue), Some(stores.acks), Some(stores.audit))
+        }
+        None => (None, None, None),
+    };
+    
+    let spill = match (database_queue, &config.queue_spill_dir) {
+        (Some(queue), _) => Some(queue),
+        (None, Some(dir)) => {
+            let name = config.profile.as_deref().unwrap_or("default");
+            let queue: Box<dyn QueueStore> = Box::new(SpillQueue::open(&dir.join(format!("{}.queue", name)))?);
+            Some(queue)
+        }
+        (None, None) => None,
+    };
+    if let Some(spill) = &spill {
+        info!(
+            "{}  Spill queue: {} (after {} queued files, {} left from a previous run)",
+            prefix, spill.location(), config.max_queued_files, spill.len()
+        );
+    }
+    
+    let shadow = config.shadow_webhook_url.as_ref().map(|url| {
+        info!(
+            "{}  Shadow webhook: {} (max {} concurrent, report every {}s)",
+            prefix, url, config.shadow_max_concurrent, config.shadow_report_interval_secs
+        );
+        Shadow::new(url.clone(), config.shadow_max_concurrent)
+    });
+    
+    let ack_store = match (database_acks, &config.async_ack_dir) {
+        (Some(store), _) => Some(store),
+        (None, Some(dir)) if config.async_ack_token.is_some() => {
+            let name = config.profile.as_deref().unwrap_or("default");
+            let store: Box<dyn AckStore> = Box::new(AckFile::new(&dir.join(format!("{}.pending.json", name)))?);
+            Some(store)
+        }
+        _ => None,
+    };
+    let pending_acks = match (&config.async_ack_token, ack_store) {
+        (Some(source), Some(store)) => {
+            let timeout = Duration::from_secs(config.async_ack_timeout_secs);
+            let pending = PendingAcks::open(store, config.async_ack_max_pending, timeout)?;
+            info!(
+                "{}  Async acknowledgements: token from {}, kept in {} ({} pending, timeout {}s)",
+                prefix, source, pending.location(), pending.len(), config.async_ack_timeout_secs
+            );
+            Some(pending)
+        }
+        _ => None,
+    };
+    
+    let sequence = if config.include_sequence {
+        let name = config.profile.as_deref().unwrap_or("default");
+        let path = config.sequence_dir.as_ref().map(|dir| dir.join(format!("{}.sequence", name)));
+        let sequence = Sequence::open(path.as_deref())?;
+        match sequence.path() {
+            Some(path) => info!("{}  Sequence numbers: continuing after {}, kept in {}", prefix, sequence.last(), path.display()),
+            None => info!("{}  Sequence numbers: starting at 1, not kept across restarts", prefix),
+        }
+        Some(sequence)
+    } else {
+        None
+    };
+    
+    let outcomes = config.outcome_webhook_url.as_ref().map(|url| {
+        info!("{}  Outcome webhook: {} (flushed every {}s)", prefix, url, config.outcome_flush_secs);
+        OutcomeNotifier::start(client.clone(), url.clone(), Duration::from_secs(config.outcome_flush_secs), prefix.clone())
+    });
+    
+    let syslog = match &config.syslog_target {
+        Some(target) => {
+            info!("{}  Syslog: {} (facility {:?})", prefix, target, config.syslog_facility);
+            Some(SyslogAudit::start(target.clone(), config.syslog_facility, prefix.clone())?)
+        }
+        None => None,
+    };
+    if config.audit_skips {
+        info!("{}  Audit skips: yes", prefix);
+    }
+    
+    if let Some(min) = config.min_content_bytes {
+        info!("{}  Min content bytes: {}", prefix, min);
+    }
+    if config.utf16_xml == Utf16Handling::Skip {
+        info!("{}  UTF-16 files: skipped", prefix);
+    }
+    if let Some(check) = &config.nfs_safe_mode {
+        info!(
+            "{}  NFS safe mode: comparing {} KB at each end every {} ms",
+            prefix, check.sample_bytes / 1024, check.interval.as_millis()
+        );
+    }
+    let settle = match (config.adaptive_settle, &config.settle_state_dir) {
+        (Some(policy), Some(dir)) => {
+            let name = config.profile.as_deref().unwrap_or("default");
+            Some(SettleLearner::open(policy, &dir.join(format!("{}.settle.json", name)))?)
+        }
+        (Some(policy), None) => Some(SettleLearner::new(policy)),
+        (None, _) => None,
+    };
+    if let Some(learner) = &settle {
+        let policy = learner.policy();
+        let kept = match learner.path() {
+            Some(path) => format!("kept in {} ({} directories learned)", path.display(), learner.len()),
+            None => "not kept across restarts".to_string(),
+        };
+        info!(
+            "{}  Settle delay: adaptive, p{} per directory within {}-{} ms, polled every {} ms, {}",
+            prefix, policy.percentile, policy.min.as_millis(), policy.max.as_millis(), policy.poll.as_millis(), kept
+        );
+    }
+    
+    let intake = config.max_files_per_sec.map(|rate| {
+        info!("{}  Max files per second: {}", prefix, rate);
+        IntakeLimiter::new(rate)
+    });
+    
+    let path_case = match config.path_case {
+        Some(case) => {
+            info!("{}  Path names: {} (PATH_CASE_SENSITIVE)", prefix, case.as_str());
+            case
+        }
+        None => {
+            let case = PathCase::detect(&config.watch_dir);
+            if case == PathCase::Insensitive {
+                info!("{}  Path names: {} (detected)", prefix, case.as_str());
+            }
+            case
+        }
+    };
+    
+    // Create an ignore list for files we've just modified
+    let ignore_list = IgnoreList::new(config.ignore_list_max_entries, path_case);
+    let config_injects = config.debug_inject_events;
+    let config_presigned = config.presigned.is_some();
+    let config_locks = config.lock_per_path;
+    if !config_locks {
+        info!("{}  Per-path locking: off, overlapping events for a file may be processed at once", prefix);
+    }
+    let modifications = config.watch_events.modify.then(|| {
+        info!(
+            "{}  Watch events: {}, modifications delivered after {} ms without changes",
+            prefix, config.watch_events, config.modify_debounce.as_millis()
+        );
+        Modifications::new(config.modify_debounce, path_case)
+    });
+    let existing = config.scan_on_startup.then(|| {
+        match config.scan_max_age {
+            Some(age) => info!("{}  Startup scan: files modified within the last {}s", prefix, age.as_secs()),
+            None => info!("{}  Startup scan: all files", prefix),
+        }
+        if let Some(concurrency) = config.scan_dir_concurrency {
+            info!("{}  Startup scan: {} top-level directories at a time", prefix, concurrency);
+        }
+        ExistingFiles::new(path_case)
+    });
+    let catchup = config.catchup.as_ref().map(|policy| {
+        let caps: Vec<String> = [
+            policy.max_files.map(|files| format!("the newest {} files", files)),
+            policy.max_age.map(|age| format!("files modified within {}s", age.as_secs())),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        info!("{}  Catch-up: {}, reported in {}", prefix, caps.join(" and "), policy.report.display());
+        if let Some(dir) = &policy.defer_dir {
+            info!("{}  Catch-up: files left out moved to {}", prefix, dir.display());
+        }
+        CatchupProgress::default()
+    });
+    let reorder = (config.ordering == DeliveryOrder::Mtime).then(|| {
+        info!("{}  Ordering: by mtime, within {} ms", prefix, config.ordering_window_ms);
+        Reorder::new(Duration::from_millis(config.ordering_window_ms), path_case)
+    });
+    let batcher = config.batch.map(|policy| {
+        info!(
+            "{}  Batches: up to {} files, sent after {} ms without new files or {} ms at most",
+            prefix, policy.size, policy.idle.as_millis(), policy.max_wait.as_millis()
+        );
+        Batcher::new(policy)
+    });
+    
+    Ok(AppState {
+        config,
+        client,
+        path_case,
+        ignore_list,
+        limiter,
+        write_guard,
+        content_registry: Arc::clone(content_registry),
+        hooks,
+        throttles: Arc::clone(throttles),
+        delivered_inodes: DeliveredInodes::default(),
+        delivered_content: DeliveredContent::new(path_case),
+        bundle_progress: BundleProgress::new(path_case),
+        queued: AtomicUsize::new(0),
+        queue_space: Notify::new(),
+        spill,
+        shadow,
+        intake,
+        outcomes,
+        syslog,
+        audit,
+        pending_acks,
+        sequence,
+        handled_elsewhere: None,
+        skips: Skips::new(prefix),
+        injected_events: config_injects.then(ManualSource::default),
+        reorder,
+        batcher,
+        presign_progress: config_presigned.then(|| PresignProgress::new(path_case)),
+        path_locks: config_locks.then(|| PathLocks::new(path_case)),
+        settle,
+        modifications,
+        existing,
+        catchup,
+    })
+}
@@ -0,0 +1,701 @@
+use std::path::{Path, PathBuf};
+
+use crate::outcomes::OutcomeRecord;
+use crate::pending_ack::PendingAck;
+
+/// Canned queries of `xml-watcher db query`
+pub const QUERIES: &[&str] = &["recent-failures", "pending-retries", "per-day-counts"];
+
+/// Paths waiting for delivery that didn't fit in memory.
+///
+/// Paths handed out by `pop` stay stored until `complete` has been called for
+/// every one of them, so that anything not known to be finished is handed out
+/// again after a restart (at-least-once).
+pub trait QueueStore: Send + Sync {
+    // Where the paths are kept, for the log
+    fn location(&self) -> String;
+
+    fn len(&self) -> usize;
+
+    fn push(&self, item: &Path) -> Result<(), String>;
+
+    /// Take up to `max` paths, oldest first.
+    fn pop(&self, max: usize) -> Result<Vec<PathBuf>, String>;
+
+    /// Mark one handed out path as processed.
+    fn complete(&self) -> Result<(), String>;
+}
+
+/// Where the files waiting for an acknowledgement are kept across restarts.
+pub trait AckStore: Send + Sync {
+    fn location(&self) -> String;
+
+    fn load(&self) -> Result<Vec<PendingAck>, String>;
+
+    /// Replace what is stored with `entries`.
+    fn save(&self, entries: &[PendingAck]) -> Result<(), String>;
+}
+
+/// Keeps the outcome of every file, for `xml-watcher db query`.
+pub trait AuditStore: Send + Sync {
+    fn record(&self, record: &OutcomeRecord) -> Result<(), String>;
+}
+
+/// The stores of one profile in the STORAGE_DB_PATH database.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct DatabaseStores {
+    pub queue: Box<dyn QueueStore>,
+    pub acks: Box<dyn AckStore>,
+    pub audit: Box<dyn AuditStore>,
+}
+
+/// Open the database at `path`, creating or migrating its schema, with the
+/// rows of `profile`.
+#[cfg(feature = "sqlite")]
+pub fn open_database(path: &Path, profile: &str) -> Result<DatabaseStores, String> {
+    sqlite::open(path, profile)
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn open_database(_path: &Path, _profile: &str) -> Result<DatabaseStores, String> {
+    Err(unavailable())
+}
+
+/// Run the canned query `name` against the database at `path`, as a table.
+#[cfg(feature = "sqlite")]
+pub fn run_query(path: &Path, name: &str) -> Result<String, String> {
+    sqlite::query(path, name)
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn run_query(_path: &Path, _name: &str) -> Result<String, String> {
+    Err(unavailable())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn unavailable() -> String {
+    "STORAGE_DB_PATH isn't available: the watcher was built without the 'sqlite' feature".to_string()
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use chrono::{DateTime, Utc};
+    use rusqlite::types::ValueRef;
+    use rusqlite::{params, Connection, OpenFlags, TransactionBehavior};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{AckStore, AuditStore, DatabaseStores, QueueStore};
+    use crate::outcomes::OutcomeRecord;
+    use crate::pending_ack::PendingAck;
+
+    // How long a statement waits for another connection to finish writing,
+    // e.g. another profile's or a running `db query`
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Applied in order, each once; the schema version is the number applied,
+    // kept in the database's user_version. Only ever append to this.
+    const MIGRATIONS: &[&str] = &[
+        "CREATE TABLE spill (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            profile TEXT NOT NULL,
+            path TEXT NOT NULL
+        );
+        CREATE INDEX spill_profile ON spill (profile, id);
+        CREATE TABLE pending_acks (
+            profile TEXT NOT NULL,
+            token TEXT NOT NULL,
+            path TEXT NOT NULL,
+            status INTEGER NOT NULL,
+            detected_at TEXT NOT NULL,
+            accepted_at TEXT NOT NULL,
+            PRIMARY KEY (profile, token)
+        );
+        CREATE TABLE outcomes (
+            event_id TEXT PRIMARY KEY,
+            profile TEXT NOT NULL,
+            path TEXT NOT NULL,
+            outcome TEXT NOT NULL,
+            reason TEXT,
+            failure_kind TEXT,
+            content_policy TEXT,
+            status INTEGER,
+            attempts INTEGER NOT NULL,
+            duration_ms INTEGER NOT NULL,
+            detected_at TEXT NOT NULL,
+            completed_at TEXT NOT NULL
+        );
+        CREATE INDEX outcomes_completed_at ON outcomes (completed_at);",
+    ];
+
+    // Outcomes of files that weren't delivered and won't be on their own
+    const RECENT_FAILURES: &str = "
+        SELECT completed_at, profile, path, outcome, failure_kind, status, attempts
+        FROM outcomes
+        WHERE outcome IN ('failed', 'rejected', 'quarantined')
+        ORDER BY completed_at DESC
+        LIMIT 50";
+
+    // Spilled files still to be delivered, including those being delivered
+    // right now, and accepted files waiting for their acknowledgement
+    const PENDING_RETRIES: &str = "
+        SELECT 'queued' AS waiting_for, profile, path, NULL AS accepted_at
+        FROM spill
+        UNION ALL
+        SELECT 'acknowledgement', profile, path, accepted_at
+        FROM pending_acks
+        ORDER BY profile, accepted_at";
+
+    const PER_DAY_COUNTS: &str = "
+        SELECT substr(completed_at, 1, 10) AS day, profile, outcome, count(*) AS files
+        FROM outcomes
+        GROUP BY day, profile, outcome
+        ORDER BY day DESC, profile, outcome";
+
+    type Shared = Arc<Mutex<Connection>>;
+
+    pub fn open(path: &Path, profile: &str) -> Result<DatabaseStores, String> {
+        let failed = |e: rusqlite::Error| format!("failed to open {}: {}", path.display(), e);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+        }
+        let mut connection = Connection::open(path).map_err(failed)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(failed)?;
+        // Readers don't block the writer, and a crash never leaves a write
+        // half done
+        let mode: String = connection
+            .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+            .map_err(failed)?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            return Err(format!("{} can't be switched to WAL mode (mode is {})", path.display(), mode));
+        }
+        migrate(&mut connection).map_err(|e| format!("failed to migrate {}: {}", path.display(), e))?;
+
+        let connection = Arc::new(Mutex::new(connection));
+        let location = path.display().to_string();
+        let queue = SqliteQueue::open(Arc::clone(&connection), profile, &location)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Ok(DatabaseStores {
+            queue: Box::new(queue),
+            acks: Box::new(SqliteAcks {
+                connection: Arc::clone(&connection),
+                profile: profile.to_string(),
+                location: location.clone(),
+            }),
+            audit: Box::new(SqliteAudit {
+                connection,
+                profile: profile.to_string(),
+            }),
+        })
+    }
+
+    fn migrate(connection: &mut Connection) -> Result<(), String> {
+        let failed = |e: rusqlite::Error| e.to_string();
+        // Taking the write lock first, so that two watchers starting together
+        // don't both apply the same migration
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(failed)?;
+        let version: i64 = transaction
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(failed)?;
+        let version = version as usize;
+        if version > MIGRATIONS.len() {
+            // Written by a newer watcher; leave it alone rather than guess
+            return Err(format!(
+                "schema version {} is newer than this watcher's {}",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+        for migration in &MIGRATIONS[version..] {
+            transaction.execute_batch(migration).map_err(failed)?;
+        }
+        transaction
+            .pragma_update(None, "user_version", MIGRATIONS.len() as i64)
+            .map_err(failed)?;
+        transaction.commit().map_err(failed)
+    }
+
+    pub fn query(path: &Path, name: &str) -> Result<String, String> {
+        let sql = match name {
+            "recent-failures" => RECENT_FAILURES,
+            "pending-retries" => PENDING_RETRIES,
+            "per-day-counts" => PER_DAY_COUNTS,
+            _ => return Err(format!("Unknown query '{}', expected one of: {}", name, super::QUERIES.join(", "))),
+        };
+        let failed = |e: rusqlite::Error| format!("failed to query {}: {}", path.display(), e);
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(failed)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(failed)?;
+        let mut statement = connection.prepare(sql).map_err(failed)?;
+        let columns: Vec<String> = statement.column_names().iter().map(|name| name.to_string()).collect();
+        let mut rows = vec![columns.clone()];
+        let mut results = statement.query([]).map_err(failed)?;
+        while let Some(row) = results.next().map_err(failed)? {
+            let mut cells = Vec::with_capacity(columns.len());
+            for index in 0..columns.len() {
+                cells.push(match row.get_ref(index).map_err(failed)? {
+                    ValueRef::Null => String::new(),
+                    ValueRef::Integer(value) => value.to_string(),
+                    ValueRef::Real(value) => value.to_string(),
+                    ValueRef::Text(text) | ValueRef::Blob(text) => String::from_utf8_lossy(text).into_owned(),
+                });
+            }
+            rows.push(cells);
+        }
+        Ok(table(&rows))
+    }
+
+    // Rows as left-aligned columns, the first being the header
+    fn table(rows: &[Vec<String>]) -> String {
+        let mut widths = vec![0; rows[0].len()];
+        for row in rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let mut text = String::new();
+        for row in rows {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            text.push_str(line.join("  ").trim_end());
+            text.push('\n');
+        }
+        if rows.len() == 1 {
+            text.push_str("(no rows)\n");
+        }
+        text
+    }
+
+    // The `spill` rows of a profile, handed out in id order. Like the spill
+    // file, rows are only deleted once every one handed out has completed.
+    struct SqliteQueue {
+        connection: Shared,
+        profile: String,
+        location: String,
+        state: Mutex<QueueState>,
+    }
+
+    struct QueueState {
+        // The last row handed out
+        last_id: i64,
+        pending: usize,
+        in_flight: usize,
+    }
+
+    impl SqliteQueue {
+        fn open(connection: Shared, profile: &str, location: &str) -> rusqlite::Result<Self> {
+            let pending: i64 = connection.lock().unwrap().query_row(
+                "SELECT count(*) FROM spill WHERE profile = ?1",
+                [profile],
+                |row| row.get(0),
+            )?;
+            Ok(SqliteQueue {
+                connection,
+                profile: profile.to_string(),
+                location: location.to_string(),
+                state: Mutex::new(QueueState { last_id: 0, pending: pending as usize, in_flight: 0 }),
+            })
+        }
+
+        fn remove_if_done(&self, state: &QueueState) -> Result<(), String> {
+            if state.pending > 0 || state.in_flight > 0 || state.last_id == 0 {
+                return Ok(());
+            }
+            self.connection
+                .lock()
+                .unwrap()
+                .execute("DELETE FROM spill WHERE profile = ?1 AND id <= ?2", params![self.profile, state.last_id])
+                .map_err(|e| format!("failed to update {}: {}", self.location, e))?;
+            Ok(())
+        }
+    }
+
+    impl QueueStore for SqliteQueue {
+        fn location(&self) -> String {
+            self.location.clone()
+        }
+
+        fn len(&self) -> usize {
+            self.state.lock().unwrap().pending
+        }
+
+        fn push(&self, item: &Path) -> Result<(), String> {
+            let path = item
+                .to_str()
+                .ok_or_else(|| format!("{} can't be written to the spill queue", item.display()))?;
+            let mut state = self.state.lock().unwrap();
+            self.connection
+                .lock()
+                .unwrap()
+                .execute("INSERT INTO spill (profile, path) VALUES (?1, ?2)", params![self.profile, path])
+                .map_err(|e| format!("failed to write {}: {}", self.location, e))?;
+            state.pending += 1;
+            Ok(())
+        }
+
+        fn pop(&self, max: usize) -> Result<Vec<PathBuf>, String> {
+            let mut state = self.state.lock().unwrap();
+            if state.pending == 0 || max == 0 {
+                return Ok(Vec::new());
+            }
+            let rows: Vec<(i64, String)> = {
+                let connection = self.connection.lock().unwrap();
+                let failed = |e: rusqlite::Error| format!("failed to read {}: {}", self.location, e);
+                let mut statement = connection
+                    .prepare_cached("SELECT id, path FROM spill WHERE profile = ?1 AND id > ?2 ORDER BY id LIMIT ?3")
+                    .map_err(failed)?;
+                let rows = statement
+                    .query_map(params![self.profile, state.last_id, max as i64], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .map_err(failed)?;
+                rows.collect::<rusqlite::Result<_>>().map_err(failed)?
+            };
+            if let Some((id, _)) = rows.last() {
+                state.last_id = *id;
+            }
+            state.pending = state.pending.saturating_sub(rows.len());
+            if rows.is_empty() {
+                state.pending = 0;
+            }
+            state.in_flight += rows.len();
+            self.remove_if_done(&state)?;
+            Ok(rows.into_iter().map(|(_, path)| PathBuf::from(path)).collect())
+        }
+
+        fn complete(&self) -> Result<(), String> {
+            let mut state = self.state.lock().unwrap();
+            state.in_flight = state.in_flight.saturating_sub(1);
+            self.remove_if_done(&state)
+        }
+    }
+
+    struct SqliteAcks {
+        connection: Shared,
+        profile: String,
+        location: String,
+    }
+
+    impl AckStore for SqliteAcks {
+        fn location(&self) -> String {
+            self.location.clone()
+        }
+
+        fn load(&self) -> Result<Vec<PendingAck>, String> {
+            let connection = self.connection.lock().unwrap();
+            let failed = |e: rusqlite::Error| format!("failed to read {}: {}", self.location, e);
+            let mut statement = connection
+                .prepare(
+                    "SELECT token, path, status, detected_at, accepted_at FROM pending_acks
+                    WHERE profile = ?1 ORDER BY accepted_at",
+                )
+                .map_err(failed)?;
+            let rows = statement
+                .query_map([&self.profile], |row| {
+                    Ok(PendingAck {
+                        token: row.get(0)?,
+                        path: PathBuf::from(row.get::<_, String>(1)?),
+                        status: row.get(2)?,
+                        detected_at: timestamp(row.get(3)?),
+                        accepted_at: timestamp(row.get(4)?),
+                    })
+                })
+                .map_err(failed)?;
+            rows.collect::<rusqlite::Result<_>>().map_err(failed)
+        }
+
+        fn save(&self, entries: &[PendingAck]) -> Result<(), String> {
+            let failed = |e: rusqlite::Error| format!("failed to save {}: {}", self.location, e);
+            let mut connection = self.connection.lock().unwrap();
+            let transaction = connection.transaction().map_err(failed)?;
+            transaction
+                .execute("DELETE FROM pending_acks WHERE profile = ?1", [&self.profile])
+                .map_err(failed)?;
+            {
+                let mut insert = transaction
+                    .prepare_cached(
+                        "INSERT INTO pending_acks (profile, token, path, status, detected_at, accepted_at)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    )
+                    .map_err(failed)?;
+                for entry in entries {
+                    insert
+                        .execute(params![
+                            self.profile,
+                            entry.token,
+                            entry.path.to_string_lossy(),
+                            entry.status,
+                            entry.detected_at.to_rfc3339(),
+                            entry.accepted_at.to_rfc3339(),
+                        ])
+                        .map_err(failed)?;
+                }
+            }
+            transaction.commit().map_err(failed)
+        }
+    }
+
+    // Timestamps are written by `save`; one that doesn't parse is treated as
+    // now, so that it isn't expired straight away
+    fn timestamp(value: String) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&value)
+            .map(|at| at.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now())
+    }
+
+    struct SqliteAudit {
+        connection: Shared,
+        profile: String,
+    }
+
+    impl AuditStore for SqliteAudit {
+        fn record(&self, record: &OutcomeRecord) -> Result<(), String> {
+            self.connection
+                .lock()
+                .unwrap()
+                .prepare_cached(
+                    "INSERT INTO outcomes (event_id, profile, path, outcome, reason, failure_kind,
+                        content_policy, status, attempts, duration_ms, detected_at, completed_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                )
+                .and_then(|mut insert| {
+                    insert.execute(params![
+                        record.event_id,
+                        self.profile,
+                        record.path,
+                        record.outcome,
+                        record.reason,
+                        record.failure_kind,
+                        record.content_policy,
+                        record.status,
+                        record.attempts,
+                        record.duration_ms as i64,
+                        record.detected_at,
+                        record.completed_at,
+                    ])
+                })
+                .map(|_| ())
+                .map_err(|e| format!("failed to record the outcome of {}: {}", record.path, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pending_ack::AckFile;
+    use crate::spill::SpillQueue;
+    use chrono::{TimeZone, Utc};
+
+    // Each backend, opened on a fresh directory, with a way to reopen it as a
+    // restart would
+    type Open<T> = fn(&Path) -> T;
+
+    fn queue_backends() -> Vec<(&'static str, Open<Box<dyn QueueStore>>)> {
+        vec![
+            ("spill file", |dir| Box::new(SpillQueue::open(&dir.join("spill/queue.txt")).unwrap())),
+            #[cfg(feature = "sqlite")]
+            ("sqlite", |dir| open_database(&dir.join("watcher.db"), "default").unwrap().queue),
+        ]
+    }
+
+    fn ack_backends() -> Vec<(&'static str, Open<Box<dyn AckStore>>)> {
+        vec![
+            ("ack file", |dir| Box::new(AckFile::new(&dir.join("acks/pending.json")).unwrap())),
+            #[cfg(feature = "sqlite")]
+            ("sqlite", |dir| open_database(&dir.join("watcher.db"), "default").unwrap().acks),
+        ]
+    }
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(|name| PathBuf::from(format!("/watch/{}", name))).collect()
+    }
+
+    #[test]
+    fn queues_hand_out_paths_oldest_first() {
+        for (backend, open) in queue_backends() {
+            let dir = tempfile::tempdir().unwrap();
+            let queue = open(dir.path());
+            for path in paths(&["a.xml", "b.xml", "c.xml"]) {
+                queue.push(&path).unwrap();
+            }
+            assert_eq!(queue.len(), 3, "{}", backend);
+            assert_eq!(queue.pop(2).unwrap(), paths(&["a.xml", "b.xml"]), "{}", backend);
+            assert_eq!(queue.len(), 1, "{}", backend);
+            assert_eq!(queue.pop(0).unwrap(), paths(&[]), "{}", backend);
+            assert_eq!(queue.pop(5).unwrap(), paths(&["c.xml"]), "{}", backend);
+            assert_eq!(queue.pop(5).unwrap(), paths(&[]), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn paths_not_completed_are_handed_out_again_after_a_restart() {
+        for (backend, open) in queue_backends() {
+            let dir = tempfile::tempdir().unwrap();
+            let queue = open(dir.path());
+            for path in paths(&["a.xml", "b.xml"]) {
+                queue.push(&path).unwrap();
+            }
+            assert_eq!(queue.pop(2).unwrap().len(), 2);
+            queue.complete().unwrap();
+            drop(queue);
+
+            let queue = open(dir.path());
+            assert_eq!(queue.len(), 2, "{}", backend);
+            assert_eq!(queue.pop(5).unwrap(), paths(&["a.xml", "b.xml"]), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn queues_start_over_once_everything_completed() {
+        for (backend, open) in queue_backends() {
+            let dir = tempfile::tempdir().unwrap();
+            let queue = open(dir.path());
+            queue.push(Path::new("/watch/a.xml")).unwrap();
+            queue.pop(1).unwrap();
+            queue.push(Path::new("/watch/b.xml")).unwrap();
+            assert_eq!(queue.pop(1).unwrap(), paths(&["b.xml"]), "{}", backend);
+            queue.complete().unwrap();
+            queue.complete().unwrap();
+            queue.push(Path::new("/watch/c.xml")).unwrap();
+            drop(queue);
+
+            let queue = open(dir.path());
+            assert_eq!(queue.pop(5).unwrap(), paths(&["c.xml"]), "{}", backend);
+            queue.complete().unwrap();
+            drop(queue);
+            assert_eq!(open(dir.path()).len(), 0, "{}", backend);
+        }
+    }
+
+    #[test]
+    fn spill_files_refuse_paths_that_arent_one_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.txt");
+        let queue = SpillQueue::open(&path).unwrap();
+        assert!(queue.push(Path::new("/watch/two\nlines.xml")).is_err());
+        assert!(queue.push(Path::new("")).is_err());
+
+        queue.push(Path::new("/watch/a.xml")).unwrap();
+        queue.pop(1).unwrap();
+        queue.complete().unwrap();
+        // Truncated, not deleted
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    fn pending(token: &str, second: u32) -> PendingAck {
+        PendingAck {
+            token: token.to_string(),
+            path: PathBuf::from(format!("/watch/{}.xml", token)),
+            status: 202,
+            detected_at: Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, second).unwrap(),
+            accepted_at: Utc.with_ymd_and_hms(2026, 1, 2, 3, 5, second).unwrap(),
+        }
+    }
+
+    #[test]
+    fn ack_stores_keep_what_was_saved_last() {
+        for (backend, open) in ack_backends() {
+            let dir = tempfile::tempdir().unwrap();
+            let store = open(dir.path());
+            assert!(store.load().unwrap().is_empty(), "{}", backend);
+            store.save(&[pending("t1", 1), pending("t2", 2)]).unwrap();
+            store.save(&[pending("t2", 2), pending("t3", 3)]).unwrap();
+            drop(store);
+
+            let loaded = open(dir.path()).load().unwrap();
+            let tokens: Vec<&str> = loaded.iter().map(|entry| entry.token.as_str()).collect();
+            assert_eq!(tokens, ["t2", "t3"], "{}", backend);
+            assert_eq!(loaded[1].path, Path::new("/watch/t3.xml"), "{}", backend);
+            assert_eq!(loaded[1].accepted_at, pending("t3", 3).accepted_at, "{}", backend);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn outcome(event_id: &str, outcome: &'static str, completed_at: &str) -> OutcomeRecord {
+        OutcomeRecord {
+            event_id: event_id.to_string(),
+            profile: None,
+            path: format!("{}.xml", event_id),
+            outcome,
+            reason: None,
+            failure_kind: (outcome == "failed").then_some("connection_refused"),
+            content_policy: None,
+            status: None,
+            attempts: 3,
+            duration_ms: 10,
+            detected_at: completed_at.to_string(),
+            completed_at: completed_at.to_string(),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn profiles_share_a_database_without_seeing_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.db");
+        let first = open_database(&path, "first").unwrap();
+        let second = open_database(&path, "second").unwrap();
+        first.queue.push(Path::new("/watch/a.xml")).unwrap();
+        first.acks.save(&[pending("t1", 1)]).unwrap();
+        assert_eq!(second.queue.len(), 0);
+        assert!(second.acks.load().unwrap().is_empty());
+        assert_eq!(second.queue.pop(5).unwrap(), paths(&[]));
+        // Reopening applies no migration twice
+        drop(open_database(&path, "first").unwrap());
+        assert_eq!(first.queue.pop(5).unwrap(), paths(&["a.xml"]));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn canned_queries_read_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.db");
+        let stores = open_database(&path, "default").unwrap();
+        stores.audit.record(&outcome("e1", "delivered", "2026-01-01T10:00:00Z")).unwrap();
+        stores.audit.record(&outcome("e2", "failed", "2026-01-01T11:00:00Z")).unwrap();
+        stores.audit.record(&outcome("e3", "failed", "2026-01-02T09:00:00Z")).unwrap();
+        // The event id is unique
+        assert!(stores.audit.record(&outcome("e3", "failed", "2026-01-02T09:00:00Z")).is_err());
+        stores.queue.push(Path::new("/watch/queued.xml")).unwrap();
+
+        let failures = run_query(&path, "recent-failures").unwrap();
+        let lines: Vec<&str> = failures.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("completed_at"));
+        assert!(lines[1].contains("e3.xml") && lines[1].contains("connection_refused"));
+        assert!(lines[2].contains("e2.xml"));
+
+        let counts = run_query(&path, "per-day-counts").unwrap();
+        let lines: Vec<Vec<&str>> = counts.lines().map(|line| line.split_whitespace().collect()).collect();
+        assert_eq!(lines[0], ["day", "profile", "outcome", "files"]);
+        assert_eq!(lines[1..], [["2026-01-02", "default", "failed", "1"], ["2026-01-01", "default", "delivered", "1"], ["2026-01-01", "default", "failed", "1"]]);
+
+        assert!(run_query(&path, "pending-retries").unwrap().contains("/watch/queued.xml"));
+        assert!(run_query(&path, "everything").unwrap_err().contains("recent-failures, pending-retries, per-day-counts"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn empty_results_say_so() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.db");
+        drop(open_database(&path, "default").unwrap());
+        assert_eq!(
+            run_query(&path, "recent-failures").unwrap(),
+            "completed_at  profile  path  outcome  failure_kind  status  attempts\n(no rows)\n"
+        );
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    #[test]
+    fn the_database_needs_the_sqlite_feature() {
+        let path = Path::new("/nonexistent/watcher.db");
+        assert!(open_database(path, "default").is_err_and(|e| e.contains("'sqlite' feature")));
+        assert!(run_query(path, "recent-failures").is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use log::{debug, Level};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::history::{notable, Kind};
+
+/// Response header through which a receiver asks watchers to slow down.
+pub const THROTTLE_HEADER: &str = "X-Watcher-Throttle";
+
+/// A rate requested by a receiver, e.g. `rate=10/m;until=2024-05-01T12:00:00Z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleRequest {
+    // Minimum time between two requests to the host
+    pub interval: Duration,
+    pub until: DateTime<Utc>,
+}
+
+impl ThrottleRequest {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut rate = None;
+        let mut until = None;
+        for part in value.split(';') {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", part.trim()))?;
+            match key.trim() {
+                "rate" => rate = Some(value.trim()),
+                "until" => until = Some(value.trim()),
+                other => return Err(format!("unknown key '{}'", other)),
+            }
+        }
+
+        let rate = rate.ok_or("missing 'rate'")?;
+        let (count, unit) = rate
+            .split_once('/')
+            .ok_or_else(|| format!("rate '{}' is not of the form N/unit", rate))?;
+        let count: u32 = count
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("invalid request count '{}'", count))?;
+        let period = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            other => return Err(format!("unknown rate unit '{}'", other)),
+        };
+
+        let until = until.ok_or("missing 'until'")?;
+        let until = DateTime::parse_from_rfc3339(until)
+            .map_err(|e| format!("invalid 'until' '{}': {}", until, e))?
+            .with_timezone(&Utc);
+
+        Ok(ThrottleRequest {
+            interval: period / count,
+            until,
+        })
+    }
+}
+
+struct HostThrottle {
+    request: ThrottleRequest,
+    next_slot: Instant,
+}
+
+/// Receiver-requested rate limits, per webhook host.
+///
+/// A receiver can only slow watchers down: a throttle spaces requests out but
+/// never lets more through than the concurrency limit would. Throttles lapse
+/// on their own once their `until` has passed.
+#[derive(Default)]
+pub struct Throttles {
+    hosts: Mutex<HashMap<String, HostThrottle>>,
+}
+
+impl Throttles {
+    /// Wait until the next request to `host` may be sent.
+    pub async fn wait(&self, host: &str) {
+        let slot = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let Some(throttle) = hosts.get_mut(host) else {
+                return;
+            };
+            if throttle.request.until <= Utc::now() {
+                hosts.remove(host);
+                notable!(
+                    Level::Info,
+                    Kind::ThrottleExpired,
+                    "Throttle requested by {} has expired, restoring the configured rate", host
+                );
+                return;
+            }
+            let slot = throttle.next_slot.max(Instant::now());
+            throttle.next_slot = slot + throttle.request.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// The throttles in force, by host.
+    pub fn summary(&self) -> serde_json::Value {
+        let now = Utc::now();
+        let hosts = self.hosts.lock().unwrap();
+        let mut active: Vec<(&String, &HostThrottle)> =
+            hosts.iter().filter(|(_, throttle)| throttle.request.until > now).collect();
+        active.sort_by_key(|(host, _)| *host);
+        active
+            .into_iter()
+            .map(|(host, throttle)| {
+                serde_json::json!({
+                    "host": host,
+                    "interval_ms": throttle.request.interval.as_millis() as u64,
+                    "until": throttle.request.until.to_rfc3339(),
+                })
+            })
+            .collect()
+    }
+
+    /// Apply the throttle header of a response from `host`, if it has one.
+    pub fn observe(&self, host: &str, header: Option<&str>) {
+        let Some(header) = header else {
+            return;
+        };
+        let request = match ThrottleRequest::parse(header) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Ignoring malformed {} header from {}: {}", THROTTLE_HEADER, host, e);
+                return;
+            }
+        };
+        if request.until <= Utc::now() {
+            return;
+        }
+
+        let mut hosts = self.hosts.lock().unwrap();
+        let unchanged = hosts.get(host).map(|t| t.request == request).unwrap_or(false);
+        if unchanged {
+            return;
+        }
+        let next_slot = hosts.get(host).map(|t| t.next_slot).unwrap_or_else(Instant::now);
+        hosts.insert(host.to_string(), HostThrottle { request, next_slot });
+        notable!(
+            Level::Info,
+            Kind::ThrottleRequested,
+            "{} asked to be throttled to one request every {} ms until {}",
+            host,
+            request.interval.as_millis(),
+            request.until.to_rfc3339()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_parsed_into_an_interval() {
+        let request = ThrottleRequest::parse("rate=10/m; until=2024-05-01T12:00:00Z").unwrap();
+        assert_eq!(request.interval, Duration::from_secs(6));
+        assert_eq!(request.until.to_rfc3339(), "2024-05-01T12:00:00+00:00");
+
+        let request = ThrottleRequest::parse("until=2024-05-01T14:00:00+02:00;rate=4/s").unwrap();
+        assert_eq!(request.interval, Duration::from_millis(250));
+        assert_eq!(request.until.to_rfc3339(), "2024-05-01T12:00:00+00:00");
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        for value in [
+            "",
+            "rate=10/m",
+            "until=2024-05-01T12:00:00Z",
+            "rate=0/m;until=2024-05-01T12:00:00Z",
+            "rate=10/d;until=2024-05-01T12:00:00Z",
+            "rate=10;until=2024-05-01T12:00:00Z",
+            "rate=10/m;until=tomorrow",
+            "rate=10/m;until=2024-05-01T12:00:00Z;burst=5",
+        ] {
+            assert!(ThrottleRequest::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn summary_lists_the_throttles_in_force() {
+        let throttles = Throttles::default();
+        let until = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        throttles.observe("b.example", Some(&format!("rate=2/s;until={}", until)));
+        throttles.observe("a.example", Some(&format!("rate=1/m;until={}", until)));
+        // Expired and malformed requests are ignored
+        throttles.observe("c.example", Some("rate=1/s;until=2000-01-01T00:00:00Z"));
+        throttles.observe("d.example", Some("rate=fast"));
+        throttles.observe("e.example", None);
+
+        let summary = throttles.summary();
+        let hosts: Vec<&str> = summary.as_array().unwrap().iter().map(|t| t["host"].as_str().unwrap()).collect();
+        assert_eq!(hosts, ["a.example", "b.example"]);
+        assert_eq!(summary[0]["interval_ms"], 60_000);
+        assert_eq!(summary[1]["interval_ms"], 500);
+    }
+}
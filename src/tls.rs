@@ -0,0 +1,558 @@
+use base64::Engine;
+use reqwest::ClientBuilder;
+use std::path::{Path, PathBuf};
+
+// In the message of a rejected chain, which is how FailureKind tells a pin
+// mismatch from other certificate problems
+#[cfg(feature = "rustls")]
+const PIN_MISMATCH: &str = "no certificate in the chain matches WEBHOOK_PINNED_SPKI_SHA256";
+
+/// How the HTTP client's connections are secured, shared by every request of
+/// a profile.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub backend: TlsBackend,
+    // PEM certificates trusted besides the system's trust store
+    pub ca_bundle: Option<PathBuf>,
+    // PEM certificate chain and private key presented to receivers that ask
+    pub client_certificate: Option<(PathBuf, PathBuf)>,
+    // With TLS_BACKEND=rustls only; empty for no pinning
+    pub pinned_spki: Vec<SpkiPin>,
+}
+
+impl TlsOptions {
+    /// Set up `builder` with the backend, trusted roots, client certificate
+    /// and pins.
+    pub fn configure(&self, builder: ClientBuilder) -> Result<ClientBuilder, String> {
+        let ca_certificates = match &self.ca_bundle {
+            Some(path) => {
+                let certificates = pem_file(path, "TLS_CA_BUNDLE")?
+                    .into_iter()
+                    .filter(|(label, _)| label == "CERTIFICATE")
+                    .map(|(_, der)| der)
+                    .collect::<Vec<_>>();
+                if certificates.is_empty() {
+                    return Err(format!("TLS_CA_BUNDLE '{}' contains no certificates", path.display()));
+                }
+                certificates
+            }
+            None => Vec::new(),
+        };
+        let client_certificate = match &self.client_certificate {
+            Some((cert, key)) => Some(ClientCertificate::load(cert, key)?),
+            None => None,
+        };
+        match self.backend {
+            TlsBackend::Native => configure_native(builder, &ca_certificates, client_certificate.as_ref()),
+            TlsBackend::Rustls => configure_rustls(builder, &ca_certificates, client_certificate.as_ref(), &self.pinned_spki),
+        }
+    }
+}
+
+// TLS_CLIENT_CERT and TLS_CLIENT_KEY, as read from their files
+struct ClientCertificate {
+    // The certificate first, then any intermediates, DER
+    #[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+    chain: Vec<Vec<u8>>,
+    #[cfg_attr(not(feature = "native-tls"), allow(dead_code))]
+    key_label: String,
+    #[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+    key: Vec<u8>,
+    // The PEM text of both, which native-tls takes as is
+    #[cfg_attr(not(feature = "native-tls"), allow(dead_code))]
+    pem: (Vec<u8>, Vec<u8>),
+}
+
+impl ClientCertificate {
+    fn load(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let chain: Vec<Vec<u8>> = pem_file(cert_path, "TLS_CLIENT_CERT")?
+            .into_iter()
+            .filter(|(label, _)| label == "CERTIFICATE")
+            .map(|(_, der)| der)
+            .collect();
+        if chain.is_empty() {
+            return Err(format!("TLS_CLIENT_CERT '{}' contains no certificates", cert_path.display()));
+        }
+        let (key_label, key) = pem_file(key_path, "TLS_CLIENT_KEY")?
+            .into_iter()
+            .find(|(label, _)| label.ends_with("PRIVATE KEY"))
+            .ok_or_else(|| format!("TLS_CLIENT_KEY '{}' contains no private key", key_path.display()))?;
+        if key_label.starts_with("ENCRYPTED") {
+            return Err(format!("TLS_CLIENT_KEY '{}' is encrypted; the key has to be unencrypted", key_path.display()));
+        }
+        let pem = (
+            std::fs::read(cert_path).map_err(|e| format!("Failed to read TLS_CLIENT_CERT: {}", e))?,
+            std::fs::read(key_path).map_err(|e| format!("Failed to read TLS_CLIENT_KEY: {}", e))?,
+        );
+        Ok(ClientCertificate { chain, key_label, key, pem })
+    }
+}
+
+#[cfg(feature = "native-tls")]
+fn configure_native(
+    mut builder: ClientBuilder,
+    ca_certificates: &[Vec<u8>],
+    client_certificate: Option<&ClientCertificate>,
+) -> Result<ClientBuilder, String> {
+    builder = builder.use_native_tls();
+    for der in ca_certificates {
+        let certificate = reqwest::Certificate::from_der(der)
+            .map_err(|e| format!("Invalid certificate in TLS_CA_BUNDLE: {}", e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    if let Some(client) = client_certificate {
+        // native-tls only reads PKCS#8 keys from PEM
+        if client.key_label != "PRIVATE KEY" {
+            return Err(format!(
+                "TLS_BACKEND=native needs TLS_CLIENT_KEY in PKCS#8 form (BEGIN PRIVATE KEY), not {}; \
+                 convert it with `openssl pkcs8 -topk8 -nocrypt` or use TLS_BACKEND=rustls",
+                client.key_label
+            ));
+        }
+        let identity = reqwest::Identity::from_pkcs8_pem(&client.pem.0, &client.pem.1)
+            .map_err(|e| format!("Invalid TLS_CLIENT_CERT or TLS_CLIENT_KEY: {}", e))?;
+        builder = builder.identity(identity);
+    }
+    Ok(builder)
+}
+
+#[cfg(not(feature = "native-tls"))]
+fn configure_native(
+    _: ClientBuilder,
+    _: &[Vec<u8>],
+    _: Option<&ClientCertificate>,
+) -> Result<ClientBuilder, String> {
+    Err(TlsBackend::Native.unavailable())
+}
+
+#[cfg(feature = "rustls")]
+fn configure_rustls(
+    builder: ClientBuilder,
+    ca_certificates: &[Vec<u8>],
+    client_certificate: Option<&ClientCertificate>,
+    pins: &[SpkiPin],
+) -> Result<ClientBuilder, String> {
+    let config = pinned::rustls_config(ca_certificates, client_certificate, pins)?;
+    Ok(builder.use_preconfigured_tls(config))
+}
+
+#[cfg(not(feature = "rustls"))]
+fn configure_rustls(
+    _: ClientBuilder,
+    _: &[Vec<u8>],
+    _: Option<&ClientCertificate>,
+    _: &[SpkiPin],
+) -> Result<ClientBuilder, String> {
+    Err(TlsBackend::Rustls.unavailable())
+}
+
+// The blocks of a PEM file, as their label and DER contents
+fn pem_file(path: &Path, name: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {} '{}': {}", name, path.display(), e))?;
+    let mut blocks = Vec::new();
+    let mut lines = text.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let Some(label) = line.strip_prefix("-----BEGIN ").and_then(|rest| rest.strip_suffix("-----")) else {
+            continue;
+        };
+        let end = format!("-----END {}-----", label);
+        let mut encoded = String::new();
+        loop {
+            match lines.next() {
+                Some(line) if line == end => break,
+                // Headers of old-style encrypted keys
+                Some(line) if line.contains(':') => {}
+                Some(line) => encoded.push_str(line),
+                None => return Err(format!("{} '{}': no {} after BEGIN {}", name, path.display(), end, label)),
+            }
+        }
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(&encoded)
+            .map_err(|e| format!("{} '{}': invalid {} block: {}", name, path.display(), label, e))?;
+        blocks.push((label.to_string(), der));
+    }
+    Ok(blocks)
+}
+
+/// The TLS implementation of the HTTP client, TLS_BACKEND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsBackend {
+    // OpenSSL through native-tls, with its trust store
+    Native,
+    // rustls with the system's trust store
+    Rustls,
+}
+
+impl TlsBackend {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let backend = match value.to_lowercase().as_str() {
+            "native" => TlsBackend::Native,
+            "rustls" => TlsBackend::Rustls,
+            other => return Err(format!("Invalid TLS_BACKEND '{}': expected 'native' or 'rustls'", other)),
+        };
+        if !backend.is_available() {
+            return Err(backend.unavailable());
+        }
+        Ok(backend)
+    }
+
+    fn unavailable(&self) -> String {
+        format!(
+            "TLS_BACKEND={} isn't available: the watcher was built without the '{}' feature",
+            self.as_str(),
+            self.feature()
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsBackend::Native => "native",
+            TlsBackend::Rustls => "rustls",
+        }
+    }
+
+    fn feature(&self) -> &'static str {
+        match self {
+            TlsBackend::Native => "native-tls",
+            TlsBackend::Rustls => "rustls",
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        match self {
+            TlsBackend::Native => cfg!(feature = "native-tls"),
+            TlsBackend::Rustls => cfg!(feature = "rustls"),
+        }
+    }
+}
+
+/// The SHA-256 hash of a certificate's subjectPublicKeyInfo, one entry of
+/// WEBHOOK_PINNED_SPKI_SHA256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpkiPin([u8; 32]);
+
+impl SpkiPin {
+    /// Parse a base64 hash, as `openssl x509 -pubkey | openssl pkey -pubin
+    /// -outform der | openssl dgst -sha256 -binary | base64` prints it. A
+    /// leading `sha256/`, as in HPKP, is accepted.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let encoded = value.trim();
+        let encoded = encoded.strip_prefix("sha256/").unwrap_or(encoded);
+        let hash = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("Invalid WEBHOOK_PINNED_SPKI_SHA256 '{}': {}", value, e))?;
+        let hash = hash.try_into().map_err(|hash: Vec<u8>| {
+            format!(
+                "Invalid WEBHOOK_PINNED_SPKI_SHA256 '{}': a SHA-256 hash is 32 bytes, not {}",
+                value,
+                hash.len()
+            )
+        })?;
+        Ok(SpkiPin(hash))
+    }
+}
+
+// The pins are checked by rustls, during the handshake
+#[cfg(feature = "rustls")]
+impl SpkiPin {
+    /// The pin of a DER-encoded X.509 certificate, or `None` if it can't be
+    /// parsed that far.
+    pub fn of_certificate(der: &[u8]) -> Option<Self> {
+        use sha2::{Digest, Sha256};
+        Some(SpkiPin(Sha256::digest(subject_public_key_info(der)?).into()))
+    }
+
+    /// Whether any certificate of a chain has one of `pins`.
+    pub fn matches_chain<'a>(pins: &[SpkiPin], chain: impl IntoIterator<Item = &'a [u8]>) -> bool {
+        chain
+            .into_iter()
+            .filter_map(SpkiPin::of_certificate)
+            .any(|pin| pins.contains(&pin))
+    }
+}
+
+// The DER of the subjectPublicKeyInfo of a certificate: the seventh element of
+// its TBSCertificate, after the optional version. Only what leads there is
+// parsed.
+#[cfg(feature = "rustls")]
+fn subject_public_key_info(der: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = der_sequence(der)?;
+    let (mut fields, _) = der_sequence(certificate)?;
+    // version [0] EXPLICIT, absent in v1 certificates
+    if fields.first() == Some(&0xA0) {
+        fields = der_element(fields)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        fields = der_element(fields)?.2;
+    }
+    let (spki, _, _) = der_element(fields)?;
+    (spki.first() == Some(&0x30)).then_some(spki)
+}
+
+// The contents of the SEQUENCE at the start of `data`, and what follows it
+#[cfg(feature = "rustls")]
+fn der_sequence(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    match der_element(data)? {
+        (element, contents, rest) if element[0] == 0x30 => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+// The DER element at the start of `data`: the whole of it, its contents and
+// what follows it. Tags in certificates fit in one byte; lengths take up to
+// four.
+#[cfg(feature = "rustls")]
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (tag, rest) = data.split_first()?;
+    if tag & 0x1F == 0x1F {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0x00..=0x7F => (first as usize, rest),
+        0x81..=0x84 => {
+            let count = (first & 0x7F) as usize;
+            let bytes = rest.get(..count)?;
+            let len = bytes.iter().fold(0usize, |len, byte| len << 8 | *byte as usize);
+            (len, &rest[count..])
+        }
+        _ => return None,
+    };
+    let contents = rest.get(..len)?;
+    let header = data.len() - rest.len();
+    Some((&data[..header + len], contents, &rest[len..]))
+}
+
+#[cfg(feature = "rustls")]
+mod pinned {
+    use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+    use rustls::{Certificate, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerName};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use super::{ClientCertificate, SpkiPin, PIN_MISMATCH};
+
+    /// A rustls configuration trusting the system's trust store and
+    /// `ca_certificates` and, with `pins`, only chains in which some
+    /// certificate has one of them.
+    pub fn rustls_config(
+        ca_certificates: &[Vec<u8>],
+        client_certificate: Option<&ClientCertificate>,
+        pins: &[SpkiPin],
+    ) -> Result<ClientConfig, String> {
+        let certificates = rustls_native_certs::load_native_certs()
+            .map_err(|e| format!("Failed to load the system's trusted certificates: {}", e))?;
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(&certificates);
+        for der in ca_certificates {
+            roots
+                .add(&Certificate(der.clone()))
+                .map_err(|e| format!("Invalid certificate in TLS_CA_BUNDLE: {}", e))?;
+        }
+        if roots.is_empty() {
+            return Err("TLS_BACKEND=rustls found no trusted certificates in the system's trust store".to_string());
+        }
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let verifier = WebPkiVerifier::new(roots, None);
+        let verifier: Arc<dyn ServerCertVerifier> = match pins {
+            [] => Arc::new(verifier),
+            pins => Arc::new(PinnedVerifier {
+                verifier,
+                pins: pins.to_vec(),
+            }),
+        };
+        let builder = builder.with_custom_certificate_verifier(verifier);
+        let mut config = match client_certificate {
+            Some(client) => builder
+                .with_client_auth_cert(
+                    client.chain.iter().cloned().map(Certificate).collect(),
+                    PrivateKey(client.key.clone()),
+                )
+                .map_err(|e| format!("Invalid TLS_CLIENT_CERT or TLS_CLIENT_KEY: {}", e))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    // The usual verification, then the pins
+    struct PinnedVerifier {
+        verifier: WebPkiVerifier,
+        pins: Vec<SpkiPin>,
+    }
+
+    impl ServerCertVerifier for PinnedVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            intermediates: &[Certificate],
+            server_name: &ServerName,
+            scts: &mut dyn Iterator<Item = &[u8]>,
+            ocsp_response: &[u8],
+            now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let verified = self
+                .verifier
+                .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+            let chain = std::iter::once(end_entity).chain(intermediates).map(|certificate| certificate.0.as_slice());
+            match SpkiPin::matches_chain(&self.pins, chain) {
+                true => Ok(verified),
+                false => Err(rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(PinMismatch)))),
+            }
+        }
+    }
+
+    // Shown through rustls' Debug formatting of the certificate error
+    struct PinMismatch;
+
+    impl std::fmt::Debug for PinMismatch {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(PIN_MISMATCH)
+        }
+    }
+
+    impl std::fmt::Display for PinMismatch {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(PIN_MISMATCH)
+        }
+    }
+
+    impl std::error::Error for PinMismatch {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CA and a certificate it issued for localhost, with the pins
+    // `openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst
+    // -sha256 -binary | base64` prints for them
+    const CA_PIN: &str = "Yuyd5nQSbRYL3avk9YtNlVcvWdhzKcqN310h2EUyL7A=";
+    #[cfg(feature = "rustls")]
+    const LOCALHOST_PIN: &str = "N7XCn0wr1r11/GECRmWkG4PP+OaUD0TTyS7Zelnq+OY=";
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[cfg(feature = "rustls")]
+    fn certificate(name: &str) -> Vec<u8> {
+        pem_file(&fixture(name), "certificate").unwrap().remove(0).1
+    }
+
+    #[test]
+    fn pins_are_parsed_from_base64() {
+        let pin = SpkiPin::parse(CA_PIN).unwrap();
+        assert_eq!(SpkiPin::parse(&format!(" sha256/{} ", CA_PIN)), Ok(pin));
+        assert!(SpkiPin::parse("not base64!").is_err());
+        // The SHA-1 length
+        assert!(SpkiPin::parse("AAAAAAAAAAAAAAAAAAAAAAAAAAA=").unwrap_err().contains("not 20"));
+    }
+
+    #[test]
+    fn pem_files_are_split_into_blocks() {
+        let blocks = pem_file(&fixture("tls-localhost-key.pem"), "TLS_CLIENT_KEY").unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "PRIVATE KEY");
+        assert!(pem_file(&fixture("missing.pem"), "TLS_CA_BUNDLE").is_err());
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn pins_of_certificates_match_openssl() {
+        let (ca, localhost) = (certificate("tls-ca.pem"), certificate("tls-localhost.pem"));
+        assert_eq!(SpkiPin::of_certificate(&ca), Some(SpkiPin::parse(CA_PIN).unwrap()));
+        assert_eq!(SpkiPin::of_certificate(&localhost), Some(SpkiPin::parse(LOCALHOST_PIN).unwrap()));
+        assert_eq!(SpkiPin::of_certificate(&localhost[..localhost.len() / 2]), None);
+        assert_eq!(SpkiPin::of_certificate(b"\x30\x03\x02\x01\x01"), None);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn any_certificate_of_the_chain_can_match() {
+        let (ca, localhost) = (certificate("tls-ca.pem"), certificate("tls-localhost.pem"));
+        let ca_pin = [SpkiPin::parse(CA_PIN).unwrap()];
+        assert!(SpkiPin::matches_chain(&ca_pin, [localhost.as_slice(), ca.as_slice()]));
+        assert!(!SpkiPin::matches_chain(&ca_pin, [localhost.as_slice()]));
+        assert!(!SpkiPin::matches_chain(&[], [localhost.as_slice(), ca.as_slice()]));
+    }
+
+    // Answers every request over TLS with an empty 200, presenting `chain`
+    #[cfg(feature = "rustls")]
+    fn tls_receiver(chain: Vec<Vec<u8>>) -> std::net::SocketAddr {
+        use std::io::{Read, Write};
+
+        let key = pem_file(&fixture("tls-localhost-key.pem"), "key").unwrap().remove(0).1;
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(chain.into_iter().map(rustls::Certificate).collect(), rustls::PrivateKey(key))
+            .unwrap();
+        let config = std::sync::Arc::new(config);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for socket in listener.incoming().flatten() {
+                let connection = rustls::ServerConnection::new(std::sync::Arc::clone(&config)).unwrap();
+                let mut stream = rustls::StreamOwned::new(connection, socket);
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                if request.ends_with(b"\r\n\r\n") {
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+                    stream.conn.send_close_notify();
+                    let _ = stream.flush();
+                }
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "rustls")]
+    async fn get_with_pins(addr: std::net::SocketAddr, pins: &[&str]) -> Result<u16, crate::failure::FailureKind> {
+        let options = TlsOptions {
+            backend: TlsBackend::Rustls,
+            ca_bundle: Some(fixture("tls-ca.pem")),
+            client_certificate: None,
+            pinned_spki: pins.iter().map(|pin| SpkiPin::parse(pin).unwrap()).collect(),
+        };
+        let client = options.configure(reqwest::Client::builder().resolve("localhost", addr)).unwrap().build().unwrap();
+        match client.get(format!("https://localhost:{}/", addr.port())).send().await {
+            Ok(response) => Ok(response.status().as_u16()),
+            Err(e) => Err(crate::failure::FailureKind::of(&e)),
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn connections_fail_closed_when_no_pin_matches() {
+        use crate::failure::FailureKind;
+
+        let (ca, localhost) = (certificate("tls-ca.pem"), certificate("tls-localhost.pem"));
+        let addr = tls_receiver(vec![localhost.clone()]);
+        assert_eq!(get_with_pins(addr, &[]).await, Ok(200));
+        assert_eq!(get_with_pins(addr, &[CA_PIN, LOCALHOST_PIN]).await, Ok(200));
+        // The CA is trusted, but only pinned if the receiver presents it
+        assert_eq!(get_with_pins(addr, &[CA_PIN]).await, Err(FailureKind::TlsPinMismatch));
+
+        let addr = tls_receiver(vec![localhost, ca]);
+        assert_eq!(get_with_pins(addr, &[CA_PIN]).await, Ok(200));
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn untrusted_certificates_are_not_pin_mismatches() {
+        use crate::failure::FailureKind;
+
+        // Presents the CA's certificate, which isn't issued for localhost
+        let addr = tls_receiver(vec![certificate("tls-ca.pem")]);
+        assert_eq!(get_with_pins(addr, &[CA_PIN]).await, Err(FailureKind::TlsCertificate));
+    }
+}
@@ -0,0 +1,314 @@
+use log::{info, warn, Level};
+use notify::event::CreateKind;
+use notify::{
+    Config as NotifyConfig, ErrorKind, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode,
+    Result as NotifyResult, Watcher,
+};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::events::{EventSink, EventSource, FileEvent, RunningSource};
+use crate::history::{notable, Kind};
+
+/// Where the events of a watch root come from, WATCH_BACKEND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchBackend {
+    // inotify or the platform's equivalent, falling back to polling per subtree
+    Native,
+    // Rescans of the whole tree, for filesystems that report no events
+    Poll,
+}
+
+impl WatchBackend {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "native" => Ok(WatchBackend::Native),
+            "poll" => Ok(WatchBackend::Poll),
+            other => Err(format!("Invalid WATCH_BACKEND '{}': expected 'native' or 'poll'", other)),
+        }
+    }
+}
+
+/// How subtrees that exceed the native watch budget are handled.
+#[derive(Debug, Clone, Copy)]
+pub struct FallbackSettings {
+    // How often the polling watcher rescans subtrees it covers
+    pub poll_interval: Duration,
+    // How often native registration is re-attempted for polled subtrees
+    pub retry_interval: Duration,
+}
+
+/// The watchers covering one watch root. Dropping it stops event delivery.
+pub struct RootWatch {
+    _native: Arc<Mutex<RecommendedWatcher>>,
+    _poller: Option<Arc<Mutex<PollWatcher>>>,
+}
+
+/// Events of the native backend, with the fallbacks of `watch_root` or, given
+/// an AUTO_WATCH_PATTERN, for the subdirectories of `watch_matching_subdirs`.
+pub struct NotifySource {
+    pub root: PathBuf,
+    pub pattern: Option<Regex>,
+    pub fallback: FallbackSettings,
+    pub prefix: String,
+}
+
+impl EventSource for NotifySource {
+    fn start(self: Box<Self>, sink: EventSink) -> Result<RunningSource, String> {
+        let prefix = self.prefix.clone();
+        let handler = move |res: NotifyResult<Event>| match res {
+            Ok(event) => sink(FileEvent::from(event)),
+            Err(e) => notable!(Level::Warn, Kind::WatchFailed, "{}Watch error: {}", prefix, e),
+        };
+        let watch = match self.pattern {
+            Some(pattern) => watch_matching_subdirs(&self.root, pattern, handler, &self.prefix)?,
+            None => watch_root(&self.root, handler, self.fallback, &self.prefix)?,
+        };
+        notable!(Level::Info, Kind::WatchRegistered, "{}Watching {}", self.prefix, self.root.display());
+        Ok(Box::new(watch))
+    }
+}
+
+/// Events found by rescanning the whole tree every `interval`, for
+/// WATCH_BACKEND=poll. Renames show up as a removal and a creation.
+pub struct PollSource {
+    pub root: PathBuf,
+    pub interval: Duration,
+    pub prefix: String,
+}
+
+impl EventSource for PollSource {
+    fn start(self: Box<Self>, sink: EventSink) -> Result<RunningSource, String> {
+        let handler = move |res: NotifyResult<Event>| {
+            if let Ok(event) = res {
+                sink(FileEvent::from(event));
+            }
+        };
+        let mut poller = PollWatcher::new(handler, NotifyConfig::default().with_poll_interval(self.interval))
+            .map_err(|e| format!("Failed to create polling watcher: {}", e))?;
+        poller
+            .watch(&self.root, RecursiveMode::Recursive)
+            .map_err(|e| format!("Failed to poll {}: {}", self.root.display(), e))?;
+        notable!(
+            Level::Info, Kind::WatchRegistered,
+            "{}Polling {} every {}s", self.prefix, self.root.display(), self.interval.as_secs()
+        );
+        Ok(Box::new(poller))
+    }
+}
+
+fn is_watch_limit(error: &notify::Error) -> bool {
+    matches!(error.kind, ErrorKind::MaxFilesWatch)
+}
+
+/// Watch `root` recursively with the native backend.
+///
+/// When the backend runs out of watch descriptors (inotify's
+/// `fs.inotify.max_user_watches`), the root is registered non-recursively and each
+/// top-level subdirectory is registered on its own. Subtrees that still don't fit
+/// are covered by a polling watcher instead, and native registration is retried
+/// periodically in case the limit was raised.
+pub fn watch_root<F>(root: &Path, handler: F, fallback: FallbackSettings, prefix: &str) -> Result<RootWatch, String>
+where
+    F: Fn(NotifyResult<Event>) + Clone + Send + 'static,
+{
+    let mut native = notify::recommended_watcher(handler.clone())
+        .map_err(|e| format!("Failed to create watcher: {}", e))?;
+
+    let failed = match native.watch(root, RecursiveMode::Recursive) {
+        Ok(_) => Vec::new(),
+        Err(e) if is_watch_limit(&e) => register_per_subtree(&mut native, root)?,
+        Err(e) => return Err(format!("Failed to watch directory: {}", e)),
+    };
+
+    let native = Arc::new(Mutex::new(native));
+    if failed.is_empty() {
+        return Ok(RootWatch { _native: native, _poller: None });
+    }
+
+    notable!(
+        Level::Warn,
+        Kind::WatchLimited,
+        "{}Watch limit reached: {} subtree(s) of {} could not be watched natively and will be polled every {}s. Raise the fs.inotify.max_user_watches sysctl to restore native events.",
+        prefix,
+        failed.len(),
+        root.display(),
+        fallback.poll_interval.as_secs()
+    );
+    for subtree in &failed {
+        warn!("{}  Polling: {}", prefix, subtree.display());
+    }
+
+    let mut poller = PollWatcher::new(handler, NotifyConfig::default().with_poll_interval(fallback.poll_interval))
+        .map_err(|e| format!("Failed to create polling watcher: {}", e))?;
+    for subtree in &failed {
+        poller
+            .watch(subtree, RecursiveMode::Recursive)
+            .map_err(|e| format!("Failed to poll {}: {}", subtree.display(), e))?;
+    }
+    let poller = Arc::new(Mutex::new(poller));
+
+    spawn_retry(
+        Arc::clone(&native),
+        Arc::clone(&poller),
+        failed,
+        fallback.retry_interval,
+        prefix.to_string(),
+    );
+
+    Ok(RootWatch { _native: native, _poller: Some(poller) })
+}
+
+// Returns the subtrees that could not be registered because of the watch limit
+fn register_per_subtree(native: &mut RecommendedWatcher, root: &Path) -> Result<Vec<PathBuf>, String> {
+    // Drop whatever the failed recursive attempt managed to register
+    native.unwatch(root).ok();
+
+    match native.watch(root, RecursiveMode::NonRecursive) {
+        Ok(_) => {}
+        Err(e) if is_watch_limit(&e) => return Ok(vec![root.to_path_buf()]),
+        Err(e) => return Err(format!("Failed to watch directory: {}", e)),
+    }
+
+    let entries = std::fs::read_dir(root).map_err(|e| format!("Failed to list {}: {}", root.display(), e))?;
+    let mut failed = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        match native.watch(&path, RecursiveMode::Recursive) {
+            Ok(_) => {}
+            Err(e) if is_watch_limit(&e) => {
+                native.unwatch(&path).ok();
+                failed.push(path);
+            }
+            Err(e) => return Err(format!("Failed to watch {}: {}", path.display(), e)),
+        }
+    }
+    Ok(failed)
+}
+
+fn spawn_retry(
+    native: Arc<Mutex<RecommendedWatcher>>,
+    poller: Arc<Mutex<PollWatcher>>,
+    mut pending: Vec<PathBuf>,
+    interval: Duration,
+    prefix: String,
+) {
+    tokio::spawn(async move {
+        while !pending.is_empty() {
+            tokio::time::sleep(interval).await;
+            let mut native = native.lock().unwrap();
+            let mut poller = poller.lock().unwrap();
+            pending.retain(|subtree| match native.watch(subtree, RecursiveMode::Recursive) {
+                Ok(_) => {
+                    poller.unwatch(subtree).ok();
+                    notable!(Level::Info, Kind::WatchRestored, "{}Native watch restored for {}", prefix, subtree.display());
+                    false
+                }
+                Err(_) => {
+                    native.unwatch(subtree).ok();
+                    true
+                }
+            });
+            if !pending.is_empty() {
+                warn!("{}{} subtree(s) still polled because of the watch limit", prefix, pending.len());
+            }
+        }
+    });
+}
+
+/// Watch `root` non-recursively and, recursively, each subdirectory whose name
+/// matches `pattern`, including subdirectories created later.
+///
+/// Files already present in a subdirectory when it is picked up are reported as
+/// created, so that files written right after the directory appeared aren't lost.
+pub fn watch_matching_subdirs<F>(root: &Path, pattern: Regex, handler: F, prefix: &str) -> Result<RootWatch, String>
+where
+    F: Fn(NotifyResult<Event>) + Clone + Send + 'static,
+{
+    // Watches can't be added from inside the event handler (the backend would
+    // wait on itself), so new directories are handed to a registration thread
+    let (new_dirs, pending) = channel::<PathBuf>();
+    let root_path = root.to_path_buf();
+    let forward = handler.clone();
+    let mut native = notify::recommended_watcher(move |res: NotifyResult<Event>| {
+        if let Ok(event) = &res {
+            if matches!(event.kind, EventKind::Create(_)) {
+                for path in &event.paths {
+                    if path.parent() == Some(root_path.as_path()) && path.is_dir() {
+                        new_dirs.send(path.clone()).ok();
+                    }
+                }
+            }
+        }
+        forward(res);
+    })
+    .map_err(|e| format!("Failed to create watcher: {}", e))?;
+
+    native
+        .watch(root, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("Failed to watch directory: {}", e))?;
+
+    let mut matching = Vec::new();
+    let entries = std::fs::read_dir(root).map_err(|e| format!("Failed to list {}: {}", root.display(), e))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() && name_matches(&pattern, &path) {
+            native
+                .watch(&path, RecursiveMode::Recursive)
+                .map_err(|e| format!("Failed to watch {}: {}", path.display(), e))?;
+            matching.push(path);
+        }
+    }
+    info!("{}  Watching {} matching subdirectories", prefix, matching.len());
+
+    let native = Arc::new(Mutex::new(native));
+    let registrar = Arc::clone(&native);
+    let prefix = prefix.to_string();
+    std::thread::spawn(move || {
+        for dir in pending {
+            if !name_matches(&pattern, &dir) {
+                continue;
+            }
+            if let Err(e) = registrar.lock().unwrap().watch(&dir, RecursiveMode::Recursive) {
+                notable!(Level::Error, Kind::WatchFailed, "{}Failed to watch new directory {}: {}", prefix, dir.display(), e);
+                continue;
+            }
+            notable!(Level::Info, Kind::WatchRegistered, "{}Watching new directory {}", prefix, dir.display());
+            report_existing_files(&dir, &handler);
+        }
+    });
+
+    Ok(RootWatch { _native: native, _poller: None })
+}
+
+/// Whether the name of `path` matches AUTO_WATCH_PATTERN.
+pub fn name_matches(pattern: &Regex, path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| pattern.is_match(name))
+        .unwrap_or(false)
+}
+
+// Report every file below `dir` as created
+fn report_existing_files<F>(dir: &Path, handler: &F)
+where
+    F: Fn(NotifyResult<Event>),
+{
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            report_existing_files(&path, handler);
+        } else {
+            handler(Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(path)));
+        }
+    }
+}
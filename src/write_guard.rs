@@ -0,0 +1,337 @@
+use log::error;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+/// A feature that modifies files in the watched tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WriteCapability {
+    Overwrite,
+    Backup,
+    Quarantine,
+    RetryLater,
+    CatchupDefer,
+}
+
+impl WriteCapability {
+    pub const ALL: &'static [WriteCapability] = &[
+        WriteCapability::Overwrite,
+        WriteCapability::Backup,
+        WriteCapability::Quarantine,
+        WriteCapability::RetryLater,
+        WriteCapability::CatchupDefer,
+    ];
+
+    // The configuration option that enables the capability
+    pub fn feature(&self) -> &'static str {
+        match self {
+            WriteCapability::Overwrite => "OVERWRITE_WITH_RESPONSE",
+            WriteCapability::Backup => "BACKUP_BEFORE_OVERWRITE",
+            WriteCapability::Quarantine => "PRE_DELIVERY_FAILURE=quarantine",
+            WriteCapability::RetryLater => "RETRY_LATER_DIR",
+            WriteCapability::CatchupDefer => "CATCHUP_DEFER_DIR",
+        }
+    }
+}
+
+/// Single gate for every filesystem mutation the watcher performs.
+///
+/// A write is refused unless the capability behind it was enabled in the
+/// configuration and `READ_ONLY` is not set. Independently of that, every
+/// path written, created or removed must resolve to a location inside one of
+/// the allowed directories (the watch directory and the configured backup,
+/// quarantine, retry-later and catch-up directories), after following symlinks.
+#[derive(Debug, Clone)]
+pub struct WriteGuard {
+    enabled: HashSet<WriteCapability>,
+    read_only: bool,
+    allowed_dirs: Vec<PathBuf>,
+}
+
+impl WriteGuard {
+    pub fn new(requested: &[WriteCapability], read_only: bool, allowed_dirs: Vec<PathBuf>) -> Self {
+        let enabled = if read_only {
+            HashSet::new()
+        } else {
+            requested.iter().copied().collect()
+        };
+        WriteGuard { enabled, read_only, allowed_dirs }
+    }
+
+    pub fn allows(&self, capability: WriteCapability) -> bool {
+        self.enabled.contains(&capability)
+    }
+
+    pub fn check(&self, capability: WriteCapability, path: &Path) -> Result<(), String> {
+        if self.allows(capability) {
+            return self.check_confined(capability, path);
+        }
+        let reason = if self.read_only {
+            "READ_ONLY is enabled".to_string()
+        } else {
+            format!("{} is not enabled", capability.feature())
+        };
+        Err(format!(
+            "refusing to write {} for {}: {}",
+            path.display(),
+            capability.feature(),
+            reason
+        ))
+    }
+
+    // Refuse paths outside the allowed directories. This should never trigger:
+    // when it does, a path computation has gone wrong, which is logged loudly.
+    fn check_confined(&self, capability: WriteCapability, path: &Path) -> Result<(), String> {
+        let refuse = |reason: String| {
+            error!(
+                "Refused to write {} for {}: {}. This is a bug or a dangerous configuration",
+                path.display(),
+                capability.feature(),
+                reason
+            );
+            Err(format!("refusing to write {} for {}: {}", path.display(), capability.feature(), reason))
+        };
+
+        let resolved = match resolve(path) {
+            Ok(resolved) => resolved,
+            Err(reason) => return refuse(reason),
+        };
+        let inside = self
+            .allowed_dirs
+            .iter()
+            .filter_map(|dir| resolve(dir).ok())
+            .any(|dir| resolved.starts_with(dir));
+        if inside {
+            Ok(())
+        } else {
+            refuse(format!("{} is outside the watch, backup, quarantine, retry-later and catch-up directories", resolved.display()))
+        }
+    }
+
+    pub async fn write(&self, capability: WriteCapability, path: &Path, contents: &[u8]) -> Result<(), String> {
+        self.check(capability, path)?;
+        tokio::fs::write(path, contents)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Copy `from` to `to`, creating the destination's parent directories.
+    pub async fn copy(&self, capability: WriteCapability, from: &Path, to: &Path) -> Result<(), String> {
+        self.check(capability, to)?;
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        tokio::fs::copy(from, to)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Move `from` to `to`, creating the destination's parent directories.
+    ///
+    /// Falls back to copy and delete when the two are on different filesystems.
+    pub async fn rename(&self, capability: WriteCapability, from: &Path, to: &Path) -> Result<(), String> {
+        self.check(capability, from)?;
+        self.check(capability, to)?;
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        if tokio::fs::rename(from, to).await.is_ok() {
+            return Ok(());
+        }
+        tokio::fs::copy(from, to)
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::fs::remove_file(from)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Human readable summary of the effective mode for the startup banner.
+    pub fn describe(&self) -> String {
+        let mut features: Vec<&str> = WriteCapability::ALL
+            .iter()
+            .filter(|capability| self.allows(**capability))
+            .map(|capability| capability.feature())
+            .collect();
+        if features.is_empty() {
+            return if self.read_only {
+                "read-only (READ_ONLY=true)".to_string()
+            } else {
+                "read-only".to_string()
+            };
+        }
+        features.sort_unstable();
+        format!("read-write ({})", features.join(", "))
+    }
+}
+
+/// The absolute location `path` refers to, following symlinks in the part of
+/// it that exists. Paths with `..` components are rejected rather than
+/// resolved, since the directories they would pass through may not exist yet.
+pub fn resolve(path: &Path) -> Result<PathBuf, String> {
+    if path.components().any(|component| component == Component::ParentDir) {
+        return Err(format!("{} contains '..'", path.display()));
+    }
+    let absolute = std::path::absolute(path).map_err(|e| format!("failed to resolve {}: {}", path.display(), e))?;
+
+    // Canonicalize the deepest existing ancestor and append the rest
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                return Ok(missing.iter().rev().fold(canonical, |resolved, name| resolved.join(name)));
+            }
+            // A dangling symlink would be followed when written to
+            Err(_) if existing.symlink_metadata().is_ok() => {
+                return Err(format!("{} is a dangling symlink", existing.display()));
+            }
+            Err(_) => {
+                let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                    return Err(format!("failed to resolve {}", path.display()));
+                };
+                missing.push(name);
+                existing = parent;
+            }
+        }
+    }
+}
+
+/// Replace the file at `path` with `contents`, so that a crash never leaves it
+/// half written: the data goes to a temporary file next to it, which is flushed
+/// to disk and then renamed over it.
+///
+/// For the watcher's own state files, outside the watched tree, which need no
+/// `WriteGuard`.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let temporary = path.with_extension("tmp");
+    File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|e| format!("failed to save {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn each_capability_writes_only_when_enabled() {
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("order.xml");
+        for capability in WriteCapability::ALL {
+            let allowed = WriteGuard::new(&[*capability], false, vec![root.path().to_path_buf()]);
+            let others: Vec<WriteCapability> =
+                WriteCapability::ALL.iter().copied().filter(|other| other != capability).collect();
+            let refused = WriteGuard::new(&others, false, vec![root.path().to_path_buf()]);
+            let read_only = WriteGuard::new(WriteCapability::ALL, true, vec![root.path().to_path_buf()]);
+
+            assert!(allowed.write(*capability, &target, b"<a/>").await.is_ok());
+            let error = refused.write(*capability, &target, b"<b/>").await.unwrap_err();
+            assert!(error.contains(&format!("{} is not enabled", capability.feature())), "{}", error);
+            let error = read_only.write(*capability, &target, b"<b/>").await.unwrap_err();
+            assert!(error.contains("READ_ONLY is enabled"), "{}", error);
+            assert_eq!(std::fs::read(&target).unwrap(), b"<a/>");
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_copies_and_renames_touch_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let from = root.path().join("order.xml");
+        std::fs::write(&from, "<a/>").unwrap();
+        let guard = WriteGuard::new(&[WriteCapability::Overwrite], false, vec![root.path().to_path_buf()]);
+
+        let backup = root.path().join("backup/order.xml");
+        assert!(guard.copy(WriteCapability::Backup, &from, &backup).await.is_err());
+        let quarantined = root.path().join("quarantine/order.xml");
+        assert!(guard.rename(WriteCapability::Quarantine, &from, &quarantined).await.is_err());
+        assert!(from.exists());
+        assert!(!root.path().join("backup").exists() && !root.path().join("quarantine").exists());
+
+        let enabled = [WriteCapability::Backup, WriteCapability::Quarantine];
+        let guard = WriteGuard::new(&enabled, false, vec![root.path().to_path_buf()]);
+        guard.copy(WriteCapability::Backup, &from, &backup).await.unwrap();
+        guard.rename(WriteCapability::Quarantine, &from, &quarantined).await.unwrap();
+        assert!(!from.exists() && backup.exists() && quarantined.exists());
+    }
+
+    #[test]
+    fn the_banner_names_the_enabled_features() {
+        let guard = WriteGuard::new(&[WriteCapability::RetryLater, WriteCapability::Overwrite], false, Vec::new());
+        assert_eq!(guard.describe(), "read-write (OVERWRITE_WITH_RESPONSE, RETRY_LATER_DIR)");
+        assert_eq!(WriteGuard::new(&[], false, Vec::new()).describe(), "read-only");
+        let read_only = WriteGuard::new(WriteCapability::ALL, true, Vec::new());
+        assert_eq!(read_only.describe(), "read-only (READ_ONLY=true)");
+        assert!(WriteCapability::ALL.iter().all(|capability| !read_only.allows(*capability)));
+    }
+
+    fn confined_to(root: &Path) -> WriteGuard {
+        WriteGuard::new(WriteCapability::ALL, false, vec![root.to_path_buf()])
+    }
+
+    #[test]
+    fn parent_components_are_refused_even_when_they_stay_inside() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("sub")).unwrap();
+        let guard = confined_to(root.path());
+        assert!(resolve(&root.path().join("sub/../a.xml")).unwrap_err().contains("'..'"));
+        assert!(guard.check(WriteCapability::Overwrite, &root.path().join("sub/../a.xml")).is_err());
+        assert!(guard.check(WriteCapability::Overwrite, &root.path().join("../outside.xml")).is_err());
+    }
+
+    #[test]
+    fn symlinks_are_followed_before_confining() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let watch = root.path().join("watch");
+        std::fs::create_dir_all(watch.join("real")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), watch.join("escape")).unwrap();
+        std::os::unix::fs::symlink(watch.join("real"), watch.join("alias")).unwrap();
+        std::os::unix::fs::symlink(watch.join("missing"), watch.join("dangling.xml")).unwrap();
+        let guard = confined_to(&watch);
+
+        assert!(guard.check(WriteCapability::Overwrite, &watch.join("escape/a.xml")).is_err());
+        assert!(guard.check(WriteCapability::Backup, &watch.join("escape/new/dir/a.xml")).is_err());
+        assert!(guard.check(WriteCapability::Overwrite, &watch.join("alias/a.xml")).is_ok());
+        let resolved = resolve(&watch.join("alias/new/a.xml")).unwrap();
+        assert_eq!(resolved, watch.canonicalize().unwrap().join("real/new/a.xml"));
+        assert!(resolve(&watch.join("dangling.xml")).unwrap_err().contains("dangling symlink"));
+        assert!(guard.check(WriteCapability::Overwrite, &watch.join("dangling.xml")).is_err());
+    }
+
+    #[test]
+    fn absolute_names_joined_onto_a_directory_are_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let guard = confined_to(root.path());
+        // What a template producing an absolute path turns a join into
+        let smuggled = root.path().join(outside.path().join("a.xml"));
+        assert_eq!(smuggled, outside.path().join("a.xml"));
+        assert!(guard.check(WriteCapability::RetryLater, &smuggled).is_err());
+        assert!(guard.check(WriteCapability::RetryLater, Path::new("/etc/passwd")).is_err());
+        assert!(guard.check(WriteCapability::RetryLater, &root.path().join("retry/a.xml")).is_ok());
+    }
+
+    #[test]
+    fn atomic_writes_replace_the_whole_file() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("state.json");
+        write_atomically(&path, b"[1, 2, 3]").unwrap();
+        write_atomically(&path, b"[]").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"[]");
+        assert!(!root.path().join("state.tmp").exists());
+
+        let error = write_atomically(&root.path().join("missing/state.json"), b"[]").unwrap_err();
+        assert!(error.starts_with("failed to save"), "{}", error);
+    }
+}
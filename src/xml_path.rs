@@ -0,0 +1,94 @@
+use std::fmt;
+
+/// An absolute element path such as `/Response/Status`, optionally ending in
+/// an attribute (`/Response/@status`): the small subset of XPath the watcher
+/// uses to pick values out of XML documents.
+///
+/// Element names are compared without namespace prefixes, and the first
+/// matching element wins.
+#[derive(Debug, Clone)]
+pub struct XmlPath {
+    steps: Vec<String>,
+    attribute: Option<String>,
+}
+
+impl XmlPath {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let relative = path
+            .strip_prefix('/')
+            .ok_or_else(|| format!("path '{}' must start at the root element", path))?;
+        let mut steps: Vec<String> = relative.split('/').map(str::to_string).collect();
+        let attribute = match steps.last().and_then(|last| last.strip_prefix('@')) {
+            Some(attribute) => {
+                let attribute = attribute.to_string();
+                steps.pop();
+                Some(attribute)
+            }
+            None => None,
+        };
+        if steps.is_empty() || steps.iter().any(|step| !is_element_name(step)) {
+            return Err(format!(
+                "unsupported path '{}': only absolute element paths like /Response/Status or /Response/@status are supported",
+                path
+            ));
+        }
+        Ok(XmlPath { steps, attribute })
+    }
+
+    /// The trimmed text (or attribute value) at the path in `document`.
+    pub fn find(&self, document: &roxmltree::Document) -> Option<String> {
+        let root = document.root_element();
+        if root.tag_name().name() != self.steps[0] {
+            return None;
+        }
+        let mut candidates = vec![root];
+        for step in &self.steps[1..] {
+            candidates = candidates
+                .iter()
+                .flat_map(|node| node.children())
+                .filter(|child| child.is_element() && child.tag_name().name() == step)
+                .collect();
+        }
+        let node = candidates.into_iter().next()?;
+        let value = match &self.attribute {
+            Some(attribute) => node.attribute(attribute.as_str())?.to_string(),
+            None => node
+                .descendants()
+                .filter(|descendant| descendant.is_text())
+                .filter_map(|text| text.text())
+                .collect(),
+        };
+        Some(value.trim().to_string())
+    }
+
+    /// Parse `data` and look the path up in it.
+    pub fn find_in(&self, data: &[u8]) -> Result<Option<String>, String> {
+        let text = std::str::from_utf8(data).map_err(|e| format!("not valid UTF-8: {}", e))?;
+        let document = parse_document(text)?;
+        Ok(self.find(&document))
+    }
+}
+
+impl fmt::Display for XmlPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.steps.join("/"))?;
+        if let Some(attribute) = &self.attribute {
+            write!(f, "/@{}", attribute)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an XML document for `XmlPath` lookups. Documents with a DOCTYPE are
+/// accepted; entity expansion stays bounded by the parser.
+pub fn parse_document(text: &str) -> Result<roxmltree::Document<'_>, String> {
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    roxmltree::Document::parse_with_options(text, options).map_err(|e| format!("not valid XML: {}", e))
+}
+
+fn is_element_name(step: &str) -> bool {
+    !step.is_empty() && step != "." && step != ".." && !step.contains(['[', ']', '(', ')', '*', '@', '='])
+}